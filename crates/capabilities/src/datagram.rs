@@ -0,0 +1,154 @@
+// Copyright 2026-Present Datadog, Inc. https://www.datadoghq.com/
+// SPDX-License-Identifier: Apache-2.0
+
+//! Wasm datagram socket backed by Node.js `dgram` (UDP) and `net` (Unix
+//! domain sockets).
+//!
+//! Used for DogStatsD, which is fire-and-forget: sends never block or fail
+//! the caller, and a socket that errors is reopened by the JS side on the
+//! next send. Node has no Unix datagram sockets, so only DogStatsD's
+//! `unixstream://` sockets are supported: each message is written with its
+//! little-endian `u32` length in front. `unix://` (a `SOCK_DGRAM` socket, the
+//! agent's default) is rejected rather than sent framing it can't read.
+
+use std::io;
+
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen(module = "/src/http_transport.js")]
+extern "C" {
+    #[wasm_bindgen(js_name = "datagramOpen")]
+    fn js_datagram_open(kind: &str, host_or_path: &str, port: u16) -> JsValue;
+
+    #[wasm_bindgen(js_name = "datagramSend")]
+    fn js_datagram_send(socket: &JsValue, payload: &[u8]);
+
+    #[wasm_bindgen(js_name = "datagramClose")]
+    fn js_datagram_close(socket: &JsValue);
+}
+
+/// Default DogStatsD port when a `udp://` URL doesn't give one.
+const DEFAULT_UDP_PORT: u16 = 8125;
+
+/// Where a [`WasmDatagramSocket`] sends.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DatagramTarget {
+    Udp { host: String, port: u16 },
+    UnixStream { path: String },
+}
+
+impl DatagramTarget {
+    /// Parse `udp://host[:port]` or `unixstream:///path/to/socket`.
+    pub fn parse(url: &str) -> io::Result<Self> {
+        let invalid =
+            |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("{msg}: {url}"));
+        if let Some(path) = url.strip_prefix("unixstream://") {
+            if path.is_empty() {
+                return Err(invalid("missing socket path"));
+            }
+            return Ok(DatagramTarget::UnixStream {
+                path: path.to_string(),
+            });
+        }
+        if url.starts_with("unix://") {
+            return Err(invalid(
+                "unix datagram sockets are not supported, use unixstream:// or udp://",
+            ));
+        }
+        let authority = url
+            .strip_prefix("udp://")
+            .ok_or_else(|| invalid("unsupported datagram URL scheme"))?
+            .trim_end_matches('/');
+        // `[::1]:8125`, `[::1]`, `host:8125` or `host`.
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                let port = port.parse().map_err(|_| invalid("invalid port"))?;
+                (host, port)
+            }
+            _ => (authority, DEFAULT_UDP_PORT),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(invalid("missing host"));
+        }
+        Ok(DatagramTarget::Udp {
+            host: host.to_string(),
+            port,
+        })
+    }
+
+    /// Largest payload worth packing into one send: a single Ethernet-MTU UDP
+    /// datagram, or DogStatsD's default buffer for Unix stream sockets.
+    pub fn max_payload(&self) -> usize {
+        match self {
+            DatagramTarget::Udp { .. } => 1432,
+            DatagramTarget::UnixStream { .. } => 8192,
+        }
+    }
+}
+
+/// A fire-and-forget datagram socket. Closed on drop.
+#[derive(Debug)]
+pub struct WasmDatagramSocket {
+    target: DatagramTarget,
+    socket: JsValue,
+}
+
+impl WasmDatagramSocket {
+    pub fn open(target: DatagramTarget) -> Self {
+        let socket = match &target {
+            DatagramTarget::Udp { host, port } => js_datagram_open("udp", host, *port),
+            DatagramTarget::UnixStream { path } => js_datagram_open("unixstream", path, 0),
+        };
+        WasmDatagramSocket { target, socket }
+    }
+
+    pub fn target(&self) -> &DatagramTarget {
+        &self.target
+    }
+
+    /// Queue one datagram. Delivery errors are swallowed on the JS side.
+    pub fn send(&self, payload: &[u8]) {
+        js_datagram_send(&self.socket, payload);
+    }
+}
+
+impl Drop for WasmDatagramSocket {
+    fn drop(&mut self) {
+        js_datagram_close(&self.socket);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_targets() {
+        assert_eq!(
+            DatagramTarget::parse("udp://127.0.0.1:9125").unwrap(),
+            DatagramTarget::Udp {
+                host: "127.0.0.1".into(),
+                port: 9125
+            }
+        );
+        assert_eq!(
+            DatagramTarget::parse("udp://[::1]").unwrap(),
+            DatagramTarget::Udp {
+                host: "::1".into(),
+                port: DEFAULT_UDP_PORT
+            }
+        );
+        assert_eq!(
+            DatagramTarget::parse("unixstream:///var/run/datadog/dsd.socket").unwrap(),
+            DatagramTarget::UnixStream {
+                path: "/var/run/datadog/dsd.socket".into()
+            }
+        );
+        assert!(DatagramTarget::parse("http://localhost:8125").is_err());
+        assert!(DatagramTarget::parse("udp://host:port").is_err());
+        assert!(DatagramTarget::parse("unixstream://").is_err());
+        let err = DatagramTarget::parse("unix:///var/run/datadog/dsd.socket").unwrap_err();
+        assert!(err.to_string().contains("unixstream://"));
+    }
+}
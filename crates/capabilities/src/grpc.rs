@@ -0,0 +1,597 @@
+// Copyright 2026-Present Datadog, Inc. https://www.datadoghq.com/
+// SPDX-License-Identifier: Apache-2.0
+
+//! Wasm gRPC transport for OTLP trace export, backed by Node.js `http2`.
+//!
+//! libdatadog's OTLP exporter only speaks HTTP (`http/json`,
+//! `http/protobuf`). For `grpc` the pipeline configures it for
+//! `http/protobuf` and polls its sends under [`with_otlp_grpc`]; requests to
+//! the configured endpoint are then carried as unary calls to the OTLP
+//! `TraceService/Export` method instead. An OTLP/HTTP protobuf body is already
+//! an `ExportTraceServiceRequest`, gRPC's request message, so only the framing
+//! and the status reporting change.
+//!
+//! Calls failing with a retryable gRPC status are retried here with
+//! exponential backoff (honouring `grpc-retry-pushback-ms`). The final outcome
+//! is handed back to libdatadog as the equivalent OTLP/HTTP response, except
+//! that a call this layer gave up retrying answers with a non-retryable 500 so
+//! libdatadog doesn't retry it again. The status it actually failed with is
+//! reported separately, as the [`GrpcStatusError`] [`with_otlp_grpc`] returns.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use bytes::Bytes;
+use http::HeaderMap;
+use js_sys::{Array, JsString, Number, Uint8Array};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+
+use libdd_capabilities::http::HttpError;
+use libdd_capabilities::sleep::SleepCapability;
+
+use crate::http::{current_request_signal, current_tls_options, parse_response_headers};
+use crate::WasmSleepCapability;
+
+#[wasm_bindgen(module = "/src/http_transport.js")]
+extern "C" {
+    #[wasm_bindgen(js_name = "grpcRequest")]
+    fn js_grpc_request(
+        origin: &str,
+        path: &str,
+        headers: Vec<String>,
+        body: &[u8],
+        signal: &JsValue,
+        tls_options: &JsValue,
+    ) -> js_sys::Promise;
+}
+
+/// The OTLP trace service's unary export method.
+pub const EXPORT_PATH: &str = "/opentelemetry.proto.collector.trace.v1.TraceService/Export";
+
+/// Attempts per export, including the first.
+const MAX_ATTEMPTS: u32 = 4;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+/// What libdatadog is told once the retries of a retryable code are over:
+/// the 429/503/504 those codes map to would have it retry on its own.
+const GAVE_UP_HTTP_STATUS: u16 = 500;
+
+/// gRPC status codes the transport acts on.
+pub mod code {
+    pub const OK: u32 = 0;
+    pub const CANCELLED: u32 = 1;
+    pub const UNKNOWN: u32 = 2;
+    pub const INVALID_ARGUMENT: u32 = 3;
+    pub const DEADLINE_EXCEEDED: u32 = 4;
+    pub const NOT_FOUND: u32 = 5;
+    pub const PERMISSION_DENIED: u32 = 7;
+    pub const RESOURCE_EXHAUSTED: u32 = 8;
+    pub const ABORTED: u32 = 10;
+    pub const OUT_OF_RANGE: u32 = 11;
+    pub const UNIMPLEMENTED: u32 = 12;
+    pub const INTERNAL: u32 = 13;
+    pub const UNAVAILABLE: u32 = 14;
+    pub const DATA_LOSS: u32 = 15;
+    pub const UNAUTHENTICATED: u32 = 16;
+}
+
+/// Whether an export failing with `code` may succeed if sent again, per the
+/// OTLP specification's list of retryable gRPC codes.
+pub fn is_retryable(code: u32) -> bool {
+    matches!(
+        code,
+        code::CANCELLED
+            | code::DEADLINE_EXCEEDED
+            | code::RESOURCE_EXHAUSTED
+            | code::ABORTED
+            | code::OUT_OF_RANGE
+            | code::UNAVAILABLE
+            | code::DATA_LOSS
+    )
+}
+
+/// The OTLP/HTTP status equivalent to a gRPC status.
+pub fn http_status(code: u32) -> u16 {
+    match code {
+        code::OK => 200,
+        code::INVALID_ARGUMENT | code::OUT_OF_RANGE => 400,
+        code::UNAUTHENTICATED => 401,
+        code::PERMISSION_DENIED => 403,
+        code::NOT_FOUND => 404,
+        code::RESOURCE_EXHAUSTED => 429,
+        code::UNIMPLEMENTED => 501,
+        code::UNAVAILABLE => 503,
+        code::DEADLINE_EXCEEDED => 504,
+        _ => 500,
+    }
+}
+
+/// The gRPC status for a response that carried no `grpc-status`, from its
+/// HTTP status (gRPC's "HTTP to gRPC status code mapping").
+pub fn status_from_http(status: u16) -> u32 {
+    match status {
+        400 => code::INTERNAL,
+        401 => code::UNAUTHENTICATED,
+        403 => code::PERMISSION_DENIED,
+        404 => code::UNIMPLEMENTED,
+        429 | 502..=504 => code::UNAVAILABLE,
+        _ => code::UNKNOWN,
+    }
+}
+
+/// Prefix `message` with gRPC's message framing: an uncompressed flag byte
+/// and the big-endian `u32` length.
+pub fn frame_message(message: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(5 + message.len());
+    framed.push(0);
+    framed.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    framed.extend_from_slice(message);
+    framed
+}
+
+/// The single message of a unary response body. An empty body is an empty
+/// message (the trailers-only form of a response).
+pub fn unframe_message(body: &[u8]) -> Result<&[u8], String> {
+    if body.is_empty() {
+        return Ok(body);
+    }
+    let Some((header, rest)) = body.split_first_chunk::<5>() else {
+        return Err(format!(
+            "truncated gRPC message header ({} bytes)",
+            body.len()
+        ));
+    };
+    if header[0] != 0 {
+        return Err("compressed gRPC message was not requested".to_string());
+    }
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    rest.get(..len).ok_or_else(|| {
+        format!(
+            "truncated gRPC message: {} of {len} bytes",
+            rest.len().min(len)
+        )
+    })
+}
+
+/// Decode a `grpc-message` value (percent-encoded UTF-8). Malformed escapes
+/// are kept as-is rather than losing the message.
+pub fn decode_grpc_message(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(byte) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                out.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// An export that still failed with a retryable status once the retries ran
+/// out.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GrpcStatusError {
+    pub code: u32,
+    pub message: String,
+    /// The server's last `grpc-retry-pushback-ms`, when it asked for a wait.
+    pub retry_after: Option<Duration>,
+}
+
+impl GrpcStatusError {
+    /// The OTLP/HTTP status equivalent to the failure.
+    pub fn http_status(&self) -> u16 {
+        http_status(self.code)
+    }
+}
+
+impl fmt::Display for GrpcStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "grpc-status {}: {}", self.code, self.message)
+    }
+}
+
+/// The outcome of one call: `grpc-status`, `grpc-message` and the response
+/// message.
+#[derive(Debug)]
+pub struct CallStatus {
+    pub code: u32,
+    pub message: String,
+    pub body: Bytes,
+    /// The server's `grpc-retry-pushback-ms`: wait this long before retrying,
+    /// or don't retry at all when negative.
+    pub pushback: Option<i64>,
+}
+
+impl CallStatus {
+    /// Read the status from the trailers, or from the headers for a
+    /// trailers-only response, falling back on the HTTP status.
+    pub fn from_response(
+        http_status: u16,
+        headers: &HeaderMap,
+        trailers: &HeaderMap,
+        body: &[u8],
+    ) -> Self {
+        let get = |name: &str| {
+            trailers
+                .get(name)
+                .or_else(|| headers.get(name))
+                .and_then(|v| v.to_str().ok())
+        };
+        let message = get("grpc-message")
+            .map(decode_grpc_message)
+            .unwrap_or_default();
+        let pushback = get("grpc-retry-pushback-ms").and_then(|v| v.trim().parse().ok());
+        let code = match get("grpc-status").and_then(|v| v.trim().parse().ok()) {
+            Some(code) => code,
+            None if http_status != 200 => status_from_http(http_status),
+            None => {
+                return CallStatus {
+                    code: code::INTERNAL,
+                    message: "response carried no grpc-status".to_string(),
+                    body: Bytes::new(),
+                    pushback,
+                }
+            }
+        };
+        if code != code::OK {
+            return CallStatus {
+                code,
+                message,
+                body: Bytes::new(),
+                pushback,
+            };
+        }
+        match unframe_message(body) {
+            Ok(message_bytes) => CallStatus {
+                code,
+                message,
+                body: Bytes::copy_from_slice(message_bytes),
+                pushback,
+            },
+            Err(e) => CallStatus {
+                code: code::INTERNAL,
+                message: e,
+                body: Bytes::new(),
+                pushback,
+            },
+        }
+    }
+
+    /// How long to wait before attempt `attempt + 1`, or `None` to stop. A
+    /// pushback longer than [`MAX_BACKOFF`] stops too: the export is not held
+    /// up that long.
+    pub fn retry_delay(&self, attempt: u32) -> Option<Duration> {
+        if !is_retryable(self.code) || attempt + 1 >= MAX_ATTEMPTS {
+            return None;
+        }
+        match self.pushback {
+            Some(ms) if ms < 0 => None,
+            Some(ms) => Some(Duration::from_millis(ms as u64)).filter(|d| *d <= MAX_BACKOFF),
+            None => Some((INITIAL_BACKOFF * 2u32.pow(attempt)).min(MAX_BACKOFF)),
+        }
+    }
+
+    /// The error to report for a retryable status the retries are over for.
+    pub fn gave_up(&self) -> Option<GrpcStatusError> {
+        is_retryable(self.code).then(|| GrpcStatusError {
+            code: self.code,
+            message: self.message.clone(),
+            retry_after: self
+                .pushback
+                .and_then(|ms| u64::try_from(ms).ok())
+                .map(Duration::from_millis),
+        })
+    }
+
+    /// The OTLP/HTTP response for libdatadog, with `status`: the response
+    /// message on success, the `grpc-message` text otherwise.
+    fn into_http_response(self, status: u16) -> Result<http::Response<Bytes>, HttpError> {
+        let mut builder = http::Response::builder()
+            .status(status)
+            .header("grpc-status", self.code.to_string());
+        let body = if self.code == code::OK {
+            builder = builder.header(http::header::CONTENT_TYPE, "application/x-protobuf");
+            self.body
+        } else {
+            Bytes::from(format!("grpc-status {}: {}", self.code, self.message))
+        };
+        builder.body(body).map_err(|e| HttpError::Other(e.into()))
+    }
+}
+
+thread_local! {
+    /// Origin of the OTLP endpoint to reach over gRPC while the future being
+    /// polled runs (see [`with_otlp_grpc`]).
+    static GRPC_ORIGIN: RefCell<Option<String>> = const { RefCell::new(None) };
+
+    /// The latest export the future being polled gave up retrying (see
+    /// [`with_otlp_grpc`]).
+    static GAVE_UP: RefCell<Option<GrpcStatusError>> = const { RefCell::new(None) };
+}
+
+/// `scheme://authority` of `uri`, the part a gRPC channel connects to.
+fn origin(uri: &http::Uri) -> Option<String> {
+    Some(format!("{}://{}", uri.scheme_str()?, uri.authority()?))
+}
+
+/// Poll `fut` with requests to `endpoint` carried over gRPC (see the module
+/// docs). Scoped like [`crate::http::with_request_signal`]. Returns, with the
+/// output, the status of the latest export given up after its retries: that
+/// export answered libdatadog with a 500, and its real status is this one.
+pub async fn with_otlp_grpc<F: Future>(
+    endpoint: &str,
+    fut: F,
+) -> (F::Output, Option<GrpcStatusError>) {
+    let origin = endpoint.parse::<http::Uri>().ok().as_ref().and_then(origin);
+    let mut fut = std::pin::pin!(fut);
+    let mut gave_up = None;
+    let output = std::future::poll_fn(|cx| {
+        let prev = GRPC_ORIGIN.with(|o| o.replace(origin.clone()));
+        let prev_gave_up = GAVE_UP.with(|g| g.replace(None));
+        let res = fut.as_mut().poll(cx);
+        GRPC_ORIGIN.with(|o| *o.borrow_mut() = prev);
+        if let Some(error) = GAVE_UP.with(|g| g.replace(prev_gave_up)) {
+            gave_up = Some(error);
+        }
+        res
+    })
+    .await;
+    (output, gave_up)
+}
+
+/// Whether `req` is an export to the endpoint set by [`with_otlp_grpc`].
+pub fn routes_to_grpc(req: &http::Request<Bytes>) -> bool {
+    req.method() == http::Method::POST
+        && GRPC_ORIGIN.with(|o| {
+            o.borrow()
+                .as_deref()
+                .is_some_and(|target| origin(req.uri()).as_deref() == Some(target))
+        })
+}
+
+/// Unary gRPC client for OTLP trace export.
+#[derive(Debug, Clone)]
+pub struct WasmGrpcClient;
+
+impl WasmGrpcClient {
+    /// Send an OTLP/HTTP protobuf export request as a gRPC `Export` call,
+    /// retrying retryable failures, and answer as OTLP/HTTP would.
+    pub async fn export(
+        &self,
+        req: http::Request<Bytes>,
+    ) -> Result<http::Response<Bytes>, HttpError> {
+        let origin = origin(req.uri()).ok_or_else(|| {
+            HttpError::InvalidRequest(anyhow::anyhow!("gRPC endpoint needs a scheme and host"))
+        })?;
+        let mut headers = vec![
+            "content-type".to_string(),
+            "application/grpc".to_string(),
+            "te".to_string(),
+            "trailers".to_string(),
+        ];
+        for (name, value) in req.headers() {
+            // Framing and connection headers are HTTP/1.1-only or set above;
+            // HTTP/2 rejects connection-specific headers outright.
+            if matches!(
+                name.as_str(),
+                "content-type"
+                    | "content-length"
+                    | "host"
+                    | "te"
+                    | "connection"
+                    | "keep-alive"
+                    | "transfer-encoding"
+                    | "upgrade"
+            ) {
+                continue;
+            }
+            if let Ok(value) = value.to_str() {
+                headers.push(name.as_str().to_string());
+                headers.push(value.to_string());
+            }
+        }
+        let body = frame_message(req.body());
+        let signal = current_request_signal();
+        let tls_options = current_tls_options();
+
+        let mut attempt = 0;
+        loop {
+            let status = self
+                .call(&origin, &headers, &body, &signal, &tls_options)
+                .await;
+            let status = match status {
+                Ok(status) => status,
+                // A call the caller aborted is not retried.
+                Err(e) if is_aborted(&signal) => return Err(e),
+                // The channel couldn't be used at all: gRPC reports that as
+                // UNAVAILABLE, which is retryable.
+                Err(e) => CallStatus {
+                    code: code::UNAVAILABLE,
+                    message: e.to_string(),
+                    body: Bytes::new(),
+                    pushback: None,
+                },
+            };
+            if let Some(delay) = status.retry_delay(attempt) {
+                WasmSleepCapability.sleep(delay).await;
+                attempt += 1;
+                continue;
+            }
+            return match status.gave_up() {
+                Some(error) => {
+                    GAVE_UP.with(|g| *g.borrow_mut() = Some(error));
+                    status.into_http_response(GAVE_UP_HTTP_STATUS)
+                }
+                None => {
+                    let http_status = http_status(status.code);
+                    status.into_http_response(http_status)
+                }
+            };
+        }
+    }
+
+    async fn call(
+        &self,
+        origin: &str,
+        headers: &[String],
+        body: &[u8],
+        signal: &JsValue,
+        tls_options: &JsValue,
+    ) -> Result<CallStatus, HttpError> {
+        let result = JsFuture::from(js_grpc_request(
+            origin,
+            EXPORT_PATH,
+            headers.to_vec(),
+            body,
+            signal,
+            tls_options,
+        ))
+        .await
+        .map_err(|e| HttpError::Network(anyhow::anyhow!("{:?}", e)))?;
+        let result: js_sys::ArrayTuple<(Number, Array<JsString>, Array<JsString>, Uint8Array)> =
+            js_sys::ArrayTuple::unchecked_from_js(result);
+        let http_status = result
+            .get0()
+            .as_f64()
+            .ok_or_else(|| HttpError::Other(anyhow::anyhow!("status is not a number")))?
+            as u16;
+        let headers = parse_response_headers(result.get1())?;
+        let trailers = parse_response_headers(result.get2())?;
+        let body = result.get3().to_vec();
+        Ok(CallStatus::from_response(
+            http_status,
+            &headers,
+            &trailers,
+            &body,
+        ))
+    }
+}
+
+fn is_aborted(signal: &JsValue) -> bool {
+    !signal.is_undefined()
+        && js_sys::Reflect::get(signal, &JsValue::from_str("aborted"))
+            .ok()
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_and_unframes_messages() {
+        let framed = frame_message(b"abc");
+        assert_eq!(framed, [0, 0, 0, 0, 3, b'a', b'b', b'c']);
+        assert_eq!(unframe_message(&framed).unwrap(), b"abc");
+        assert_eq!(unframe_message(&[]).unwrap(), b"");
+        assert!(unframe_message(&framed[..3]).is_err());
+        assert!(unframe_message(&framed[..6]).is_err());
+        assert!(unframe_message(&[1, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn reads_status_from_trailers_or_headers() {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", "14".parse().unwrap());
+        trailers.insert("grpc-message", "collector%20is%20draining".parse().unwrap());
+        let status = CallStatus::from_response(200, &HeaderMap::new(), &trailers, &[]);
+        assert_eq!(status.code, code::UNAVAILABLE);
+        assert_eq!(status.message, "collector is draining");
+
+        // Trailers-only response: the status is in the headers.
+        let status = CallStatus::from_response(200, &trailers, &HeaderMap::new(), &[]);
+        assert_eq!(status.code, code::UNAVAILABLE);
+
+        let mut ok = HeaderMap::new();
+        ok.insert("grpc-status", "0".parse().unwrap());
+        let status = CallStatus::from_response(200, &HeaderMap::new(), &ok, &frame_message(b"x"));
+        assert_eq!(status.code, code::OK);
+        assert_eq!(&status.body[..], b"x");
+
+        // No grpc-status: fall back on the HTTP status.
+        let status = CallStatus::from_response(503, &HeaderMap::new(), &HeaderMap::new(), &[]);
+        assert_eq!(status.code, code::UNAVAILABLE);
+    }
+
+    #[test]
+    fn retries_retryable_codes_with_backoff() {
+        let status = |code, pushback| CallStatus {
+            code,
+            message: String::new(),
+            body: Bytes::new(),
+            pushback,
+        };
+        assert_eq!(
+            status(code::UNAVAILABLE, None).retry_delay(0),
+            Some(INITIAL_BACKOFF)
+        );
+        assert_eq!(
+            status(code::UNAVAILABLE, None).retry_delay(2),
+            Some(INITIAL_BACKOFF * 4)
+        );
+        assert_eq!(
+            status(code::UNAVAILABLE, None).retry_delay(MAX_ATTEMPTS - 1),
+            None
+        );
+        assert_eq!(
+            status(code::RESOURCE_EXHAUSTED, Some(250)).retry_delay(0),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(status(code::UNAVAILABLE, Some(-1)).retry_delay(0), None);
+        let too_long = MAX_BACKOFF.as_millis() as i64 + 1;
+        assert_eq!(status(code::UNAVAILABLE, Some(too_long)).retry_delay(0), None);
+        assert_eq!(status(code::INVALID_ARGUMENT, None).retry_delay(0), None);
+        assert_eq!(status(code::OK, None).retry_delay(0), None);
+    }
+
+    #[test]
+    fn maps_status_to_http() {
+        assert_eq!(http_status(code::OK), 200);
+        assert_eq!(http_status(code::UNAVAILABLE), 503);
+        assert_eq!(http_status(code::UNAUTHENTICATED), 401);
+        assert_eq!(http_status(code::DATA_LOSS), 500);
+    }
+
+    #[test]
+    fn reports_the_real_status_of_exhausted_retries() {
+        let status = |code, pushback| CallStatus {
+            code,
+            message: "try later".to_string(),
+            body: Bytes::new(),
+            pushback,
+        };
+        let error = status(code::RESOURCE_EXHAUSTED, Some(60_000)).gave_up().unwrap();
+        assert_eq!(error.http_status(), 429);
+        assert_eq!(error.retry_after, Some(Duration::from_secs(60)));
+        assert_eq!(error.to_string(), "grpc-status 8: try later");
+        let error = status(code::UNAVAILABLE, Some(-1)).gave_up().unwrap();
+        assert_eq!(error.http_status(), 503);
+        assert_eq!(error.retry_after, None);
+        assert_eq!(status(code::UNAUTHENTICATED, None).gave_up(), None);
+
+        let failed = status(code::UNAVAILABLE, None)
+            .into_http_response(GAVE_UP_HTTP_STATUS)
+            .unwrap();
+        assert_eq!(failed.status(), 500);
+        assert_eq!(failed.headers()["grpc-status"], "14");
+        assert_eq!(&failed.body()[..], b"grpc-status 14: try later");
+    }
+
+    #[test]
+    fn decodes_grpc_message() {
+        assert_eq!(decode_grpc_message("a%20b%25"), "a b%");
+        assert_eq!(decode_grpc_message("bad%zz%2"), "bad%zz%2");
+        assert_eq!(decode_grpc_message("%E2%9C%93"), "\u{2713}");
+    }
+}
@@ -5,10 +5,17 @@
 //!
 //! The JS transport is imported via `wasm_bindgen(module = ...)` from
 //! `http_transport.js`, which ships alongside the wasm output.
+//!
+//! TCP requests honour a forward proxy (see [`set_proxy`]): plain HTTP is sent
+//! to the proxy in absolute form and HTTPS is tunnelled with `CONNECT`.
+//! Loopback hosts are always reached directly.
 
+use std::cell::{Cell, RefCell};
 use std::future::Future;
+use std::rc::Rc;
 use std::io::Write as _;
 use std::sync::LazyLock;
+use std::time::Duration;
 
 use bytes::Bytes;
 use http::{HeaderMap, HeaderName, HeaderValue};
@@ -37,8 +44,35 @@ extern "C" {
         body_ptr: *const u8,
         body_len: u32,
         wasm_memory: &JsValue,
+        signal: &JsValue,
+        proxy: &str,
+        tls_options: &JsValue,
     ) -> js_sys::Promise;
 
+    #[wasm_bindgen(js_name = "tlsOptionsError")]
+    fn js_tls_options_error(options: &JsValue) -> Option<String>;
+
+    #[wasm_bindgen(js_name = "proxyFor")]
+    fn js_proxy_for(is_https: bool, host: &str, port: u16, allow_env: bool) -> Option<String>;
+
+    #[wasm_bindgen(js_name = "setProxy")]
+    pub fn set_proxy(config: &JsValue);
+
+    #[wasm_bindgen(js_name = "setKeepAlive")]
+    pub fn set_keep_alive(config: &JsValue);
+
+    #[wasm_bindgen(js_name = "closeIdleConnections")]
+    pub fn close_idle_connections();
+
+    #[wasm_bindgen(js_name = "requestSignal")]
+    fn js_request_signal(signal: &JsValue, timeout_ms: Option<u32>) -> JsValue;
+
+    #[wasm_bindgen(js_name = "abortReason")]
+    fn js_abort_reason(signal: &JsValue) -> js_sys::Promise;
+
+    #[wasm_bindgen(js_name = "releaseSignal")]
+    fn js_release_signal(signal: &JsValue);
+
     #[wasm_bindgen(js_name = "setStorage")]
     pub fn set_storage(new_storage: &JsValue);
 
@@ -46,6 +80,210 @@ extern "C" {
     pub fn set_response_header_observer(observer: &JsValue);
 }
 
+thread_local! {
+    /// Headers for agent trace payload requests issued by the future being
+    /// polled (see [`with_trace_request_headers`]).
+    static TRACE_REQUEST_HEADERS: RefCell<Rc<[(String, String)]>> = RefCell::new(Rc::from([]));
+
+    /// AbortSignal for requests issued by the future being polled (see
+    /// [`with_request_signal`]).
+    static REQUEST_SIGNAL: RefCell<JsValue> = const { RefCell::new(JsValue::UNDEFINED) };
+
+    /// Whether requests issued by the future being polled may use the
+    /// environment's proxy settings (see [`with_env_proxy`]).
+    static ENV_PROXY: Cell<bool> = const { Cell::new(false) };
+
+    /// TLS options for HTTPS requests issued by the future being polled (see
+    /// [`with_tls_options`]).
+    static TLS_OPTIONS: RefCell<JsValue> = const { RefCell::new(JsValue::UNDEFINED) };
+
+    /// `Retry-After` of the latest agent response (see [`last_retry_after`]).
+    static RETRY_AFTER: Cell<Option<Duration>> = const { Cell::new(None) };
+
+    /// Latest `Datadog-Agent-State` response header value seen by the future
+    /// being polled (see [`with_agent_state`]).
+    static AGENT_STATE: RefCell<Option<String>> = const { RefCell::new(None) };
+
+    /// Completed requests by whether they reused a kept-alive connection
+    /// (see [`connection_stats`]).
+    static CONNECTION_STATS: Cell<ConnectionStats> = const { Cell::new(ConnectionStats { opened: 0, reused: 0 }) };
+}
+
+/// Process-wide count of completed requests that opened a new connection
+/// versus reused an idle kept-alive one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    pub opened: u64,
+    pub reused: u64,
+}
+
+/// Connection reuse across every request made through [`WasmHttpClient`] so
+/// far. The JS transport keeps one keep-alive agent per origin (see
+/// `setKeepAlive`), so a high `reused` share means flushes skip the TCP and
+/// TLS handshakes.
+pub fn connection_stats() -> ConnectionStats {
+    CONNECTION_STATS.with(Cell::get)
+}
+
+/// Poll `fut` with `headers` added to every agent trace payload request it
+/// issues (`POST /v0.4/traces` or `/v0.5/traces`).
+///
+/// `TraceExporter` builds its trace requests internally and has no hook for
+/// per-payload headers, so the pipeline scopes them to one send here. Every
+/// attempt of the send carries them, retries included. Used for the
+/// client-computed-stats / dropped-P0 headers. Scoped like
+/// [`with_request_signal`], so concurrent sends of different states each keep
+/// their own.
+pub async fn with_trace_request_headers<F: Future>(
+    headers: Vec<(String, String)>,
+    fut: F,
+) -> F::Output {
+    let headers: Rc<[(String, String)]> = headers.into();
+    let mut fut = std::pin::pin!(fut);
+    std::future::poll_fn(|cx| {
+        let prev = TRACE_REQUEST_HEADERS.with(|h| h.replace(headers.clone()));
+        let res = fut.as_mut().poll(cx);
+        TRACE_REQUEST_HEADERS.with(|h| *h.borrow_mut() = prev);
+        res
+    })
+    .await
+}
+
+fn current_trace_request_headers() -> Rc<[(String, String)]> {
+    TRACE_REQUEST_HEADERS.with(|h| h.borrow().clone())
+}
+
+/// Whether `path` is one of the agent's trace intake endpoints. OTLP's
+/// `/v1/traces` and the agentless intake don't take the agent headers.
+fn is_agent_trace_path(path: &str) -> bool {
+    matches!(path, "/v0.4/traces" | "/v0.5/traces")
+}
+
+/// Combine an optional caller `AbortSignal` with an optional timeout into a
+/// single signal (`undefined` when there is neither). A timeout aborts with a
+/// `TimeoutError` reason.
+pub fn request_signal(signal: &JsValue, timeout_ms: Option<u32>) -> JsValue {
+    js_request_signal(signal, timeout_ms)
+}
+
+/// Resolve with the abort reason once `signal` aborts.
+pub async fn abort_reason(signal: &JsValue) -> JsValue {
+    JsFuture::from(js_abort_reason(signal))
+        .await
+        .unwrap_or_else(|e| e)
+}
+
+/// Detach the listeners and timer [`request_signal`] and [`abort_reason`]
+/// attached for `signal`. Call once the call using it has settled, so a
+/// long-lived caller signal doesn't accumulate listeners.
+pub fn release_request_signal(signal: &JsValue) {
+    js_release_signal(signal)
+}
+
+/// Poll `fut` with `signal` as the `AbortSignal` of every request it issues:
+/// when the signal aborts, the in-flight Node request is destroyed and the
+/// request fails. The signal is installed only while `fut` itself is being
+/// polled, so interleaved futures each keep their own.
+pub async fn with_request_signal<F: Future>(signal: JsValue, fut: F) -> F::Output {
+    let mut fut = std::pin::pin!(fut);
+    std::future::poll_fn(|cx| {
+        let prev = REQUEST_SIGNAL.with(|s| s.replace(signal.clone()));
+        let res = fut.as_mut().poll(cx);
+        REQUEST_SIGNAL.with(|s| *s.borrow_mut() = prev);
+        res
+    })
+    .await
+}
+
+/// Poll `fut` with `options` (`{ ca, cert, key, passphrase, servername,
+/// rejectUnauthorized }`) as the TLS settings of every HTTPS request it
+/// issues. Scoped like [`with_request_signal`], so states configured with
+/// different TLS material can send concurrently.
+pub async fn with_tls_options<F: Future>(options: JsValue, fut: F) -> F::Output {
+    let mut fut = std::pin::pin!(fut);
+    std::future::poll_fn(|cx| {
+        let prev = TLS_OPTIONS.with(|o| o.replace(options.clone()));
+        let res = fut.as_mut().poll(cx);
+        TLS_OPTIONS.with(|o| *o.borrow_mut() = prev);
+        res
+    })
+    .await
+}
+
+/// Poll `fut` with the `HTTP_PROXY` / `HTTPS_PROXY` / `NO_PROXY` environment
+/// settings applying to the requests it issues, when no proxy was configured
+/// with [`set_proxy`]. For OTLP and intake requests only: the agent is
+/// reached directly unless a proxy is configured explicitly. Scoped like
+/// [`with_request_signal`].
+pub async fn with_env_proxy<F: Future>(fut: F) -> F::Output {
+    let mut fut = std::pin::pin!(fut);
+    std::future::poll_fn(|cx| {
+        let prev = ENV_PROXY.with(|p| p.replace(true));
+        let res = fut.as_mut().poll(cx);
+        ENV_PROXY.with(|p| p.set(prev));
+        res
+    })
+    .await
+}
+
+/// The `AbortSignal` installed by [`with_request_signal`] for the future
+/// being polled, or `undefined`.
+pub(crate) fn current_request_signal() -> JsValue {
+    REQUEST_SIGNAL.with(|s| s.borrow().clone())
+}
+
+/// The TLS options installed by [`with_tls_options`] for the future being
+/// polled, or `undefined`.
+pub(crate) fn current_tls_options() -> JsValue {
+    TLS_OPTIONS.with(|o| o.borrow().clone())
+}
+
+/// Why `options` can't be used as TLS settings (unparseable PEM, a key that
+/// doesn't match its certificate, wrong types), or `None` when they can.
+pub fn tls_options_error(options: &JsValue) -> Option<String> {
+    js_tls_options_error(options)
+}
+
+/// `Retry-After` hint of the most recent agent response, if it sent one.
+/// `TraceExporter` doesn't surface response headers with its errors, so this
+/// is how a failed trace send recovers the agent's back-off hint.
+pub fn last_retry_after() -> Option<Duration> {
+    RETRY_AFTER.with(Cell::get)
+}
+
+/// Parse a `Retry-After` header in its delay-seconds form (the HTTP-date form
+/// isn't used by the agent and is ignored).
+pub fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get(http::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// Poll `fut` and return, with its output, the `Datadog-Agent-State` header
+/// of the latest response to the requests it issued, if any carried one. The
+/// agent changes it whenever its `/info` changes, so a new value means cached
+/// agent info is stale. Scoped like [`with_request_signal`], so each caller
+/// only sees the state of the agent it talks to.
+pub async fn with_agent_state<F: Future>(fut: F) -> (F::Output, Option<String>) {
+    let mut fut = std::pin::pin!(fut);
+    let mut seen = None;
+    let output = std::future::poll_fn(|cx| {
+        let prev = AGENT_STATE.with(|s| s.replace(None));
+        let res = fut.as_mut().poll(cx);
+        if let Some(state) = AGENT_STATE.with(|s| s.replace(prev)) {
+            seen = Some(state);
+        }
+        res
+    })
+    .await;
+    (output, seen)
+}
+
 /// Wasm [`HttpClientCapability`] that delegates HTTP to Node.js `http.request`.
 ///
 /// The wasm analogue of libdatadog's native `NativeHttpClient`. Bundled into
@@ -62,9 +300,22 @@ impl HttpClientCapability for WasmHttpClient {
     #[allow(clippy::manual_async_fn)]
     fn request(
         &self,
-        req: http::Request<Bytes>,
+        mut req: http::Request<Bytes>,
     ) -> impl Future<Output = Result<http::Response<Bytes>, HttpError>> + MaybeSend {
         async move {
+            if req.method() == http::Method::POST && is_agent_trace_path(req.uri().path()) {
+                for (name, value) in current_trace_request_headers().iter() {
+                    // Queued by the pipeline with fixed names and numeric
+                    // values; skip rather than fail the send on a bad one.
+                    if let (Ok(name), Ok(value)) = (
+                        HeaderName::from_bytes(name.as_bytes()),
+                        HeaderValue::from_str(value),
+                    ) {
+                        req.headers_mut().insert(name, value);
+                    }
+                }
+            }
+
             let scheme = req.uri().scheme_str().unwrap_or("http");
 
             // Unix domain socket / Windows named pipe: ddcommon's `parse_uri`
@@ -86,15 +337,30 @@ impl HttpClientCapability for WasmHttpClient {
                 (host.to_owned(), port, is_https, String::new())
             };
 
+            // Proxy selection (explicit config, HTTP(S)_PROXY, NO_PROXY,
+            // loopback bypass) lives on the JS side, which can read the
+            // environment. A plain-HTTP request through a proxy carries the
+            // absolute URI in its request line; an HTTPS one is tunnelled with
+            // CONNECT and keeps the origin form.
+            let proxy = if socket_path.is_empty() {
+                let allow_env = ENV_PROXY.with(Cell::get);
+                js_proxy_for(is_https, &host, port, allow_env).unwrap_or_default()
+            } else {
+                String::new()
+            };
+
             // For a socket request there is no meaningful network host; HTTP/1.1
             // still requires a Host header, so send a stable placeholder (the
             // agent does not validate Host over a socket).
             let head = if socket_path.is_empty() {
-                serialize_request_head(&req, &host, port, is_https, false)?
+                let absolute_form = !proxy.is_empty() && !is_https;
+                serialize_request_head(&req, &host, port, is_https, false, absolute_form)?
             } else {
-                serialize_request_head(&req, "localhost", port, is_https, true)?
+                serialize_request_head(&req, "localhost", port, is_https, true, false)?
             };
             let body = req.into_body();
+            let signal = current_request_signal();
+            let tls_options = current_tls_options();
 
             let result = JsFuture::from(http_request(
                 &host,
@@ -106,10 +372,29 @@ impl HttpClientCapability for WasmHttpClient {
                 body.as_ptr(),
                 body.len() as u32,
                 WASM_MEMORY.as_ref(),
+                &signal,
+                &proxy,
+                &tls_options,
             ))
             .await
             .map_err(|e| HttpError::Network(anyhow::anyhow!("{:?}", e)))?;
 
+            // The optional fourth element says whether Node put the request
+            // on a pooled socket.
+            let reused = js_sys::Reflect::get_u32(&result, 3)
+                .ok()
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false);
+            CONNECTION_STATS.with(|c| {
+                let mut stats = c.get();
+                if reused {
+                    stats.reused += 1;
+                } else {
+                    stats.opened += 1;
+                }
+                c.set(stats);
+            });
+
             let result: js_sys::ArrayTuple<(Number, Array<JsString>, Uint8Array)> =
                 js_sys::ArrayTuple::unchecked_from_js(result);
 
@@ -120,6 +405,13 @@ impl HttpClientCapability for WasmHttpClient {
                 as u16;
 
             let headers = parse_response_headers(result.get1())?;
+            RETRY_AFTER.with(|r| r.set(parse_retry_after(&headers)));
+            if let Some(state) = headers
+                .get("datadog-agent-state")
+                .and_then(|v| v.to_str().ok())
+            {
+                AGENT_STATE.with(|s| *s.borrow_mut() = Some(state.to_owned()));
+            }
 
             let body = Bytes::from(result.get2().to_vec());
 
@@ -138,7 +430,7 @@ impl HttpClientCapability for WasmHttpClient {
 /// Parse response headers from Node's flat `[name, value, name, value, ...]`
 /// array (`res.rawHeaders`): even indices are (lowercased) header names, odd
 /// indices their string values.
-fn parse_response_headers(header_js: Array<JsString>) -> Result<HeaderMap, HttpError> {
+pub(crate) fn parse_response_headers(header_js: Array<JsString>) -> Result<HeaderMap, HttpError> {
     let len = header_js.length() as usize;
     let mut headers = HeaderMap::with_capacity(len / 2);
     for i in 0..(len / 2) {
@@ -198,12 +490,16 @@ fn hex_decode(s: &str) -> Option<Vec<u8>> {
 ///
 /// `is_socket` requests (unix socket / named pipe) omit the `:port` suffix on
 /// the Host header — there is no TCP port for a socket transport.
+///
+/// `absolute_form` writes the full `http://host[:port]/path` request target
+/// (RFC 9112 §3.2.2), which is what a forward proxy expects for plain HTTP.
 fn serialize_request_head(
     req: &http::Request<Bytes>,
     host: &str,
     port: u16,
     is_https: bool,
     is_socket: bool,
+    absolute_form: bool,
 ) -> Result<Vec<u8>, HttpError> {
     let method = req.method().as_str();
     let path_and_query = req
@@ -214,21 +510,26 @@ fn serialize_request_head(
     let body_len = req.body().len();
     let headers = req.headers();
 
+    let default_port = if is_https { 443 } else { 80 };
+    let authority = if is_socket || port == default_port {
+        host.to_owned()
+    } else {
+        format!("{host}:{port}")
+    };
+
     let mut buf = Vec::with_capacity(256 + headers.len() * 64);
 
     buf.extend_from_slice(method.as_bytes());
     buf.push(b' ');
+    if absolute_form {
+        let scheme = if is_https { "https" } else { "http" };
+        write!(&mut buf, "{scheme}://{authority}").map_err(|e| HttpError::Other(e.into()))?;
+    }
     buf.extend_from_slice(path_and_query.as_bytes());
     buf.extend_from_slice(b" HTTP/1.1\r\n");
 
     buf.extend_from_slice(b"Host: ");
-    buf.extend_from_slice(host.as_bytes());
-    if !is_socket {
-        let default_port = if is_https { 443 } else { 80 };
-        if port != default_port {
-            write!(&mut buf, ":{port}").map_err(|e| HttpError::Other(e.into()))?;
-        }
-    }
+    buf.extend_from_slice(authority.as_bytes());
     buf.extend_from_slice(b"\r\n");
 
     write!(&mut buf, "Content-Length: {body_len}\r\n").map_err(|e| HttpError::Other(e.into()))?;
@@ -1,5 +1,9 @@
+const dgram = require('node:dgram')
 const http = require('node:http')
+const http2 = require('node:http2')
 const https = require('node:https')
+const net = require('node:net')
+const tls = require('node:tls')
 
 let storage = f => f()
 
@@ -38,26 +42,388 @@ module.exports.setResponseHeaderObserver = function (new_observer) {
   responseHeaderObserver = new_observer
 }
 
-module.exports.httpRequest = function (host, port, isHttps, socketPath, head_ptr, head_len, body_ptr, body_len, wasm_memory) {
+// Sink for the exporter's log-output mode: called with each serialized
+// payload as a string. Defaults to stdout, one payload per line, which is
+// where serverless forwarders read traces from.
+function defaultLogOutputSink (line) {
+  process.stdout.write(line.endsWith('\n') ? line : line + '\n')
+}
+
+let logOutputSink = defaultLogOutputSink
+
+module.exports.setLogOutputSink = function (new_sink) {
+  logOutputSink = new_sink || defaultLogOutputSink
+}
+
+module.exports.writeLogOutput = function (bytes) {
+  // `bytes` is a view into wasm memory; decoding copies it.
+  logOutputSink(Buffer.from(bytes).toString('utf8'))
+}
+
+// Forward proxy settings: `{ httpProxy, httpsProxy, noProxy }`. While unset
+// (null) they are read from HTTP_PROXY / HTTPS_PROXY / NO_PROXY (or their
+// lowercase forms) on every request, so changes to the environment apply
+// without reconfiguring; those only apply to requests the caller marks as
+// bound for OTLP or the intake, never to the agent. Pass `{}` to disable
+// proxying regardless of the environment.
+let proxyConfig = null
+
+module.exports.setProxy = function (config) {
+  proxyConfig = config ?? null
+}
+
+function proxySettings (allowEnv) {
+  if (proxyConfig) return proxyConfig
+  if (!allowEnv) return {}
+  const env = process.env
+  return {
+    httpProxy: env.HTTP_PROXY ?? env.http_proxy,
+    httpsProxy: env.HTTPS_PROXY ?? env.https_proxy,
+    noProxy: env.NO_PROXY ?? env.no_proxy,
+  }
+}
+
+// Loopback hosts are always reached directly: a local agent or collector is
+// never behind the proxy.
+function isLoopback (host) {
+  host = host.toLowerCase().replace(/^\[|\]$/g, '')
+  if (host === 'localhost' || host.endsWith('.localhost')) return true
+  if (net.isIPv4(host)) return host.startsWith('127.')
+  if (net.isIPv6(host)) {
+    return host === '::1' || /^(0{0,4}:){7}0{0,3}1$/.test(host) || /^::ffff:127\./.test(host)
+  }
+  return false
+}
+
+function splitNoProxyEntry (entry) {
+  const bracketed = /^\[([^\]]+)\](?::(\d+))?$/.exec(entry)
+  if (bracketed) return [bracketed[1], bracketed[2]]
+  const hostPort = /^([^:]+):(\d+)$/.exec(entry)
+  if (hostPort) return [hostPort[1], hostPort[2]]
+  return [entry, undefined]
+}
+
+// NO_PROXY is a comma/space separated list of hosts, domain suffixes
+// (`example.com`, `.example.com` and `*.example.com` all match subdomains),
+// optionally with a `:port`, or `*` for everything.
+function bypassesProxy (noProxy, host, port) {
+  if (!noProxy) return false
+  host = host.toLowerCase().replace(/^\[|\]$/g, '')
+  return noProxy.split(/[\s,]+/).some((entry) => {
+    if (!entry) return false
+    if (entry === '*') return true
+    const [entryHost, entryPort] = splitNoProxyEntry(entry.toLowerCase())
+    if (entryPort !== undefined && Number(entryPort) !== port) return false
+    const suffix = entryHost.replace(/^\*?\./, '')
+    return host === suffix || host.endsWith('.' + suffix)
+  })
+}
+
+// The proxy URL to reach `host:port` through, or undefined to connect
+// directly. `allowEnv` lets the environment's proxy settings apply when none
+// were configured.
+module.exports.proxyFor = function (isHttps, host, port, allowEnv) {
+  if (isLoopback(host)) return undefined
+  const settings = proxySettings(allowEnv)
+  const proxy = isHttps ? settings.httpsProxy : settings.httpProxy
+  if (!proxy || bypassesProxy(settings.noProxy, host, port)) return undefined
+  return proxy
+}
+
+function proxyEndpoint (proxyUrl) {
+  const isHttps = proxyUrl.protocol === 'https:'
+  return {
+    transport: isHttps ? https : http,
+    host: proxyUrl.hostname.replace(/^\[|\]$/g, ''),
+    port: Number(proxyUrl.port) || (isHttps ? 443 : 80),
+  }
+}
+
+function proxyAuthorization (proxyUrl) {
+  if (!proxyUrl.username) return undefined
+  const credentials = decodeURIComponent(proxyUrl.username) + ':' + decodeURIComponent(proxyUrl.password)
+  return 'Basic ' + Buffer.from(credentials).toString('base64')
+}
+
+// Open a CONNECT tunnel to `host:port` through the proxy; resolves with the
+// raw socket once the proxy answers 200.
+function openTunnel (proxyUrl, host, port, signal) {
+  return new Promise((resolve, reject) => {
+    const { transport, host: proxyHost, port: proxyPort } = proxyEndpoint(proxyUrl)
+    const authority = `${net.isIPv6(host) ? `[${host}]` : host}:${port}`
+    const headers = { host: authority }
+    const auth = proxyAuthorization(proxyUrl)
+    if (auth) headers['proxy-authorization'] = auth
+    const req = transport.request({
+      host: proxyHost,
+      port: proxyPort,
+      method: 'CONNECT',
+      path: authority,
+      headers,
+      agent: false,
+    })
+    req.on('connect', (res, socket) => {
+      if (res.statusCode !== 200) {
+        socket.destroy()
+        reject(new Error(`proxy CONNECT to ${authority} failed with status ${res.statusCode}`))
+        return
+      }
+      resolve(socket)
+    })
+    req.on('error', reject)
+    if (signal) {
+      const onAbort = () => req.destroy(signal.reason)
+      signal.addEventListener('abort', onAbort, { once: true })
+      req.on('close', () => signal.removeEventListener('abort', onAbort))
+    }
+    req.end()
+  })
+}
+
+// Connection reuse: one keep-alive agent per origin (scheme, host and port,
+// the socket path, or the proxy for proxied plain HTTP), so successive trace,
+// stats and /info requests skip the TCP connect and TLS handshake. The idle
+// timeout stays under Node's default 5s server keep-alive timeout so a socket
+// is never reused just as the agent closes it. CONNECT tunnels are not pooled.
+const DEFAULT_KEEP_ALIVE = { enabled: true, maxSockets: 8, idleTimeoutMs: 4000 }
+
+let keepAlive = DEFAULT_KEEP_ALIVE
+const agents = new Map()
+
+// `{ enabled, maxSockets, idleTimeoutMs }`; missing fields keep their
+// defaults and `null` restores them. New requests get fresh agents with the
+// new limits; the old ones close their idle sockets and stop pooling, so
+// requests already in flight on them still complete.
+module.exports.setKeepAlive = function (config) {
+  keepAlive = { ...DEFAULT_KEEP_ALIVE, ...config }
+  for (const agent of agents.values()) {
+    agent.keepAlive = false
+    closeIdleSockets(agent)
+  }
+  agents.clear()
+}
+
+// Node's global agent keeps connections alive too, so a disabled pool means
+// `agent: false` (a fresh connection per request), not the default agent.
+function agentFor (transport, key) {
+  if (!keepAlive.enabled) return false
+  let agent = agents.get(key)
+  if (!agent) {
+    agent = new transport.Agent({
+      keepAlive: true,
+      maxSockets: keepAlive.maxSockets,
+      maxFreeSockets: keepAlive.maxSockets,
+      // Applied to pooled sockets too: an idle socket is destroyed once it
+      // times out.
+      timeout: keepAlive.idleTimeoutMs,
+      scheduling: 'lifo',
+    })
+    agents.set(key, agent)
+  }
+  return agent
+}
+
+function closeIdleSockets (agent) {
+  for (const sockets of Object.values(agent.freeSockets)) {
+    for (const socket of sockets) socket.destroy()
+  }
+}
+
+// Close every pooled idle socket so nothing lingers after shutdown. In-flight
+// requests are left alone; their sockets are released when they finish.
+module.exports.closeIdleConnections = function () {
+  for (const agent of agents.values()) closeIdleSockets(agent)
+  // `close` lets streams still in flight finish first.
+  for (const session of grpcSessions) session.close()
+}
+
+// TLS settings for HTTPS requests: `{ ca, cert, key, passphrase, servername,
+// rejectUnauthorized }`, as accepted by `tls.connect`. Anything else on the
+// object is ignored so a caller can't switch off unrelated socket behaviour.
+const TLS_OPTION_KEYS = ['ca', 'cert', 'key', 'passphrase', 'servername', 'rejectUnauthorized']
+
+function pickTlsOptions (options) {
+  const picked = {}
+  if (!options) return picked
+  for (const name of TLS_OPTION_KEYS) {
+    if (options[name] !== undefined && options[name] !== null) picked[name] = options[name]
+  }
+  return picked
+}
+
+// Why `options` can't be used for TLS, or undefined when it can. Loads the CA,
+// certificate and key the way a connection would, so unreadable PEM, a key
+// that doesn't match the certificate or a wrong passphrase surface up front.
+module.exports.tlsOptionsError = function (options) {
+  if (options === undefined || options === null) return undefined
+  if (typeof options !== 'object') return 'TLS options must be an object'
+  const picked = pickTlsOptions(options)
+  if (picked.servername !== undefined && typeof picked.servername !== 'string') {
+    return 'servername must be a string'
+  }
+  if (picked.rejectUnauthorized !== undefined && typeof picked.rejectUnauthorized !== 'boolean') {
+    return 'rejectUnauthorized must be a boolean'
+  }
+  if ((picked.cert === undefined) !== (picked.key === undefined)) {
+    return 'cert and key must be given together'
+  }
+  try {
+    tls.createSecureContext(picked)
+  } catch (error) {
+    return String(error && error.message)
+  }
+  return undefined
+}
+
+// Insert a header line just before the blank line ending a rendered head.
+function withHeader (head, line) {
+  return Buffer.concat([head.subarray(0, head.length - 2), Buffer.from(line + '\r\n\r\n')])
+}
+
+// Listener removals and timers to undo once the call a signal belongs to has
+// settled (see `releaseSignal`). Keyed weakly so an unreleased signal doesn't
+// leak its entry.
+const signalCleanups = new WeakMap()
+
+function onRelease (signal, cleanup) {
+  const cleanups = signalCleanups.get(signal)
+  if (cleanups) {
+    cleanups.push(cleanup)
+  } else {
+    signalCleanups.set(signal, [cleanup])
+  }
+}
+
+// Combine the caller's AbortSignal (if any) with an optional timeout into the
+// signal handed to `httpRequest`. A timeout aborts with a 'TimeoutError'
+// DOMException so the Rust side can tell it apart from a caller abort.
+module.exports.requestSignal = function (signal, timeoutMs) {
+  if (timeoutMs === undefined || timeoutMs === null) return signal
+  const controller = new AbortController()
+  const timer = setTimeout(() => {
+    controller.abort(new DOMException('request timed out', 'TimeoutError'))
+  }, timeoutMs)
+  // Like `sleep`, an abandoned deadline must not keep the process alive.
+  timer.unref?.()
+  onRelease(controller.signal, () => clearTimeout(timer))
+  controller.signal.addEventListener('abort', () => clearTimeout(timer), { once: true })
+  if (signal) {
+    if (signal.aborted) {
+      controller.abort(signal.reason)
+    } else {
+      // The caller's signal may outlive many calls; drop the forwarding
+      // listener when this one settles.
+      const forward = () => controller.abort(signal.reason)
+      signal.addEventListener('abort', forward, { once: true })
+      onRelease(controller.signal, () => signal.removeEventListener('abort', forward))
+    }
+  }
+  return controller.signal
+}
+
+// Resolves with the abort reason once `signal` aborts (never rejects). Left
+// pending, without a listener, once the signal is released.
+module.exports.abortReason = function (signal) {
+  if (signal.aborted) return Promise.resolve(signal.reason)
+  return new Promise((resolve) => {
+    const onAbort = () => resolve(signal.reason)
+    signal.addEventListener('abort', onAbort, { once: true })
+    onRelease(signal, () => signal.removeEventListener('abort', onAbort))
+  })
+}
+
+// Undo what `requestSignal` and `abortReason` attached to `signal`: called
+// when the call using it settles, whether it finished, failed or was
+// cancelled.
+module.exports.releaseSignal = function (signal) {
+  if (!signal) return
+  const cleanups = signalCleanups.get(signal)
+  if (!cleanups) return
+  signalCleanups.delete(signal)
+  for (const cleanup of cleanups) cleanup()
+}
+
+module.exports.httpRequest = function (host, port, isHttps, socketPath, head_ptr, head_len, body_ptr, body_len, wasm_memory, signal, proxy, tlsOptions) {
   // A non-empty socketPath routes over a Unix domain socket (or Windows named
   // pipe) instead of TCP. Sockets are always plaintext HTTP/1.1, so https is
   // ignored in that mode.
   const useSocket = typeof socketPath === 'string' && socketPath.length > 0
-  const transport = useSocket ? http : (isHttps ? https : http)
+  // A non-empty proxy (from `proxyFor`) routes TCP requests through a forward
+  // proxy: plain HTTP is sent to the proxy with the absolute-form head the
+  // Rust side rendered, HTTPS goes through a CONNECT tunnel.
+  const useProxy = !useSocket && typeof proxy === 'string' && proxy.length > 0
+  // Only HTTPS to the origin uses them; a proxy itself is reached without.
+  const originTls = isHttps && !useSocket ? pickTlsOptions(tlsOptions) : {}
+  // Set when the last attempt failed because a pooled socket had already been
+  // closed by the server; such a request never reached it and is retried once.
+  let staleSocket = false
+  let retriedStaleSocket = false
 
   function attempt () {
     return new Promise((resolve, reject) => {
+      if (signal?.aborted) {
+        reject(signal.reason)
+        return
+      }
       storage(() => {
+        let proxyUrl
+        if (useProxy) {
+          try {
+            proxyUrl = new URL(proxy)
+          } catch (error) {
+            reject(error)
+            return
+          }
+        }
+        const tunnel = proxyUrl && isHttps
+          ? openTunnel(proxyUrl, host, port, signal)
+          : Promise.resolve()
+        tunnel.then((tunnelSocket) => send(proxyUrl, tunnelSocket)).catch(reject)
+      })
+
+      function send (proxyUrl, tunnelSocket) {
         // wasm_memory.buffer is replaced each time WebAssembly.Memory grows, so
         // the views must be recreated on every attempt against the current buffer.
         const headView = new Uint8Array(wasm_memory.buffer, head_ptr, head_len)
         const bodyView = new Uint8Array(wasm_memory.buffer, body_ptr, body_len)
+        let head = Buffer.from(headView)
 
         // host/port (or socketPath) drive connection selection; method/path/
         // headers are placeholders because we replace the rendered head below.
-        const requestOptions = useSocket
-          ? { socketPath, method: 'POST', path: '/' }
-          : { host, port, method: 'POST', path: '/' }
+        let transport = isHttps ? https : http
+        let requestOptions
+        let agentKey
+        if (useSocket) {
+          transport = http
+          requestOptions = { socketPath, method: 'POST', path: '/' }
+          agentKey = 'unix:' + socketPath
+        } else if (tunnelSocket) {
+          requestOptions = {
+            host,
+            port,
+            method: 'POST',
+            path: '/',
+            createConnection: () => tls.connect({
+              socket: tunnelSocket,
+              servername: net.isIP(host) ? undefined : host,
+              ...originTls,
+            }),
+          }
+        } else if (proxyUrl) {
+          const endpoint = proxyEndpoint(proxyUrl)
+          transport = endpoint.transport
+          requestOptions = { host: endpoint.host, port: endpoint.port, method: 'POST', path: '/' }
+          agentKey = 'proxy:' + proxyUrl.href
+          const auth = proxyAuthorization(proxyUrl)
+          if (auth) head = withHeader(head, 'Proxy-Authorization: ' + auth)
+        } else {
+          // An agent pools sockets by their TLS options too, so requests
+          // with different CAs or client certificates never share one.
+          requestOptions = { host, port, method: 'POST', path: '/', ...originTls }
+          agentKey = `${isHttps ? 'https' : 'http'}://${host}:${port}`
+        }
+        const agent = agentKey ? agentFor(transport, agentKey) : undefined
+        if (agent !== undefined) requestOptions.agent = agent
         const req = transport.request(requestOptions, (res) => {
           const chunks = []
           res.on('data', chunk => chunks.push(chunk))
@@ -82,23 +448,39 @@ module.exports.httpRequest = function (host, port, isHttps, socketPath, head_ptr
               // the Uint8Array(typedArray) copy ctor) is required to avoid
               // handing the Rust side unrelated pooled memory.
               new Uint8Array(body),
+              req.reusedSocket,
             ])
           })
         })
-        req.on('error', reject)
+        req.on('error', (error) => {
+          // Pooled sockets to an origin that just failed (agent restarted,
+          // connection reset) are likely dead too; drop them so the next
+          // request reconnects instead of failing on a stale socket.
+          if (agent) closeIdleSockets(agent)
+          staleSocket = req.reusedSocket && error?.code === 'ECONNRESET'
+          reject(error)
+        })
+
+        // Destroying the request rejects through the 'error' handler above
+        // with the abort reason, and frees the socket right away.
+        if (signal) {
+          const onAbort = () => req.destroy(signal.reason)
+          signal.addEventListener('abort', onAbort, { once: true })
+          req.on('close', () => signal.removeEventListener('abort', onAbort))
+        }
 
         // Bypass Node's headers: the Rust side has already produced the full
         // request head in HTTP/1.1 wire format. Setting _header before write()
         // makes write/end skip _implicitHeader and _send prepends our bytes.
 
         try {
-          req._header = Buffer.from(headView)
+          req._header = head
           req.write(bodyView)
           req.end()
         } catch (error) {
           reject(error)
         }
-      })
+      }
     })
   }
 
@@ -108,9 +490,194 @@ module.exports.httpRequest = function (host, port, isHttps, socketPath, head_ptr
       if (isDetachedBufferError(error)) {
         return attemptWithRetry()
       }
+      if (staleSocket && !retriedStaleSocket) {
+        retriedStaleSocket = true
+        return attemptWithRetry()
+      }
       throw error
     })
   }
 
   return attemptWithRetry()
 }
+
+// gRPC (OTLP export). One HTTP/2 session per origin and TLS options object is
+// kept open and multiplexes every call; it is dropped on error or GOAWAY and
+// reopened by the next call. A session only keeps the process alive while it
+// has calls in flight.
+const grpcSessions = new Set()
+const grpcSessionsByOrigin = new Map()
+const grpcSessionsByTls = new WeakMap()
+
+function grpcSession (origin, tlsOptions) {
+  let sessions = grpcSessionsByOrigin
+  if (tlsOptions) {
+    sessions = grpcSessionsByTls.get(tlsOptions)
+    if (!sessions) {
+      sessions = new Map()
+      grpcSessionsByTls.set(tlsOptions, sessions)
+    }
+  }
+  const existing = sessions.get(origin)
+  if (existing && !existing.closed && !existing.destroyed) return existing
+
+  const session = http2.connect(origin, pickTlsOptions(tlsOptions))
+  session.inFlight = 0
+  const forget = () => {
+    if (sessions.get(origin) === session) sessions.delete(origin)
+    grpcSessions.delete(session)
+  }
+  // Errors surface through the streams; without a listener they would throw.
+  session.on('error', forget)
+  session.on('goaway', forget)
+  session.on('close', forget)
+  session.unref()
+  sessions.set(origin, session)
+  grpcSessions.add(session)
+  return session
+}
+
+function flattenHeaders (headers) {
+  const flat = []
+  for (const [name, value] of Object.entries(headers)) {
+    if (name.startsWith(':')) continue
+    flat.push(name, Array.isArray(value) ? value.join(', ') : String(value))
+  }
+  return flat
+}
+
+// One unary call. Resolves with [httpStatus, headers, trailers, body] (header
+// lists flat like `rawHeaders`) whatever the grpc-status; the Rust side reads
+// the status and decides on retries. Rejects only when the call couldn't be
+// completed at all.
+module.exports.grpcRequest = function (origin, path, headers, body, signal, tlsOptions) {
+  // `body` is a view into wasm memory; copy it before anything async.
+  const payload = Buffer.from(body)
+  return new Promise((resolve, reject) => {
+    if (signal?.aborted) {
+      reject(signal.reason)
+      return
+    }
+    storage(() => {
+      let session
+      let stream
+      try {
+        session = grpcSession(origin, origin.startsWith('https:') ? tlsOptions : undefined)
+        const requestHeaders = { ':method': 'POST', ':path': path }
+        for (let i = 0; i + 1 < headers.length; i += 2) requestHeaders[headers[i]] = headers[i + 1]
+        stream = session.request(requestHeaders)
+      } catch (error) {
+        reject(error)
+        return
+      }
+      if (session.inFlight++ === 0) session.ref()
+
+      let settled = false
+      const settle = (fn, value) => {
+        if (settled) return
+        settled = true
+        if (--session.inFlight === 0) session.unref()
+        fn(value)
+      }
+
+      let responseHeaders = {}
+      let trailers = {}
+      const chunks = []
+      stream.on('response', (h) => { responseHeaders = h })
+      stream.on('trailers', (t) => { trailers = t })
+      stream.on('data', chunk => chunks.push(chunk))
+      stream.on('end', () => settle(resolve, [
+        Number(responseHeaders[':status']) || 0,
+        flattenHeaders(responseHeaders),
+        flattenHeaders(trailers),
+        new Uint8Array(Buffer.concat(chunks)),
+      ]))
+      // A session failure cancels its pending streams; report the cause
+      // (e.g. ECONNREFUSED) rather than the cancellation.
+      stream.on('error', error => {
+        settle(reject, error.code === 'ERR_HTTP2_STREAM_CANCEL' && error.cause ? error.cause : error)
+      })
+      stream.on('close', () => {
+        settle(reject, new Error(`gRPC stream closed with code ${stream.rstCode}`))
+      })
+
+      if (signal) {
+        const onAbort = () => {
+          settle(reject, signal.reason)
+          stream.close(http2.constants.NGHTTP2_CANCEL)
+        }
+        signal.addEventListener('abort', onAbort, { once: true })
+        stream.on('close', () => signal.removeEventListener('abort', onAbort))
+      }
+
+      stream.end(payload)
+    })
+  })
+}
+
+// Fire-and-forget datagram sockets for DogStatsD. `kind` is 'udp' (Node
+// `dgram`) or 'unixstream', a DogStatsD stream socket where each message is
+// prefixed by its u32 little-endian length (Node has no Unix datagram
+// sockets). Errors are swallowed and the socket is
+// reopened on the next send; neither socket keeps the process alive.
+module.exports.datagramOpen = function (kind, hostOrPath, port) {
+  return { kind, hostOrPath, port, socket: null }
+}
+
+function datagramSocket (handle) {
+  if (handle.socket) return handle.socket
+  let socket
+  if (handle.kind === 'unixstream') {
+    socket = net.createConnection(handle.hostOrPath)
+  } else {
+    socket = dgram.createSocket(net.isIPv6(handle.hostOrPath) ? 'udp6' : 'udp4')
+  }
+  const reset = () => {
+    if (handle.socket === socket) handle.socket = null
+  }
+  socket.on('error', () => {
+    reset()
+    try {
+      if (handle.kind === 'unixstream') {
+        socket.destroy()
+      } else {
+        socket.close()
+      }
+    } catch {
+      // Already closed.
+    }
+  })
+  socket.on('close', reset)
+  socket.unref()
+  handle.socket = socket
+  return socket
+}
+
+module.exports.datagramSend = function (handle, payload) {
+  // `payload` is a view into wasm memory; copy it before the async send.
+  const data = Buffer.from(payload)
+  try {
+    const socket = datagramSocket(handle)
+    if (handle.kind === 'unixstream') {
+      const length = Buffer.alloc(4)
+      length.writeUInt32LE(data.length)
+      socket.write(Buffer.concat([length, data]))
+    } else {
+      socket.send(data, handle.port, handle.hostOrPath, () => {})
+    }
+  } catch {
+    // Best effort, like DogStatsD itself.
+    handle.socket = null
+  }
+}
+
+module.exports.datagramClose = function (handle) {
+  const socket = handle.socket
+  handle.socket = null
+  if (!socket) return
+  if (handle.kind === 'unixstream') {
+    socket.end()
+  } else {
+    socket.close()
+  }
+}
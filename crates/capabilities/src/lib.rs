@@ -8,6 +8,13 @@
 //! and JS transports. The wasm binding crate pins this type as the capability
 //! generic for libdatadog's `TraceExporter`, mirroring libdatadog's native
 //! `NativeCapabilities`.
+//!
+//! For OTLP over gRPC, [`WasmCapabilities`] hands requests to the endpoint
+//! scoped with [`grpc::with_otlp_grpc`] to [`WasmGrpcClient`] instead of
+//! [`WasmHttpClient`].
+//!
+//! [`WasmDatagramSocket`] is not part of the bundle: `TraceExporter` doesn't
+//! use it; the pipeline sends its own DogStatsD health metrics through it.
 
 use std::future::Future;
 use std::time::Duration;
@@ -15,17 +22,22 @@ use std::time::Duration;
 use libdd_capabilities::http::HttpError;
 use libdd_capabilities::{HttpClientCapability, LogWriterCapability, MaybeSend, SleepCapability};
 
+pub mod datagram;
+pub mod grpc;
 pub mod http;
+pub mod log;
 pub mod sleep;
 
+pub use datagram::WasmDatagramSocket;
+pub use grpc::WasmGrpcClient;
 pub use http::WasmHttpClient;
 pub use sleep::WasmSleepCapability;
 
 /// Bundle of wasm platform capabilities for libdatadog's `TraceExporter`.
 ///
 /// Mirrors libdatadog's native `NativeCapabilities`: delegates HTTP to
-/// [`WasmHttpClient`] and sleep to [`WasmSleepCapability`]. Log output is a
-/// no-op (see the [`LogWriterCapability`] impl). Per-function bounds stay
+/// [`WasmHttpClient`], sleep to [`WasmSleepCapability`] and log output to the
+/// JS sink in [`log`]. Per-function bounds stay
 /// minimal in libdatadog (e.g. stats-only code uses [`WasmHttpClient`]
 /// directly), so this bundle is only needed where the full `TraceExporter`
 /// capability set is.
@@ -58,11 +70,18 @@ impl HttpClientCapability for WasmCapabilities {
         }
     }
 
+    #[allow(clippy::manual_async_fn)]
     fn request(
         &self,
         req: ::http::Request<::bytes::Bytes>,
     ) -> impl Future<Output = Result<::http::Response<::bytes::Bytes>, HttpError>> + MaybeSend {
-        self.http.request(req)
+        async move {
+            if grpc::routes_to_grpc(&req) {
+                WasmGrpcClient.export(req).await
+            } else {
+                self.http.request(req).await
+            }
+        }
     }
 }
 
@@ -80,12 +99,9 @@ impl SleepCapability for WasmCapabilities {
 }
 
 impl LogWriterCapability for WasmCapabilities {
-    fn write_log_output(&self, _bytes: &[u8]) -> std::io::Result<()> {
-        // The wasm binding runs exclusively in trace-export mode; the
-        // TraceExporter only invokes this in log-output mode, which this
-        // binding never enables. Implemented as a no-op to satisfy the
-        // capability bound. If log-output mode is added for wasm, route the
-        // buffer to a JS sink (e.g. process.stderr) here.
-        Ok(())
+    fn write_log_output(&self, bytes: &[u8]) -> std::io::Result<()> {
+        // Only called when the exporter was built in log-output mode
+        // (`setLogOutput`).
+        log::write_log_output(bytes)
     }
 }
@@ -0,0 +1,35 @@
+// Copyright 2026-Present Datadog, Inc. https://www.datadoghq.com/
+// SPDX-License-Identifier: Apache-2.0
+
+//! Log-output sink for `TraceExporter`'s log-output mode.
+//!
+//! In log-output mode (serverless, where a forwarder reads traces from
+//! stdout) the exporter hands each serialized payload to
+//! `LogWriterCapability::write_log_output` instead of sending it over HTTP.
+//! In wasm the bytes go to a JS sink, `process.stdout` unless the host
+//! installs its own with [`set_log_output_sink`].
+
+use std::io;
+
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen(module = "/src/http_transport.js")]
+extern "C" {
+    #[wasm_bindgen(js_name = "writeLogOutput", catch)]
+    fn js_write_log_output(bytes: &[u8]) -> Result<(), JsValue>;
+
+    #[wasm_bindgen(js_name = "setLogOutputSink")]
+    pub fn set_log_output_sink(sink: &JsValue);
+}
+
+/// Write one serialized payload to the JS sink. A throwing sink surfaces as
+/// an I/O error.
+pub fn write_log_output(bytes: &[u8]) -> io::Result<()> {
+    js_write_log_output(bytes).map_err(|e| {
+        let msg = js_sys::Reflect::get(&e, &JsValue::from_str("message"))
+            .ok()
+            .and_then(|m| m.as_string())
+            .unwrap_or_else(|| format!("{e:?}"));
+        io::Error::other(format!("log output sink failed: {msg}"))
+    })
+}
@@ -0,0 +1,234 @@
+// Copyright 2026-Present Datadog, Inc. https://www.datadoghq.com/
+// SPDX-License-Identifier: Apache-2.0
+
+//! Agent `/info` discovery for the pipeline WASM module.
+//!
+//! The agent advertises its endpoints and features on `GET /info`. The
+//! pipeline fetches it through [`WasmHttpClient`], caches it in an
+//! [`AgentInfoCache`], and uses it to pick the trace endpoint, to turn
+//! client-side stats off when the agent can't receive them, and to pick up the
+//! peer tags and span kinds used for stats aggregation. The cache is refetched
+//! on a fixed interval or as soon as a response of the same state's agent
+//! carries a new `Datadog-Agent-State`.
+
+use bytes::Bytes;
+use libdatadog_nodejs_capabilities::WasmHttpClient;
+use libdd_capabilities::http::HttpClientCapability;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::{ErrorKind, PipelineError};
+
+const INFO_PATH: &str = "/info";
+const AGENT_STATE_HEADER: &str = "datadog-agent-state";
+
+const V04_TRACES_PATH: &str = "/v0.4/traces";
+const V05_TRACES_PATH: &str = "/v0.5/traces";
+const STATS_PATH: &str = "/v0.6/stats";
+
+/// How long a fetched `/info` is trusted when the agent state doesn't change.
+const REFRESH_INTERVAL_NS: u64 = 5 * 60 * 1_000_000_000;
+
+/// The subset of the agent's `/info` response the tracer acts on.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AgentInfo {
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub endpoints: Vec<String>,
+    #[serde(default)]
+    pub client_drop_p0s: Option<bool>,
+    #[serde(default)]
+    pub span_kinds_stats_computed: Vec<String>,
+    #[serde(default)]
+    pub peer_tags: Vec<String>,
+    #[serde(default)]
+    pub feature_flags: Vec<String>,
+}
+
+impl AgentInfo {
+    pub fn from_json(body: &str) -> Result<Self, PipelineError> {
+        serde_json::from_str(body)
+            .map_err(|e| PipelineError::serialization(format!("invalid agent info: {e}")))
+    }
+
+    /// Whether the agent serves `path`. An agent that lists no endpoints at
+    /// all is assumed to serve everything (nothing to negotiate against).
+    pub fn supports(&self, path: &str) -> bool {
+        self.endpoints.is_empty() || self.endpoints.iter().any(|e| e == path)
+    }
+
+    /// Whether traces should go to `/v0.5/traces`: the host's preference when
+    /// the agent serves it, otherwise whichever of v0.4/v0.5 is available.
+    pub fn use_v05(&self, requested: bool) -> bool {
+        let v04 = self.supports(V04_TRACES_PATH);
+        let v05 = self.supports(V05_TRACES_PATH);
+        if requested {
+            v05 || !v04
+        } else {
+            !v04 && v05
+        }
+    }
+
+    pub fn stats_supported(&self) -> bool {
+        self.supports(STATS_PATH)
+    }
+
+    /// Whether the agent accepts traces with rejected chunks left out. Agents
+    /// that don't say are assumed to.
+    pub fn drop_p0_allowed(&self) -> bool {
+        self.client_drop_p0s != Some(false)
+    }
+}
+
+/// Cached `/info` plus what is needed to decide when to refetch it.
+/// Discovery is off until the host first asks for a refresh.
+#[derive(Default)]
+pub struct AgentInfoCache {
+    info: Option<AgentInfo>,
+    state: Option<String>,
+    latest_state: Option<String>,
+    fetched_at_ns: u64,
+    enabled: bool,
+}
+
+impl AgentInfoCache {
+    pub fn info(&self) -> Option<&AgentInfo> {
+        self.info.as_ref()
+    }
+
+    pub fn store(&mut self, info: AgentInfo, state: Option<String>, now_ns: u64) {
+        self.info = Some(info);
+        self.latest_state.clone_from(&state);
+        self.state = state;
+        self.mark_attempt(now_ns);
+    }
+
+    /// Note the `Datadog-Agent-State` of a response from the agent.
+    pub fn observe_state(&mut self, state: String) {
+        self.latest_state = Some(state);
+    }
+
+    /// Record a fetch attempt (successful or not) so a failing agent is only
+    /// retried once per interval.
+    pub fn mark_attempt(&mut self, now_ns: u64) {
+        self.enabled = true;
+        self.fetched_at_ns = now_ns;
+    }
+
+    /// Whether discovery is on and the cache is older than the refresh
+    /// interval or the agent has since answered with a state other than the
+    /// one the cache was fetched at.
+    pub fn needs_refresh(&self, now_ns: u64) -> bool {
+        self.enabled
+            && (now_ns.saturating_sub(self.fetched_at_ns) >= REFRESH_INTERVAL_NS
+                || self.latest_state.as_ref().is_some_and(|s| self.state.as_ref() != Some(s)))
+    }
+
+    /// Stats stay on until the agent says it can't take them.
+    pub fn stats_supported(&self) -> bool {
+        self.info.as_ref().is_none_or(AgentInfo::stats_supported)
+    }
+
+    pub fn drop_p0_allowed(&self) -> bool {
+        self.info.as_ref().is_none_or(AgentInfo::drop_p0_allowed)
+    }
+}
+
+/// Fetch `/info` from the agent. Returns the parsed info and the
+/// `Datadog-Agent-State` it was served with.
+pub async fn fetch(agent_url: &str) -> Result<(AgentInfo, Option<String>), PipelineError> {
+    let uri: http::Uri = format!("{agent_url}{INFO_PATH}")
+        .parse()
+        .map_err(|e| PipelineError::invalid_input(format!("invalid agent info URL: {e}")))?;
+    let req = http::Request::builder()
+        .method(http::Method::GET)
+        .uri(uri)
+        .body(Bytes::new())
+        .map_err(|e| {
+            PipelineError::new(
+                ErrorKind::Internal,
+                format!("failed to build agent info request: {e}"),
+            )
+        })?;
+    let resp = WasmHttpClient::new_client()
+        .request(req)
+        .await
+        .map_err(|e| PipelineError::from_http("agent info request error", &e))?;
+    if !resp.status().is_success() {
+        return Err(PipelineError::http_status(
+            format!("agent info request failed with status {}", resp.status()),
+            resp.status().as_u16(),
+            libdatadog_nodejs_capabilities::http::parse_retry_after(resp.headers()),
+        ));
+    }
+    let state = resp
+        .headers()
+        .get(AGENT_STATE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned);
+    let body = std::str::from_utf8(resp.body())
+        .map_err(|e| PipelineError::serialization(format!("agent info body is not utf-8: {e}")))?;
+    Ok((AgentInfo::from_json(body)?, state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(endpoints: &[&str]) -> AgentInfo {
+        AgentInfo {
+            endpoints: endpoints.iter().map(|e| e.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_info_response() {
+        let info = AgentInfo::from_json(
+            r#"{"version": "7.60.0", "endpoints": ["/v0.4/traces", "/v0.6/stats"],
+                "client_drop_p0s": true, "peer_tags": ["peer.service"],
+                "feature_flags": ["discovery"], "config": {"default_env": "none"}}"#,
+        )
+        .unwrap();
+        assert_eq!(info.version.as_deref(), Some("7.60.0"));
+        assert_eq!(info.peer_tags, vec!["peer.service"]);
+        assert_eq!(info.feature_flags, vec!["discovery"]);
+        assert!(info.stats_supported());
+        assert!(info.drop_p0_allowed());
+        assert!(AgentInfo::from_json("not json").is_err());
+    }
+
+    #[test]
+    fn negotiates_trace_endpoint() {
+        let both = info(&[V04_TRACES_PATH, V05_TRACES_PATH]);
+        assert!(both.use_v05(true));
+        assert!(!both.use_v05(false));
+        let v04_only = info(&[V04_TRACES_PATH]);
+        assert!(!v04_only.use_v05(true));
+        let v05_only = info(&[V05_TRACES_PATH]);
+        assert!(v05_only.use_v05(false));
+        // Nothing advertised: keep the host's choice.
+        assert!(info(&[]).use_v05(true));
+        assert!(!info(&[]).use_v05(false));
+    }
+
+    #[test]
+    fn refreshes_on_interval_or_state_change() {
+        let mut cache = AgentInfoCache::default();
+        cache.observe_state("a".into());
+        assert!(!cache.needs_refresh(u64::MAX), "off until enabled");
+        assert!(cache.stats_supported());
+
+        cache.store(info(&[V04_TRACES_PATH]), Some("a".into()), 10);
+        assert!(!cache.stats_supported());
+        assert!(!cache.needs_refresh(20));
+        cache.observe_state("a".into());
+        assert!(!cache.needs_refresh(20));
+        assert!(cache.needs_refresh(10 + REFRESH_INTERVAL_NS));
+        cache.observe_state("b".into());
+        assert!(cache.needs_refresh(20));
+
+        cache.store(info(&[V04_TRACES_PATH]), Some("b".into()), 30);
+        assert!(!cache.needs_refresh(40));
+    }
+}
@@ -0,0 +1,373 @@
+// Copyright 2026-Present Datadog, Inc. https://www.datadoghq.com/
+// SPDX-License-Identifier: Apache-2.0
+
+//! Agentless export straight to the Datadog trace intake.
+//!
+//! Without an agent, traces go to `https://trace.agent.<site>/api/v0.2/traces`
+//! as the protobuf `AgentPayload` the agent itself would forward, and client
+//! stats to `/api/v0.2/stats` as a msgpack `StatsPayload`. Both carry the
+//! `DD-API-KEY` header. The key is kept in an [`ApiKey`] whose `Debug` output
+//! is redacted, and every error from this path is scrubbed of it.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use bytes::Bytes;
+use libdatadog_nodejs_capabilities::WasmHttpClient;
+use libdd_capabilities::http::HttpClientCapability;
+use libdd_trace_protobuf::pb;
+use libdd_trace_utils::span::v04::{AttributeAnyValue, AttributeArrayValue, Span, SpanEvent};
+use prost::Message;
+
+use crate::errors::{ErrorKind, PipelineError};
+use crate::sampling;
+use crate::trace_data::WasmTraceData;
+
+const TRACES_PATH: &str = "/api/v0.2/traces";
+const STATS_PATH: &str = "/api/v0.2/stats";
+const API_KEY_HEADER: &str = "DD-API-KEY";
+const ORIGIN_KEY: &str = "_dd.origin";
+/// `TraceChunk.priority` of a chunk without a sampling decision (the agent's
+/// `PriorityNone`).
+const PRIORITY_NONE: i32 = -128;
+
+/// A Datadog API key. Never printed.
+#[derive(Clone)]
+pub struct ApiKey(String);
+
+impl ApiKey {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ApiKey(<redacted>)")
+    }
+}
+
+/// Where and how to reach the intake.
+#[derive(Clone, Debug)]
+pub struct Intake {
+    base_url: String,
+    api_key: ApiKey,
+}
+
+impl Intake {
+    /// Intake for `site` (e.g. `datadoghq.eu`), or at `url` when given (a
+    /// proxy or a local stand-in).
+    pub fn new(site: &str, api_key: String, url: Option<String>) -> Result<Self, PipelineError> {
+        if api_key.trim().is_empty() {
+            return Err(PipelineError::invalid_input(
+                "agentless export needs an API key",
+            ));
+        }
+        let base_url = match url {
+            Some(url) => url,
+            None if site.is_empty() => {
+                return Err(PipelineError::invalid_input(
+                    "agentless export needs a site or an intake URL",
+                ))
+            }
+            None => format!("https://trace.agent.{site}"),
+        };
+        Ok(Intake {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key: ApiKey(api_key),
+        })
+    }
+
+    pub fn traces_url(&self) -> String {
+        format!("{}{TRACES_PATH}", self.base_url)
+    }
+
+    pub fn stats_url(&self) -> String {
+        format!("{}{STATS_PATH}", self.base_url)
+    }
+
+    pub fn api_key_header(&self) -> (&'static str, &str) {
+        (API_KEY_HEADER, self.api_key.as_str())
+    }
+
+    /// `err` with every occurrence of the API key blanked out.
+    pub fn redact(&self, err: PipelineError) -> PipelineError {
+        err.redact(self.api_key.as_str())
+    }
+}
+
+/// Tracer identification for the `TracerPayload` envelope.
+pub struct TracerMeta {
+    pub lang: String,
+    pub lang_version: String,
+    pub tracer_version: String,
+    pub runtime_id: String,
+    pub env: String,
+    pub hostname: String,
+    pub app_version: String,
+}
+
+/// Encode `chunks` as a protobuf `AgentPayload`.
+pub fn encode_traces(chunks: &[Vec<Span<WasmTraceData>>], meta: &TracerMeta) -> Vec<u8> {
+    let chunks = chunks
+        .iter()
+        .map(|spans| pb::TraceChunk {
+            priority: spans
+                .iter()
+                .find_map(sampling::existing_priority)
+                .map_or(PRIORITY_NONE, |p| p as i32),
+            origin: spans
+                .iter()
+                .find_map(|s| s.meta.get(ORIGIN_KEY))
+                .map(|o| o.0.to_string())
+                .unwrap_or_default(),
+            spans: spans.iter().map(to_pb_span).collect(),
+            ..Default::default()
+        })
+        .collect();
+    let tracer_payload = pb::TracerPayload {
+        language_name: meta.lang.clone(),
+        language_version: meta.lang_version.clone(),
+        tracer_version: meta.tracer_version.clone(),
+        runtime_id: meta.runtime_id.clone(),
+        chunks,
+        env: meta.env.clone(),
+        hostname: meta.hostname.clone(),
+        app_version: meta.app_version.clone(),
+        ..Default::default()
+    };
+    pb::AgentPayload {
+        host_name: meta.hostname.clone(),
+        env: meta.env.clone(),
+        tracer_payloads: vec![tracer_payload],
+        ..Default::default()
+    }
+    .encode_to_vec()
+}
+
+fn to_pb_span(span: &Span<WasmTraceData>) -> pb::Span {
+    pb::Span {
+        service: span.service.0.to_string(),
+        name: span.name.0.to_string(),
+        resource: span.resource.0.to_string(),
+        r#type: span.r#type.0.to_string(),
+        // The high 64 bits travel in the `_dd.p.tid` tag, as in v0.4.
+        trace_id: span.trace_id as u64,
+        span_id: span.span_id,
+        parent_id: span.parent_id,
+        start: span.start,
+        duration: span.duration,
+        error: span.error,
+        meta: span
+            .meta
+            .iter()
+            .map(|(k, v)| (k.0.to_string(), v.0.to_string()))
+            .collect::<HashMap<_, _>>(),
+        metrics: span
+            .metrics
+            .iter()
+            .map(|(k, v)| (k.0.to_string(), *v))
+            .collect::<HashMap<_, _>>(),
+        meta_struct: span
+            .meta_struct
+            .iter()
+            .map(|(k, v)| (k.0.to_string(), v.0.to_vec()))
+            .collect::<HashMap<_, _>>(),
+        span_links: span
+            .span_links
+            .iter()
+            .map(|link| pb::SpanLink {
+                trace_id: link.trace_id,
+                trace_id_high: link.trace_id_high,
+                span_id: link.span_id,
+                attributes: link
+                    .attributes
+                    .iter()
+                    .map(|(k, v)| (k.0.to_string(), v.0.to_string()))
+                    .collect(),
+                tracestate: link.tracestate.0.to_string(),
+                flags: link.flags,
+            })
+            .collect(),
+        span_events: span.span_events.iter().map(to_pb_span_event).collect(),
+    }
+}
+
+fn to_pb_span_event(event: &SpanEvent<WasmTraceData>) -> pb::SpanEvent {
+    pb::SpanEvent {
+        time_unix_nano: event.time_unix_nano,
+        name: event.name.0.to_string(),
+        attributes: event
+            .attributes
+            .iter()
+            .map(|(k, v)| (k.0.to_string(), to_pb_attribute(v)))
+            .collect(),
+    }
+}
+
+fn to_pb_attribute(value: &AttributeAnyValue<WasmTraceData>) -> pb::AttributeAnyValue {
+    use pb::attribute_any_value::AttributeAnyValueType as AnyType;
+    match value {
+        AttributeAnyValue::SingleValue(value) => {
+            let value = to_pb_array_value(value);
+            pb::AttributeAnyValue {
+                // The scalar discriminants are the same in both enums.
+                r#type: value.r#type,
+                string_value: value.string_value,
+                bool_value: value.bool_value,
+                int_value: value.int_value,
+                double_value: value.double_value,
+                array_value: None,
+            }
+        }
+        AttributeAnyValue::Array(values) => pb::AttributeAnyValue {
+            r#type: AnyType::ArrayValue as i32,
+            array_value: Some(pb::AttributeArray {
+                values: values.iter().map(to_pb_array_value).collect(),
+            }),
+            ..Default::default()
+        },
+    }
+}
+
+fn to_pb_array_value(value: &AttributeArrayValue<WasmTraceData>) -> pb::AttributeArrayValue {
+    use pb::attribute_array_value::AttributeArrayValueType as ArrayType;
+    match value {
+        AttributeArrayValue::String(s) => pb::AttributeArrayValue {
+            r#type: ArrayType::StringValue as i32,
+            string_value: s.0.to_string(),
+            ..Default::default()
+        },
+        AttributeArrayValue::Boolean(b) => pb::AttributeArrayValue {
+            r#type: ArrayType::BoolValue as i32,
+            bool_value: *b,
+            ..Default::default()
+        },
+        AttributeArrayValue::Integer(i) => pb::AttributeArrayValue {
+            r#type: ArrayType::IntValue as i32,
+            int_value: *i,
+            ..Default::default()
+        },
+        AttributeArrayValue::Double(d) => pb::AttributeArrayValue {
+            r#type: ArrayType::DoubleValue as i32,
+            double_value: *d,
+            ..Default::default()
+        },
+    }
+}
+
+/// POST an encoded `AgentPayload` to the intake. Returns the response body.
+pub async fn send_traces(
+    intake: &Intake,
+    body: Vec<u8>,
+    lang: &str,
+) -> Result<String, PipelineError> {
+    send_traces_inner(intake, body, lang)
+        .await
+        .map_err(|e| intake.redact(e))
+}
+
+async fn send_traces_inner(
+    intake: &Intake,
+    body: Vec<u8>,
+    lang: &str,
+) -> Result<String, PipelineError> {
+    let uri: http::Uri = intake
+        .traces_url()
+        .parse()
+        .map_err(|e| PipelineError::invalid_input(format!("invalid intake URL: {e}")))?;
+    let (key_header, key) = intake.api_key_header();
+    let req = http::Request::builder()
+        .method(http::Method::POST)
+        .uri(uri)
+        .header("Content-Type", "application/x-protobuf")
+        .header("X-Datadog-Reported-Languages", lang)
+        .header(key_header, key)
+        .body(Bytes::from(body))
+        .map_err(|e| {
+            PipelineError::new(
+                ErrorKind::Internal,
+                format!("failed to build intake request: {e}"),
+            )
+        })?;
+    let resp = WasmHttpClient::new_client()
+        .request(req)
+        .await
+        .map_err(|e| PipelineError::from_http("intake request error", &e))?;
+    if !resp.status().is_success() {
+        return Err(PipelineError::http_status(
+            format!("intake responded with {}", resp.status()),
+            resp.status().as_u16(),
+            libdatadog_nodejs_capabilities::http::parse_retry_after(resp.headers()),
+        ));
+    }
+    Ok(String::from_utf8_lossy(resp.body()).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_intake_urls_and_redacts_the_key() {
+        let intake = Intake::new("datadoghq.eu", "abc123secret".into(), None).unwrap();
+        assert_eq!(
+            intake.traces_url(),
+            "https://trace.agent.datadoghq.eu/api/v0.2/traces"
+        );
+        assert!(!format!("{intake:?}").contains("abc123secret"));
+
+        let local = Intake::new("", "k3y".into(), Some("http://127.0.0.1:9/".into())).unwrap();
+        assert_eq!(local.stats_url(), "http://127.0.0.1:9/api/v0.2/stats");
+        let err = local.redact(PipelineError::invalid_input("bad header k3y"));
+        assert_eq!(err.to_string(), "bad header <redacted>");
+
+        assert!(Intake::new("datadoghq.com", " ".into(), None).is_err());
+        assert!(Intake::new("", "key".into(), None).is_err());
+    }
+
+    #[test]
+    fn encodes_span_events_and_missing_priority() {
+        let mut span = Span::<WasmTraceData> {
+            name: "evented".into(),
+            ..Default::default()
+        };
+        let mut attributes = HashMap::new();
+        attributes.insert(
+            "k".into(),
+            AttributeAnyValue::SingleValue(AttributeArrayValue::String("v".into())),
+        );
+        attributes.insert(
+            "n".into(),
+            AttributeAnyValue::Array(vec![
+                AttributeArrayValue::Integer(1),
+                AttributeArrayValue::Double(2.5),
+            ]),
+        );
+        span.span_events.push(SpanEvent {
+            time_unix_nano: 42,
+            name: "exception".into(),
+            attributes,
+        });
+        let meta = TracerMeta {
+            lang: "nodejs".into(),
+            lang_version: String::new(),
+            tracer_version: String::new(),
+            runtime_id: String::new(),
+            env: String::new(),
+            hostname: String::new(),
+            app_version: String::new(),
+        };
+
+        let payload = pb::AgentPayload::decode(&*encode_traces(&[vec![span]], &meta)).unwrap();
+        let chunk = &payload.tracer_payloads[0].chunks[0];
+        assert_eq!(chunk.priority, PRIORITY_NONE);
+        let event = &chunk.spans[0].span_events[0];
+        assert_eq!(event.time_unix_nano, 42);
+        assert_eq!(event.name, "exception");
+        assert_eq!(event.attributes["k"].string_value, "v");
+        let array = event.attributes["n"].array_value.as_ref().unwrap();
+        assert_eq!(array.values[0].int_value, 1);
+        assert_eq!(array.values[1].double_value, 2.5);
+    }
+}
@@ -0,0 +1,229 @@
+// Copyright 2026-Present Datadog, Inc. https://www.datadoghq.com/
+// SPDX-License-Identifier: Apache-2.0
+
+//! Accumulation of prepared trace chunks into a single agent payload.
+//!
+//! `prepareChunk` pushes each finished local trace into a [`PreparedBatch`] and
+//! `sendPreparedChunk` drains the whole batch into one
+//! `send_trace_chunks_async` call. Without [`BatchLimits`] every chunk is due
+//! as soon as it is prepared (one request per trace, the historical behavior);
+//! with limits the batch is only reported as due once it reaches the span or
+//! byte cap, or when JS decides to flush it on its own schedule. A batch that
+//! reached a cap is full: it takes no more chunks until it is sent.
+
+use libdd_trace_utils::span::v04::Span;
+
+use crate::trace_data::WasmTraceData;
+
+type WasmSpan = Span<WasmTraceData>;
+
+/// Fixed per-span msgpack cost: the v0.4 map header, the field-name keys and
+/// the numeric fields (ids, start, duration, error).
+const SPAN_OVERHEAD: usize = 160;
+/// Per-entry cost of a map key/value pair on top of the string bytes
+/// (two string headers).
+const ENTRY_OVERHEAD: usize = 4;
+/// Per span event / span link cost on top of their strings and attributes.
+const NESTED_OVERHEAD: usize = 48;
+
+/// Size caps for a batch. A zero field means "no cap" on that dimension; both
+/// zero disables batching.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BatchLimits {
+    pub max_spans: usize,
+    pub max_bytes: usize,
+}
+
+impl BatchLimits {
+    pub fn is_enabled(&self) -> bool {
+        self.max_spans > 0 || self.max_bytes > 0
+    }
+
+    /// Whether `batch` should be sent now. Without batching any non-empty
+    /// batch is due.
+    pub fn is_due(&self, batch: &PreparedBatch) -> bool {
+        if batch.is_empty() {
+            return false;
+        }
+        if !self.is_enabled() {
+            return true;
+        }
+        (self.max_spans > 0 && batch.span_count >= self.max_spans)
+            || (self.max_bytes > 0 && batch.encoded_size >= self.max_bytes)
+    }
+
+    /// Whether `batch` has reached a cap and must be sent before another
+    /// chunk is added. Never true without batching, where each chunk replaces
+    /// the previous one instead.
+    pub fn is_full(&self, batch: &PreparedBatch) -> bool {
+        self.is_enabled() && self.is_due(batch)
+    }
+}
+
+/// Chunks prepared but not yet sent, with running totals for the caps. The
+/// first `pinned` chunks are only ever drained by a send, never recycled as
+/// stale.
+#[derive(Default)]
+pub struct PreparedBatch {
+    chunks: Vec<Vec<WasmSpan>>,
+    pinned: usize,
+    span_count: usize,
+    encoded_size: usize,
+}
+
+impl PreparedBatch {
+    pub fn push(&mut self, chunk: Vec<WasmSpan>) {
+        self.add_totals(&chunk);
+        self.chunks.push(chunk);
+    }
+
+    /// Add a chunk that only a send drains, ahead of the unpinned ones.
+    pub fn push_pinned(&mut self, chunk: Vec<WasmSpan>) {
+        self.add_totals(&chunk);
+        self.chunks.insert(self.pinned, chunk);
+        self.pinned += 1;
+    }
+
+    /// Pin every chunk currently in the batch.
+    pub fn pin_all(&mut self) {
+        self.pinned = self.chunks.len();
+    }
+
+    /// Drain every accumulated chunk, resetting the totals.
+    pub fn take(&mut self) -> Vec<Vec<WasmSpan>> {
+        self.pinned = 0;
+        self.span_count = 0;
+        self.encoded_size = 0;
+        std::mem::take(&mut self.chunks)
+    }
+
+    /// Drain the chunks that aren't pinned.
+    pub fn take_stale(&mut self) -> Vec<Vec<WasmSpan>> {
+        let stale = self.chunks.split_off(self.pinned);
+        for chunk in &stale {
+            self.span_count -= chunk.len();
+            self.encoded_size -= chunk.iter().map(estimate_encoded_size).sum::<usize>();
+        }
+        stale
+    }
+
+    fn add_totals(&mut self, chunk: &[WasmSpan]) {
+        self.span_count += chunk.len();
+        self.encoded_size += chunk.iter().map(estimate_encoded_size).sum::<usize>();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn span_count(&self) -> usize {
+        self.span_count
+    }
+
+    pub fn encoded_size(&self) -> usize {
+        self.encoded_size
+    }
+}
+
+/// Approximate v0.4 msgpack size of `span`. Cheap enough to run on every
+/// prepared span (string lengths only, no serialization); it only has to be
+/// close enough to keep payloads under the configured byte cap.
+pub fn estimate_encoded_size(span: &WasmSpan) -> usize {
+    let mut size = SPAN_OVERHEAD
+        + span.service.0.len()
+        + span.name.0.len()
+        + span.resource.0.len()
+        + span.r#type.0.len();
+    for (k, v) in span.meta.iter() {
+        size += ENTRY_OVERHEAD + k.0.len() + v.0.len();
+    }
+    for (k, _) in span.metrics.iter() {
+        size += ENTRY_OVERHEAD + k.0.len() + 8;
+    }
+    for (k, v) in span.meta_struct.iter() {
+        size += ENTRY_OVERHEAD + k.0.len() + v.0.len();
+    }
+    for event in &span.span_events {
+        size += NESTED_OVERHEAD + event.name.0.len();
+        for (k, _) in event.attributes.iter() {
+            // Attribute values are typed; count a nominal 16 bytes per value.
+            size += ENTRY_OVERHEAD + k.0.len() + 16;
+        }
+    }
+    for link in &span.span_links {
+        size += NESTED_OVERHEAD + link.tracestate.0.len();
+        for (k, v) in link.attributes.iter() {
+            size += ENTRY_OVERHEAD + k.0.len() + v.0.len();
+        }
+    }
+    size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(names: &[&str]) -> Vec<WasmSpan> {
+        names
+            .iter()
+            .map(|name| WasmSpan {
+                name: (*name).into(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn is_full_only_with_limits() {
+        let mut batch = PreparedBatch::default();
+        batch.push(chunk(&["a", "b"]));
+        assert!(!BatchLimits::default().is_full(&batch));
+        assert!(BatchLimits::default().is_due(&batch));
+        let limits = BatchLimits {
+            max_spans: 3,
+            max_bytes: 0,
+        };
+        assert!(!limits.is_full(&batch));
+        batch.push(chunk(&["c"]));
+        assert!(limits.is_full(&batch));
+    }
+
+    #[test]
+    fn take_stale_keeps_pinned_chunks() {
+        let mut batch = PreparedBatch::default();
+        batch.push(chunk(&["kept"]));
+        batch.pin_all();
+        batch.push(chunk(&["stale", "stale"]));
+        let kept_size = estimate_encoded_size(&chunk(&["kept"])[0]);
+
+        let stale = batch.take_stale();
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].len(), 2);
+        assert_eq!(batch.chunk_count(), 1);
+        assert_eq!(batch.span_count(), 1);
+        assert_eq!(batch.encoded_size(), kept_size);
+        assert!(batch.take_stale().is_empty());
+
+        assert_eq!(batch.take().len(), 1);
+        batch.push(chunk(&["next"]));
+        assert_eq!(batch.take_stale().len(), 1, "a send unpins the batch");
+    }
+
+    #[test]
+    fn push_pinned_survives_take_stale() {
+        let mut batch = PreparedBatch::default();
+        batch.push(chunk(&["stale"]));
+        batch.push_pinned(chunk(&["orphan", "orphan"]));
+        batch.push(chunk(&["stale"]));
+
+        let stale = batch.take_stale();
+        assert_eq!(stale.len(), 2);
+        assert_eq!(batch.chunk_count(), 1);
+        assert_eq!(batch.span_count(), 2);
+        assert_eq!(batch.take()[0].len(), 2);
+    }
+}
@@ -0,0 +1,438 @@
+// Copyright 2026-Present Datadog, Inc. https://www.datadoghq.com/
+// SPDX-License-Identifier: Apache-2.0
+
+//! Extended change-queue opcodes.
+//!
+//! libdatadog's `flush_change_buffer` understands opcodes 0..=12. The ops
+//! below carry variable-length payloads (meta_struct bytes, span events, span
+//! links) and are applied by the pipeline itself, as are the trace-level ops
+//! 10..=12, whose state the pipeline keeps (see `trace_tags`). [`parse`]
+//! splits the queue into runs of libdatadog ops and the extended ops between
+//! them, so the caller can hand each run to libdatadog and apply the extended
+//! ops in queue order.
+//!
+//! Queue layout: `[count: u64]` then `count` entries, each
+//! `[opcode: u16][span_id: u64]` followed by the opcode's arguments. The
+//! extended ops' arguments are (all little-endian, strings as
+//! `[len: u32][utf8]`, byte blobs as `[len: u32][bytes]`):
+//!
+//! - `SetMetaStruct` (13): key string, value blob (msgpack).
+//! - `AddSpanEvent` (14): `time_unix_nano: u64`, name string, attribute blob.
+//! - `AddSpanLink` (15): `trace_id_high: u64`, `trace_id_low: u64`,
+//!   `span_id: u64`, `flags: u32`, tracestate string, attribute blob.
+//!
+//! The trace-level ops keep libdatadog's arguments: string table ids (`u32`)
+//! for `SetTraceMetaAttr` (10, key and value), `SetTraceMetricsAttr` (11, key
+//! then an `f64`) and `SetTraceOrigin` (12).
+//!
+//! Attribute blobs use the `addSpanEvent` encoding and are decoded when the op
+//! is applied. Every read is bounded, so a truncated entry errors instead of
+//! reading past the queue.
+
+use std::ops::Range;
+
+use crate::errors::PipelineError;
+use crate::utils::get_num;
+
+pub const SET_TRACE_META_ATTR: u16 = 10;
+pub const SET_TRACE_METRICS_ATTR: u16 = 11;
+pub const SET_TRACE_ORIGIN: u16 = 12;
+pub const SET_META_STRUCT: u16 = 13;
+pub const ADD_SPAN_EVENT: u16 = 14;
+pub const ADD_SPAN_LINK: u16 = 15;
+
+/// Byte offset of the first entry (after the u64 count).
+pub const HEADER_LEN: usize = 8;
+
+/// Argument size of the fixed-size libdatadog opcodes, `None` for anything
+/// else.
+fn fixed_args_len(opcode: u16) -> Option<usize> {
+    Some(match opcode {
+        // Create: trace id (u128), segment id (u64), parent id (u64)
+        0 => 32,
+        // SetMetaAttr: key and value string ids
+        1 => 8,
+        // SetMetricAttr: key string id and f64
+        2 => 12,
+        // SetServiceName, SetResourceName, SetError, SetType, SetName: one
+        // string id or i32
+        3 | 4 | 5 | 8 | 9 => 4,
+        // SetStart, SetDuration: i64
+        6 | 7 => 8,
+        _ => return None,
+    })
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ExtendedOp {
+    SetTraceMetaAttr {
+        key: u32,
+        value: u32,
+    },
+    SetTraceMetricsAttr {
+        key: u32,
+        value: f64,
+    },
+    SetTraceOrigin {
+        value: u32,
+    },
+    SetMetaStruct {
+        key: String,
+        value: Vec<u8>,
+    },
+    AddSpanEvent {
+        time_unix_nano: u64,
+        name: String,
+        attrs: Vec<u8>,
+    },
+    AddSpanLink {
+        trace_id_high: u64,
+        trace_id_low: u64,
+        span_id: u64,
+        flags: u32,
+        tracestate: String,
+        attrs: Vec<u8>,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Entry {
+    /// `count` consecutive libdatadog ops stored at `range` of the queue.
+    Ops { range: Range<usize>, count: u64 },
+    /// An extended op for `span_id`.
+    Extended { span_id: u64, op: ExtendedOp },
+}
+
+impl Entry {
+    fn is_extended(&self) -> bool {
+        matches!(self, Entry::Extended { .. })
+    }
+}
+
+/// Split the queued entries into libdatadog runs and extended ops, in order.
+/// Returns `None` when the queue holds only libdatadog ops (the common case),
+/// so the caller can flush it in place. `on_op` sees every libdatadog op as
+/// `(opcode, span_id, args)`.
+pub fn parse(
+    queue: &[u8],
+    on_op: &mut impl FnMut(u16, u64, &[u8]),
+) -> Result<Option<Vec<Entry>>, PipelineError> {
+    let mut idx = 0;
+    let count: u64 = get_num(queue, &mut idx).ok_or_else(|| truncated("count"))?;
+    let mut entries = Vec::new();
+    let mut run_start = idx;
+    let mut run_count = 0u64;
+    for _ in 0..count {
+        let entry_start = idx;
+        let opcode: u16 = read_u16(queue, &mut idx)?;
+        let span_id: u64 = get_num(queue, &mut idx).ok_or_else(|| truncated("span id"))?;
+        if let Some(len) = fixed_args_len(opcode) {
+            let args_start = idx;
+            skip(queue, &mut idx, len)?;
+            on_op(opcode, span_id, &queue[args_start..idx]);
+            run_count += 1;
+            continue;
+        }
+        let op = match opcode {
+            SET_TRACE_META_ATTR => ExtendedOp::SetTraceMetaAttr {
+                key: read_u32(queue, &mut idx)?,
+                value: read_u32(queue, &mut idx)?,
+            },
+            SET_TRACE_METRICS_ATTR => ExtendedOp::SetTraceMetricsAttr {
+                key: read_u32(queue, &mut idx)?,
+                value: get_num(queue, &mut idx).ok_or_else(|| truncated("arguments"))?,
+            },
+            SET_TRACE_ORIGIN => ExtendedOp::SetTraceOrigin {
+                value: read_u32(queue, &mut idx)?,
+            },
+            SET_META_STRUCT => ExtendedOp::SetMetaStruct {
+                key: read_str(queue, &mut idx)?,
+                value: read_blob(queue, &mut idx)?.to_vec(),
+            },
+            ADD_SPAN_EVENT => ExtendedOp::AddSpanEvent {
+                time_unix_nano: read_u64(queue, &mut idx)?,
+                name: read_str(queue, &mut idx)?,
+                attrs: read_blob(queue, &mut idx)?.to_vec(),
+            },
+            ADD_SPAN_LINK => ExtendedOp::AddSpanLink {
+                trace_id_high: read_u64(queue, &mut idx)?,
+                trace_id_low: read_u64(queue, &mut idx)?,
+                span_id: read_u64(queue, &mut idx)?,
+                flags: get_num(queue, &mut idx).ok_or_else(|| truncated("flags"))?,
+                tracestate: read_str(queue, &mut idx)?,
+                attrs: read_blob(queue, &mut idx)?.to_vec(),
+            },
+            other => {
+                return Err(PipelineError::invalid_input(format!(
+                    "change queue: unknown opcode {other}"
+                )))
+            }
+        };
+        if run_count > 0 {
+            entries.push(Entry::Ops {
+                range: run_start..entry_start,
+                count: run_count,
+            });
+        }
+        entries.push(Entry::Extended { span_id, op });
+        run_start = idx;
+        run_count = 0;
+    }
+    if !entries.iter().any(Entry::is_extended) {
+        return Ok(None);
+    }
+    if run_count > 0 {
+        entries.push(Entry::Ops {
+            range: run_start..idx,
+            count: run_count,
+        });
+    }
+    Ok(Some(entries))
+}
+
+fn truncated(what: &str) -> PipelineError {
+    PipelineError::invalid_input(format!("change queue: truncated entry ({what})"))
+}
+
+fn skip(buf: &[u8], idx: &mut usize, len: usize) -> Result<(), PipelineError> {
+    if *idx > buf.len() || len > buf.len() - *idx {
+        return Err(truncated("arguments"));
+    }
+    *idx += len;
+    Ok(())
+}
+
+fn read_u16(buf: &[u8], idx: &mut usize) -> Result<u16, PipelineError> {
+    let bytes = buf
+        .get(*idx..*idx + 2)
+        .ok_or_else(|| truncated("opcode"))?;
+    *idx += 2;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(buf: &[u8], idx: &mut usize) -> Result<u32, PipelineError> {
+    get_num(buf, idx).ok_or_else(|| truncated("arguments"))
+}
+
+fn read_u64(buf: &[u8], idx: &mut usize) -> Result<u64, PipelineError> {
+    get_num(buf, idx).ok_or_else(|| truncated("arguments"))
+}
+
+fn read_blob<'a>(buf: &'a [u8], idx: &mut usize) -> Result<&'a [u8], PipelineError> {
+    let len: u32 = get_num(buf, idx).ok_or_else(|| truncated("length"))?;
+    let start = *idx;
+    skip(buf, idx, len as usize)?;
+    Ok(&buf[start..*idx])
+}
+
+fn read_str(buf: &[u8], idx: &mut usize) -> Result<String, PipelineError> {
+    let bytes = read_blob(buf, idx)?;
+    std::str::from_utf8(bytes)
+        .map(str::to_owned)
+        .map_err(|e| PipelineError::invalid_input(format!("change queue: invalid utf8: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blob(out: &mut Vec<u8>, bytes: &[u8]) {
+        out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        out.extend_from_slice(bytes);
+    }
+
+    fn op(out: &mut Vec<u8>, opcode: u16, span_id: u64) {
+        out.extend_from_slice(&opcode.to_le_bytes());
+        out.extend_from_slice(&span_id.to_le_bytes());
+    }
+
+    fn parse(queue: &[u8]) -> Result<Option<Vec<Entry>>, PipelineError> {
+        super::parse(queue, &mut |_, _, _| {})
+    }
+
+    fn queue(count: u64, body: &[u8]) -> Vec<u8> {
+        let mut out = count.to_le_bytes().to_vec();
+        out.extend_from_slice(body);
+        out
+    }
+
+    /// SetName, SetMetaStruct, SetStart, AddSpanEvent, AddSpanLink.
+    fn mixed_body() -> Vec<u8> {
+        let mut body = Vec::new();
+        op(&mut body, 9, 1);
+        body.extend_from_slice(&7u32.to_le_bytes());
+        op(&mut body, SET_META_STRUCT, 1);
+        blob(&mut body, b"appsec");
+        blob(&mut body, &[0x81, 0xa1, 0x61, 0x01]);
+        op(&mut body, 6, 1);
+        body.extend_from_slice(&42i64.to_le_bytes());
+        op(&mut body, ADD_SPAN_EVENT, 1);
+        body.extend_from_slice(&99u64.to_le_bytes());
+        blob(&mut body, b"boom");
+        blob(&mut body, &[]);
+        op(&mut body, ADD_SPAN_LINK, 1);
+        for n in [1u64, 2, 3] {
+            body.extend_from_slice(&n.to_le_bytes());
+        }
+        body.extend_from_slice(&0x8000_0001u32.to_le_bytes());
+        blob(&mut body, b"dd=s:1");
+        blob(&mut body, &[]);
+        body
+    }
+
+    #[test]
+    fn standard_ops_flush_in_place() {
+        let mut body = Vec::new();
+        op(&mut body, 0, 1);
+        body.extend_from_slice(&[0u8; 32]);
+        op(&mut body, 2, 1);
+        body.extend_from_slice(&[0u8; 12]);
+        assert_eq!(parse(&queue(2, &body)).unwrap(), None);
+        assert_eq!(parse(&queue(0, &[])).unwrap(), None);
+    }
+
+    #[test]
+    fn reports_libdatadog_ops_with_their_args() {
+        let mut seen = Vec::new();
+        super::parse(&queue(5, &mixed_body()), &mut |opcode, span_id, args| {
+            seen.push((opcode, span_id, args.to_vec()))
+        })
+        .unwrap();
+        assert_eq!(
+            seen,
+            vec![
+                (9, 1, 7u32.to_le_bytes().to_vec()),
+                (6, 1, 42i64.to_le_bytes().to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn splits_runs_around_extended_ops() {
+        let q = queue(5, &mixed_body());
+        let entries = parse(&q).unwrap().unwrap();
+        assert_eq!(entries.len(), 5);
+        assert_eq!(
+            entries[0],
+            Entry::Ops {
+                range: 8..22,
+                count: 1
+            }
+        );
+        assert_eq!(
+            entries[1],
+            Entry::Extended {
+                span_id: 1,
+                op: ExtendedOp::SetMetaStruct {
+                    key: "appsec".into(),
+                    value: vec![0x81, 0xa1, 0x61, 0x01],
+                },
+            }
+        );
+        assert!(matches!(entries[2], Entry::Ops { count: 1, .. }));
+        assert_eq!(
+            entries[3],
+            Entry::Extended {
+                span_id: 1,
+                op: ExtendedOp::AddSpanEvent {
+                    time_unix_nano: 99,
+                    name: "boom".into(),
+                    attrs: vec![],
+                },
+            }
+        );
+        assert_eq!(
+            entries[4],
+            Entry::Extended {
+                span_id: 1,
+                op: ExtendedOp::AddSpanLink {
+                    trace_id_high: 1,
+                    trace_id_low: 2,
+                    span_id: 3,
+                    flags: 0x8000_0001,
+                    tracestate: "dd=s:1".into(),
+                    attrs: vec![],
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn applies_trace_level_ops_itself() {
+        let mut body = Vec::new();
+        op(&mut body, 0, 1);
+        body.extend_from_slice(&[0u8; 32]);
+        op(&mut body, SET_TRACE_META_ATTR, 1);
+        body.extend_from_slice(&3u32.to_le_bytes());
+        body.extend_from_slice(&4u32.to_le_bytes());
+        op(&mut body, SET_TRACE_METRICS_ATTR, 1);
+        body.extend_from_slice(&5u32.to_le_bytes());
+        body.extend_from_slice(&2.0f64.to_le_bytes());
+        op(&mut body, SET_TRACE_ORIGIN, 1);
+        body.extend_from_slice(&6u32.to_le_bytes());
+        let full = queue(4, &body);
+
+        let entries = parse(&full).unwrap().unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                Entry::Ops {
+                    range: 8..50,
+                    count: 1
+                },
+                Entry::Extended {
+                    span_id: 1,
+                    op: ExtendedOp::SetTraceMetaAttr { key: 3, value: 4 },
+                },
+                Entry::Extended {
+                    span_id: 1,
+                    op: ExtendedOp::SetTraceMetricsAttr {
+                        key: 5,
+                        value: 2.0
+                    },
+                },
+                Entry::Extended {
+                    span_id: 1,
+                    op: ExtendedOp::SetTraceOrigin { value: 6 },
+                },
+            ]
+        );
+        for len in 0..full.len() {
+            assert!(parse(&full[..len]).is_err(), "truncated to {len} bytes");
+        }
+    }
+
+    #[test]
+    fn rejects_every_truncation() {
+        let full = queue(5, &mixed_body());
+        for len in 0..full.len() {
+            assert!(parse(&full[..len]).is_err(), "truncated to {len} bytes");
+        }
+    }
+
+    #[test]
+    fn rejects_inflated_lengths_and_counts() {
+        let mut body = Vec::new();
+        op(&mut body, SET_META_STRUCT, 1);
+        body.extend_from_slice(&u32::MAX.to_le_bytes());
+        body.extend_from_slice(b"key");
+        assert!(parse(&queue(1, &body)).is_err());
+
+        let full = mixed_body();
+        assert!(parse(&queue(6, &full)).is_err());
+        assert!(parse(&queue(u64::MAX, &full)).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_opcodes_and_bad_utf8() {
+        let mut body = Vec::new();
+        op(&mut body, 200, 1);
+        assert!(parse(&queue(1, &body)).is_err());
+
+        let mut body = Vec::new();
+        op(&mut body, SET_META_STRUCT, 1);
+        blob(&mut body, &[0xff, 0xfe]);
+        blob(&mut body, &[]);
+        assert!(parse(&queue(1, &body)).is_err());
+    }
+}
@@ -0,0 +1,310 @@
+// Copyright 2026-Present Datadog, Inc. https://www.datadoghq.com/
+// SPDX-License-Identifier: Apache-2.0
+
+//! Tracer health metrics over DogStatsD.
+//!
+//! [`HealthReporter`] turns the exporter [`Counters`] into the
+//! `datadog.tracer.*` health metrics other tracers emit: counters are sent as
+//! DogStatsD counts of what changed since the previous emission, live sizes as
+//! gauges. Lines are packed into as few datagrams as the socket's payload limit
+//! allows and sent through [`WasmDatagramSocket`].
+
+use std::fmt::Write as _;
+use std::time::Duration;
+
+use libdatadog_nodejs_capabilities::datagram::DatagramTarget;
+use libdatadog_nodejs_capabilities::WasmDatagramSocket;
+
+use crate::metrics::{Counters, Snapshot};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricKind {
+    Count,
+    Gauge,
+}
+
+/// Render one DogStatsD line (without the trailing newline).
+pub fn format_metric(out: &mut String, name: &str, value: u64, kind: MetricKind, tags: &[&str]) {
+    let kind = match kind {
+        MetricKind::Count => "c",
+        MetricKind::Gauge => "g",
+    };
+    let _ = write!(out, "{name}:{value}|{kind}");
+    for (i, tag) in tags.iter().enumerate() {
+        out.push_str(if i == 0 { "|#" } else { "," });
+        out.push_str(tag);
+    }
+}
+
+/// Pack newline-separated `lines` into payloads of at most `max_payload`
+/// bytes. A single line longer than the limit is sent on its own.
+pub fn pack_lines(lines: &[String], max_payload: usize) -> Vec<String> {
+    let mut payloads = Vec::new();
+    let mut current = String::new();
+    for line in lines {
+        if !current.is_empty() && current.len() + 1 + line.len() > max_payload {
+            payloads.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(line);
+    }
+    if !current.is_empty() {
+        payloads.push(current);
+    }
+    payloads
+}
+
+/// The health metric lines for `now` relative to the counters at the previous
+/// emission. Counts that didn't move are left out; gauges are always sent.
+pub fn health_lines(prev: &Counters, now: &Snapshot, tags: &[String]) -> Vec<String> {
+    let cur = &now.counters;
+    let base: Vec<&str> = tags.iter().map(String::as_str).collect();
+    let mut lines = Vec::new();
+    let mut emit = |name: &str, value: u64, kind: MetricKind, extra: &[&str]| {
+        if kind == MetricKind::Count && value == 0 {
+            return;
+        }
+        let mut line = String::new();
+        let mut all = base.clone();
+        all.extend_from_slice(extra);
+        format_metric(&mut line, name, value, kind, &all);
+        lines.push(line);
+    };
+    let delta = |cur: u64, prev: u64| cur.saturating_sub(prev);
+
+    emit(
+        "datadog.tracer.queue.enqueued.traces",
+        delta(cur.chunks_prepared, prev.chunks_prepared),
+        MetricKind::Count,
+        &[],
+    );
+    emit(
+        "datadog.tracer.queue.enqueued.spans",
+        delta(cur.spans_prepared, prev.spans_prepared),
+        MetricKind::Count,
+        &[],
+    );
+    for (reason, cur_spans, prev_spans, cur_chunks, prev_chunks) in [
+        (
+            "reason:unsent",
+            cur.stale_spans_recycled,
+            prev.stale_spans_recycled,
+            cur.stale_chunks_recycled,
+            prev.stale_chunks_recycled,
+        ),
+        (
+            "reason:p0_drop",
+            cur.p0_spans_dropped,
+            prev.p0_spans_dropped,
+            cur.p0_chunks_dropped,
+            prev.p0_chunks_dropped,
+        ),
+        (
+            "reason:discarded",
+            cur.spans_discarded,
+            prev.spans_discarded,
+            cur.chunks_discarded,
+            prev.chunks_discarded,
+        ),
+        (
+            "reason:orphaned",
+            cur.orphan_spans_dropped,
+            prev.orphan_spans_dropped,
+            cur.orphan_chunks_dropped,
+            prev.orphan_chunks_dropped,
+        ),
+        (
+            "reason:send_failed",
+            cur.spans_failed,
+            prev.spans_failed,
+            cur.chunks_failed,
+            prev.chunks_failed,
+        ),
+    ] {
+        emit(
+            "datadog.tracer.queue.dropped.traces",
+            delta(cur_chunks, prev_chunks),
+            MetricKind::Count,
+            &[reason],
+        );
+        emit(
+            "datadog.tracer.queue.dropped.spans",
+            delta(cur_spans, prev_spans),
+            MetricKind::Count,
+            &[reason],
+        );
+    }
+    emit(
+        "datadog.tracer.flush.traces",
+        delta(cur.chunks_sent, prev.chunks_sent),
+        MetricKind::Count,
+        &[],
+    );
+    emit(
+        "datadog.tracer.flush.spans",
+        delta(cur.spans_sent, prev.spans_sent),
+        MetricKind::Count,
+        &[],
+    );
+    emit(
+        "datadog.tracer.flush.bytes",
+        delta(cur.bytes_sent, prev.bytes_sent),
+        MetricKind::Count,
+        &[],
+    );
+    emit(
+        "datadog.tracer.api.requests",
+        delta(
+            cur.payloads_sent + cur.payloads_failed,
+            prev.payloads_sent + prev.payloads_failed,
+        ),
+        MetricKind::Count,
+        &[],
+    );
+    for (class, count) in &cur.send_errors {
+        let before = prev.send_errors.get(class).copied().unwrap_or(0);
+        let tag = format!("type:{class}");
+        emit(
+            "datadog.tracer.api.errors",
+            delta(*count, before),
+            MetricKind::Count,
+            &[&tag],
+        );
+    }
+    emit(
+        "datadog.tracer.stats.buckets",
+        delta(cur.stats_buckets_flushed, prev.stats_buckets_flushed),
+        MetricKind::Count,
+        &[],
+    );
+    emit(
+        "datadog.tracer.queue.pending.spans",
+        now.pending_spans as u64,
+        MetricKind::Gauge,
+        &[],
+    );
+    emit(
+        "datadog.tracer.spans.live",
+        now.live_spans as u64,
+        MetricKind::Gauge,
+        &[],
+    );
+    emit(
+        "datadog.tracer.string_table.entries",
+        now.string_table_entries as u64,
+        MetricKind::Gauge,
+        &[],
+    );
+    lines
+}
+
+/// Periodic health metric emission to one DogStatsD endpoint.
+pub struct HealthReporter {
+    socket: WasmDatagramSocket,
+    tags: Vec<String>,
+    interval: Duration,
+    last_emit_ns: u64,
+    last: Counters,
+}
+
+impl HealthReporter {
+    pub fn new(target: DatagramTarget, tags: Vec<String>, interval: Duration, now_ns: u64) -> Self {
+        HealthReporter {
+            socket: WasmDatagramSocket::open(target),
+            tags,
+            interval,
+            last_emit_ns: now_ns,
+            last: Counters::default(),
+        }
+    }
+
+    pub fn is_due(&self, now_ns: u64) -> bool {
+        now_ns.saturating_sub(self.last_emit_ns) >= self.interval.as_nanos() as u64
+    }
+
+    /// How long until the next emission is due.
+    pub fn until_due(&self, now_ns: u64) -> Duration {
+        let elapsed = Duration::from_nanos(now_ns.saturating_sub(self.last_emit_ns));
+        self.interval.saturating_sub(elapsed)
+    }
+
+    /// Send the metrics for `snapshot` and remember its counters as the base
+    /// for the next deltas.
+    pub fn emit(&mut self, snapshot: Snapshot, now_ns: u64) {
+        let lines = health_lines(&self.last, &snapshot, &self.tags);
+        for payload in pack_lines(&lines, self.socket.target().max_payload()) {
+            self.socket.send(payload.as_bytes());
+        }
+        self.last = snapshot.counters;
+        self.last_emit_ns = now_ns;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(counters: Counters) -> Snapshot {
+        Snapshot {
+            counters,
+            live_spans: 4,
+            live_segments: 1,
+            string_table_entries: 7,
+            pending_chunks: 0,
+            pending_spans: 0,
+            pending_bytes: 0,
+            connections_opened: 0,
+            connections_reused: 0,
+        }
+    }
+
+    #[test]
+    fn formats_lines_with_tags() {
+        let mut line = String::new();
+        format_metric(&mut line, "a.b", 3, MetricKind::Count, &["env:prod", "x:y"]);
+        assert_eq!(line, "a.b:3|c|#env:prod,x:y");
+        line.clear();
+        format_metric(&mut line, "a.b", 3, MetricKind::Gauge, &[]);
+        assert_eq!(line, "a.b:3|g");
+    }
+
+    #[test]
+    fn packs_lines_under_the_payload_limit() {
+        let lines: Vec<String> = (0..5).map(|i| format!("m{i}:1|c")).collect();
+        let payloads = pack_lines(&lines, 14);
+        assert_eq!(payloads, vec!["m0:1|c\nm1:1|c", "m2:1|c\nm3:1|c", "m4:1|c"]);
+        assert_eq!(pack_lines(&lines, 1).len(), 5);
+    }
+
+    #[test]
+    fn emits_deltas_since_the_previous_emission() {
+        let mut prev = Counters::default();
+        prev.record_prepared(2);
+        let mut cur = prev.clone();
+        cur.record_prepared(3);
+        cur.record_error(&crate::errors::PipelineError::http_status("x", 503, None));
+
+        let lines = health_lines(&prev, &snapshot(cur), &["env:test".to_string()]);
+        assert!(lines.contains(&"datadog.tracer.queue.enqueued.traces:1|c|#env:test".to_string()));
+        assert!(lines.contains(&"datadog.tracer.queue.enqueued.spans:3|c|#env:test".to_string()));
+        assert!(lines.contains(&"datadog.tracer.api.errors:1|c|#env:test,type:5xx".to_string()));
+        assert!(lines.contains(&"datadog.tracer.spans.live:4|g|#env:test".to_string()));
+        assert!(!lines
+            .iter()
+            .any(|l| l.starts_with("datadog.tracer.flush.traces")));
+    }
+
+    #[test]
+    fn reports_dropped_orphans_but_not_exported_ones() {
+        let prev = Counters::default();
+        let mut cur = prev.clone();
+        cur.record_orphans(3, false);
+        cur.record_orphans(5, true);
+
+        let lines = health_lines(&prev, &snapshot(cur), &[]);
+        assert!(lines.contains(&"datadog.tracer.queue.dropped.traces:1|c|#reason:orphaned".to_string()));
+        assert!(lines.contains(&"datadog.tracer.queue.dropped.spans:3|c|#reason:orphaned".to_string()));
+    }
+}
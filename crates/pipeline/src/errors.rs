@@ -0,0 +1,265 @@
+// Copyright 2026-Present Datadog, Inc. https://www.datadoghq.com/
+// SPDX-License-Identifier: Apache-2.0
+
+//! Typed errors thrown to JS by the pipeline exports.
+//!
+//! Every failure is a JS `Error` with a stable `name` and `code` from
+//! [`ErrorKind`], so the host can branch on the kind instead of matching
+//! message text. HTTP status failures also carry `statusCode`, and
+//! `retryAfterMs` when the agent sent a `Retry-After`; `retryable` says
+//! whether trying again later can help.
+
+use std::fmt;
+use std::time::Duration;
+
+use libdatadog_nodejs_capabilities::grpc::GrpcStatusError;
+use libdd_capabilities::http::HttpError;
+use libdd_data_pipeline::trace_exporter::error::{NetworkErrorKind, TraceExporterError};
+use wasm_bindgen::JsValue;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The request didn't complete (connection refused/reset, socket error).
+    Network,
+    /// A deadline passed before the call finished.
+    Timeout,
+    /// The caller's `AbortSignal` fired.
+    Aborted,
+    /// The agent answered with a non-success status.
+    HttpStatus,
+    /// A payload or response couldn't be encoded or decoded.
+    Serialization,
+    /// The caller passed malformed arguments or buffers.
+    InvalidInput,
+    /// No live span has the given id.
+    UnknownSpan,
+    /// The same async export was called again before the previous call
+    /// finished.
+    ReentrantCall,
+    /// The prepared batch reached a `setBatchLimits` cap and must be sent
+    /// before another chunk is prepared.
+    BatchFull,
+    /// The state was shut down (see `shutdown`).
+    ShutDown,
+    /// The exporter couldn't be built from its configuration; fatal.
+    ExporterBuild,
+    /// Anything else.
+    Internal,
+}
+
+impl ErrorKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorKind::Network => "NetworkError",
+            ErrorKind::Timeout => "TimeoutError",
+            ErrorKind::Aborted => "AbortError",
+            ErrorKind::HttpStatus => "HttpStatusError",
+            ErrorKind::Serialization => "SerializationError",
+            ErrorKind::InvalidInput => "InvalidInputError",
+            ErrorKind::UnknownSpan => "UnknownSpanError",
+            ErrorKind::ReentrantCall => "ReentrantCallError",
+            ErrorKind::BatchFull => "BatchFullError",
+            ErrorKind::ShutDown => "ShutDownError",
+            ErrorKind::ExporterBuild => "NativeExporterBuildError",
+            ErrorKind::Internal => "InternalError",
+        }
+    }
+
+    pub fn code(self) -> &'static str {
+        match self {
+            ErrorKind::Network => "ERR_NETWORK",
+            ErrorKind::Timeout => "ERR_TIMEOUT",
+            ErrorKind::Aborted => "ERR_ABORTED",
+            ErrorKind::HttpStatus => "ERR_HTTP_STATUS",
+            ErrorKind::Serialization => "ERR_SERIALIZATION",
+            ErrorKind::InvalidInput => "ERR_INVALID_INPUT",
+            ErrorKind::UnknownSpan => "ERR_UNKNOWN_SPAN",
+            ErrorKind::ReentrantCall => "ERR_REENTRANT_CALL",
+            ErrorKind::BatchFull => "ERR_BATCH_FULL",
+            ErrorKind::ShutDown => "ERR_SHUT_DOWN",
+            ErrorKind::ExporterBuild => "ERR_EXPORTER_BUILD",
+            ErrorKind::Internal => "ERR_INTERNAL",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PipelineError {
+    kind: ErrorKind,
+    message: String,
+    status_code: Option<u16>,
+    retry_after: Option<Duration>,
+}
+
+impl PipelineError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        PipelineError {
+            kind,
+            message: message.into(),
+            status_code: None,
+            retry_after: None,
+        }
+    }
+
+    pub fn invalid_input(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::InvalidInput, message)
+    }
+
+    pub fn unknown_span(message: impl fmt::Display) -> Self {
+        Self::new(ErrorKind::UnknownSpan, message.to_string())
+    }
+
+    pub fn serialization(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::Serialization, message)
+    }
+
+    /// A non-success agent response.
+    pub fn http_status(
+        message: impl Into<String>,
+        status: u16,
+        retry_after: Option<Duration>,
+    ) -> Self {
+        PipelineError {
+            status_code: Some(status),
+            retry_after,
+            ..Self::new(ErrorKind::HttpStatus, message)
+        }
+    }
+
+    /// Classify a failed `send_trace_chunks_async`. `retry_after` is the
+    /// agent's hint from the failing response, if any.
+    pub fn from_exporter(
+        ctx: &str,
+        err: &TraceExporterError,
+        retry_after: Option<Duration>,
+    ) -> Self {
+        let message = format!("{ctx}: {err:?}");
+        match err {
+            TraceExporterError::Request(e) => {
+                Self::http_status(message, e.status().as_u16(), retry_after)
+            }
+            TraceExporterError::Network(e) if e.kind() == NetworkErrorKind::TimedOut => {
+                Self::new(ErrorKind::Timeout, message)
+            }
+            TraceExporterError::Network(_) | TraceExporterError::Io(_) => {
+                Self::new(ErrorKind::Network, message)
+            }
+            TraceExporterError::Serialization(_) | TraceExporterError::Deserialization(_) => {
+                Self::serialization(message)
+            }
+            TraceExporterError::Builder(_) => Self::new(ErrorKind::ExporterBuild, message),
+            TraceExporterError::Shutdown(_) => Self::new(ErrorKind::ShutDown, message),
+            _ => Self::new(ErrorKind::Internal, message),
+        }
+    }
+
+    /// An OTLP gRPC export the transport gave up retrying, with the HTTP
+    /// status its gRPC status maps to rather than the 500 libdatadog saw.
+    pub fn from_grpc(ctx: &str, err: &GrpcStatusError) -> Self {
+        Self::http_status(format!("{ctx}: {err}"), err.http_status(), err.retry_after)
+    }
+
+    /// Classify a failed request made directly through `WasmHttpClient`.
+    pub fn from_http(ctx: &str, err: &HttpError) -> Self {
+        let message = format!("{ctx}: {err:?}");
+        match err {
+            HttpError::Network(_) => Self::new(ErrorKind::Network, message),
+            HttpError::InvalidRequest(_) => Self::invalid_input(message),
+            _ => Self::new(ErrorKind::Internal, message),
+        }
+    }
+
+    /// Replace every occurrence of `secret` in the message.
+    pub fn redact(mut self, secret: &str) -> Self {
+        if !secret.is_empty() {
+            self.message = self.message.replace(secret, "<redacted>");
+        }
+        self
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn status_code(&self) -> Option<u16> {
+        self.status_code
+    }
+
+    /// Whether the same call may succeed later: transport failures, rate
+    /// limiting, agent-side errors and a full batch (once it is sent).
+    pub fn retryable(&self) -> bool {
+        match self.kind {
+            ErrorKind::Network | ErrorKind::Timeout | ErrorKind::BatchFull => true,
+            ErrorKind::HttpStatus => self
+                .status_code
+                .is_some_and(|s| s == 408 || s == 429 || s >= 500),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<PipelineError> for JsValue {
+    fn from(err: PipelineError) -> JsValue {
+        let js_err = js_sys::Error::new(&err.message);
+        js_err.set_name(err.kind.name());
+        let set = |key: &str, value: JsValue| {
+            js_sys::Reflect::set(&js_err, &JsValue::from_str(key), &value)
+                .expect("Reflect::set on a freshly created Error cannot fail");
+        };
+        set("code", JsValue::from_str(err.kind.code()));
+        set("retryable", JsValue::from_bool(err.retryable()));
+        if let Some(status) = err.status_code {
+            set("statusCode", JsValue::from_f64(status as f64));
+        }
+        if let Some(retry_after) = err.retry_after {
+            set(
+                "retryAfterMs",
+                JsValue::from_f64(retry_after.as_millis() as f64),
+            );
+        }
+        js_err.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retryable_by_kind_and_status() {
+        assert!(PipelineError::new(ErrorKind::Network, "x").retryable());
+        assert!(PipelineError::http_status("x", 429, None).retryable());
+        assert!(PipelineError::http_status("x", 503, None).retryable());
+        assert!(!PipelineError::http_status("x", 400, None).retryable());
+        assert!(!PipelineError::invalid_input("x").retryable());
+        assert!(PipelineError::new(ErrorKind::BatchFull, "x").retryable());
+    }
+
+    #[test]
+    fn names_and_codes_are_distinct() {
+        let kinds = [
+            ErrorKind::Network,
+            ErrorKind::Timeout,
+            ErrorKind::Aborted,
+            ErrorKind::HttpStatus,
+            ErrorKind::Serialization,
+            ErrorKind::InvalidInput,
+            ErrorKind::UnknownSpan,
+            ErrorKind::ReentrantCall,
+            ErrorKind::BatchFull,
+            ErrorKind::ShutDown,
+            ErrorKind::ExporterBuild,
+            ErrorKind::Internal,
+        ];
+        let names: std::collections::HashSet<_> = kinds.iter().map(|k| k.name()).collect();
+        let codes: std::collections::HashSet<_> = kinds.iter().map(|k| k.code()).collect();
+        assert_eq!(names.len(), kinds.len());
+        assert_eq!(codes.len(), kinds.len());
+    }
+}
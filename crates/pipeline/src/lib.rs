@@ -1,5 +1,8 @@
-use libdatadog_nodejs_capabilities::WasmCapabilities;
+use libdatadog_nodejs_capabilities::datagram::DatagramTarget;
+use libdatadog_nodejs_capabilities::{WasmCapabilities, WasmSleepCapability};
+use libdd_capabilities::sleep::SleepCapability;
 use libdd_data_pipeline::trace_exporter::agent_response::AgentResponse;
+use libdd_data_pipeline::trace_exporter::error::TraceExporterError;
 use libdd_data_pipeline::trace_exporter::{
     TraceExporter, TraceExporterBuilder, TraceExporterOutputFormat,
 };
@@ -9,6 +12,8 @@ use std::cell::{Cell, RefCell, UnsafeCell};
 use std::ffi::CStr;
 use std::time::Duration;
 
+use serde::Serialize;
+
 use wasm_bindgen::prelude::*;
 
 mod span_string;
@@ -20,10 +25,41 @@ use trace_data::*;
 
 mod stats;
 
+mod batch;
+
+mod sampling;
+
+mod sampling_rules;
+
+mod span_sampling;
+
+mod agent_info;
+
+mod agentless;
+
+mod errors;
+use errors::{ErrorKind, PipelineError};
+
+mod change_queue;
+
+mod span_index;
+
+mod trace_tags;
+
+mod snapshot;
+
+mod orphans;
+
+mod partial;
+
+mod metrics;
+
+mod dogstatsd;
+
 use libdd_trace_utils::change_buffer::{ChangeBuffer, ChangeBufferState};
-use libdd_trace_utils::span::v04::{AttributeAnyValue, AttributeArrayValue, SpanEvent};
+use libdd_trace_utils::span::v04::{AttributeAnyValue, AttributeArrayValue, SpanEvent, SpanLink};
 use span_string::SpanString;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 mod utils;
 use utils::*;
@@ -33,10 +69,11 @@ fn init() {
     console_error_panic_hook::set_once();
 }
 
-// --- span event attribute decoding ---
+// --- span event / span link attribute decoding ---
 //
-// `addSpanEvent` receives its attributes as a flat little-endian buffer built
-// by dd-trace-js. Layout: repeated entries until the buffer is exhausted, each
+// `addSpanEvent` and `addSpanLink` receive their attributes as a flat
+// little-endian buffer built by dd-trace-js. Layout: repeated entries until the
+// buffer is exhausted, each
 //   [key_len: u32][key: utf8][tag: u8] + value
 // where the value depends on `tag`:
 //   0 String  [len: u32][utf8]
@@ -49,39 +86,60 @@ fn init() {
 // (String=0, Boolean=1, Integer=2, Double=3, Array=4). Every read is bounded
 // against the buffer so a malformed/truncated buffer errors instead of
 // panicking (matching the hardening in `stringTableInsertMany`/`prepareChunk`).
+// `ctx` names the calling export, which prefixes error messages, and the kind
+// of attributes being decoded, which the messages describe.
 
-fn se_need(buf: &[u8], idx: usize, n: usize) -> Result<(), JsValue> {
+#[derive(Clone, Copy)]
+struct AttrCtx {
+    export: &'static str,
+    kind: &'static str,
+}
+
+const SPAN_EVENT_ATTRS: AttrCtx = AttrCtx {
+    export: "addSpanEvent",
+    kind: "span-event",
+};
+const SPAN_LINK_ATTRS: AttrCtx = AttrCtx {
+    export: "addSpanLink",
+    kind: "span-link",
+};
+
+fn se_need(buf: &[u8], idx: usize, n: usize, ctx: AttrCtx) -> Result<(), PipelineError> {
     // Avoid `idx + n` overflowing: on wasm32 `usize` is 32-bit, and `n` can be
     // a u32-derived length (e.g. a crafted `key_len`) near `usize::MAX`, which
     // would wrap and let a too-large read slip past the bound and trap on the
     // slice. `idx` never exceeds `buf.len()` (it only advances after a checked
     // read), so `buf.len() - idx` is the safe remaining-byte form.
     if idx > buf.len() || n > buf.len() - idx {
-        return Err(JsValue::from_str(
-            "addSpanEvent: truncated span-event attribute buffer",
-        ));
+        return Err(se_truncated(ctx));
     }
     Ok(())
 }
 
-fn se_read_u8(buf: &[u8], idx: &mut usize) -> Result<u8, JsValue> {
-    se_need(buf, *idx, 1)?;
+fn se_truncated(ctx: AttrCtx) -> PipelineError {
+    PipelineError::invalid_input(format!(
+        "{}: truncated {} attribute buffer",
+        ctx.export, ctx.kind
+    ))
+}
+
+fn se_read_u8(buf: &[u8], idx: &mut usize, ctx: AttrCtx) -> Result<u8, PipelineError> {
+    se_need(buf, *idx, 1, ctx)?;
     let b = buf[*idx];
     *idx += 1;
     Ok(b)
 }
 
-fn se_read_u32(buf: &[u8], idx: &mut usize) -> Result<u32, JsValue> {
-    se_need(buf, *idx, 4)?;
-    get_num(buf, idx)
-        .ok_or_else(|| JsValue::from_str("addSpanEvent: truncated span-event attribute buffer"))
+fn se_read_u32(buf: &[u8], idx: &mut usize, ctx: AttrCtx) -> Result<u32, PipelineError> {
+    se_need(buf, *idx, 4, ctx)?;
+    get_num(buf, idx).ok_or_else(|| se_truncated(ctx))
 }
 
-fn se_read_str(buf: &[u8], idx: &mut usize) -> Result<SpanString, JsValue> {
-    let len = se_read_u32(buf, idx)? as usize;
-    se_need(buf, *idx, len)?;
+fn se_read_str(buf: &[u8], idx: &mut usize, ctx: AttrCtx) -> Result<SpanString, PipelineError> {
+    let len = se_read_u32(buf, idx, ctx)? as usize;
+    se_need(buf, *idx, len, ctx)?;
     let s = std::str::from_utf8(&buf[*idx..*idx + len])
-        .map_err(|e| JsValue::from_str(&format!("addSpanEvent: invalid utf8: {e}")))?;
+        .map_err(|e| PipelineError::invalid_input(format!("{}: invalid utf8: {e}", ctx.export)))?;
     *idx += len;
     Ok(s.into())
 }
@@ -90,60 +148,92 @@ fn se_read_scalar(
     buf: &[u8],
     idx: &mut usize,
     tag: u8,
-) -> Result<AttributeArrayValue<WasmTraceData>, JsValue> {
+    ctx: AttrCtx,
+) -> Result<AttributeArrayValue<WasmTraceData>, PipelineError> {
     match tag {
-        0 => Ok(AttributeArrayValue::String(se_read_str(buf, idx)?)),
-        1 => Ok(AttributeArrayValue::Boolean(se_read_u8(buf, idx)? != 0)),
+        0 => Ok(AttributeArrayValue::String(se_read_str(buf, idx, ctx)?)),
+        1 => Ok(AttributeArrayValue::Boolean(se_read_u8(buf, idx, ctx)? != 0)),
         2 => {
-            se_need(buf, *idx, 8)?;
-            let n = get_num(buf, idx)
-                .ok_or_else(|| JsValue::from_str("addSpanEvent: truncated span-event attribute buffer"))?;
+            se_need(buf, *idx, 8, ctx)?;
+            let n = get_num(buf, idx).ok_or_else(|| se_truncated(ctx))?;
             Ok(AttributeArrayValue::Integer(n))
         }
         3 => {
-            se_need(buf, *idx, 8)?;
-            let n = get_num(buf, idx)
-                .ok_or_else(|| JsValue::from_str("addSpanEvent: truncated span-event attribute buffer"))?;
+            se_need(buf, *idx, 8, ctx)?;
+            let n = get_num(buf, idx).ok_or_else(|| se_truncated(ctx))?;
             Ok(AttributeArrayValue::Double(n))
         }
-        _ => Err(JsValue::from_str(
-            "addSpanEvent: invalid span-event attribute tag",
-        )),
+        _ => Err(PipelineError::invalid_input(format!(
+            "{}: invalid {} attribute tag",
+            ctx.export, ctx.kind
+        ))),
     }
 }
 
 fn decode_span_event_attributes(
     buf: &[u8],
-) -> Result<HashMap<SpanString, AttributeAnyValue<WasmTraceData>>, JsValue> {
+    ctx: AttrCtx,
+) -> Result<HashMap<SpanString, AttributeAnyValue<WasmTraceData>>, PipelineError> {
     let mut attributes = HashMap::new();
     let mut idx = 0usize;
     while idx < buf.len() {
-        let key = se_read_str(buf, &mut idx)?;
-        let tag = se_read_u8(buf, &mut idx)?;
+        let key = se_read_str(buf, &mut idx, ctx)?;
+        let tag = se_read_u8(buf, &mut idx, ctx)?;
         let value = if tag == 4 {
-            let count = se_read_u32(buf, &mut idx)? as usize;
+            let count = se_read_u32(buf, &mut idx, ctx)? as usize;
             // Each item is at least 1 byte (its tag), so cap the pre-allocation
             // to the remaining buffer: an inflated count can't force a huge
             // allocation, and the per-item bounded reads catch truncation.
             let mut items = Vec::with_capacity(count.min(buf.len().saturating_sub(idx)));
             for _ in 0..count {
-                let item_tag = se_read_u8(buf, &mut idx)?;
+                let item_tag = se_read_u8(buf, &mut idx, ctx)?;
                 if item_tag == 4 {
-                    return Err(JsValue::from_str(
-                        "addSpanEvent: nested arrays are not supported",
-                    ));
+                    return Err(PipelineError::invalid_input(format!(
+                        "{}: nested arrays are not supported",
+                        ctx.export
+                    )));
                 }
-                items.push(se_read_scalar(buf, &mut idx, item_tag)?);
+                items.push(se_read_scalar(buf, &mut idx, item_tag, ctx)?);
             }
             AttributeAnyValue::Array(items)
         } else {
-            AttributeAnyValue::SingleValue(se_read_scalar(buf, &mut idx, tag)?)
+            AttributeAnyValue::SingleValue(se_read_scalar(buf, &mut idx, tag, ctx)?)
         };
         attributes.insert(key, value);
     }
     Ok(attributes)
 }
 
+// Span link attributes are string-valued on the v0.4 wire format, so the typed
+// values are stringified the way dd-trace-js's own encoder does: scalars via
+// their JS string form and arrays flattened into `key.0`, `key.1`, ...
+fn decode_span_link_attributes(buf: &[u8]) -> Result<HashMap<SpanString, SpanString>, PipelineError> {
+    let decoded = decode_span_event_attributes(buf, SPAN_LINK_ATTRS)?;
+    let mut attributes = HashMap::with_capacity(decoded.len());
+    for (key, value) in decoded {
+        match value {
+            AttributeAnyValue::SingleValue(v) => {
+                attributes.insert(key, link_attribute_string(v));
+            }
+            AttributeAnyValue::Array(items) => {
+                for (i, v) in items.into_iter().enumerate() {
+                    attributes.insert(format!("{key}.{i}").into(), link_attribute_string(v));
+                }
+            }
+        }
+    }
+    Ok(attributes)
+}
+
+fn link_attribute_string(value: AttributeArrayValue<WasmTraceData>) -> SpanString {
+    match value {
+        AttributeArrayValue::String(s) => s,
+        AttributeArrayValue::Boolean(b) => SpanString::from(if b { "true" } else { "false" }),
+        AttributeArrayValue::Integer(n) => n.to_string().into(),
+        AttributeArrayValue::Double(n) => n.to_string().into(),
+    }
+}
+
 #[wasm_bindgen]
 /// All mutable state is behind RefCell to allow `&self` methods on the
 /// wasm-bindgen wrapper. This prevents re-entrant borrow panics when:
@@ -153,6 +243,10 @@ fn decode_span_event_attributes(
 ///   span operations need access
 pub struct WasmSpanState {
     change_queue: Vec<u8>,
+    /// The pointer libdatadog's `ChangeBuffer` was built from. Extended ops
+    /// (see `change_queue`) rewrite the queue through it, the same way
+    /// libdatadog resets the count after a flush.
+    change_queue_raw: std::ptr::NonNull<u8>,
     string_table_input: Vec<u8>,
     /// UnsafeCell because send_trace_chunks_async needs &mut self across an
     /// await point. WASM is single-threaded so this is safe — we just need
@@ -168,21 +262,44 @@ pub struct WasmSpanState {
     exporter: UnsafeCell<Option<TraceExporter<WasmCapabilities, LocalRuntime>>>,
     builder: UnsafeCell<Option<TraceExporterBuilder<LocalRuntime>>>,
     cbs: RefCell<ChangeBufferState<WasmTraceData>>,
+    /// Which segment each live span belongs to, fed by `flush_queue`.
+    span_index: RefCell<span_index::SpanIndex>,
+    /// The string table as inserted into the change buffer state, for the
+    /// trace-level ops the pipeline applies itself. libdatadog doesn't report
+    /// its size either, so `getMetrics` counts the entries here.
+    string_table: RefCell<HashMap<u32, SpanString>>,
     stats_collector: RefCell<Option<stats::StatsCollector>>,
-    prepared_spans: RefCell<Option<Vec<libdd_trace_utils::span::v04::Span<WasmTraceData>>>>,
+    /// Chunks prepared by `prepareChunk` and not yet sent. Without batch
+    /// limits this holds at most one chunk; with `setBatchLimits` it
+    /// accumulates until JS sends it as a single payload.
+    prepared: RefCell<batch::PreparedBatch>,
+    /// Span/byte caps for `prepared` (see `setBatchLimits`). Default: disabled.
+    batch_limits: Cell<batch::BatchLimits>,
+    /// Max age of unfinished spans for `reapOrphans` (see `setMaxSpanAge`).
+    orphan_policy: Cell<Option<orphans::OrphanPolicy>>,
+    /// Finished spans a segment needs for `preparePartialChunk`; 0 is off.
+    partial_flush_min_spans: Cell<usize>,
     /// Re-entrancy guard for `sendPreparedChunk`. wasm-bindgen async exports
     /// can be invoked again from JS before the prior future resolves; without
     /// this, two calls would each take `&mut` out of `exporter`/`builder` and
     /// alias across the await (UB). The guard makes a re-entrant call return
     /// an error instead.
     sending: Cell<bool>,
+    /// Set while a `flushStats` request is in flight. An overlapping
+    /// `flushStats` (or `shutdown`'s final one) waits for it to finish rather
+    /// than racing it for the same buckets.
+    flushing_stats: Cell<bool>,
+    /// Set by `shutdown`; every later call that processes spans, reads span
+    /// data or talks to the agent fails.
+    shut_down: Cell<bool>,
     /// When true, the lazily-built exporter is configured for v0.5 output
     /// (`/v0.5/traces`) instead of the default v0.4. v0.5 is a smaller, fixed
     /// 12-field schema with NO slots for `meta_struct`/`span_events`/`span_links`,
     /// so libdatadog's v0.5 serializer silently drops them — this mirrors
-    /// dd-trace-js master's v0.5 encoder and is intentional. Caller (dd-trace-js)
-    /// must only enable this after confirming the agent advertises `/v0.5/traces`
-    /// (libdd does NOT downgrade V05 the way it does V1). The output format is
+    /// dd-trace-js master's v0.5 encoder and is intentional. libdd does NOT
+    /// downgrade V05 the way it does V1: unless `/info` was fetched with
+    /// `refreshAgentInfo` (which falls back to v0.4 when the agent lacks
+    /// `/v0.5/traces`), the caller must confirm agent support. The output format is
     /// fixed once the exporter is built on the first send, so `setUseV05` only
     /// takes effect if called before then.
     use_v05: Cell<bool>,
@@ -195,14 +312,65 @@ pub struct WasmSpanState {
     /// OTLP wire protocol (`http/json` default, or `http/protobuf`). Only
     /// applied when `otlp_endpoint` is set.
     otlp_protocol: Cell<Option<OtlpProtocol>>,
+    /// When true, OTLP export goes over gRPC: the exporter is configured for
+    /// `http/protobuf` and its requests to the endpoint are carried as gRPC
+    /// calls by the capability (see `setOtlpProtocol`).
+    otlp_grpc: Cell<bool>,
     /// Extra HTTP headers for OTLP export (e.g. collector auth), as key/value
     /// pairs. Only applied when `otlp_endpoint` is set.
     otlp_headers: RefCell<Vec<(String, String)>>,
+    /// When true, the lazily-built exporter writes payloads to the JS log sink
+    /// (see `setLogOutput`) instead of sending them anywhere over HTTP.
+    log_output: Cell<bool>,
+    /// When set, traces and stats bypass the agent and the exporter and go
+    /// straight to the Datadog intake (see `setAgentless`).
+    agentless: RefCell<Option<agentless::Intake>>,
+    /// Tracer identification for agentless payloads.
+    tracer_meta: agentless::TracerMeta,
+    /// TLS options for HTTPS requests (see `setTlsOptions`); `undefined`
+    /// uses Node's defaults.
+    tls_options: RefCell<JsValue>,
+    /// Whether `tls_options` has been validated since it was last set.
+    tls_checked: Cell<bool>,
     /// Latched message from a failed lazy `build_async`. Building is one-shot and
     /// a failure is fatal (bad config), so once set every send returns it (as a
     /// distinguishable error) instead of a misleading "builder already consumed",
     /// letting the host stop retrying.
     build_error: RefCell<Option<String>>,
+    /// Latest agent `rate_by_service` table, refreshed from every
+    /// `AgentResponse::Changed` body returned by a send.
+    agent_rates: RefCell<sampling::AgentRates>,
+    /// When true, `prepareChunk` stamps a priority from `agent_rates` on chunk
+    /// roots that don't already carry one (see `setAgentSampling`).
+    agent_sampling: Cell<bool>,
+    /// `DD_TRACE_SAMPLING_RULES` sampler, evaluated on chunk roots before the
+    /// agent rates (see `setSamplingRules`).
+    rules_sampler: RefCell<Option<sampling_rules::RulesSampler>>,
+    /// `DD_SPAN_SAMPLING_RULES` sampler, applied to rejected chunks (see
+    /// `setSpanSamplingRules`).
+    span_sampler: RefCell<Option<span_sampling::SpanSampler>>,
+    /// When true (and stats are enabled), `prepareChunk` drops rejected chunks
+    /// after feeding them to stats (see `setDropP0Traces`).
+    drop_p0: Cell<bool>,
+    /// Traces/spans dropped by `drop_p0` since the last send, reported to the
+    /// agent in the `Datadog-Client-Dropped-P0-*` headers.
+    dropped_p0_traces: Cell<u64>,
+    dropped_p0_spans: Cell<u64>,
+    /// Agent URL the exporter and stats collector were configured with, kept
+    /// for `/info` discovery.
+    agent_url: String,
+    /// Cached agent `/info` (see `refreshAgentInfo`). Until it is fetched the
+    /// configured endpoints and stats are used as-is.
+    agent_info: RefCell<agent_info::AgentInfoCache>,
+    /// Tracer-level `env`, used for the agent rate lookup when the root span
+    /// has no `env` tag of its own.
+    env: String,
+    /// Exporter counters reported by `getMetrics`.
+    metrics: RefCell<metrics::Counters>,
+    /// DogStatsD health metric emission (see `setHealthMetrics`).
+    health: RefCell<Option<dogstatsd::HealthReporter>>,
+    /// Set while `runHealthMetrics` is emitting on its timer.
+    health_timer: Cell<bool>,
 }
 
 /// Clears an in-flight flag on drop, so an early return or a dropped future
@@ -214,6 +382,22 @@ impl Drop for InFlightGuard<'_> {
     }
 }
 
+/// What `shutdown` managed to deliver before its deadline.
+#[derive(Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ShutdownReport {
+    timed_out: bool,
+    sent_chunks: usize,
+    sent_spans: usize,
+    dropped_chunks: usize,
+    dropped_spans: usize,
+    stats_flushed: bool,
+    errors: Vec<String>,
+}
+
+/// Poll interval while waiting for an in-flight send or stats flush to finish.
+const IN_FLIGHT_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
 #[wasm_bindgen]
 impl WasmSpanState {
     #[wasm_bindgen(constructor)]
@@ -251,13 +435,10 @@ impl WasmSpanState {
             .enable_agent_rates_payload_version();
 
         let mut change_queue = vec![0u8; change_queue_size as usize];
-        let change_buffer = unsafe {
-            ChangeBuffer::from_raw_parts(
-                std::ptr::NonNull::new(change_queue.as_mut_ptr())
-                    .expect("Vec::as_mut_ptr is never null"),
-                change_queue.len(),
-            )
-        };
+        let change_queue_raw = std::ptr::NonNull::new(change_queue.as_mut_ptr())
+            .expect("Vec::as_mut_ptr is never null");
+        let change_buffer =
+            unsafe { ChangeBuffer::from_raw_parts(change_queue_raw, change_queue.len()) };
         let change_buffer_state = ChangeBufferState::new(
             change_buffer,
             tracer_service.into(),
@@ -277,6 +458,11 @@ impl WasmSpanState {
                     tracer_version: tracer_version.to_string(),
                     runtime_id: runtime_id.to_string(),
                     service: tracer_service.to_string(),
+                    container: stats::ContainerMeta::default(),
+                    tags: Vec::new(),
+                    git_commit_sha: String::new(),
+                    process_tags: String::new(),
+                    process_tags_hash: 0,
                 },
             ))
         } else {
@@ -285,18 +471,53 @@ impl WasmSpanState {
 
         Ok(WasmSpanState {
             change_queue,
+            change_queue_raw,
             string_table_input: vec![0u8; string_table_input_size as usize],
             exporter: UnsafeCell::new(None),
             builder: UnsafeCell::new(Some(builder)),
             cbs: RefCell::new(change_buffer_state),
+            span_index: RefCell::new(span_index::SpanIndex::default()),
+            string_table: RefCell::new(HashMap::new()),
             stats_collector: RefCell::new(stats_collector),
-            prepared_spans: RefCell::new(None),
+            prepared: RefCell::new(batch::PreparedBatch::default()),
+            batch_limits: Cell::new(batch::BatchLimits::default()),
+            orphan_policy: Cell::new(None),
+            partial_flush_min_spans: Cell::new(0),
             sending: Cell::new(false),
+            flushing_stats: Cell::new(false),
+            shut_down: Cell::new(false),
             use_v05: Cell::new(false),
             otlp_endpoint: RefCell::new(None),
             otlp_protocol: Cell::new(None),
+            otlp_grpc: Cell::new(false),
             otlp_headers: RefCell::new(Vec::new()),
+            log_output: Cell::new(false),
+            agentless: RefCell::new(None),
+            tracer_meta: agentless::TracerMeta {
+                lang: lang.to_string(),
+                lang_version: lang_version.to_string(),
+                tracer_version: tracer_version.to_string(),
+                runtime_id: runtime_id.to_string(),
+                env: env.to_string(),
+                hostname: hostname.to_string(),
+                app_version: app_version.to_string(),
+            },
+            tls_options: RefCell::new(JsValue::UNDEFINED),
+            tls_checked: Cell::new(false),
             build_error: RefCell::new(None),
+            agent_rates: RefCell::new(sampling::AgentRates::default()),
+            agent_sampling: Cell::new(false),
+            rules_sampler: RefCell::new(None),
+            span_sampler: RefCell::new(None),
+            drop_p0: Cell::new(false),
+            dropped_p0_traces: Cell::new(0),
+            dropped_p0_spans: Cell::new(0),
+            agent_url: url.to_string(),
+            agent_info: RefCell::new(agent_info::AgentInfoCache::default()),
+            env: env.to_string(),
+            metrics: RefCell::new(metrics::Counters::default()),
+            health: RefCell::new(None),
+            health_timer: Cell::new(false),
         })
     }
 
@@ -304,31 +525,99 @@ impl WasmSpanState {
     /// first `sendPreparedChunk` (the exporter is built lazily on first send and
     /// the output format is fixed at build time; later calls have no effect).
     ///
-    /// v0.5 silently drops `meta_struct` (and top-level `span_events`/`span_links`)
-    /// because the v0.5 wire schema has no slots for them — the caller is
-    /// responsible for only enabling this when the agent supports `/v0.5/traces`.
+    /// v0.5 silently drops `meta_struct` (and top-level `span_events`/`span_links`,
+    /// including links added with `addSpanLink`) because the v0.5 wire schema
+    /// has no slots for them — the caller is
+    /// responsible for only enabling this when the agent supports `/v0.5/traces`,
+    /// or for calling `refreshAgentInfo` first so the exporter can check.
     #[wasm_bindgen(js_name = "setUseV05")]
-    pub fn set_use_v05(&self, v: bool) {
+    pub fn set_use_v05(&self, v: bool) -> Result<(), JsValue> {
+        self.check_open("setUseV05")?;
         self.use_v05.set(v);
+        Ok(())
     }
 
     /// Route trace export through libdatadog's OTLP HTTP exporter to `url`
     /// instead of the Datadog agent. Must be called before the first send.
     /// Takes precedence over `setUseV05` (OTLP bypasses the agent entirely).
     #[wasm_bindgen(js_name = "setOtlpEndpoint")]
-    pub fn set_otlp_endpoint(&self, url: String) {
+    pub fn set_otlp_endpoint(&self, url: String) -> Result<(), JsValue> {
+        self.check_open("setOtlpEndpoint")?;
         *self.otlp_endpoint.borrow_mut() = Some(url);
+        Ok(())
     }
 
-    /// Select the OTLP wire protocol: `http/json` (default) or `http/protobuf`.
-    /// Rejects unsupported values (e.g. `grpc`). Only takes effect with an OTLP
-    /// endpoint set, before the first send.
+    /// Write trace payloads in libdatadog's log-output format to the JS log
+    /// sink (`process.stdout` unless replaced with `setLogOutputSink`), for
+    /// serverless environments where a forwarder reads traces from stdout.
+    /// Must be called before the first send. Takes precedence over the agent
+    /// and OTLP settings; `/info` discovery and client-side stats are off, as
+    /// there is no agent to talk to.
+    #[wasm_bindgen(js_name = "setLogOutput")]
+    pub fn set_log_output(&self, enabled: bool) -> Result<(), JsValue> {
+        self.check_open("setLogOutput")?;
+        self.log_output.set(enabled);
+        Ok(())
+    }
+
+    /// TLS settings for HTTPS requests to the OTLP endpoint, the intake or
+    /// an `https://` agent URL: `{ ca, cert, key, passphrase, servername,
+    /// rejectUnauthorized }`, as for Node's `tls.connect` (`ca`, `cert` and
+    /// `key` are PEM strings or Buffers; `cert` and `key` together enable
+    /// mTLS). Must be called before the first request, which validates them:
+    /// unusable material fails that send, stats flush or `/info` refresh, and
+    /// every later one, with a `NativeExporterBuildError`.
+    #[wasm_bindgen(js_name = "setTlsOptions")]
+    pub fn set_tls_options(&self, options: JsValue) -> Result<(), JsValue> {
+        self.check_open("setTlsOptions")?;
+        *self.tls_options.borrow_mut() = options;
+        self.tls_checked.set(false);
+        Ok(())
+    }
+
+    /// Send traces and client stats directly to the Datadog intake for `site`
+    /// (e.g. `datadoghq.com`), authenticated with `apiKey`, for environments
+    /// without an agent. `intakeUrl` replaces the site's intake URL (a proxy,
+    /// or a local stand-in in tests). Takes precedence over the agent and
+    /// OTLP settings, but not over `setLogOutput`; `/info` discovery is off.
+    /// The API key is redacted from every error.
+    #[wasm_bindgen(js_name = "setAgentless")]
+    pub fn set_agentless(
+        &self,
+        site: &str,
+        api_key: String,
+        intake_url: Option<String>,
+    ) -> Result<(), JsValue> {
+        self.check_open("setAgentless")?;
+        let intake = agentless::Intake::new(site, api_key, intake_url)?;
+        if let Some(collector) = self.stats_collector.borrow_mut().as_mut() {
+            collector.set_intake(Some(intake.clone()));
+        }
+        *self.agentless.borrow_mut() = Some(intake);
+        Ok(())
+    }
+
+    /// Select the OTLP wire protocol: `http/json` (default), `http/protobuf`
+    /// or `grpc`. With `grpc` the endpoint is the collector's gRPC address
+    /// (e.g. `http://localhost:4317`) and traces are sent with the
+    /// `TraceService/Export` method over HTTP/2; calls failing with a retryable
+    /// gRPC status are retried, and failures reject with the equivalent HTTP
+    /// status (plus `retryAfterMs` from a `grpc-retry-pushback-ms`).
+    /// Rejects other values. Only takes effect with an OTLP endpoint set,
+    /// before the first send.
     #[wasm_bindgen(js_name = "setOtlpProtocol")]
     pub fn set_otlp_protocol(&self, protocol: String) -> Result<(), JsValue> {
-        let parsed = protocol
-            .parse::<OtlpProtocol>()
-            .map_err(|e| JsValue::from_str(&format!("setOtlpProtocol: {e}")))?;
+        self.check_open("setOtlpProtocol")?;
+        let grpc = protocol == "grpc";
+        let parsed = if grpc {
+            OtlpProtocol::HttpProtobuf
+        } else {
+            protocol
+                .parse::<OtlpProtocol>()
+                .map_err(|e| PipelineError::invalid_input(format!("setOtlpProtocol: {e}")))?
+        };
         self.otlp_protocol.set(Some(parsed));
+        self.otlp_grpc.set(grpc);
         Ok(())
     }
 
@@ -337,7 +626,8 @@ impl WasmSpanState {
     /// OTLP endpoint set, before the first send. A trailing unpaired element on
     /// an odd-length array is ignored. Each call replaces any previously set headers.
     #[wasm_bindgen(js_name = "setOtlpHeaders")]
-    pub fn set_otlp_headers(&self, kv: Vec<String>) {
+    pub fn set_otlp_headers(&self, kv: Vec<String>) -> Result<(), JsValue> {
+        self.check_open("setOtlpHeaders")?;
         // chunks_exact drops a trailing unpaired element; the host always passes
         // complete [key, value] pairs.
         let headers = kv
@@ -345,6 +635,232 @@ impl WasmSpanState {
             .map(|pair| (pair[0].clone(), pair[1].clone()))
             .collect();
         *self.otlp_headers.borrow_mut() = headers;
+        Ok(())
+    }
+
+    /// Accumulate prepared chunks and send them as one payload instead of one
+    /// request per local trace. `prepareChunk` then returns `true` only once
+    /// the batch holds at least `max_spans` spans or an estimated `max_bytes`
+    /// of encoded payload (a zero cap is unbounded); JS should also send the
+    /// batch on its own flush interval (see `getPreparedSpanCount`). A batch
+    /// that reached a cap is full and must be sent before the next chunk is
+    /// prepared. Passing `0, 0` restores the default of one chunk per send.
+    /// Chunks already prepared are kept for the next send; returns whether
+    /// that send is due under the new limits.
+    #[wasm_bindgen(js_name = "setBatchLimits")]
+    pub fn set_batch_limits(&self, max_spans: u32, max_bytes: u32) -> Result<bool, JsValue> {
+        self.check_open("setBatchLimits")?;
+        let limits = batch::BatchLimits {
+            max_spans: max_spans as usize,
+            max_bytes: max_bytes as usize,
+        };
+        self.batch_limits.set(limits);
+        let mut prepared = self.prepared.borrow_mut();
+        prepared.pin_all();
+        Ok(limits.is_due(&prepared))
+    }
+
+    /// Evict local traces holding a span that is still unfinished
+    /// `max_age_ms` after its start, on each `reapOrphans` call. With
+    /// `export_orphans` the evicted spans are prepared as partial chunks for
+    /// the next `sendPreparedChunk`, their unfinished spans closed and marked
+    /// as errors (`_dd.orphaned`); otherwise they are dropped. Exported
+    /// orphans stay prepared until sent, even without batch limits, where
+    /// `prepareChunk` recycles other unsent chunks. `0` turns reaping off.
+    #[wasm_bindgen(js_name = "setMaxSpanAge")]
+    pub fn set_max_span_age(&self, max_age_ms: u32, export_orphans: bool) -> Result<(), JsValue> {
+        self.check_open("setMaxSpanAge")?;
+        self.orphan_policy.set((max_age_ms > 0).then(|| orphans::OrphanPolicy {
+            max_age_ns: max_age_ms as u64 * 1_000_000,
+            export: export_orphans,
+        }));
+        Ok(())
+    }
+
+    /// Let `preparePartialChunk` flush a local trace early once at least
+    /// `min_spans` of its spans have finished. `0` (the default) turns partial
+    /// flushing off.
+    #[wasm_bindgen(js_name = "setPartialFlushMinSpans")]
+    pub fn set_partial_flush_min_spans(&self, min_spans: u32) -> Result<(), JsValue> {
+        self.check_open("setPartialFlushMinSpans")?;
+        self.partial_flush_min_spans.set(min_spans as usize);
+        Ok(())
+    }
+
+    /// Number of spans prepared and waiting for `sendPreparedChunk`.
+    #[wasm_bindgen(js_name = "getPreparedSpanCount")]
+    pub fn get_prepared_span_count(&self) -> u32 {
+        self.prepared.borrow().span_count() as u32
+    }
+
+    /// Estimated encoded size in bytes of the spans waiting for
+    /// `sendPreparedChunk`.
+    #[wasm_bindgen(js_name = "getPreparedEncodedSize")]
+    pub fn get_prepared_encoded_size(&self) -> u32 {
+        self.prepared.borrow().encoded_size() as u32
+    }
+
+    /// Let `prepareChunk` make the priority sampling decision from the agent's
+    /// `rate_by_service`: chunk roots without `_sampling_priority_v1` get
+    /// auto-keep/auto-reject plus `_dd.agent_psr`. Off by default (the host
+    /// samples), and until the agent has answered a send every trace is kept.
+    #[wasm_bindgen(js_name = "setAgentSampling")]
+    pub fn set_agent_sampling(&self, enabled: bool) -> Result<(), JsValue> {
+        self.check_open("setAgentSampling")?;
+        self.agent_sampling.set(enabled);
+        Ok(())
+    }
+
+    /// Configure trace sampling rules from the `DD_TRACE_SAMPLING_RULES` JSON
+    /// array (`service`/`name`/`resource`/`tags` glob matchers and a
+    /// `sample_rate`). `prepareChunk` evaluates them against each chunk root
+    /// that has no priority yet: the first match sets a user keep/reject and
+    /// `_dd.rule_psr`, and kept traces are capped at `rateLimit` per second
+    /// (default 100, negative for no limit) with `_dd.limit_psr`. Roots no rule
+    /// matches fall through to agent rates (`setAgentSampling`). An empty array
+    /// removes the rules. Replaces any previous rules and limiter state.
+    #[wasm_bindgen(js_name = "setSamplingRules")]
+    pub fn set_sampling_rules(&self, json: &str, rate_limit: Option<f64>) -> Result<(), JsValue> {
+        self.check_open("setSamplingRules")?;
+        let sampler = sampling_rules::RulesSampler::from_json(json, rate_limit)
+            .map_err(|e| PipelineError::invalid_input(format!("setSamplingRules: {e}")))?;
+        *self.rules_sampler.borrow_mut() = (!sampler.is_empty()).then_some(sampler);
+        Ok(())
+    }
+
+    /// Configure single span sampling from the `DD_SPAN_SAMPLING_RULES` JSON
+    /// array (`service`/`name` globs, `sample_rate`, `max_per_second`). For a
+    /// chunk whose root priority is reject/auto-reject, `prepareChunk` tags the
+    /// spans a rule keeps with `_dd.span_sampling.*` and sends only those
+    /// spans; a rejected chunk with no matching span is sent unchanged. An
+    /// empty array removes the rules.
+    #[wasm_bindgen(js_name = "setSpanSamplingRules")]
+    pub fn set_span_sampling_rules(&self, json: &str) -> Result<(), JsValue> {
+        self.check_open("setSpanSamplingRules")?;
+        let sampler = span_sampling::SpanSampler::from_json(json)
+            .map_err(|e| PipelineError::invalid_input(format!("setSpanSamplingRules: {e}")))?;
+        *self.span_sampler.borrow_mut() = (!sampler.is_empty()).then_some(sampler);
+        Ok(())
+    }
+
+    /// Set the peer tag keys used for client-side stats aggregation, typically
+    /// the `peer_tags` list from the agent's `/info`. Replaces the previous
+    /// list; spans already aggregated keep their buckets. A later
+    /// `refreshAgentInfo` that returns peer tags overrides this. No-op when
+    /// stats are disabled.
+    #[wasm_bindgen(js_name = "setStatsPeerTags")]
+    pub fn set_stats_peer_tags(&self, peer_tags: Vec<String>) -> Result<(), JsValue> {
+        self.check_open("setStatsPeerTags")?;
+        if let Some(collector) = self.stats_collector.borrow_mut().as_mut() {
+            collector.set_peer_tags(peer_tags);
+        }
+        Ok(())
+    }
+
+    /// Set the span kinds eligible for client-side stats. An empty array
+    /// restores the default (`client`, `server`, `producer`, `consumer`).
+    /// No-op when stats are disabled.
+    #[wasm_bindgen(js_name = "setStatsSpanKinds")]
+    pub fn set_stats_span_kinds(&self, span_kinds: Vec<String>) -> Result<(), JsValue> {
+        self.check_open("setStatsSpanKinds")?;
+        if let Some(collector) = self.stats_collector.borrow_mut().as_mut() {
+            collector.set_span_kinds(span_kinds);
+        }
+        Ok(())
+    }
+
+    /// Set the client-side stats bucket duration in milliseconds (default
+    /// 10000). Stats aggregated with the previous duration are flushed with
+    /// the next `flushStats`. No-op when stats are disabled.
+    #[wasm_bindgen(js_name = "setStatsBucketDuration")]
+    pub fn set_stats_bucket_duration(&self, millis: u32) -> Result<(), JsValue> {
+        self.check_open("setStatsBucketDuration")?;
+        if millis == 0 {
+            return Err(PipelineError::invalid_input(
+                "setStatsBucketDuration: duration must be greater than zero",
+            )
+            .into());
+        }
+        if let Some(collector) = self.stats_collector.borrow_mut().as_mut() {
+            collector.set_bucket_size(Duration::from_millis(millis as u64));
+        }
+        Ok(())
+    }
+
+    /// Set the container identification reported with client-side stats: the
+    /// payload's `container_id`/`image_tag` and the `Datadog-Container-ID`,
+    /// `Datadog-Entity-ID` and `Datadog-External-Env` request headers. Empty
+    /// strings leave a field unset. No-op when stats are disabled.
+    #[wasm_bindgen(js_name = "setStatsContainerInfo")]
+    pub fn set_stats_container_info(
+        &self,
+        container_id: &str,
+        entity_id: &str,
+        external_env: &str,
+        image_tag: &str,
+    ) -> Result<(), JsValue> {
+        self.check_open("setStatsContainerInfo")?;
+        if let Some(collector) = self.stats_collector.borrow_mut().as_mut() {
+            collector.meta_mut().container = stats::ContainerMeta {
+                container_id: container_id.to_string(),
+                entity_id: entity_id.to_string(),
+                external_env: external_env.to_string(),
+                image_tag: image_tag.to_string(),
+            };
+        }
+        Ok(())
+    }
+
+    /// Set the `tags` and `git_commit_sha` of the client-side stats payload
+    /// (`tags` as `key:value` strings). No-op when stats are disabled.
+    #[wasm_bindgen(js_name = "setStatsTags")]
+    pub fn set_stats_tags(&self, tags: Vec<String>, git_commit_sha: &str) -> Result<(), JsValue> {
+        self.check_open("setStatsTags")?;
+        if let Some(collector) = self.stats_collector.borrow_mut().as_mut() {
+            let meta = collector.meta_mut();
+            meta.tags = tags;
+            meta.git_commit_sha = git_commit_sha.to_string();
+        }
+        Ok(())
+    }
+
+    /// Set the process tags (comma-separated `key:value` string) and their
+    /// hash as computed by the tracer, reported with client-side stats.
+    /// No-op when stats are disabled.
+    #[wasm_bindgen(js_name = "setStatsProcessTags")]
+    pub fn set_stats_process_tags(
+        &self,
+        process_tags: &str,
+        process_tags_hash: u64,
+    ) -> Result<(), JsValue> {
+        self.check_open("setStatsProcessTags")?;
+        if let Some(collector) = self.stats_collector.borrow_mut().as_mut() {
+            let meta = collector.meta_mut();
+            meta.process_tags = process_tags.to_string();
+            meta.process_tags_hash = process_tags_hash;
+        }
+        Ok(())
+    }
+
+    /// Drop rejected (priority <= 0) chunks in `prepareChunk` once they have
+    /// been fed to client-side stats, instead of sending them to the agent.
+    /// Chunks with an error span or a single-span-sampled span are still sent.
+    /// Only takes effect when stats are enabled; the dropped counts are
+    /// reported with the next trace payload. Off by default.
+    #[wasm_bindgen(js_name = "setDropP0Traces")]
+    pub fn set_drop_p0_traces(&self, enabled: bool) -> Result<(), JsValue> {
+        self.check_open("setDropP0Traces")?;
+        self.drop_p0.set(enabled);
+        Ok(())
+    }
+
+    /// The agent sample rate for `service`/`env` from the latest
+    /// `rate_by_service`, falling back to the agent default rate, or `1` when
+    /// the agent hasn't sent any rates yet.
+    #[wasm_bindgen(js_name = "getAgentSampleRate")]
+    pub fn get_agent_sample_rate(&self, service: &str, env: &str) -> Result<f64, JsValue> {
+        self.check_open("getAgentSampleRate")?;
+        Ok(self.agent_rates.borrow().rate_for(service, env))
     }
 
     #[wasm_bindgen]
@@ -368,9 +884,16 @@ impl WasmSpanState {
     }
 
     /// Prepare a chunk of spans for sending. Flushes the change buffer,
-    /// extracts spans, feeds stats. Returns `true` if a chunk was prepared
-    /// (there are spans to send) and `false` if there was nothing to send.
-    /// Must be followed by `sendPreparedChunk()` to actually send.
+    /// extracts spans, feeds stats. Returns `true` when a send is due: without
+    /// batch limits that is whenever a chunk was prepared (there are spans to
+    /// send), with `setBatchLimits` only once the batch reaches a cap. Returns
+    /// `false` if there is nothing to send yet. Must be followed by
+    /// `sendPreparedChunk()` to actually send. Ids of spans `reapOrphans` or
+    /// a discard already took out are skipped; if the first one is, the chunk
+    /// has no local root. Any other id that isn't live throws an
+    /// `UnknownSpanError`. Later chunks of a local trace get the sampling priority decided
+    /// for its first chunk instead of being sampled again. Throws a
+    /// `BatchFullError`, leaving the spans live, while the batch is full.
     #[wasm_bindgen(js_name = "prepareChunk")]
     pub fn prepare_chunk(
         &self,
@@ -378,61 +901,440 @@ impl WasmSpanState {
         first_is_local_root: bool,
         chunk: &[u8],
     ) -> Result<bool, JsValue> {
+        self.check_open("prepareChunk")?;
         // Validate the JS-supplied count against the actual buffer size before
         // doing any work: each span id is a u64 (8 bytes). This prevents an
         // out-of-bounds read panic (and a huge `Vec::with_capacity`) when the
         // caller passes a `len` larger than the chunk can hold.
         if (len as usize).saturating_mul(8) > chunk.len() {
-            return Err(JsValue::from_str(
+            return Err(PipelineError::invalid_input(
                 "prepareChunk: len exceeds the span-id bytes available in chunk",
-            ));
+            )
+            .into());
         }
+        let limits = self.batch_limits.get();
         if len == 0 {
             // Nothing to send: drop any previously prepared-but-unsent chunk so
             // a caller that ignores this `false` cannot later resend a stale one.
-            if let Some(old_spans) = self.prepared_spans.borrow_mut().take() {
-                self.cbs.borrow_mut().recycle_spans(old_spans);
+            // A batch is meant to outlive individual calls, so it is kept.
+            if !limits.is_enabled() {
+                self.recycle_prepared();
             }
             return Ok(false);
         }
+        self.check_batch_room("prepareChunk")?;
 
-        self.cbs.borrow_mut()
-            .flush_change_buffer()
-            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        self.flush_queue()?;
 
         let mut count = len;
         let mut index = 0;
         let mut span_ids = Vec::with_capacity(count as usize);
         while count > 0 {
             let span_id: u64 = get_num(chunk, &mut index)
-                .ok_or_else(|| JsValue::from_str("prepareChunk: span id index out of bounds"))?;
+                .ok_or_else(|| PipelineError::invalid_input("prepareChunk: span id index out of bounds"))?;
             span_ids.push(span_id);
             count -= 1;
         }
+        let first_id = span_ids[0];
+        let evicted: Vec<u64> = {
+            let cbs = self.cbs.borrow();
+            let index = self.span_index.borrow();
+            let (live, evicted) = span_ids
+                .into_iter()
+                .partition(|id| cbs.get_span(*id).is_ok() || !index.is_evicted(*id));
+            span_ids = live;
+            evicted
+        };
+        let first_is_local_root = first_is_local_root && span_ids.first() == Some(&first_id);
+        if span_ids.is_empty() {
+            // Nothing left to send, as for an empty chunk.
+            self.clear_evicted(&evicted);
+            if !limits.is_enabled() {
+                self.recycle_prepared();
+            }
+            return Ok(false);
+        }
 
-        let spans_vec = self
+        let mut spans_vec = self
             .cbs.borrow_mut()
             .flush_chunk(&span_ids, first_is_local_root)
-            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+            .map_err(PipelineError::unknown_span)?;
+        self.clear_evicted(&evicted);
+        // A trace flushed over several chunks keeps the sampling decision made
+        // for its first one, so the rules and their limiter only see it once.
+        // Its local root carries the trace-level attributes, taken before the
+        // segment's last span goes.
+        let (segment_id, inherited_priority, tags) = {
+            let mut index = self.span_index.borrow_mut();
+            let segment_id = span_ids.first().and_then(|id| index.segment_of(*id));
+            let priority = segment_id.and_then(|segment_id| index.sampling_priority(segment_id));
+            let tags = segment_id
+                .filter(|_| first_is_local_root)
+                .and_then(|segment_id| index.trace_tags(segment_id).cloned());
+            for span in &spans_vec {
+                index.remove(span.span_id);
+            }
+            (segment_id, priority, tags)
+        };
+        if let (Some(tags), Some(root)) = (tags, spans_vec.first_mut()) {
+            tags.apply_to(root);
+        }
+        let (due, priority) = self.prepare_spans(
+            spans_vec,
+            first_is_local_root,
+            !limits.is_enabled(),
+            false,
+            inherited_priority,
+        );
+        if let (Some(segment_id), Some(priority)) = (segment_id, priority) {
+            self.span_index
+                .borrow_mut()
+                .set_sampling_priority(segment_id, priority);
+        }
+        Ok(due)
+    }
 
-        if let Some(collector) = self.stats_collector.borrow_mut().as_mut() {
-            collector.add_spans(&spans_vec);
+    /// Sample, feed stats and queue for sending a chunk already taken out of
+    /// the change buffer state. With `replace_pending`, any prepared chunk
+    /// that was never sent is recycled first; a `pin`ned chunk is never
+    /// recycled, only sent. `inherited_priority` is given to a root without a
+    /// priority of its own. Returns whether a send is due and the chunk's
+    /// sampling priority.
+    fn prepare_spans(
+        &self,
+        mut spans_vec: Vec<libdd_trace_utils::span::v04::Span<WasmTraceData>>,
+        first_is_local_root: bool,
+        replace_pending: bool,
+        pin: bool,
+        inherited_priority: Option<f64>,
+    ) -> (bool, Option<f64>) {
+        let limits = self.batch_limits.get();
+        self.metrics.borrow_mut().record_prepared(spans_vec.len());
+
+        let root_index = sampling::chunk_root_index(&spans_vec, first_is_local_root);
+        let mut priority = None;
+        if let Some(root) = root_index {
+            let root = &mut spans_vec[root];
+            if let Some(inherited) = inherited_priority {
+                if sampling::existing_priority(root).is_none() {
+                    sampling::set_priority(root, inherited);
+                }
+            }
+            let decided = match self.rules_sampler.borrow_mut().as_mut() {
+                Some(sampler) => sampler.sample(root, now_ns()),
+                None => false,
+            };
+            if !decided && self.agent_sampling.get() {
+                sampling::apply_agent_rate(root, &self.agent_rates.borrow(), &self.env);
+            }
+            priority = sampling::existing_priority(root);
+        }
+
+        let stats_supported = self.client_stats_supported();
+        let stats_enabled = match self.stats_collector.borrow_mut().as_mut() {
+            Some(collector) if stats_supported => {
+                collector.add_spans(&spans_vec);
+                true
+            }
+            _ => false,
+        };
+
+        // Stats above saw the whole chunk; a rejected chunk is then reduced to
+        // its single-span-sampled spans.
+        if priority.is_some_and(|p| p <= 0.0) {
+            if let Some(sampler) = self.span_sampler.borrow_mut().as_mut() {
+                if sampler.sample_chunk(&mut spans_vec, now_ns()) > 0 {
+                    spans_vec = self.keep_span_sampled(spans_vec, priority);
+                }
+            }
+        }
+
+        // Without batching, recycle any previously prepared spans that were
+        // never sent (e.g. if the prior send was skipped by JS back-pressure).
+        // Reusing the pre-allocated HashMaps avoids allocator fragmentation in
+        // WASM.
+        if replace_pending {
+            self.recycle_prepared();
         }
 
-        // Recycle any previously prepared spans that were never sent (e.g.
-        // if the prior send was skipped by JS back-pressure). Reusing the
-        // pre-allocated HashMaps avoids allocator fragmentation in WASM.
-        if let Some(old_spans) = self.prepared_spans.borrow_mut().take() {
-            self.cbs.borrow_mut().recycle_spans(old_spans);
+        // The agent no longer needs a rejected chunk once its stats are
+        // computed here, unless it carries errors or kept spans.
+        if self.drop_p0.get()
+            && stats_enabled
+            && self.agent_info.borrow().drop_p0_allowed()
+            && priority.is_some_and(|p| p <= 0.0)
+            && !spans_vec
+                .iter()
+                .any(|s| s.error != 0 || span_sampling::is_span_sampled(s))
+        {
+            self.dropped_p0_traces.set(self.dropped_p0_traces.get() + 1);
+            self.dropped_p0_spans
+                .set(self.dropped_p0_spans.get() + spans_vec.len() as u64);
+            self.metrics.borrow_mut().record_p0_dropped(spans_vec.len());
+            self.cbs.borrow_mut().recycle_spans(spans_vec);
+            return (limits.is_due(&self.prepared.borrow()), priority);
         }
 
         // Store prepared spans for the subsequent sendPreparedChunk call
-        let has_spans = !spans_vec.is_empty();
-        *self.prepared_spans.borrow_mut() = Some(spans_vec);
-        Ok(has_spans)
+        let mut prepared = self.prepared.borrow_mut();
+        if !spans_vec.is_empty() {
+            if pin {
+                prepared.push_pinned(spans_vec);
+            } else {
+                prepared.push(spans_vec);
+            }
+        }
+        (limits.is_due(&prepared), priority)
+    }
+
+    /// Split out the spans kept by single span sampling, recycling the rest.
+    /// The chunk's (reject) priority is carried over to kept spans in case the
+    /// root itself was dropped.
+    fn keep_span_sampled(
+        &self,
+        spans: Vec<libdd_trace_utils::span::v04::Span<WasmTraceData>>,
+        priority: Option<f64>,
+    ) -> Vec<libdd_trace_utils::span::v04::Span<WasmTraceData>> {
+        let (mut kept, dropped): (Vec<_>, Vec<_>) =
+            spans.into_iter().partition(span_sampling::is_span_sampled);
+        if let Some(priority) = priority {
+            for span in &mut kept {
+                if sampling::existing_priority(span).is_none() {
+                    sampling::set_priority(span, priority);
+                }
+            }
+        }
+        self.cbs.borrow_mut().recycle_spans(dropped);
+        kept
+    }
+
+    /// Reject a new chunk while the batch is full, before any span is taken
+    /// out of the change buffer state.
+    fn check_batch_room(&self, ctx: &str) -> Result<(), PipelineError> {
+        if self.batch_limits.get().is_full(&self.prepared.borrow()) {
+            return Err(PipelineError::new(
+                ErrorKind::BatchFull,
+                format!("{ctx}: the prepared batch is full, send it with sendPreparedChunk first"),
+            ));
+        }
+        Ok(())
+    }
+
+    fn recycle_prepared(&self) {
+        let stale = self.prepared.borrow_mut().take_stale();
+        let mut cbs = self.cbs.borrow_mut();
+        let mut metrics = self.metrics.borrow_mut();
+        for old_spans in stale {
+            metrics.record_stale(old_spans.len());
+            cbs.recycle_spans(old_spans);
+        }
+    }
+
+    /// Prepare the finished spans of segment `segmentId` as a chunk while the
+    /// rest of the local trace is still running, once at least the
+    /// `setPartialFlushMinSpans` count have finished. The chunk's root gets
+    /// the segment's trace-level tags, origin and sampling priority, plus
+    /// `_dd.partial_version` (1 for the first partial chunk of the trace);
+    /// the segment keeps them for the chunks that follow, which reuse the
+    /// sampling decision of the trace's first chunk. Returns whether a send is
+    /// due, as `prepareChunk` does, and `false` without preparing anything
+    /// when partial flushing is off, too few spans have finished, or every
+    /// span has (use `prepareChunk` for a complete trace). Throws a
+    /// `BatchFullError` while the batch is full, as `prepareChunk` does.
+    #[wasm_bindgen(js_name = "preparePartialChunk")]
+    pub fn prepare_partial_chunk(&self, segment_id: u64) -> Result<bool, JsValue> {
+        self.check_open("preparePartialChunk")?;
+        let min_spans = self.partial_flush_min_spans.get();
+        if min_spans == 0 {
+            return Ok(false);
+        }
+        self.check_batch_room("preparePartialChunk")?;
+        self.flush_queue()?;
+        let (finished, live) = {
+            let index = self.span_index.borrow();
+            (
+                index.finished_spans_of(segment_id),
+                index.spans_of(segment_id).len(),
+            )
+        };
+        if finished.len() < min_spans || finished.len() == live {
+            return Ok(false);
+        }
+        let tags = self
+            .span_index
+            .borrow()
+            .trace_tags(segment_id)
+            .cloned()
+            .unwrap_or_default();
+
+        let mut spans = self.take_spans(finished, false)?;
+        let Some(root) = sampling::chunk_root_index(&spans, false) else {
+            return Ok(false);
+        };
+        let (version, inherited_priority) = {
+            let mut index = self.span_index.borrow_mut();
+            (
+                index.next_partial_version(segment_id),
+                index.sampling_priority(segment_id),
+            )
+        };
+        partial::apply_trace_tags(&mut spans[root], &tags, version);
+        self.metrics.borrow_mut().partial_chunks_prepared += 1;
+        let limits = self.batch_limits.get();
+        let (due, priority) =
+            self.prepare_spans(spans, false, !limits.is_enabled(), false, inherited_priority);
+        if let Some(priority) = priority {
+            self.span_index
+                .borrow_mut()
+                .set_sampling_priority(segment_id, priority);
+        }
+        Ok(due)
+    }
+
+    /// Drop the spans with `spanIds` (a `BigUint64Array`) without exporting
+    /// them, e.g. for traces the host sampled out or filtered. Their
+    /// allocations go back to the span pool. Ids that aren't live are
+    /// ignored. Returns the number of spans discarded; they are counted as
+    /// one discarded chunk in `getMetrics`.
+    #[wasm_bindgen(js_name = "discardChunk")]
+    pub fn discard_chunk(&self, span_ids: &[u64]) -> Result<u32, JsValue> {
+        self.check_open("discardChunk")?;
+        self.flush_queue()?;
+        Ok(self.discard_spans(span_ids.to_vec())? as u32)
+    }
+
+    /// Drop every live span of segment `segmentId` without exporting. The
+    /// trace-level state goes with them: `getTraceMetaAttr` and friends
+    /// return `null` for the segment afterwards. Returns the number of spans
+    /// discarded; an unknown segment discards nothing.
+    #[wasm_bindgen(js_name = "discardSegment")]
+    pub fn discard_segment(&self, segment_id: u64) -> Result<u32, JsValue> {
+        self.check_open("discardSegment")?;
+        self.flush_queue()?;
+        let span_ids = self.span_index.borrow().spans_of(segment_id);
+        Ok(self.discard_spans(span_ids)? as u32)
+    }
+
+    /// Evict the local traces that have an unfinished span older than the
+    /// `setMaxSpanAge` limit at `nowNs` (epoch nanoseconds), dropping or
+    /// preparing them as configured. Exported orphans get the segment's
+    /// trace-level tags as a partial chunk does. Returns whether a send is
+    /// due for them, as `prepareChunk` does; `false` when reaping is off or
+    /// nothing was exported. Reaped traces and spans are counted in
+    /// `getMetrics` (`orphanChunksReaped`, `orphanSpansReaped`, and
+    /// `orphanChunksDropped`, `orphanSpansDropped` for those not exported).
+    /// When orphans are exported, throws a `BatchFullError` without reaping
+    /// while the batch is full.
+    #[wasm_bindgen(js_name = "reapOrphans")]
+    pub fn reap_orphans(&self, now_ns: u64) -> Result<bool, JsValue> {
+        self.check_open("reapOrphans")?;
+        let Some(policy) = self.orphan_policy.get() else {
+            return Ok(false);
+        };
+        if policy.export {
+            self.check_batch_room("reapOrphans")?;
+        }
+        self.flush_queue()?;
+        let expired: Vec<(u64, Vec<u64>)> = {
+            let cbs = self.cbs.borrow();
+            let index = self.span_index.borrow();
+            index
+                .segments()
+                .filter(|(_, spans)| {
+                    spans.iter().any(|id| {
+                        !index.is_finished(*id)
+                            && cbs
+                                .get_span(*id)
+                                .is_ok_and(|span| policy.is_expired(span.start, now_ns))
+                    })
+                })
+                .map(|(segment_id, spans)| (segment_id, spans.iter().copied().collect()))
+                .collect()
+        };
+        let mut due = false;
+        for (segment_id, span_ids) in expired {
+            // The segment's state goes with its last span, so take what the
+            // chunk needs from it first.
+            let (unfinished, inherited_priority, tags, version): (HashSet<u64>, _, _, _) = {
+                let mut index = self.span_index.borrow_mut();
+                (
+                    span_ids.iter().copied().filter(|id| !index.is_finished(*id)).collect(),
+                    index.sampling_priority(segment_id),
+                    index.trace_tags(segment_id).cloned().unwrap_or_default(),
+                    index.next_partial_version(segment_id),
+                )
+            };
+            let mut spans = self.take_spans(span_ids, true)?;
+            if spans.is_empty() {
+                continue;
+            }
+            self.metrics
+                .borrow_mut()
+                .record_orphans(spans.len(), policy.export);
+            if policy.export {
+                for span in spans.iter_mut().filter(|s| unfinished.contains(&s.span_id)) {
+                    orphans::mark_unfinished(span, now_ns);
+                }
+                if let Some(root) = sampling::chunk_root_index(&spans, false) {
+                    partial::apply_trace_tags(&mut spans[root], &tags, version);
+                }
+                // Pinned: a later prepareChunk doesn't recycle it unsent.
+                due |= self.prepare_spans(spans, false, false, true, inherited_priority).0;
+            } else {
+                self.cbs.borrow_mut().recycle_spans(spans);
+            }
+        }
+        Ok(due)
+    }
+
+    fn discard_spans(&self, span_ids: Vec<u64>) -> Result<usize, PipelineError> {
+        let spans = self.take_spans(span_ids, true)?;
+        let discarded = spans.len();
+        if discarded > 0 {
+            self.cbs.borrow_mut().recycle_spans(spans);
+            self.metrics.borrow_mut().record_discarded(discarded);
+        }
+        Ok(discarded)
+    }
+
+    /// Take the live spans among `span_ids` out of the change buffer state
+    /// as one chunk, without preparing it. With `evict` the index remembers
+    /// them, for `prepareChunk` to skip.
+    fn take_spans(
+        &self,
+        mut span_ids: Vec<u64>,
+        evict: bool,
+    ) -> Result<Vec<libdd_trace_utils::span::v04::Span<WasmTraceData>>, PipelineError> {
+        let mut cbs = self.cbs.borrow_mut();
+        span_ids.sort_unstable();
+        span_ids.dedup();
+        span_ids.retain(|id| cbs.get_span(*id).is_ok());
+        if span_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let spans = cbs
+            .flush_chunk(&span_ids, false)
+            .map_err(PipelineError::unknown_span)?;
+        let mut index = self.span_index.borrow_mut();
+        for span in &spans {
+            if evict {
+                index.evict(span.span_id);
+            } else {
+                index.remove(span.span_id);
+            }
+        }
+        Ok(spans)
+    }
+
+    /// Forget the evicted ids a chunk has skipped.
+    fn clear_evicted(&self, span_ids: &[u64]) {
+        let mut index = self.span_index.borrow_mut();
+        for span_id in span_ids {
+            index.clear_evicted(*span_id);
+        }
     }
 
-    /// Send the previously prepared chunk.
+    /// Send every previously prepared chunk as a single payload.
     ///
     /// Uses `&self` (not `&mut self`); exclusive access to the exporter is
     /// enforced at runtime by the `sending` re-entrancy guard below rather
@@ -440,21 +1342,82 @@ impl WasmSpanState {
     /// two `&mut` to the exporter could co-exist is async re-entrancy (JS
     /// calling this again before the prior future resolves) — the guard
     /// rejects that with an error instead of allowing aliasing (UB).
+    ///
+    /// `timeoutMs` and `signal` (an `AbortSignal`) are optional. Either one
+    /// destroys the in-flight agent request and rejects with a `TimeoutError`
+    /// or `AbortError`; the chunks being sent are dropped.
     #[wasm_bindgen(js_name = "sendPreparedChunk")]
-    pub async fn send_prepared_chunk(&self) -> Result<JsValue, JsValue> {
+    pub async fn send_prepared_chunk(
+        &self,
+        timeout_ms: Option<u32>,
+        signal: JsValue,
+    ) -> Result<JsValue, JsValue> {
+        self.check_open("sendPreparedChunk")?;
+        let result = cancellable(
+            "sendPreparedChunk",
+            timeout_ms,
+            &signal,
+            self.with_tls(self.send_prepared_chunk_inner()),
+        )
+        .await
+        .map(|s| JsValue::from_str(&s))
+        .map_err(|e| self.record_error(e));
+        self.emit_health_if_due();
+        result
+    }
+
+    async fn send_prepared_chunk_inner(&self) -> Result<String, PipelineError> {
         if self.sending.get() {
-            return Err(JsValue::from_str("sendPreparedChunk is already in flight"));
+            return Err(PipelineError::new(
+                ErrorKind::ReentrantCall,
+                "sendPreparedChunk is already in flight",
+            ));
         }
         self.sending.set(true);
         let _in_flight = InFlightGuard(&self.sending);
 
-        let spans_vec = self
-            .prepared_spans
+        let (chunks, span_count, bytes) = {
+            let mut prepared = self.prepared.borrow_mut();
+            let (spans, bytes) = (prepared.span_count(), prepared.encoded_size());
+            (prepared.take(), spans, bytes)
+        };
+        if chunks.is_empty() {
+            return Err(PipelineError::invalid_input("no prepared chunk to send"));
+        }
+        let chunk_count = chunks.len();
+        let result = self.send_chunks(chunks).await;
+        self.metrics
             .borrow_mut()
-            .take()
-            .ok_or_else(|| JsValue::from_str("no prepared chunk to send"))?;
+            .record_payload(&result, chunk_count, span_count, bytes);
+        result
+    }
+
+    /// Send `chunks` as one payload, building the exporter first if needed.
+    /// Only called under the `sending` guard.
+    async fn send_chunks(
+        &self,
+        chunks: Vec<Vec<libdd_trace_utils::span::v04::Span<WasmTraceData>>>,
+    ) -> Result<String, PipelineError> {
+        let intake = self.agentless.borrow().clone();
+        if let Some(intake) = intake.filter(|_| !self.log_output.get()) {
+            let body = agentless::encode_traces(&chunks, &self.tracer_meta);
+            {
+                let mut cbs = self.cbs.borrow_mut();
+                for spans in chunks {
+                    cbs.recycle_spans(spans);
+                }
+            }
+            return libdatadog_nodejs_capabilities::http::with_env_proxy(agentless::send_traces(
+                &intake,
+                body,
+                &self.tracer_meta.lang,
+            ))
+            .await;
+        }
+
+        self.refresh_agent_info_if_stale().await;
 
-        // SAFETY: WASM is single-threaded and the `sending` guard above
+        // SAFETY: WASM is single-threaded and the `sending` guard
         // guarantees no overlapping invocation, so this is the only live
         // reference to the exporter for the duration of the awaits.
         let exporter_slot = unsafe { &mut *self.exporter.get() };
@@ -464,25 +1427,36 @@ impl WasmSpanState {
             // consistently — as a distinguishable error the host bails on —
             // rather than a misleading "builder already consumed".
             if let Some(msg) = self.build_error.borrow().clone() {
-                return Err(build_failure_error(&msg));
+                return Err(PipelineError::new(ErrorKind::ExporterBuild, msg));
             }
             // First send: build the exporter asynchronously. `build` is not
             // available on wasm (it needs a blocking runtime), so we drive
             // `build_async` here where we already have an async context.
             let mut builder = unsafe { &mut *self.builder.get() }
                 .take()
-                .ok_or_else(|| JsValue::from_str("exporter builder already consumed"))?;
+                .ok_or_else(|| {
+                    PipelineError::new(ErrorKind::ExporterBuild, "exporter builder already consumed")
+                })?;
             // Output format is decided here, at first build, and then fixed.
             // v0.5 drops meta_struct/span_events/span_links by design (the v0.5
             // schema has no slots for them); dd-trace-js only enables this after
             // confirming agent `/v0.5/traces` support via `/info`.
-            if self.use_v05.get() {
+            // Once `/info` has been fetched, the requested format is only used
+            // if the agent serves it.
+            let use_v05 = match self.agent_info.borrow().info() {
+                Some(info) => info.use_v05(self.use_v05.get()),
+                None => self.use_v05.get(),
+            };
+            if use_v05 && !self.log_output.get() {
                 builder.set_output_format(TraceExporterOutputFormat::V05);
             }
             // When an OTLP endpoint is configured, libdatadog exports traces via
             // OTLP HTTP to that endpoint instead of the Datadog agent (mutually
-            // exclusive with the agent v0.4/v0.5 path).
-            if let Some(url) = self.otlp_endpoint.borrow().as_deref() {
+            // exclusive with the agent v0.4/v0.5 path). Log output replaces
+            // both: payloads go to `WasmCapabilities::write_log_output`.
+            if self.log_output.get() {
+                builder.enable_log_output();
+            } else if let Some(url) = self.otlp_endpoint.borrow().as_deref() {
                 builder.set_otlp_endpoint(url);
                 if let Some(protocol) = self.otlp_protocol.get() {
                     builder.set_otlp_protocol(protocol);
@@ -499,34 +1473,632 @@ impl WasmSpanState {
                     // config won't change, so every later send must fail fast.
                     let msg = format!("native exporter build failed: {e:?}");
                     *self.build_error.borrow_mut() = Some(msg.clone());
-                    return Err(build_failure_error(&msg));
+                    return Err(PipelineError::new(ErrorKind::ExporterBuild, msg));
                 }
             }
         }
         let exporter = match exporter_slot.as_mut() {
             Some(exporter) => exporter,
             // Unreachable: the block above either set `Some` or returned early.
-            None => return Err(build_failure_error("native exporter unavailable")),
+            None => {
+                return Err(PipelineError::new(
+                    ErrorKind::ExporterBuild,
+                    "native exporter unavailable",
+                ))
+            }
         };
-        let resp = exporter
-            .send_trace_chunks_async(vec![spans_vec])
-            .await;
-        let response_str = resp.map(|resp| match resp {
+        // Tell the agent stats were computed here (so it doesn't compute them
+        // again) and how much it never got to see. The dropped counts are
+        // only subtracted once the agent has accepted them.
+        let mut headers = Vec::new();
+        let (dropped_traces, dropped_spans) =
+            if self.stats_collector.borrow().is_some() && self.client_stats_supported() {
+                headers.push((
+                    "Datadog-Client-Computed-Stats".to_string(),
+                    "yes".to_string(),
+                ));
+                (self.dropped_p0_traces.get(), self.dropped_p0_spans.get())
+            } else {
+                (0, 0)
+            };
+        if dropped_traces > 0 {
+            headers.push((
+                "Datadog-Client-Dropped-P0-Traces".to_string(),
+                dropped_traces.to_string(),
+            ));
+            headers.push((
+                "Datadog-Client-Dropped-P0-Spans".to_string(),
+                dropped_spans.to_string(),
+            ));
+        }
+        let send = libdatadog_nodejs_capabilities::http::with_trace_request_headers(
+            headers,
+            exporter.send_trace_chunks_async(chunks),
+        );
+        let otlp_endpoint = self
+            .otlp_endpoint
+            .borrow()
+            .clone()
+            .filter(|_| !self.log_output.get());
+        let (resp, grpc_gave_up) = match otlp_endpoint {
+            Some(endpoint) if self.otlp_grpc.get() => {
+                libdatadog_nodejs_capabilities::grpc::with_otlp_grpc(&endpoint, send).await
+            }
+            Some(_) => (
+                libdatadog_nodejs_capabilities::http::with_env_proxy(send).await,
+                None,
+            ),
+            None => (send.await, None),
+        };
+        if resp.is_ok() {
+            // P0s dropped while the send was in flight stay for the next one.
+            self.dropped_p0_traces
+                .set(self.dropped_p0_traces.get() - dropped_traces);
+            self.dropped_p0_spans
+                .set(self.dropped_p0_spans.get() - dropped_spans);
+        }
+        resp.map(|resp| match resp {
             AgentResponse::Unchanged => "unchanged".to_string(),
-            AgentResponse::Changed { body } => body,
+            AgentResponse::Changed { body } => {
+                // A body that doesn't parse keeps the previous rates; the raw
+                // body is still handed back to JS unchanged.
+                let _ = self.agent_rates.borrow_mut().update_from_json(&body);
+                body
+            }
+        })
+        .map_err(|e| match (&e, grpc_gave_up) {
+            // libdatadog only saw the 500 answered for an export given up
+            // after its gRPC retries; report the status it failed with.
+            (TraceExporterError::Request(_), Some(gave_up)) => {
+                PipelineError::from_grpc("sendPreparedChunk", &gave_up)
+            }
+            _ => PipelineError::from_exporter(
+                "sendPreparedChunk",
+                &e,
+                libdatadog_nodejs_capabilities::http::last_retry_after(),
+            ),
+        })
+    }
+
+    /// Fetch the agent's `/info` now and keep it fresh from then on: later
+    /// sends and stats flushes refetch it every few minutes or as soon as a
+    /// response from this state's agent carries a new `Datadog-Agent-State`.
+    /// The cached info picks the trace endpoint when the exporter is built on
+    /// the first send (falling back from `setUseV05` when the agent lacks
+    /// `/v0.5/traces`), turns client-side stats off when the agent has no
+    /// `/v0.6/stats`, and replaces the stats peer tags and span kinds with
+    /// the agent's `peer_tags` and `span_kinds_stats_computed`.
+    #[wasm_bindgen(js_name = "refreshAgentInfo")]
+    pub async fn refresh_agent_info(&self) -> Result<(), JsValue> {
+        self.check_open("refreshAgentInfo")?;
+        self.with_tls(self.refresh_agent_info_inner())
+            .await
+            .map_err(Into::into)
+    }
+
+    async fn refresh_agent_info_inner(&self) -> Result<(), PipelineError> {
+        let fetched = agent_info::fetch(&self.agent_url).await;
+        let mut cache = self.agent_info.borrow_mut();
+        let (info, state) = match fetched {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                cache.mark_attempt(now_ns());
+                return Err(e);
+            }
+        };
+        if let Some(collector) = self.stats_collector.borrow_mut().as_mut() {
+            if !info.peer_tags.is_empty() {
+                collector.set_peer_tags(info.peer_tags.clone());
+            }
+            if !info.span_kinds_stats_computed.is_empty() {
+                collector.set_span_kinds(info.span_kinds_stats_computed.clone());
+            }
+        }
+        cache.store(info, state, now_ns());
+        Ok(())
+    }
+
+    /// Refetch `/info` when discovery is on and the cache is stale. Called
+    /// before trace sends and stats flushes, so a state that only flushes
+    /// stats keeps its info fresh too. Only agent-bound traffic uses it.
+    async fn refresh_agent_info_if_stale(&self) {
+        if self.otlp_endpoint.borrow().is_some()
+            || self.agentless.borrow().is_some()
+            || self.log_output.get()
+        {
+            return;
+        }
+        let stale = self.agent_info.borrow().needs_refresh(now_ns());
+        if stale {
+            // Best effort: a failed refresh keeps the previous info and is
+            // retried after the refresh interval.
+            let _ = self.refresh_agent_info_inner().await;
+        }
+    }
+
+    /// The cached agent `/info` (`version`, `endpoints`, `client_drop_p0s`,
+    /// `span_kinds_stats_computed`, `peer_tags`, `feature_flags`) as an
+    /// object, or `null` before `refreshAgentInfo` has succeeded.
+    #[wasm_bindgen(js_name = "getAgentInfo")]
+    pub fn get_agent_info(&self) -> Result<JsValue, JsValue> {
+        self.check_open("getAgentInfo")?;
+        let cache = self.agent_info.borrow();
+        let Some(info) = cache.info() else {
+            return Ok(JsValue::NULL);
+        };
+        let json = serde_json::to_string(info)
+            .map_err(|e| PipelineError::serialization(format!("getAgentInfo: {e}")))?;
+        js_sys::JSON::parse(&json)
+    }
+
+    /// Snapshot of the exporter counters as an object: chunks and spans
+    /// prepared, sent, failed, dropped as P0 or recycled unsent; the largest
+    /// chunk (`maxSpansPerChunk`); estimated payload bytes sent; failed sends
+    /// by class (`sendErrors`: `network`, `timeout`, `aborted`, `4xx`, `5xx`,
+    /// `other`); re-entrant call rejections; stats payloads and buckets
+    /// flushed. Counters only grow. Also reports the live spans, segments and
+    /// string-table entries and the batch waiting to be sent (`pending*`).
+    ///
+    /// Still available after `shutdown`, so the final counts can be read.
+    #[wasm_bindgen(js_name = "getMetrics")]
+    pub fn get_metrics(&self) -> Result<JsValue, JsValue> {
+        let json = serde_json::to_string(&self.metrics_snapshot())
+            .map_err(|e| PipelineError::serialization(format!("getMetrics: {e}")))?;
+        js_sys::JSON::parse(&json)
+    }
+
+    fn metrics_snapshot(&self) -> metrics::Snapshot {
+        let index = self.span_index.borrow();
+        let prepared = self.prepared.borrow();
+        let connections = libdatadog_nodejs_capabilities::http::connection_stats();
+        metrics::Snapshot {
+            counters: self.metrics.borrow().clone(),
+            live_spans: index.span_count(),
+            live_segments: index.segment_count(),
+            string_table_entries: self.string_table.borrow().len(),
+            pending_chunks: prepared.chunk_count(),
+            pending_spans: prepared.span_count(),
+            pending_bytes: prepared.encoded_size(),
+            connections_opened: connections.opened,
+            connections_reused: connections.reused,
+        }
+    }
+
+    /// Emit `datadog.tracer.*` health metrics to DogStatsD at `url`
+    /// (`udp://host[:port]`, port 8125 by default, or
+    /// `unixstream:///path`; `unix://` datagram sockets are rejected) every
+    /// `intervalMs`, tagged with `tags` (`key:value` strings). `runHealthMetrics`
+    /// emits on a timer; `sendPreparedChunk` and `flushStats` also emit once
+    /// the interval has passed, and `flushHealthMetrics` emits right away. Counts are the
+    /// change since the previous emission of the `getMetrics` counters; live
+    /// spans, strings and pending spans are gauges. An empty `url` turns
+    /// emission off.
+    #[wasm_bindgen(js_name = "setHealthMetrics")]
+    pub fn set_health_metrics(
+        &self,
+        url: &str,
+        tags: Vec<String>,
+        interval_ms: u32,
+    ) -> Result<(), JsValue> {
+        self.check_open("setHealthMetrics")?;
+        if url.is_empty() {
+            *self.health.borrow_mut() = None;
+            return Ok(());
+        }
+        if interval_ms == 0 {
+            return Err(PipelineError::invalid_input(
+                "setHealthMetrics: interval must be greater than zero",
+            )
+            .into());
+        }
+        let target = DatagramTarget::parse(url)
+            .map_err(|e| PipelineError::invalid_input(format!("setHealthMetrics: {e}")))?;
+        *self.health.borrow_mut() = Some(dogstatsd::HealthReporter::new(
+            target,
+            tags,
+            Duration::from_millis(interval_ms as u64),
+            now_ns(),
+        ));
+        Ok(())
+    }
+
+    /// Emit the health metrics now, regardless of the interval. Returns
+    /// `false` when `setHealthMetrics` hasn't configured an endpoint.
+    #[wasm_bindgen(js_name = "flushHealthMetrics")]
+    pub fn flush_health_metrics(&self) -> Result<bool, JsValue> {
+        self.check_open("flushHealthMetrics")?;
+        Ok(self.emit_health_metrics())
+    }
+
+    /// Emit the health metrics every `setHealthMetrics` interval, sleeping
+    /// in between, so a state that sends nothing still reports. Call once
+    /// after `setHealthMetrics`; resolves when emission is turned off or the
+    /// state shuts down, or right away if the timer is already running. The
+    /// timer doesn't keep the process alive.
+    #[wasm_bindgen(js_name = "runHealthMetrics")]
+    pub async fn run_health_metrics(&self) -> Result<(), JsValue> {
+        self.check_open("runHealthMetrics")?;
+        if self.health_timer.replace(true) {
+            return Ok(());
+        }
+        let _running = InFlightGuard(&self.health_timer);
+        loop {
+            let wait = match self.health.borrow().as_ref() {
+                Some(reporter) => reporter.until_due(now_ns()),
+                None => break,
+            };
+            WasmSleepCapability::new().sleep(wait).await;
+            if self.shut_down.get() {
+                break;
+            }
+            self.emit_health_if_due();
+        }
+        Ok(())
+    }
+
+    fn emit_health_metrics(&self) -> bool {
+        let snapshot = self.metrics_snapshot();
+        match self.health.borrow_mut().as_mut() {
+            Some(reporter) => {
+                reporter.emit(snapshot, now_ns());
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn emit_health_if_due(&self) {
+        let due = self
+            .health
+            .borrow()
+            .as_ref()
+            .is_some_and(|reporter| reporter.is_due(now_ns()));
+        if due {
+            self.emit_health_metrics();
+        }
+    }
+
+    /// Drain everything and shut down: flush the change queue, wait for an
+    /// in-flight `sendPreparedChunk`, send whatever is still prepared, wait for
+    /// an in-flight `flushStats` and force the final stats bucket. The drain is
+    /// raced against `timeoutMs`; anything not delivered by then is dropped.
+    /// The final health metrics are emitted if `setHealthMetrics` is on.
+    ///
+    /// Resolves to a report object (`timedOut`, `sentChunks`, `sentSpans`,
+    /// `droppedChunks`, `droppedSpans`, `statsFlushed`, `errors`). Send
+    /// failures are reported rather than thrown. Afterwards every call that
+    /// processes spans, reads span data or talks to the agent throws a
+    /// "shut down" error, including a second `shutdown`.
+    #[wasm_bindgen]
+    pub async fn shutdown(&self, timeout_ms: u32) -> Result<JsValue, JsValue> {
+        self.check_open("shutdown")?;
+        self.shut_down.set(true);
+
+        let mut report = ShutdownReport::default();
+        if let Err(e) = self.flush_queue() {
+            report.errors.push(e.to_string());
+        }
+        let drained = with_deadline(
+            self.with_tls(async {
+                self.drain_for_shutdown(&mut report).await;
+                Ok(())
+            }),
+            Duration::from_millis(timeout_ms as u64),
+        )
+        .await;
+        report.timed_out = drained.is_none();
+        if let Some(Err(e)) = drained {
+            self.metrics.borrow_mut().record_error(&e);
+            report.errors.push(e.to_string());
+        }
+
+        // Anything still prepared (only possible after a timeout or with
+        // invalid TLS options) is dropped.
+        for chunk in self.prepared.borrow_mut().take() {
+            report.dropped_chunks += 1;
+            report.dropped_spans += chunk.len();
+            self.cbs.borrow_mut().recycle_spans(chunk);
+        }
+        self.emit_health_metrics();
+        // Pooled sockets are unref'd, but closing them lets the process exit
+        // without waiting on the agent to hang up.
+        libdatadog_nodejs_capabilities::http::close_idle_connections();
+
+        let json = serde_json::to_string(&report)
+            .map_err(|e| PipelineError::serialization(format!("shutdown: {e}")))?;
+        js_sys::JSON::parse(&json)
+    }
+
+    async fn drain_for_shutdown(&self, report: &mut ShutdownReport) {
+        while self.sending.get() {
+            WasmSleepCapability::new().sleep(IN_FLIGHT_POLL_INTERVAL).await;
+        }
+        let (chunks, spans) = {
+            let prepared = self.prepared.borrow();
+            (prepared.chunk_count(), prepared.span_count())
+        };
+        if chunks > 0 {
+            // Counted as dropped until the send completes, so a timeout
+            // mid-send reports them.
+            report.dropped_chunks += chunks;
+            report.dropped_spans += spans;
+            match self.send_prepared_chunk_inner().await {
+                Ok(_) => {
+                    report.dropped_chunks -= chunks;
+                    report.dropped_spans -= spans;
+                    report.sent_chunks = chunks;
+                    report.sent_spans = spans;
+                }
+                Err(e) => {
+                    self.metrics.borrow_mut().record_error(&e);
+                    report.errors.push(e.to_string());
+                }
+            }
+        }
+
+        match self.flush_stats_inner(true).await {
+            Ok(flushed) => report.stats_flushed = flushed,
+            Err(e) => {
+                self.metrics.borrow_mut().record_error(&e);
+                report.errors.push(e.to_string());
+            }
+        }
+    }
+
+    /// Run `fut` with this state's TLS options applied to the HTTPS requests
+    /// it issues, failing without running it when the options are invalid.
+    /// The `Datadog-Agent-State` its responses carry is kept for this state's
+    /// agent info.
+    async fn with_tls<T>(
+        &self,
+        fut: impl std::future::Future<Output = Result<T, PipelineError>>,
+    ) -> Result<T, PipelineError> {
+        self.check_tls_options()?;
+        let options = self.tls_options.borrow().clone();
+        let (result, state) = libdatadog_nodejs_capabilities::http::with_agent_state(
+            libdatadog_nodejs_capabilities::http::with_tls_options(options, fut),
+        )
+        .await;
+        if let Some(state) = state {
+            self.agent_info.borrow_mut().observe_state(state);
+        }
+        result
+    }
+
+    /// Validate the TLS options before the first request that uses them. Bad
+    /// material is a configuration error like a failed exporter build, and
+    /// latches the same way.
+    fn check_tls_options(&self) -> Result<(), PipelineError> {
+        if let Some(msg) = self.build_error.borrow().clone() {
+            return Err(PipelineError::new(ErrorKind::ExporterBuild, msg));
+        }
+        if self.tls_checked.replace(true) {
+            return Ok(());
+        }
+        let error =
+            libdatadog_nodejs_capabilities::http::tls_options_error(&self.tls_options.borrow());
+        match error {
+            Some(e) => {
+                let msg = format!("native exporter build failed: invalid TLS options: {e}");
+                *self.build_error.borrow_mut() = Some(msg.clone());
+                Err(PipelineError::new(ErrorKind::ExporterBuild, msg))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Whether anything receives client-side stats: not in log-output mode;
+    /// otherwise always the intake when agentless, or the agent when `/info`
+    /// (once fetched) lists `/v0.6/stats`.
+    fn client_stats_supported(&self) -> bool {
+        !self.log_output.get()
+            && (self.agentless.borrow().is_some() || self.agent_info.borrow().stats_supported())
+    }
+
+    fn check_open(&self, ctx: &str) -> Result<(), PipelineError> {
+        if self.shut_down.get() {
+            return Err(PipelineError::new(
+                ErrorKind::ShutDown,
+                format!("{ctx}: WasmSpanState has been shut down"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Apply the queued change-buffer operations in order. A queue of
+    /// libdatadog ops only is flushed in place. Otherwise each run of
+    /// libdatadog ops is moved to the front of the queue and flushed on its
+    /// own, and the extended ops between runs are applied here. The queue is
+    /// left empty either way, also when an entry fails.
+    fn flush_queue(&self) -> Result<(), PipelineError> {
+        let base = self.change_queue_raw.as_ptr();
+        let len = self.change_queue.len();
+        // SAFETY: `base` points at the `len` bytes of `change_queue`, which is
+        // never reallocated. JS only writes it between calls, and the slice is
+        // dropped before anything below writes through `base`.
+        let queue = unsafe { std::slice::from_raw_parts(base, len) };
+        let mut pending = span_index::PendingOps::default();
+        let parsed = change_queue::parse(queue, &mut |opcode, span_id, args| {
+            pending.observe(opcode, span_id, args)
         });
+        let result = match parsed {
+            Ok(None) => {
+                self.cbs
+                    .borrow_mut()
+                    .flush_change_buffer()
+                    .map_err(|e| PipelineError::invalid_input(e.to_string()))?;
+                pending.commit_all(&mut self.span_index.borrow_mut());
+                return Ok(());
+            }
+            Ok(Some(entries)) => self.apply_queue_entries(entries, pending),
+            Err(e) => Err(e),
+        };
+        // SAFETY: as above; the count is the first 8 bytes of the queue.
+        unsafe { std::ptr::write_bytes(base, 0, change_queue::HEADER_LEN.min(len)) };
+        result
+    }
+
+    /// Apply `entries` in order, committing the index ops of each run of
+    /// libdatadog ops once it is flushed.
+    fn apply_queue_entries(
+        &self,
+        entries: Vec<change_queue::Entry>,
+        mut pending: span_index::PendingOps,
+    ) -> Result<(), PipelineError> {
+        let base = self.change_queue_raw.as_ptr();
+        for entry in entries {
+            match entry {
+                change_queue::Entry::Ops { range, count } => {
+                    // SAFETY: `parse` only returns ranges inside the queue that
+                    // start at or after the header, so both the move (which may
+                    // overlap) and the count write stay in bounds.
+                    unsafe {
+                        std::ptr::copy(
+                            base.add(range.start),
+                            base.add(change_queue::HEADER_LEN),
+                            range.len(),
+                        );
+                        std::ptr::copy_nonoverlapping(count.to_le_bytes().as_ptr(), base, 8);
+                    }
+                    self.cbs
+                        .borrow_mut()
+                        .flush_change_buffer()
+                        .map_err(|e| PipelineError::invalid_input(e.to_string()))?;
+                    pending.commit_run(count, &mut self.span_index.borrow_mut());
+                }
+                change_queue::Entry::Extended { span_id, op } => {
+                    self.apply_extended_op(span_id, op)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_extended_op(
+        &self,
+        span_id: u64,
+        op: change_queue::ExtendedOp,
+    ) -> Result<(), PipelineError> {
+        use change_queue::ExtendedOp;
+        // Decode attributes before borrowing cbs mutably so a malformed buffer
+        // errors without holding the borrow.
+        match op {
+            ExtendedOp::SetTraceMetaAttr { key, value } => {
+                let (key, value) = (self.string(key)?, self.string(value)?);
+                self.trace_tags_of_span(span_id, |tags| tags.set_meta(key, value))?;
+            }
+            ExtendedOp::SetTraceMetricsAttr { key, value } => {
+                let key = self.string(key)?;
+                self.trace_tags_of_span(span_id, |tags| tags.set_metric(key, value))?;
+            }
+            ExtendedOp::SetTraceOrigin { value } => {
+                let value = self.string(value)?;
+                self.trace_tags_of_span(span_id, |tags| tags.set_origin(value))?;
+            }
+            ExtendedOp::SetMetaStruct { key, value } => {
+                let mut cbs = self.cbs.borrow_mut();
+                let span = cbs.span_mut(span_id).map_err(PipelineError::unknown_span)?;
+                span.meta_struct
+                    .insert(key.into(), span_bytes::SpanBytesImpl(value));
+            }
+            ExtendedOp::AddSpanEvent { time_unix_nano, name, attrs } => {
+                let attributes = decode_span_event_attributes(&attrs, SPAN_EVENT_ATTRS)?;
+                let mut cbs = self.cbs.borrow_mut();
+                let span = cbs.span_mut(span_id).map_err(PipelineError::unknown_span)?;
+                span.span_events.push(SpanEvent {
+                    time_unix_nano,
+                    name: name.into(),
+                    attributes,
+                });
+            }
+            ExtendedOp::AddSpanLink {
+                trace_id_high,
+                trace_id_low,
+                span_id: linked_span_id,
+                flags,
+                tracestate,
+                attrs,
+            } => {
+                let attributes = decode_span_link_attributes(&attrs)?;
+                let mut cbs = self.cbs.borrow_mut();
+                let span = cbs.span_mut(span_id).map_err(PipelineError::unknown_span)?;
+                span.span_links.push(SpanLink {
+                    trace_id: trace_id_low,
+                    trace_id_high,
+                    span_id: linked_span_id,
+                    attributes,
+                    tracestate: tracestate.into(),
+                    flags,
+                });
+            }
+        }
+        Ok(())
+    }
 
-        response_str
-            .map(|s| JsValue::from_str(&s))
-            .map_err(|e| JsValue::from_str(&format!("{:?}", e)))
+    /// The string table entry `id`, as a change-queue op refers to it.
+    fn string(&self, id: u32) -> Result<SpanString, PipelineError> {
+        self.string_table.borrow().get(&id).cloned().ok_or_else(|| {
+            PipelineError::invalid_input(format!("change queue: unknown string id {id}"))
+        })
+    }
+
+    /// Update the trace-level attributes of the segment live span `span_id`
+    /// belongs to.
+    fn trace_tags_of_span(
+        &self,
+        span_id: u64,
+        update: impl FnOnce(&mut trace_tags::TraceTags),
+    ) -> Result<(), PipelineError> {
+        let mut index = self.span_index.borrow_mut();
+        let tags = index
+            .trace_tags_of_span(span_id)
+            .ok_or_else(|| PipelineError::unknown_span(format!("span not found: {span_id}")))?;
+        update(tags);
+        Ok(())
     }
 
     /// Flush aggregated stats to the agent's /v0.6/stats endpoint.
     ///
-    /// Should be called periodically (e.g. every 10s) from JS, and with
-    /// `force=true` on shutdown.
+    /// Should be called periodically (e.g. once per bucket duration, 10s by
+    /// default) from JS, and with `force=true` on shutdown. Returns `false`
+    /// without sending when the agent's `/info` doesn't list `/v0.6/stats`, or in
+    /// log-output mode. A call made while another is in flight waits for it,
+    /// then flushes whatever has aggregated since.
+    /// Takes the same optional `timeoutMs` / `signal` as `sendPreparedChunk`;
+    /// a cancelled flush loses the buckets it was sending.
     #[wasm_bindgen(js_name = "flushStats")]
-    pub async fn flush_stats(&self, force: bool) -> Result<bool, JsValue> {
+    pub async fn flush_stats(
+        &self,
+        force: bool,
+        timeout_ms: Option<u32>,
+        signal: JsValue,
+    ) -> Result<bool, JsValue> {
+        self.check_open("flushStats")?;
+        let result = cancellable(
+            "flushStats",
+            timeout_ms,
+            &signal,
+            self.with_tls(self.flush_stats_inner(force)),
+        )
+        .await
+        .map_err(|e| self.record_error(e));
+        self.emit_health_if_due();
+        result
+    }
+
+    /// Count a failed `sendPreparedChunk`/`flushStats` and convert it for JS.
+    fn record_error(&self, err: PipelineError) -> JsValue {
+        self.metrics.borrow_mut().record_error(&err);
+        err.into()
+    }
+
+    async fn flush_stats_inner(&self, force: bool) -> Result<bool, PipelineError> {
+        while self.flushing_stats.get() {
+            WasmSleepCapability::new().sleep(IN_FLIGHT_POLL_INTERVAL).await;
+        }
+        self.flushing_stats.set(true);
+        let _in_flight = InFlightGuard(&self.flushing_stats);
+
         // Build the stats request under a brief *synchronous* borrow, then drop
         // the borrow BEFORE the async send. The collector therefore stays in
         // `stats_collector`, so a concurrent `prepareChunk` during the in-flight
@@ -534,20 +2106,29 @@ impl WasmSpanState {
         // the collector out for the whole await would silently drop them from
         // client-side stats.) No borrow is held across the await, so there is
         // no double-borrow hazard from overlapping calls.
+        self.refresh_agent_info_if_stale().await;
+        if !self.client_stats_supported() {
+            return Ok(false);
+        }
         let req = {
             let mut guard = self.stats_collector.borrow_mut();
             match guard.as_mut() {
-                Some(collector) => collector
-                    .prepare_request(force)
-                    .map_err(|e| JsValue::from_str(&e))?,
+                Some(collector) => collector.prepare_request(force)?,
                 None => return Ok(false),
             }
         };
         match req {
-            Some(req) => {
-                stats::StatsCollector::send_request(req)
+            Some((req, buckets)) => {
+                let agentless = self.agentless.borrow().clone();
+                match agentless {
+                    Some(intake) => libdatadog_nodejs_capabilities::http::with_env_proxy(
+                        stats::StatsCollector::send_request(req),
+                    )
                     .await
-                    .map_err(|e| JsValue::from_str(&e))?;
+                    .map_err(|e| intake.redact(e))?,
+                    None => stats::StatsCollector::send_request(req).await?,
+                }
+                self.metrics.borrow_mut().record_stats_flushed(buckets);
                 Ok(true)
             }
             None => Ok(false),
@@ -559,9 +2140,8 @@ impl WasmSpanState {
     /// flush methods); failures surface as a thrown error.
     #[wasm_bindgen(js_name = "flushChangeQueue")]
     pub fn flush_change_queue(&self) -> Result<bool, JsValue> {
-        self.cbs.borrow_mut()
-            .flush_change_buffer()
-            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        self.check_open("flushChangeQueue")?;
+        self.flush_queue()?;
         Ok(true)
     }
 
@@ -569,15 +2149,16 @@ impl WasmSpanState {
     /// Takes a flat array of key-value pairs: [key1, val1, key2, val2, ...]
     #[wasm_bindgen(js_name = "setDefaultMeta")]
     pub fn set_default_meta(&self, pairs: Vec<JsValue>) -> Result<(), JsValue> {
+        self.check_open("setDefaultMeta")?;
         let mut tags = Vec::with_capacity(pairs.len() / 2);
         let mut i = 0;
         while i + 1 < pairs.len() {
             let key = pairs[i]
                 .as_string()
-                .ok_or_else(|| JsValue::from_str("default meta key must be a string"))?;
+                .ok_or_else(|| PipelineError::invalid_input("default meta key must be a string"))?;
             let val = pairs[i + 1]
                 .as_string()
-                .ok_or_else(|| JsValue::from_str("default meta value must be a string"))?;
+                .ok_or_else(|| PipelineError::invalid_input("default meta value must be a string"))?;
             tags.push((key.into(), val.into()));
             i += 2;
         }
@@ -586,47 +2167,60 @@ impl WasmSpanState {
     }
 
     #[wasm_bindgen(js_name = "stringTableInsertOne")]
-    pub fn string_table_insert_one(&self, key: u32, val: &str) {
+    pub fn string_table_insert_one(&self, key: u32, val: &str) -> Result<(), JsValue> {
+        self.check_open("stringTableInsertOne")?;
+        let val = SpanString::from(val);
         self.cbs.borrow_mut()
-            .string_table_insert_one(key, val.into());
+            .string_table_insert_one(key, val.clone());
+        self.string_table.borrow_mut().insert(key, val);
+        Ok(())
     }
 
     #[wasm_bindgen(js_name = "stringTableInsertMany")]
     pub fn string_table_insert_many(&self, count: u32) -> Result<(), JsValue> {
+        self.check_open("stringTableInsertMany")?;
         let mut index: usize = 0;
         let mut remaining = count as usize;
         // Hold one mutable borrow for the whole bulk insert rather than
         // re-borrowing the RefCell once per string.
         let mut cbs = self.cbs.borrow_mut();
+        let mut table = self.string_table.borrow_mut();
         let buf = &self.string_table_input;
         while remaining > 0 {
             // Bound the read against the untrusted `count`: a count larger than
             // the encoded entries must error, not index out of bounds. get_num
             // does the (overflow-safe) bounds check and returns None past the end.
             let key: u32 = get_num(buf, &mut index).ok_or_else(|| {
-                JsValue::from_str("stringTableInsertMany: count exceeds the entries in the input buffer")
+                PipelineError::invalid_input(
+                    "stringTableInsertMany: count exceeds the entries in the input buffer",
+                )
             })?;
             let str_slice = &buf[index..];
             // Bound the NUL scan to the input slice so a non-terminated string
             // can't read past the buffer, and advance past the NUL terminator
             // (+ 1) so the next entry parses from the right offset.
             let cstr = CStr::from_bytes_until_nul(str_slice)
-                .map_err(|e| JsValue::from_str(&format!("{}", e)))?;
+                .map_err(|e| PipelineError::invalid_input(format!("stringTableInsertMany: {e}")))?;
             let val = cstr
                 .to_str()
-                .map_err(|e| JsValue::from_str(&format!("{}", e)))?;
+                .map_err(|e| PipelineError::invalid_input(format!("stringTableInsertMany: {e}")))?;
             index += val.len() + 1;
             // From<&str> for SpanString is a single Arc<str> allocation — no
-            // intermediate owned String.
-            cbs.string_table_insert_one(key, val.into());
+            // intermediate owned String — which the mirror shares.
+            let val = SpanString::from(val);
+            cbs.string_table_insert_one(key, val.clone());
+            table.insert(key, val);
             remaining -= 1;
         }
         Ok(())
     }
 
     #[wasm_bindgen(js_name = "stringTableEvict")]
-    pub fn string_table_evict(&self, key: u32) {
+    pub fn string_table_evict(&self, key: u32) -> Result<(), JsValue> {
+        self.check_open("stringTableEvict")?;
         self.cbs.borrow_mut().string_table_evict_one(key);
+        self.string_table.borrow_mut().remove(&key);
+        Ok(())
     }
 
     // Absent-entity convention: span-level getters return an error (JS throw)
@@ -634,25 +2228,28 @@ impl WasmSpanState {
     // return null for an unknown segment / unset attribute.
     #[wasm_bindgen(js_name = "getServiceName")]
     pub fn get_service_name(&self, span_id: u64) -> Result<String, JsValue> {
+        self.check_open("getServiceName")?;
         self.flush_change_queue()?;
         let cbs = self.cbs.borrow();
-        let span = cbs.get_span(span_id).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let span = cbs.get_span(span_id).map_err(PipelineError::unknown_span)?;
         Ok(span.service.to_string())
     }
 
     #[wasm_bindgen(js_name = "getResourceName")]
     pub fn get_resource_name(&self, span_id: u64) -> Result<String, JsValue> {
+        self.check_open("getResourceName")?;
         self.flush_change_queue()?;
         let cbs = self.cbs.borrow();
-        let span = cbs.get_span(span_id).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let span = cbs.get_span(span_id).map_err(PipelineError::unknown_span)?;
         Ok(span.resource.to_string())
     }
 
     #[wasm_bindgen(js_name = "getMetaAttr")]
     pub fn get_meta_attr(&self, span_id: u64, name: &str) -> Result<JsValue, JsValue> {
+        self.check_open("getMetaAttr")?;
         self.flush_change_queue()?;
         let cbs = self.cbs.borrow();
-        let span = cbs.get_span(span_id).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let span = cbs.get_span(span_id).map_err(PipelineError::unknown_span)?;
         // VecMap::get accepts &str directly (SpanString: Borrow<str>), so no
         // SpanString allocation is needed for the lookup.
         Ok(span.meta.get(name)
@@ -662,9 +2259,10 @@ impl WasmSpanState {
 
     #[wasm_bindgen(js_name = "getMetricAttr")]
     pub fn get_metric_attr(&self, span_id: u64, name: &str) -> Result<JsValue, JsValue> {
+        self.check_open("getMetricAttr")?;
         self.flush_change_queue()?;
         let cbs = self.cbs.borrow();
-        let span = cbs.get_span(span_id).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let span = cbs.get_span(span_id).map_err(PipelineError::unknown_span)?;
         Ok(span.metrics.get(name)
             .map(|v| JsValue::from_f64(*v))
             .unwrap_or(JsValue::NULL))
@@ -672,9 +2270,10 @@ impl WasmSpanState {
 
     #[wasm_bindgen(js_name = "getError")]
     pub fn get_error(&self, span_id: u64) -> Result<i32, JsValue> {
+        self.check_open("getError")?;
         self.flush_change_queue()?;
         let cbs = self.cbs.borrow();
-        let span = cbs.get_span(span_id).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let span = cbs.get_span(span_id).map_err(PipelineError::unknown_span)?;
         Ok(span.error)
     }
 
@@ -683,42 +2282,84 @@ impl WasmSpanState {
     // would be silently truncated as f64.
     #[wasm_bindgen(js_name = "getStart")]
     pub fn get_start(&self, span_id: u64) -> Result<i64, JsValue> {
+        self.check_open("getStart")?;
         self.flush_change_queue()?;
         let cbs = self.cbs.borrow();
-        let span = cbs.get_span(span_id).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let span = cbs.get_span(span_id).map_err(PipelineError::unknown_span)?;
         Ok(span.start)
     }
 
     #[wasm_bindgen(js_name = "getDuration")]
     pub fn get_duration(&self, span_id: u64) -> Result<i64, JsValue> {
+        self.check_open("getDuration")?;
         self.flush_change_queue()?;
         let cbs = self.cbs.borrow();
-        let span = cbs.get_span(span_id).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let span = cbs.get_span(span_id).map_err(PipelineError::unknown_span)?;
         Ok(span.duration)
     }
 
     #[wasm_bindgen(js_name = "getType")]
     pub fn get_type(&self, span_id: u64) -> Result<String, JsValue> {
+        self.check_open("getType")?;
         self.flush_change_queue()?;
         let cbs = self.cbs.borrow();
-        let span = cbs.get_span(span_id).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let span = cbs.get_span(span_id).map_err(PipelineError::unknown_span)?;
         Ok(span.r#type.to_string())
     }
 
     #[wasm_bindgen(js_name = "getName")]
     pub fn get_name(&self, span_id: u64) -> Result<String, JsValue> {
+        self.check_open("getName")?;
         self.flush_change_queue()?;
         let cbs = self.cbs.borrow();
-        let span = cbs.get_span(span_id).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let span = cbs.get_span(span_id).map_err(PipelineError::unknown_span)?;
         Ok(span.name.to_string())
     }
 
+    /// Every field of a span in one call, draining the queue once: ids, names,
+    /// timing, error, `meta`, `metrics`, `metaStructKeys`, `spanEvents`,
+    /// `spanLinks`, and `trace` with the segment's trace-level `meta`,
+    /// `metrics` and `origin` (`null` for an unknown segment). Returns an
+    /// object whose 64-bit fields (`spanId`, `parentId`, `traceIdHigh`,
+    /// `traceIdLow`, `segmentId`, `start`, `duration`) are BigInts, or with
+    /// `encoded` the same fields as a msgpack map in a `Uint8Array`.
+    #[wasm_bindgen(js_name = "getSpanSnapshot")]
+    pub fn get_span_snapshot(&self, span_id: u64, encoded: Option<bool>) -> Result<JsValue, JsValue> {
+        self.check_open("getSpanSnapshot")?;
+        self.flush_change_queue()?;
+        let cbs = self.cbs.borrow();
+        let span = cbs.get_span(span_id).map_err(PipelineError::unknown_span)?;
+        let index = self.span_index.borrow();
+        let segment_id = index.segment_of(span_id);
+        let tags = segment_id.and_then(|id| index.trace_tags(id));
+        let snapshot = snapshot::SpanSnapshot::new(span, segment_id, tags);
+        if encoded.unwrap_or(false) {
+            let bytes = snapshot.to_msgpack()?;
+            return Ok(js_sys::Uint8Array::from(bytes.as_slice()).into());
+        }
+        let obj = js_sys::JSON::parse(&snapshot.to_json()?)?;
+        // JSON numbers round 64-bit values; put the exact ones back as BigInts.
+        let exact: [(&str, Option<JsValue>); 7] = [
+            ("spanId", Some(snapshot.span_id.into())),
+            ("parentId", Some(snapshot.parent_id.into())),
+            ("traceIdHigh", Some(snapshot.trace_id_high.into())),
+            ("traceIdLow", Some(snapshot.trace_id_low.into())),
+            ("segmentId", snapshot.segment_id.map(JsValue::from)),
+            ("start", Some(snapshot.start.into())),
+            ("duration", Some(snapshot.duration.into())),
+        ];
+        for (key, value) in exact {
+            if let Some(value) = value {
+                js_sys::Reflect::set(&obj, &JsValue::from_str(key), &value)?;
+            }
+        }
+        Ok(obj)
+    }
+
     // `meta_struct` carries msgpack-encoded structured data (e.g. AppSec, Code
-    // Origin, Dynamic Instrumentation). There is no change-buffer opcode for it,
-    // so the value is written directly onto the span after draining the queue —
-    // meta_struct does not depend on any other queued op, so bypassing the queue
-    // ordering is safe (subsequent ops are applied on the next flush and never
-    // touch meta_struct).
+    // Origin, Dynamic Instrumentation). The `SetMetaStruct` opcode carries it
+    // in the change queue; this export is the direct form, applied after
+    // draining the queue.
     #[wasm_bindgen(js_name = "setMetaStruct")]
     pub fn set_meta_struct(
         &self,
@@ -726,21 +2367,24 @@ impl WasmSpanState {
         key: &str,
         value: &[u8],
     ) -> Result<(), JsValue> {
+        self.check_open("setMetaStruct")?;
         self.flush_change_queue()?;
-        let mut cbs = self.cbs.borrow_mut();
-        let span = cbs
-            .span_mut(span_id)
-            .map_err(|e| JsValue::from_str(&e.to_string()))?;
-        span.meta_struct
-            .insert(key.into(), span_bytes::SpanBytesImpl(value.to_vec()));
+        self.apply_extended_op(
+            span_id,
+            change_queue::ExtendedOp::SetMetaStruct {
+                key: key.to_owned(),
+                value: value.to_vec(),
+            },
+        )?;
         Ok(())
     }
 
     #[wasm_bindgen(js_name = "getMetaStruct")]
     pub fn get_meta_struct(&self, span_id: u64, key: &str) -> Result<JsValue, JsValue> {
+        self.check_open("getMetaStruct")?;
         self.flush_change_queue()?;
         let cbs = self.cbs.borrow();
-        let span = cbs.get_span(span_id).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let span = cbs.get_span(span_id).map_err(PipelineError::unknown_span)?;
         // VecMap::get accepts &str directly (SpanString: Borrow<str>).
         Ok(span.meta_struct.get(key)
             .map(|v| JsValue::from(js_sys::Uint8Array::from(v.0.as_slice())))
@@ -748,10 +2392,9 @@ impl WasmSpanState {
     }
 
     // Span events (OpenTelemetry-style) are serialized by libdatadog as the
-    // top-level v0.4 `span_events` field when present. Like meta_struct there
-    // is no change-buffer opcode, so the event is appended directly to the span
-    // after draining the queue (span_events do not depend on any other queued
-    // op, so bypassing queue ordering is safe). `attrs_buf` is the flat typed
+    // top-level v0.4 `span_events` field when present. The `AddSpanEvent`
+    // opcode carries them in the change queue; this export is the direct form,
+    // applied after draining the queue. `attrs_buf` is the flat typed
     // attribute encoding decoded by `decode_span_event_attributes`.
     #[wasm_bindgen(js_name = "addSpanEvent")]
     pub fn add_span_event(
@@ -761,19 +2404,16 @@ impl WasmSpanState {
         time_unix_nano: u64,
         attrs_buf: &[u8],
     ) -> Result<(), JsValue> {
+        self.check_open("addSpanEvent")?;
         self.flush_change_queue()?;
-        // Decode before borrowing cbs mutably so a malformed buffer errors
-        // without holding the borrow.
-        let attributes = decode_span_event_attributes(attrs_buf)?;
-        let mut cbs = self.cbs.borrow_mut();
-        let span = cbs
-            .span_mut(span_id)
-            .map_err(|e| JsValue::from_str(&e.to_string()))?;
-        span.span_events.push(SpanEvent {
-            time_unix_nano,
-            name: name.into(),
-            attributes,
-        });
+        self.apply_extended_op(
+            span_id,
+            change_queue::ExtendedOp::AddSpanEvent {
+                time_unix_nano,
+                name: name.to_owned(),
+                attrs: attrs_buf.to_vec(),
+            },
+        )?;
         Ok(())
     }
 
@@ -783,44 +2423,95 @@ impl WasmSpanState {
     // (String=0, Boolean=1, Integer=2, Double=3, Array=4).
     #[wasm_bindgen(js_name = "getSpanEventsJson")]
     pub fn get_span_events_json(&self, span_id: u64) -> Result<String, JsValue> {
+        self.check_open("getSpanEventsJson")?;
         self.flush_change_queue()?;
         let cbs = self.cbs.borrow();
         let span = cbs
             .get_span(span_id)
-            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+            .map_err(PipelineError::unknown_span)?;
         serde_json::to_string(&span.span_events)
-            .map_err(|e| JsValue::from_str(&format!("getSpanEventsJson: {e}")))
+            .map_err(|e| PipelineError::serialization(format!("getSpanEventsJson: {e}")).into())
     }
 
-    // Trace-level attributes live on the Segment (keyed by segment_id, which
-    // JS allocates and shares across spans in the same local trace).
+    // Span links are serialized by libdatadog as the top-level v0.4
+    // `span_links` field and mapped to OTLP links; v0.5 has no slot for them
+    // and drops them (see `setUseV05`). The `AddSpanLink` opcode carries them
+    // in the change queue; this export is the direct form, applied after
+    // draining the queue. The linked trace id arrives as two u64 halves, and
+    // `flags` is the wire value (dd-trace-js sets the high "flags set" bit
+    // itself). `attrs_buf` uses the span-event attribute encoding; see
+    // `decode_span_link_attributes` for how values become strings.
+    #[wasm_bindgen(js_name = "addSpanLink")]
+    pub fn add_span_link(
+        &self,
+        span_id: u64,
+        trace_id_high: u64,
+        trace_id_low: u64,
+        linked_span_id: u64,
+        tracestate: &str,
+        flags: u32,
+        attrs_buf: &[u8],
+    ) -> Result<(), JsValue> {
+        self.check_open("addSpanLink")?;
+        self.flush_change_queue()?;
+        self.apply_extended_op(
+            span_id,
+            change_queue::ExtendedOp::AddSpanLink {
+                trace_id_high,
+                trace_id_low,
+                span_id: linked_span_id,
+                flags,
+                tracestate: tracestate.to_owned(),
+                attrs: attrs_buf.to_vec(),
+            },
+        )?;
+        Ok(())
+    }
+
+    // Test/inspection helper mirroring `getSpanEventsJson`: the JSON shape is
+    // the serde `Serialize` impl libdatadog uses for the v0.4 msgpack payload.
+    #[wasm_bindgen(js_name = "getSpanLinksJson")]
+    pub fn get_span_links_json(&self, span_id: u64) -> Result<String, JsValue> {
+        self.check_open("getSpanLinksJson")?;
+        self.flush_change_queue()?;
+        let cbs = self.cbs.borrow();
+        let span = cbs
+            .get_span(span_id)
+            .map_err(PipelineError::unknown_span)?;
+        serde_json::to_string(&span.span_links)
+            .map_err(|e| PipelineError::serialization(format!("getSpanLinksJson: {e}")).into())
+    }
+
+    // Trace-level attributes live on the segment (keyed by segment_id, which
+    // JS allocates and shares across spans in the same local trace) until its
+    // last span has been exported, discarded or reaped.
     #[wasm_bindgen(js_name = "getTraceMetaAttr")]
     pub fn get_trace_meta_attr(&self, segment_id: u64, name: &str) -> Result<JsValue, JsValue> {
+        self.check_open("getTraceMetaAttr")?;
         self.flush_change_queue()?;
-        let cbs = self.cbs.borrow();
-        Ok(cbs.get_segment(&segment_id)
-            .and_then(|s| s.meta.get(name))
-            .map(|v| JsValue::from_str(v.0.as_ref()))
+        Ok(self.span_index.borrow().trace_tags(segment_id)
+            .and_then(|tags| tags.meta_attr(name))
+            .map(JsValue::from_str)
             .unwrap_or(JsValue::NULL))
     }
 
     #[wasm_bindgen(js_name = "getTraceMetricAttr")]
     pub fn get_trace_metric_attr(&self, segment_id: u64, name: &str) -> Result<JsValue, JsValue> {
+        self.check_open("getTraceMetricAttr")?;
         self.flush_change_queue()?;
-        let cbs = self.cbs.borrow();
-        Ok(cbs.get_segment(&segment_id)
-            .and_then(|s| s.metrics.get(name))
-            .map(|v| JsValue::from_f64(*v))
+        Ok(self.span_index.borrow().trace_tags(segment_id)
+            .and_then(|tags| tags.metric_attr(name))
+            .map(JsValue::from_f64)
             .unwrap_or(JsValue::NULL))
     }
 
     #[wasm_bindgen(js_name = "getTraceOrigin")]
     pub fn get_trace_origin(&self, segment_id: u64) -> Result<JsValue, JsValue> {
+        self.check_open("getTraceOrigin")?;
         self.flush_change_queue()?;
-        let cbs = self.cbs.borrow();
-        Ok(cbs.get_segment(&segment_id)
-            .and_then(|s| s.origin.as_ref())
-            .map(|v| JsValue::from_str(v.0.as_ref()))
+        Ok(self.span_index.borrow().trace_tags(segment_id)
+            .and_then(|tags| tags.origin())
+            .map(JsValue::from_str)
             .unwrap_or(JsValue::NULL))
     }
 }
@@ -832,7 +2523,9 @@ pub fn get_wasm_memory() -> JsValue {
 }
 
 /// Export OpCode values as a JS object.
-/// Values match the `#[repr(u64)]` OpCode enum in libdd-trace-utils.
+/// Values up to `SetTraceOrigin` match the `#[repr(u64)]` OpCode enum in
+/// libdd-trace-utils. The trace-level ops and the rest are applied by the
+/// pipeline (see `change_queue`).
 #[wasm_bindgen(js_name = "getOpCodes")]
 pub fn get_op_codes() -> JsValue {
     let obj = js_sys::Object::new();
@@ -847,9 +2540,12 @@ pub fn get_op_codes() -> JsValue {
         ("SetDuration", 7),
         ("SetType", 8),
         ("SetName", 9),
-        ("SetTraceMetaAttr", 10),
-        ("SetTraceMetricsAttr", 11),
-        ("SetTraceOrigin", 12),
+        ("SetTraceMetaAttr", change_queue::SET_TRACE_META_ATTR as u32),
+        ("SetTraceMetricsAttr", change_queue::SET_TRACE_METRICS_ATTR as u32),
+        ("SetTraceOrigin", change_queue::SET_TRACE_ORIGIN as u32),
+        ("SetMetaStruct", change_queue::SET_META_STRUCT as u32),
+        ("AddSpanEvent", change_queue::ADD_SPAN_EVENT as u32),
+        ("AddSpanLink", change_queue::ADD_SPAN_LINK as u32),
     ];
     for (name, val) in entries {
         js_sys::Reflect::set(&obj, &JsValue::from_str(name), &JsValue::from_f64(*val as f64))
@@ -858,13 +2554,44 @@ pub fn get_op_codes() -> JsValue {
     obj.into()
 }
 
-/// Build a JS `Error` tagged `NativeExporterBuildError` so the host can
-/// recognise a fatal exporter-build failure (bad config) and stop retrying,
-/// rather than treating it as a transient send error.
-fn build_failure_error(msg: &str) -> JsValue {
-    let err = js_sys::Error::new(msg);
-    err.set_name("NativeExporterBuildError");
-    err.into()
+/// Run `fut` under an optional timeout and caller `AbortSignal`. Requests it
+/// issues carry the combined signal (so JS destroys them on abort), and the
+/// call rejects as soon as the signal fires rather than waiting for
+/// libdatadog's retries to give up.
+async fn cancellable<T>(
+    ctx: &str,
+    timeout_ms: Option<u32>,
+    signal: &JsValue,
+    fut: impl std::future::Future<Output = Result<T, PipelineError>>,
+) -> Result<T, PipelineError> {
+    let signal = libdatadog_nodejs_capabilities::http::request_signal(signal, timeout_ms);
+    if signal.is_undefined() || signal.is_null() {
+        return fut.await;
+    }
+    let result = race(
+        libdatadog_nodejs_capabilities::http::with_request_signal(signal.clone(), fut),
+        async {
+            let reason = libdatadog_nodejs_capabilities::http::abort_reason(&signal).await;
+            Err(cancelled_error(ctx, &reason))
+        },
+    )
+    .await;
+    libdatadog_nodejs_capabilities::http::release_request_signal(&signal);
+    result
+}
+
+/// A `Timeout` error (the deadline passed) or `Aborted` error (the caller's
+/// signal fired) for a cancelled call.
+fn cancelled_error(ctx: &str, reason: &JsValue) -> PipelineError {
+    let timed_out = js_sys::Reflect::get(reason, &JsValue::from_str("name"))
+        .ok()
+        .and_then(|name| name.as_string())
+        .is_some_and(|name| name == "TimeoutError");
+    if timed_out {
+        PipelineError::new(ErrorKind::Timeout, format!("{ctx}: timed out"))
+    } else {
+        PipelineError::new(ErrorKind::Aborted, format!("{ctx}: aborted"))
+    }
 }
 
 #[wasm_bindgen(js_name = "setStorage")]
@@ -872,6 +2599,41 @@ pub fn set_storage(new_storage: &JsValue) {
     libdatadog_nodejs_capabilities::http::set_storage(new_storage);
 }
 
+/// Replace the sink that receives log-output payloads (see `setLogOutput`).
+/// Called with each payload as a string; `null` restores `process.stdout`.
+#[wasm_bindgen(js_name = "setLogOutputSink")]
+pub fn set_log_output_sink(sink: &JsValue) {
+    libdatadog_nodejs_capabilities::log::set_log_output_sink(sink);
+}
+
+/// Configure the forward proxy for agent, OTLP and intake requests:
+/// `{ httpProxy, httpsProxy, noProxy }`, in the format of the standard
+/// environment variables. `null` (the default) reads `HTTP_PROXY`,
+/// `HTTPS_PROXY` and `NO_PROXY` from the environment for OTLP and intake
+/// requests only, so the agent is always reached directly; `{}` disables
+/// proxying. Loopback hosts and Unix socket agent URLs never go through a
+/// proxy.
+#[wasm_bindgen(js_name = "setProxy")]
+pub fn set_proxy(config: &JsValue) {
+    libdatadog_nodejs_capabilities::http::set_proxy(config);
+}
+
+/// Configure connection reuse: `{ enabled, maxSockets, idleTimeoutMs }`.
+/// Requests keep one pool of kept-alive sockets per origin (on by default, 8
+/// sockets, 4s idle timeout); `null` restores the defaults. Reuse shows up as
+/// `connectionsOpened` / `connectionsReused` in `getMetrics`.
+#[wasm_bindgen(js_name = "setKeepAlive")]
+pub fn set_keep_alive(config: &JsValue) {
+    libdatadog_nodejs_capabilities::http::set_keep_alive(config);
+}
+
+/// Close every idle pooled connection. `shutdown` does this itself; call it
+/// directly when dropping a state without shutting it down.
+#[wasm_bindgen(js_name = "closeIdleConnections")]
+pub fn close_idle_connections() {
+    libdatadog_nodejs_capabilities::http::close_idle_connections();
+}
+
 #[wasm_bindgen(js_name = "setResponseHeaderObserver")]
 pub fn set_response_header_observer(observer: &JsValue) {
     libdatadog_nodejs_capabilities::http::set_response_header_observer(observer);
@@ -0,0 +1,200 @@
+// Copyright 2026-Present Datadog, Inc. https://www.datadoghq.com/
+// SPDX-License-Identifier: Apache-2.0
+
+//! Exporter counters behind `getMetrics`.
+//!
+//! [`Counters`] accumulates for the lifetime of a `WasmSpanState` and is never
+//! reset, so the host derives rates by diffing successive snapshots.
+//! [`Snapshot`] adds the gauges read at snapshot time (live spans, segments
+//! and strings in the change buffer state, and the unsent batch) and the
+//! transport's process-wide connection reuse counts.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::errors::{ErrorKind, PipelineError};
+
+#[derive(Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Counters {
+    /// Chunks (and their spans) handed to `prepareChunk`, before sampling or
+    /// P0 dropping.
+    pub chunks_prepared: u64,
+    pub spans_prepared: u64,
+    /// Chunks of still-running traces prepared by `preparePartialChunk`
+    /// (also counted in `chunksPrepared`).
+    pub partial_chunks_prepared: u64,
+    /// Largest chunk seen by `prepareChunk`; the mean is
+    /// `spansPrepared / chunksPrepared`.
+    pub max_spans_per_chunk: u64,
+    /// Prepared chunks that were replaced before being sent (no batching, and
+    /// JS skipped a send).
+    pub stale_chunks_recycled: u64,
+    pub stale_spans_recycled: u64,
+    /// Chunks and segments the host dropped with `discardChunk` /
+    /// `discardSegment`, and the spans they held.
+    pub chunks_discarded: u64,
+    pub spans_discarded: u64,
+    /// Local traces evicted by `reapOrphans` for holding a span that never
+    /// finished, and their spans (exported or dropped per `setMaxSpanAge`).
+    pub orphan_chunks_reaped: u64,
+    pub orphan_spans_reaped: u64,
+    /// The reaped traces and spans that were dropped rather than exported.
+    pub orphan_chunks_dropped: u64,
+    pub orphan_spans_dropped: u64,
+    /// Rejected chunks dropped after computing stats (`setDropP0Traces`).
+    pub p0_chunks_dropped: u64,
+    pub p0_spans_dropped: u64,
+    /// Trace payloads the agent accepted, with the chunks and spans they
+    /// carried and their estimated encoded size.
+    pub payloads_sent: u64,
+    pub chunks_sent: u64,
+    pub spans_sent: u64,
+    pub bytes_sent: u64,
+    /// Trace payloads that failed, with the chunks and spans lost.
+    pub payloads_failed: u64,
+    pub chunks_failed: u64,
+    pub spans_failed: u64,
+    /// Failed trace and stats sends by class: `network`, `timeout`,
+    /// `aborted`, `4xx`, `5xx` or `other`.
+    pub send_errors: BTreeMap<&'static str, u64>,
+    /// `sendPreparedChunk` / `flushStats` calls rejected because the previous
+    /// one was still in flight.
+    pub reentrant_calls: u64,
+    /// Stats payloads sent and the buckets they carried.
+    pub stats_payloads_sent: u64,
+    pub stats_buckets_flushed: u64,
+}
+
+impl Counters {
+    pub fn record_prepared(&mut self, spans: usize) {
+        self.chunks_prepared += 1;
+        self.spans_prepared += spans as u64;
+        self.max_spans_per_chunk = self.max_spans_per_chunk.max(spans as u64);
+    }
+
+    pub fn record_stale(&mut self, spans: usize) {
+        self.stale_chunks_recycled += 1;
+        self.stale_spans_recycled += spans as u64;
+    }
+
+    pub fn record_discarded(&mut self, spans: usize) {
+        self.chunks_discarded += 1;
+        self.spans_discarded += spans as u64;
+    }
+
+    pub fn record_orphans(&mut self, spans: usize, exported: bool) {
+        self.orphan_chunks_reaped += 1;
+        self.orphan_spans_reaped += spans as u64;
+        if !exported {
+            self.orphan_chunks_dropped += 1;
+            self.orphan_spans_dropped += spans as u64;
+        }
+    }
+
+    pub fn record_p0_dropped(&mut self, spans: usize) {
+        self.p0_chunks_dropped += 1;
+        self.p0_spans_dropped += spans as u64;
+    }
+
+    /// Record the outcome of one trace payload of `chunks`/`spans`/`bytes`.
+    pub fn record_payload<T>(
+        &mut self,
+        result: &Result<T, PipelineError>,
+        chunks: usize,
+        spans: usize,
+        bytes: usize,
+    ) {
+        if result.is_ok() {
+            self.payloads_sent += 1;
+            self.chunks_sent += chunks as u64;
+            self.spans_sent += spans as u64;
+            self.bytes_sent += bytes as u64;
+        } else {
+            self.payloads_failed += 1;
+            self.chunks_failed += chunks as u64;
+            self.spans_failed += spans as u64;
+        }
+    }
+
+    pub fn record_stats_flushed(&mut self, buckets: usize) {
+        self.stats_payloads_sent += 1;
+        self.stats_buckets_flushed += buckets as u64;
+    }
+
+    /// Count a failed send by class. Errors that aren't about talking to the
+    /// agent (bad input, full batch, shut down) are not counted.
+    pub fn record_error(&mut self, err: &PipelineError) {
+        let class = match err.kind() {
+            ErrorKind::ReentrantCall => {
+                self.reentrant_calls += 1;
+                return;
+            }
+            ErrorKind::InvalidInput
+            | ErrorKind::UnknownSpan
+            | ErrorKind::BatchFull
+            | ErrorKind::ShutDown => return,
+            ErrorKind::Network => "network",
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::Aborted => "aborted",
+            ErrorKind::HttpStatus => match err.status_code() {
+                Some(400..=499) => "4xx",
+                Some(500..=599) => "5xx",
+                _ => "other",
+            },
+            ErrorKind::Serialization | ErrorKind::ExporterBuild | ErrorKind::Internal => "other",
+        };
+        *self.send_errors.entry(class).or_default() += 1;
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+    #[serde(flatten)]
+    pub counters: Counters,
+    pub live_spans: usize,
+    pub live_segments: usize,
+    pub string_table_entries: usize,
+    pub pending_chunks: usize,
+    pub pending_spans: usize,
+    pub pending_bytes: usize,
+    /// Requests that opened a connection vs. reused a pooled one, across
+    /// every state in the process (the connection pool is shared).
+    pub connections_opened: u64,
+    pub connections_reused: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_send_errors() {
+        let mut counters = Counters::default();
+        counters.record_error(&PipelineError::http_status("x", 503, None));
+        counters.record_error(&PipelineError::http_status("x", 429, None));
+        counters.record_error(&PipelineError::new(ErrorKind::Network, "x"));
+        counters.record_error(&PipelineError::new(ErrorKind::ReentrantCall, "x"));
+        counters.record_error(&PipelineError::invalid_input("x"));
+        assert_eq!(counters.send_errors.get("5xx"), Some(&1));
+        assert_eq!(counters.send_errors.get("4xx"), Some(&1));
+        assert_eq!(counters.send_errors.get("network"), Some(&1));
+        assert_eq!(counters.send_errors.len(), 3);
+        assert_eq!(counters.reentrant_calls, 1);
+    }
+
+    #[test]
+    fn records_payload_outcomes() {
+        let mut counters = Counters::default();
+        counters.record_prepared(3);
+        counters.record_prepared(5);
+        counters.record_payload(&Ok::<_, PipelineError>(()), 2, 8, 1024);
+        counters.record_payload(&Err::<(), _>(PipelineError::invalid_input("x")), 1, 2, 10);
+        assert_eq!(counters.max_spans_per_chunk, 5);
+        assert_eq!((counters.chunks_sent, counters.spans_sent), (2, 8));
+        assert_eq!(counters.bytes_sent, 1024);
+        assert_eq!((counters.chunks_failed, counters.spans_failed), (1, 2));
+    }
+}
@@ -0,0 +1,78 @@
+// Copyright 2026-Present Datadog, Inc. https://www.datadoghq.com/
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reaping spans that were created but never finished.
+//!
+//! A span whose `SetDuration` never arrives (an instrumentation bug, an
+//! abandoned async context) would otherwise stay in the change buffer state
+//! forever, holding its whole local trace with it. With a max span age set,
+//! `reapOrphans` evicts every segment that has an unfinished span older than
+//! the age, either dropping it or exporting it as a partial chunk whose
+//! unfinished spans are marked by [`mark_unfinished`].
+
+use libdd_trace_utils::span::v04::Span;
+
+use crate::trace_data::WasmTraceData;
+
+/// Meta tag set on unfinished spans exported by the reaper.
+pub const ORPHAN_TAG: &str = "_dd.orphaned";
+const ORPHAN_MESSAGE: &str = "span was never finished";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OrphanPolicy {
+    pub max_age_ns: u64,
+    /// Export reaped segments as partial chunks instead of dropping them.
+    pub export: bool,
+}
+
+impl OrphanPolicy {
+    /// Whether a span that started at `start_ns` and hasn't finished is past
+    /// the max age at `now_ns`.
+    pub fn is_expired(&self, start_ns: i64, now_ns: u64) -> bool {
+        (now_ns as i128 - start_ns as i128) > self.max_age_ns as i128
+    }
+}
+
+/// Close an unfinished span at `now_ns` and flag it as an error, keeping any
+/// error details the host already set.
+pub fn mark_unfinished(span: &mut Span<WasmTraceData>, now_ns: u64) {
+    span.duration = (now_ns as i64).saturating_sub(span.start).max(0);
+    span.error = 1;
+    span.meta.insert(ORPHAN_TAG.into(), "true".into());
+    if !span.meta.contains_key("error.message") {
+        span.meta.insert("error.message".into(), ORPHAN_MESSAGE.into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expires_after_the_max_age() {
+        let policy = OrphanPolicy {
+            max_age_ns: 1_000,
+            export: false,
+        };
+        assert!(!policy.is_expired(10_000, 11_000));
+        assert!(policy.is_expired(10_000, 11_001));
+        // A start in the future (clock skew) never expires.
+        assert!(!policy.is_expired(20_000, 11_001));
+    }
+
+    #[test]
+    fn marks_unfinished_spans_as_errors() {
+        let mut span = Span::<WasmTraceData> {
+            start: 100,
+            ..Default::default()
+        };
+        mark_unfinished(&mut span, 250);
+        assert_eq!(span.duration, 150);
+        assert_eq!(span.error, 1);
+        assert_eq!(span.meta.get(ORPHAN_TAG).map(|v| &*v.0), Some("true"));
+        assert_eq!(
+            span.meta.get("error.message").map(|v| &*v.0),
+            Some(ORPHAN_MESSAGE)
+        );
+    }
+}
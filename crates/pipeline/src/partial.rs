@@ -0,0 +1,50 @@
+// Copyright 2026-Present Datadog, Inc. https://www.datadoghq.com/
+// SPDX-License-Identifier: Apache-2.0
+
+//! Partial flushing of long-running local traces.
+//!
+//! `preparePartialChunk` takes the finished spans of a segment whose root is
+//! still open. A partial chunk has no local root, so [`apply_trace_tags`]
+//! copies the segment's trace-level attributes onto the chunk's root span,
+//! along with `_dd.partial_version`, while the segment keeps them for later
+//! chunks.
+
+use libdd_trace_utils::span::v04::Span;
+
+use crate::trace_data::WasmTraceData;
+use crate::trace_tags::TraceTags;
+
+pub const PARTIAL_VERSION_KEY: &str = "_dd.partial_version";
+
+/// Copy `tags` onto `root` (keeping values the span already has) and mark it
+/// as partial chunk number `version` of its trace.
+pub fn apply_trace_tags(root: &mut Span<WasmTraceData>, tags: &TraceTags, version: u64) {
+    tags.apply_to(root);
+    root.metrics
+        .insert(PARTIAL_VERSION_KEY.into(), version as f64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampling::SAMPLING_PRIORITY_KEY;
+
+    #[test]
+    fn copies_trace_tags_and_the_partial_version() {
+        let mut tags = TraceTags::default();
+        tags.set_meta("_dd.p.dm".into(), "-3".into());
+        tags.set_meta("env".into(), "prod".into());
+        tags.set_metric(SAMPLING_PRIORITY_KEY.into(), 2.0);
+        tags.set_origin("synthetics".into());
+
+        let mut root = Span::<WasmTraceData>::default();
+        root.meta.insert("env".into(), "staging".into());
+        apply_trace_tags(&mut root, &tags, 3);
+
+        assert_eq!(root.meta.get("_dd.p.dm").map(|v| &*v.0), Some("-3"));
+        assert_eq!(root.meta.get("env").map(|v| &*v.0), Some("staging"));
+        assert_eq!(root.meta.get("_dd.origin").map(|v| &*v.0), Some("synthetics"));
+        assert_eq!(root.metrics.get(SAMPLING_PRIORITY_KEY), Some(&2.0));
+        assert_eq!(root.metrics.get(PARTIAL_VERSION_KEY), Some(&3.0));
+    }
+}
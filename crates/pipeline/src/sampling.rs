@@ -0,0 +1,124 @@
+// Copyright 2026-Present Datadog, Inc. https://www.datadoghq.com/
+// SPDX-License-Identifier: Apache-2.0
+
+//! Trace sampling decisions made inside the pipeline WASM module.
+//!
+//! The agent answers each trace payload with its `rate_by_service` table;
+//! [`AgentRates`] keeps the latest table so `prepareChunk` can stamp a priority
+//! on chunk roots without a JSON round-trip through JS.
+
+use std::collections::HashMap;
+
+use libdd_trace_utils::span::v04::Span;
+
+use crate::trace_data::WasmTraceData;
+
+pub const SAMPLING_PRIORITY_KEY: &str = "_sampling_priority_v1";
+pub const AGENT_RATE_KEY: &str = "_dd.agent_psr";
+pub const DECISION_MAKER_KEY: &str = "_dd.p.dm";
+
+pub const PRIORITY_AUTO_REJECT: f64 = 0.0;
+pub const PRIORITY_AUTO_KEEP: f64 = 1.0;
+
+/// `_dd.p.dm` value for a decision taken from the agent's rates.
+pub const MECHANISM_AGENT_RATE: &str = "-1";
+
+/// Multiplier shared by every Datadog tracer so that a trace id maps to the
+/// same keep/drop decision across languages for a given rate.
+const KNUTH_FACTOR: u64 = 1_111_111_111_111_111_111;
+
+/// Key the agent uses for the fallback rate in `rate_by_service`.
+const DEFAULT_RATE_KEY: &str = "service:,env:";
+
+/// Deterministic rate sampling on the low 64 bits of the trace id.
+pub fn sampled_by_rate(trace_id: u64, rate: f64) -> bool {
+    if rate >= 1.0 {
+        return true;
+    }
+    if rate <= 0.0 || rate.is_nan() {
+        return false;
+    }
+    trace_id.wrapping_mul(KNUTH_FACTOR) < (rate * u64::MAX as f64) as u64
+}
+
+/// Latest `rate_by_service` table received from the agent.
+#[derive(Default)]
+pub struct AgentRates {
+    rates: HashMap<String, f64>,
+}
+
+impl AgentRates {
+    /// Replace the table with the one in an agent response body. A body
+    /// without `rate_by_service` leaves the current rates untouched.
+    pub fn update_from_json(&mut self, body: &str) -> Result<(), String> {
+        let value: serde_json::Value =
+            serde_json::from_str(body).map_err(|e| format!("invalid agent response: {e}"))?;
+        let Some(table) = value.get("rate_by_service").and_then(|v| v.as_object()) else {
+            return Ok(());
+        };
+        self.rates = table
+            .iter()
+            .filter_map(|(k, v)| v.as_f64().map(|rate| (k.clone(), rate)))
+            .collect();
+        Ok(())
+    }
+
+    /// Rate for `service`/`env`, falling back to the agent default and then to
+    /// keeping everything before the agent has sent any rates.
+    pub fn rate_for(&self, service: &str, env: &str) -> f64 {
+        self.rates
+            .get(&format!("service:{service},env:{env}"))
+            .or_else(|| self.rates.get(DEFAULT_RATE_KEY))
+            .copied()
+            .unwrap_or(1.0)
+    }
+}
+
+/// Index of the local root in a chunk returned by `flush_chunk`: the first
+/// span when the host says so, otherwise the first span whose parent is not
+/// part of the chunk.
+pub fn chunk_root_index(spans: &[Span<WasmTraceData>], first_is_local_root: bool) -> Option<usize> {
+    if spans.is_empty() {
+        return None;
+    }
+    if first_is_local_root {
+        return Some(0);
+    }
+    spans
+        .iter()
+        .position(|s| s.parent_id == 0 || !spans.iter().any(|p| p.span_id == s.parent_id))
+        .or(Some(0))
+}
+
+/// Sampling priority already carried by `span`, if any (set by the host for
+/// manual/propagated decisions, or copied from the segment's trace tags).
+pub fn existing_priority(span: &Span<WasmTraceData>) -> Option<f64> {
+    span.metrics.get(SAMPLING_PRIORITY_KEY).copied()
+}
+
+/// Apply the agent rate for the root's service/env to `root` unless it
+/// already carries a priority.
+pub fn apply_agent_rate(root: &mut Span<WasmTraceData>, rates: &AgentRates, default_env: &str) {
+    if existing_priority(root).is_some() {
+        return;
+    }
+    let rate = {
+        let env = root
+            .meta
+            .get("env")
+            .map(|v| v.0.as_ref())
+            .unwrap_or(default_env);
+        rates.rate_for(root.service.0.as_ref(), env)
+    };
+    let keep = sampled_by_rate(root.trace_id as u64, rate);
+    set_priority(root, if keep { PRIORITY_AUTO_KEEP } else { PRIORITY_AUTO_REJECT });
+    root.metrics.insert(AGENT_RATE_KEY.into(), rate);
+    if keep {
+        root.meta
+            .insert(DECISION_MAKER_KEY.into(), MECHANISM_AGENT_RATE.into());
+    }
+}
+
+pub fn set_priority(span: &mut Span<WasmTraceData>, priority: f64) {
+    span.metrics.insert(SAMPLING_PRIORITY_KEY.into(), priority);
+}
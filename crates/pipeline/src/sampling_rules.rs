@@ -0,0 +1,438 @@
+// Copyright 2026-Present Datadog, Inc. https://www.datadoghq.com/
+// SPDX-License-Identifier: Apache-2.0
+
+//! Rule-based trace sampling (`DD_TRACE_SAMPLING_RULES`).
+//!
+//! Rules are evaluated in order against the local root span of a chunk at
+//! `prepareChunk` time; the first matching rule's rate decides the trace, and
+//! kept traces then go through a global [`RateLimiter`]. Everything here is
+//! plain Rust (time is passed in by the caller) so it is unit tested natively.
+
+use std::collections::HashMap;
+
+use libdd_trace_utils::span::v04::Span;
+use serde::Deserialize;
+
+use crate::sampling::{
+    existing_priority, sampled_by_rate, set_priority, DECISION_MAKER_KEY,
+};
+use crate::trace_data::WasmTraceData;
+
+pub const RULE_RATE_KEY: &str = "_dd.rule_psr";
+pub const LIMITER_RATE_KEY: &str = "_dd.limit_psr";
+
+pub const PRIORITY_USER_REJECT: f64 = -1.0;
+pub const PRIORITY_USER_KEEP: f64 = 2.0;
+
+/// `_dd.p.dm` values for a rule decision, by rule provenance.
+const MECHANISM_RULE: &str = "-3";
+const MECHANISM_REMOTE_USER_RULE: &str = "-11";
+const MECHANISM_REMOTE_DYNAMIC_RULE: &str = "-12";
+
+/// Traces per second kept by rules when the host doesn't configure a limit
+/// (the `DD_TRACE_RATE_LIMIT` default).
+pub const DEFAULT_RATE_LIMIT: f64 = 100.0;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// One entry of the `DD_TRACE_SAMPLING_RULES` JSON array.
+#[derive(Deserialize)]
+struct RuleConfig {
+    service: Option<String>,
+    name: Option<String>,
+    resource: Option<String>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+    #[serde(default = "default_sample_rate")]
+    sample_rate: f64,
+    provenance: Option<String>,
+}
+
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
+struct SamplingRule {
+    service: Option<String>,
+    name: Option<String>,
+    resource: Option<String>,
+    tags: Vec<(String, String)>,
+    sample_rate: f64,
+    mechanism: &'static str,
+}
+
+impl SamplingRule {
+    fn matches(&self, span: &Span<WasmTraceData>) -> bool {
+        let field_matches = |pattern: &Option<String>, value: &str| {
+            pattern.as_deref().is_none_or(|p| glob_match(p, value))
+        };
+        field_matches(&self.service, span.service.0.as_ref())
+            && field_matches(&self.name, span.name.0.as_ref())
+            && field_matches(&self.resource, span.resource.0.as_ref())
+            && self
+                .tags
+                .iter()
+                .all(|(key, pattern)| tag_matches(span, key, pattern))
+    }
+}
+
+/// A tag pattern matches a string tag directly and a numeric tag only when it
+/// is an integer (rendered without a fraction); other numbers only match
+/// patterns made entirely of `*`.
+fn tag_matches(span: &Span<WasmTraceData>, key: &str, pattern: &str) -> bool {
+    if let Some(value) = span.meta.get(key) {
+        return glob_match(pattern, value.0.as_ref());
+    }
+    match span.metrics.get(key) {
+        Some(value) if value.fract() == 0.0 && value.is_finite() => {
+            glob_match(pattern, &format!("{}", *value as i64))
+        }
+        Some(_) => is_match_all(pattern),
+        None => false,
+    }
+}
+
+fn is_match_all(pattern: &str) -> bool {
+    !pattern.is_empty() && pattern.chars().all(|c| c == '*')
+}
+
+/// Case-insensitive glob match supporting `*` (any run of characters) and
+/// `?` (exactly one character).
+pub fn glob_match(pattern: &str, subject: &str) -> bool {
+    if is_match_all(pattern) {
+        return true;
+    }
+    let pattern: Vec<char> = pattern.chars().flat_map(char::to_lowercase).collect();
+    let subject: Vec<char> = subject.chars().flat_map(char::to_lowercase).collect();
+
+    let (mut p, mut s) = (0usize, 0usize);
+    // Position of the last `*` seen and the subject index it was tried at, so
+    // a mismatch can backtrack by letting the star absorb one more character.
+    let mut star: Option<(usize, usize)> = None;
+    while s < subject.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == subject[s]) {
+            p += 1;
+            s += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, s));
+            p += 1;
+        } else if let Some((star_p, star_s)) = star {
+            p = star_p + 1;
+            s = star_s + 1;
+            star = Some((star_p, star_s + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Token bucket allowing `limit` decisions per second, with the effective
+/// keep rate (`_dd.limit_psr`) averaged over the current and previous
+/// one-second windows as the other Datadog tracers report it.
+pub struct RateLimiter {
+    limit: f64,
+    tokens: f64,
+    last_refill_ns: Option<u64>,
+    window_start_ns: u64,
+    window_allowed: u64,
+    window_total: u64,
+    previous_window_rate: Option<f64>,
+}
+
+impl RateLimiter {
+    /// A negative `limit` disables limiting; zero rejects everything.
+    pub fn new(limit: f64) -> Self {
+        RateLimiter {
+            limit,
+            tokens: limit.max(0.0),
+            last_refill_ns: None,
+            window_start_ns: 0,
+            window_allowed: 0,
+            window_total: 0,
+            previous_window_rate: None,
+        }
+    }
+
+    pub fn allow(&mut self, now_ns: u64) -> bool {
+        if self.limit < 0.0 {
+            return true;
+        }
+        self.roll_window(now_ns);
+        if let Some(last) = self.last_refill_ns {
+            let elapsed = now_ns.saturating_sub(last) as f64 / NANOS_PER_SEC as f64;
+            self.tokens = (self.tokens + elapsed * self.limit).min(self.limit);
+        }
+        self.last_refill_ns = Some(now_ns);
+
+        self.window_total += 1;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            self.window_allowed += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn effective_rate(&self) -> f64 {
+        if self.limit < 0.0 {
+            return 1.0;
+        }
+        let current = if self.window_total == 0 {
+            1.0
+        } else {
+            self.window_allowed as f64 / self.window_total as f64
+        };
+        match self.previous_window_rate {
+            Some(previous) => (previous + current) / 2.0,
+            None => current,
+        }
+    }
+
+    fn roll_window(&mut self, now_ns: u64) {
+        if self.window_total == 0 {
+            self.window_start_ns = now_ns;
+            return;
+        }
+        let elapsed = now_ns.saturating_sub(self.window_start_ns);
+        if elapsed < NANOS_PER_SEC {
+            return;
+        }
+        // A gap longer than one window means the previous window saw nothing.
+        self.previous_window_rate = if elapsed < 2 * NANOS_PER_SEC {
+            Some(self.window_allowed as f64 / self.window_total as f64)
+        } else {
+            None
+        };
+        self.window_start_ns = now_ns;
+        self.window_allowed = 0;
+        self.window_total = 0;
+    }
+}
+
+/// Ordered sampling rules plus the rate limiter applied to rule keeps.
+pub struct RulesSampler {
+    rules: Vec<SamplingRule>,
+    limiter: RateLimiter,
+}
+
+impl RulesSampler {
+    /// Parse the `DD_TRACE_SAMPLING_RULES` JSON array. `rate_limit` is the
+    /// traces-per-second cap on rule keeps ([`DEFAULT_RATE_LIMIT`] if `None`).
+    pub fn from_json(json: &str, rate_limit: Option<f64>) -> Result<Self, String> {
+        let configs: Vec<RuleConfig> =
+            serde_json::from_str(json).map_err(|e| format!("invalid sampling rules: {e}"))?;
+        let rules = configs
+            .into_iter()
+            .map(|c| {
+                if !(0.0..=1.0).contains(&c.sample_rate) {
+                    return Err(format!(
+                        "invalid sampling rules: sample_rate {} is not between 0 and 1",
+                        c.sample_rate
+                    ));
+                }
+                let mechanism = match c.provenance.as_deref() {
+                    Some("customer") => MECHANISM_REMOTE_USER_RULE,
+                    Some("dynamic") => MECHANISM_REMOTE_DYNAMIC_RULE,
+                    _ => MECHANISM_RULE,
+                };
+                Ok(SamplingRule {
+                    service: c.service,
+                    name: c.name,
+                    resource: c.resource,
+                    tags: c.tags.into_iter().collect(),
+                    sample_rate: c.sample_rate,
+                    mechanism,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(RulesSampler {
+            rules,
+            limiter: RateLimiter::new(rate_limit.unwrap_or(DEFAULT_RATE_LIMIT)),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Decide `root`'s priority with the first matching rule. Returns `true`
+    /// when the root carries a decision afterwards (already set, or set by a
+    /// rule) and `false` when no rule matched, leaving it to the next sampler.
+    pub fn sample(&mut self, root: &mut Span<WasmTraceData>, now_ns: u64) -> bool {
+        if existing_priority(root).is_some() {
+            return true;
+        }
+        let Some(rule) = self.rules.iter().find(|r| r.matches(root)) else {
+            return false;
+        };
+        let (rate, mechanism) = (rule.sample_rate, rule.mechanism);
+        root.metrics.insert(RULE_RATE_KEY.into(), rate);
+        if !sampled_by_rate(root.trace_id as u64, rate) {
+            set_priority(root, PRIORITY_USER_REJECT);
+            return true;
+        }
+        let allowed = self.limiter.allow(now_ns);
+        root.metrics
+            .insert(LIMITER_RATE_KEY.into(), self.limiter.effective_rate());
+        if allowed {
+            set_priority(root, PRIORITY_USER_KEEP);
+            root.meta.insert(DECISION_MAKER_KEY.into(), mechanism.into());
+        } else {
+            set_priority(root, PRIORITY_USER_REJECT);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampling::SAMPLING_PRIORITY_KEY;
+
+    fn root(service: &str, name: &str, resource: &str) -> Span<WasmTraceData> {
+        Span {
+            service: service.into(),
+            name: name.into(),
+            resource: resource.into(),
+            trace_id: 42,
+            span_id: 1,
+            ..Default::default()
+        }
+    }
+
+    fn priority(span: &Span<WasmTraceData>) -> Option<f64> {
+        span.metrics.get(SAMPLING_PRIORITY_KEY).copied()
+    }
+
+    #[test]
+    fn glob_matching() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("web-*", "web-api"));
+        assert!(glob_match("WEB-?PI", "web-api"));
+        assert!(glob_match("*.request", "express.request"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        assert!(!glob_match("a*b*c", "axxbyy"));
+        assert!(!glob_match("web-?", "web-api"));
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "x"));
+    }
+
+    #[test]
+    fn rejects_invalid_json_and_rates() {
+        assert!(RulesSampler::from_json("{", None).is_err());
+        assert!(RulesSampler::from_json(r#"[{"sample_rate": 1.5}]"#, None).is_err());
+        assert!(RulesSampler::from_json("[]", None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let mut sampler = RulesSampler::from_json(
+            r#"[
+                {"service": "web-*", "name": "http.request", "sample_rate": 0},
+                {"service": "web-*", "sample_rate": 1}
+            ]"#,
+            None,
+        )
+        .unwrap();
+
+        let mut dropped = root("web-api", "http.request", "GET /");
+        assert!(sampler.sample(&mut dropped, 0));
+        assert_eq!(priority(&dropped), Some(PRIORITY_USER_REJECT));
+        assert_eq!(dropped.metrics.get(RULE_RATE_KEY).copied(), Some(0.0));
+        assert!(dropped.meta.get(DECISION_MAKER_KEY).is_none());
+
+        let mut kept = root("web-api", "db.query", "SELECT");
+        assert!(sampler.sample(&mut kept, 0));
+        assert_eq!(priority(&kept), Some(PRIORITY_USER_KEEP));
+        assert_eq!(kept.metrics.get(RULE_RATE_KEY).copied(), Some(1.0));
+        assert_eq!(kept.metrics.get(LIMITER_RATE_KEY).copied(), Some(1.0));
+        assert_eq!(
+            kept.meta.get(DECISION_MAKER_KEY).map(|v| v.0.as_ref()),
+            Some(MECHANISM_RULE)
+        );
+
+        let mut unmatched = root("worker", "job", "run");
+        assert!(!sampler.sample(&mut unmatched, 0));
+        assert_eq!(priority(&unmatched), None);
+    }
+
+    #[test]
+    fn matches_resource_and_tags() {
+        let mut sampler = RulesSampler::from_json(
+            r#"[{"resource": "GET /health*", "tags": {"http.status_code": "2??", "team": "core"},
+                 "sample_rate": 1, "provenance": "customer"}]"#,
+            None,
+        )
+        .unwrap();
+
+        let mut span = root("svc", "web", "GET /healthz");
+        span.meta.insert("team".into(), "Core".into());
+        span.metrics.insert("http.status_code".into(), 200.0);
+        assert!(sampler.sample(&mut span, 0));
+        assert_eq!(
+            span.meta.get(DECISION_MAKER_KEY).map(|v| v.0.as_ref()),
+            Some(MECHANISM_REMOTE_USER_RULE)
+        );
+
+        let mut fractional = root("svc", "web", "GET /healthz");
+        fractional.meta.insert("team".into(), "core".into());
+        fractional.metrics.insert("http.status_code".into(), 200.5);
+        assert!(!sampler.sample(&mut fractional, 0));
+
+        let mut missing_tag = root("svc", "web", "GET /healthz");
+        missing_tag.metrics.insert("http.status_code".into(), 200.0);
+        assert!(!sampler.sample(&mut missing_tag, 0));
+    }
+
+    #[test]
+    fn keeps_an_existing_priority() {
+        let mut sampler = RulesSampler::from_json(r#"[{"sample_rate": 0}]"#, None).unwrap();
+        let mut span = root("svc", "web", "GET");
+        set_priority(&mut span, PRIORITY_USER_KEEP);
+        assert!(sampler.sample(&mut span, 0));
+        assert_eq!(priority(&span), Some(PRIORITY_USER_KEEP));
+        assert!(span.metrics.get(RULE_RATE_KEY).is_none());
+    }
+
+    #[test]
+    fn rate_limiter_caps_rule_keeps() {
+        let mut sampler = RulesSampler::from_json(r#"[{"sample_rate": 1}]"#, Some(2.0)).unwrap();
+        let decisions: Vec<Option<f64>> = (0..3)
+            .map(|_| {
+                let mut span = root("svc", "web", "GET");
+                sampler.sample(&mut span, 0);
+                priority(&span)
+            })
+            .collect();
+        assert_eq!(
+            decisions,
+            vec![
+                Some(PRIORITY_USER_KEEP),
+                Some(PRIORITY_USER_KEEP),
+                Some(PRIORITY_USER_REJECT)
+            ]
+        );
+
+        // Tokens refill over time.
+        let mut later = root("svc", "web", "GET");
+        sampler.sample(&mut later, NANOS_PER_SEC);
+        assert_eq!(priority(&later), Some(PRIORITY_USER_KEEP));
+    }
+
+    #[test]
+    fn rate_limiter_effective_rate() {
+        let mut limiter = RateLimiter::new(1.0);
+        assert!(limiter.allow(0));
+        assert!(!limiter.allow(1));
+        assert_eq!(limiter.effective_rate(), 0.5);
+        // Next window: average of the previous (0.5) and current (1.0) rates.
+        assert!(limiter.allow(NANOS_PER_SEC));
+        assert_eq!(limiter.effective_rate(), 0.75);
+
+        let mut unlimited = RateLimiter::new(-1.0);
+        assert!((0..1000).all(|i| unlimited.allow(i)));
+        assert!(!RateLimiter::new(0.0).allow(0));
+    }
+}
@@ -0,0 +1,147 @@
+// Copyright 2026-Present Datadog, Inc. https://www.datadoghq.com/
+// SPDX-License-Identifier: Apache-2.0
+
+//! Whole-span reads for `getSpanSnapshot`.
+//!
+//! [`SpanSnapshot`] borrows every field of a live span plus the trace-level
+//! attributes of its segment, so one call replaces the per-field getters. It
+//! serializes either to JSON (turned into a JS object by the caller) or to
+//! msgpack with field names, for callers that decode it themselves.
+
+use std::collections::BTreeMap;
+
+use libdd_trace_utils::span::v04::{Span, SpanEvent, SpanLink};
+use serde::Serialize;
+
+use crate::errors::PipelineError;
+use crate::trace_data::WasmTraceData;
+use crate::trace_tags::TraceTags;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpanSnapshot<'a> {
+    pub span_id: u64,
+    pub parent_id: u64,
+    pub trace_id_high: u64,
+    pub trace_id_low: u64,
+    /// `None` when the span wasn't created through the change queue.
+    pub segment_id: Option<u64>,
+    pub service: &'a str,
+    pub name: &'a str,
+    pub resource: &'a str,
+    pub r#type: &'a str,
+    pub start: i64,
+    pub duration: i64,
+    pub error: i32,
+    pub meta: BTreeMap<&'a str, &'a str>,
+    pub metrics: BTreeMap<&'a str, f64>,
+    pub meta_struct_keys: Vec<&'a str>,
+    pub span_events: &'a [SpanEvent<WasmTraceData>],
+    pub span_links: &'a [SpanLink<WasmTraceData>],
+    /// The segment's trace-level attributes, `None` for an unknown segment.
+    pub trace: Option<TraceSnapshot<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TraceSnapshot<'a> {
+    pub meta: BTreeMap<&'a str, &'a str>,
+    pub metrics: BTreeMap<&'a str, f64>,
+    pub origin: Option<&'a str>,
+}
+
+impl<'a> SpanSnapshot<'a> {
+    pub fn new(
+        span: &'a Span<WasmTraceData>,
+        segment_id: Option<u64>,
+        tags: Option<&'a TraceTags>,
+    ) -> Self {
+        SpanSnapshot {
+            span_id: span.span_id,
+            parent_id: span.parent_id,
+            trace_id_high: (span.trace_id >> 64) as u64,
+            trace_id_low: span.trace_id as u64,
+            segment_id,
+            service: &span.service.0,
+            name: &span.name.0,
+            resource: &span.resource.0,
+            r#type: &span.r#type.0,
+            start: span.start,
+            duration: span.duration,
+            error: span.error,
+            meta: span.meta.iter().map(|(k, v)| (&*k.0, &*v.0)).collect(),
+            metrics: span.metrics.iter().map(|(k, v)| (&*k.0, *v)).collect(),
+            meta_struct_keys: span.meta_struct.iter().map(|(k, _)| &*k.0).collect(),
+            span_events: &span.span_events,
+            span_links: &span.span_links,
+            trace: tags.map(|tags| TraceSnapshot {
+                meta: tags.meta().collect(),
+                metrics: tags.metrics().collect(),
+                origin: tags.origin(),
+            }),
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, PipelineError> {
+        serde_json::to_string(self)
+            .map_err(|e| PipelineError::serialization(format!("getSpanSnapshot: {e}")))
+    }
+
+    pub fn to_msgpack(&self) -> Result<Vec<u8>, PipelineError> {
+        rmp_serde::encode::to_vec_named(self)
+            .map_err(|e| PipelineError::serialization(format!("getSpanSnapshot: {e}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span() -> Span<WasmTraceData> {
+        let mut span = Span::<WasmTraceData> {
+            span_id: u64::MAX,
+            parent_id: 1,
+            trace_id: (7u128 << 64) | 9,
+            name: "web.request".into(),
+            start: 1_700_000_000_123_456_789,
+            duration: 5,
+            ..Default::default()
+        };
+        span.meta.insert("http.method".into(), "GET".into());
+        span.metrics.insert("_sampling_priority_v1".into(), 1.0);
+        span.meta_struct.insert(
+            "appsec".into(),
+            crate::span_bytes::SpanBytesImpl(vec![0x80]),
+        );
+        span
+    }
+
+    #[test]
+    fn msgpack_keeps_64_bit_values_exact() {
+        let span = span();
+        let bytes = SpanSnapshot::new(&span, Some(3), None)
+            .to_msgpack()
+            .unwrap();
+        let decoded: serde_json::Value = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(decoded["spanId"], serde_json::json!(u64::MAX));
+        assert_eq!(decoded["traceIdHigh"], 7);
+        assert_eq!(decoded["traceIdLow"], 9);
+        assert_eq!(decoded["segmentId"], 3);
+        assert_eq!(decoded["start"], 1_700_000_000_123_456_789i64);
+        assert_eq!(decoded["meta"]["http.method"], "GET");
+        assert_eq!(decoded["metrics"]["_sampling_priority_v1"], 1.0);
+        assert_eq!(decoded["metaStructKeys"], serde_json::json!(["appsec"]));
+        assert_eq!(decoded["trace"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn json_matches_msgpack() {
+        let span = span();
+        let snapshot = SpanSnapshot::new(&span, None, None);
+        let from_json: serde_json::Value =
+            serde_json::from_str(&snapshot.to_json().unwrap()).unwrap();
+        let from_msgpack: serde_json::Value =
+            rmp_serde::from_slice(&snapshot.to_msgpack().unwrap()).unwrap();
+        assert_eq!(from_json, from_msgpack);
+    }
+}
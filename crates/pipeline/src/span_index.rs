@@ -0,0 +1,481 @@
+// Copyright 2026-Present Datadog, Inc. https://www.datadoghq.com/
+// SPDX-License-Identifier: Apache-2.0
+
+//! Pipeline-side bookkeeping for live spans.
+//!
+//! libdatadog's `ChangeBufferState` holds spans and segments but doesn't say
+//! which segment a span belongs to, or which spans a segment has.
+//! [`SpanIndex`] learns both from the `Create` ops as the change queue is
+//! flushed (see `change_queue::parse`), notes which spans have finished from
+//! their `SetDuration` ops, and forgets a span once it leaves the state. The
+//! ops are collected in [`PendingOps`] while the queue is parsed and only
+//! committed once libdatadog has applied the run holding them, so a queue
+//! that fails part way leaves the index agreeing with the state.
+//!
+//! Each segment with live spans also has its state here: its trace-level
+//! attributes (see `trace_tags`), partial-flush version and sampling decision.
+//! A segment and its state are gone with its last span. The index also gives
+//! the live span and segment counts `getMetrics` reports.
+//!
+//! Spans the reaper or a discard took out are remembered as evicted, so a
+//! later `prepareChunk` still listing them can tell them from ids that were
+//! never live. Only the most recent [`MAX_EVICTED`] are kept.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::trace_tags::TraceTags;
+use crate::utils::get_num;
+
+const CREATE: u16 = 0;
+const SET_DURATION: u16 = 7;
+/// Offset of the segment id in `Create`'s args, after the u128 trace id.
+const CREATE_SEGMENT_OFFSET: usize = 16;
+/// How many evicted span ids are remembered.
+pub const MAX_EVICTED: usize = 1 << 16;
+
+#[derive(Default)]
+pub struct SpanIndex {
+    segments: HashMap<u64, u64>,
+    spans: HashMap<u64, HashSet<u64>>,
+    finished: HashSet<u64>,
+    state: HashMap<u64, SegmentState>,
+    evicted: HashSet<u64>,
+    /// Eviction order, oldest first, to bound `evicted`.
+    evicted_order: VecDeque<u64>,
+}
+
+/// What a segment's spans share: its trace-level attributes and what earlier
+/// chunks of it settled.
+#[derive(Default)]
+struct SegmentState {
+    tags: TraceTags,
+    partial_version: u64,
+    priority: Option<f64>,
+}
+
+/// A libdatadog op the index learns from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum IndexOp {
+    Create { span_id: u64, segment_id: u64 },
+    Finish { span_id: u64 },
+}
+
+impl IndexOp {
+    fn parse(opcode: u16, span_id: u64, args: &[u8]) -> Option<Self> {
+        match opcode {
+            CREATE => {
+                let mut idx = CREATE_SEGMENT_OFFSET;
+                let segment_id = get_num::<u64>(args, &mut idx)?;
+                Some(IndexOp::Create {
+                    span_id,
+                    segment_id,
+                })
+            }
+            SET_DURATION => Some(IndexOp::Finish { span_id }),
+            _ => None,
+        }
+    }
+}
+
+/// The index ops of a change queue being flushed, by position among the
+/// queue's libdatadog ops.
+#[derive(Default)]
+pub struct PendingOps {
+    ops: VecDeque<(u64, IndexOp)>,
+    seen: u64,
+    applied: u64,
+}
+
+impl PendingOps {
+    /// Note a libdatadog op seen in the change queue, in queue order.
+    pub fn observe(&mut self, opcode: u16, span_id: u64, args: &[u8]) {
+        if let Some(op) = IndexOp::parse(opcode, span_id, args) {
+            self.ops.push_back((self.seen, op));
+        }
+        self.seen += 1;
+    }
+
+    /// Commit the ops of the next `count` libdatadog ops, once libdatadog has
+    /// applied them.
+    pub fn commit_run(&mut self, count: u64, index: &mut SpanIndex) {
+        self.applied += count;
+        while let Some(&(pos, op)) = self.ops.front() {
+            if pos >= self.applied {
+                break;
+            }
+            self.ops.pop_front();
+            index.apply(op);
+        }
+    }
+
+    /// Commit every op, once libdatadog has applied the whole queue.
+    pub fn commit_all(mut self, index: &mut SpanIndex) {
+        let rest = self.seen - self.applied;
+        self.commit_run(rest, index);
+    }
+}
+
+impl SpanIndex {
+    fn apply(&mut self, op: IndexOp) {
+        match op {
+            IndexOp::Finish { span_id } => {
+                if self.segments.contains_key(&span_id) {
+                    self.finished.insert(span_id);
+                }
+            }
+            IndexOp::Create {
+                span_id,
+                segment_id,
+            } => {
+                if let Some(previous) = self.segments.insert(span_id, segment_id) {
+                    self.forget(previous, span_id);
+                }
+                self.spans.entry(segment_id).or_default().insert(span_id);
+                self.state.entry(segment_id).or_default();
+                self.evicted.remove(&span_id);
+            }
+        }
+    }
+
+    pub fn segment_of(&self, span_id: u64) -> Option<u64> {
+        self.segments.get(&span_id).copied()
+    }
+
+    pub fn is_finished(&self, span_id: u64) -> bool {
+        self.finished.contains(&span_id)
+    }
+
+    /// Number of live spans.
+    pub fn span_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Number of segments with live spans.
+    pub fn segment_count(&self) -> usize {
+        self.spans.len()
+    }
+
+    /// Every segment with live spans, with its spans.
+    pub fn segments(&self) -> impl Iterator<Item = (u64, &HashSet<u64>)> {
+        self.spans
+            .iter()
+            .map(|(segment_id, spans)| (*segment_id, spans))
+    }
+
+    /// The finished live spans of `segment_id`, in no particular order.
+    pub fn finished_spans_of(&self, segment_id: u64) -> Vec<u64> {
+        self.spans
+            .get(&segment_id)
+            .map(|spans| {
+                spans
+                    .iter()
+                    .copied()
+                    .filter(|id| self.finished.contains(id))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The trace-level attributes of `segment_id`, while it has live spans.
+    pub fn trace_tags(&self, segment_id: u64) -> Option<&TraceTags> {
+        self.state.get(&segment_id).map(|state| &state.tags)
+    }
+
+    /// The trace-level attributes of the segment live span `span_id` belongs
+    /// to.
+    pub fn trace_tags_of_span(&mut self, span_id: u64) -> Option<&mut TraceTags> {
+        let segment_id = self.segments.get(&span_id)?;
+        self.state.get_mut(segment_id).map(|state| &mut state.tags)
+    }
+
+    /// Number the next partial chunk of `segment_id`, starting at 1.
+    pub fn next_partial_version(&mut self, segment_id: u64) -> u64 {
+        match self.state.get_mut(&segment_id) {
+            Some(state) => {
+                state.partial_version += 1;
+                state.partial_version
+            }
+            None => 1,
+        }
+    }
+
+    /// The sampling priority decided for an earlier chunk of `segment_id`,
+    /// which later chunks of the trace must agree with.
+    pub fn sampling_priority(&self, segment_id: u64) -> Option<f64> {
+        self.state.get(&segment_id).and_then(|state| state.priority)
+    }
+
+    /// Keep the sampling priority of a chunk of `segment_id` for the chunks
+    /// that follow. A segment without live spans has none to follow, so
+    /// nothing is kept for it.
+    pub fn set_sampling_priority(&mut self, segment_id: u64, priority: f64) {
+        if let Some(state) = self.state.get_mut(&segment_id) {
+            state.priority = Some(priority);
+        }
+    }
+
+    /// The live spans of `segment_id`, in no particular order.
+    pub fn spans_of(&self, segment_id: u64) -> Vec<u64> {
+        self.spans
+            .get(&segment_id)
+            .map(|spans| spans.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn remove(&mut self, span_id: u64) {
+        self.finished.remove(&span_id);
+        if let Some(segment_id) = self.segments.remove(&span_id) {
+            self.forget(segment_id, span_id);
+        }
+    }
+
+    /// Remove a span the reaper or a discard took out, remembering it as
+    /// evicted.
+    pub fn evict(&mut self, span_id: u64) {
+        self.remove(span_id);
+        if self.evicted.insert(span_id) {
+            self.evicted_order.push_back(span_id);
+        }
+        while self.evicted_order.len() > MAX_EVICTED {
+            if let Some(oldest) = self.evicted_order.pop_front() {
+                self.evicted.remove(&oldest);
+            }
+        }
+    }
+
+    /// Whether `span_id` was evicted and hasn't been created again since.
+    pub fn is_evicted(&self, span_id: u64) -> bool {
+        self.evicted.contains(&span_id)
+    }
+
+    /// Stop remembering `span_id` as evicted, once a chunk has skipped it.
+    pub fn clear_evicted(&mut self, span_id: u64) {
+        self.evicted.remove(&span_id);
+    }
+
+    fn forget(&mut self, segment_id: u64, span_id: u64) {
+        if let Some(spans) = self.spans.get_mut(&segment_id) {
+            spans.remove(&span_id);
+            if spans.is_empty() {
+                self.spans.remove(&segment_id);
+                self.state.remove(&segment_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_args(segment_id: u64) -> Vec<u8> {
+        let mut args = vec![0xAB; 16];
+        args.extend_from_slice(&segment_id.to_le_bytes());
+        args.extend_from_slice(&0u64.to_le_bytes());
+        args
+    }
+
+    impl SpanIndex {
+        /// Observe and commit a single op.
+        fn record(&mut self, opcode: u16, span_id: u64, args: &[u8]) {
+            let mut pending = PendingOps::default();
+            pending.observe(opcode, span_id, args);
+            pending.commit_all(self);
+        }
+    }
+
+    fn queue_op(queue: &mut Vec<u8>, opcode: u16, span_id: u64, args: &[u8]) {
+        queue.extend_from_slice(&opcode.to_le_bytes());
+        queue.extend_from_slice(&span_id.to_le_bytes());
+        queue.extend_from_slice(args);
+    }
+
+    #[test]
+    fn maps_created_spans_to_their_segment() {
+        let mut index = SpanIndex::default();
+        index.record(CREATE, 1, &create_args(10));
+        index.record(CREATE, 2, &create_args(10));
+        index.record(9, 3, &[0; 4]);
+
+        assert_eq!(index.segment_of(1), Some(10));
+        assert_eq!(index.segment_of(2), Some(10));
+        assert_eq!(index.segment_of(3), None);
+        assert_eq!(index.span_count(), 2);
+        assert_eq!(index.segment_count(), 1);
+
+        let mut spans = index.spans_of(10);
+        spans.sort_unstable();
+        assert_eq!(spans, vec![1, 2]);
+
+        index.remove(1);
+        assert_eq!(index.segment_of(1), None);
+        assert_eq!(index.spans_of(10), vec![2]);
+        assert_eq!(index.segment_count(), 1);
+        index.remove(2);
+        assert!(index.spans_of(10).is_empty());
+        assert_eq!(index.segment_count(), 0);
+        assert!(index.spans.is_empty());
+    }
+
+    #[test]
+    fn tracks_finished_spans() {
+        let mut index = SpanIndex::default();
+        index.record(CREATE, 1, &create_args(10));
+        index.record(SET_DURATION, 1, &5i64.to_le_bytes());
+        // Not created through the queue: nothing to track.
+        index.record(SET_DURATION, 2, &5i64.to_le_bytes());
+
+        assert!(index.is_finished(1));
+        assert!(!index.is_finished(2));
+        index.remove(1);
+        assert!(!index.is_finished(1));
+        assert!(index.finished.is_empty());
+    }
+
+    #[test]
+    fn keeps_partial_state_while_the_segment_has_spans() {
+        let mut index = SpanIndex::default();
+        index.record(CREATE, 1, &create_args(10));
+        index.record(CREATE, 2, &create_args(10));
+        index.record(SET_DURATION, 2, &5i64.to_le_bytes());
+        assert_eq!(index.finished_spans_of(10), vec![2]);
+
+        assert_eq!(index.next_partial_version(10), 1);
+        index.set_sampling_priority(10, 2.0);
+        index.remove(2);
+        assert_eq!(index.next_partial_version(10), 2);
+        assert_eq!(index.sampling_priority(10), Some(2.0));
+
+        index.remove(1);
+        assert_eq!(index.sampling_priority(10), None);
+        assert!(index.state.is_empty());
+        assert_eq!(index.next_partial_version(10), 1);
+    }
+
+    #[test]
+    fn keeps_no_state_for_a_segment_without_spans() {
+        let mut index = SpanIndex::default();
+        index.set_sampling_priority(10, 1.0);
+        assert_eq!(index.next_partial_version(10), 1);
+        assert_eq!(index.sampling_priority(10), None);
+        assert!(index.trace_tags(10).is_none());
+        assert!(index.trace_tags_of_span(1).is_none());
+        assert!(index.state.is_empty());
+    }
+
+    #[test]
+    fn drops_trace_tags_with_the_last_span() {
+        let mut index = SpanIndex::default();
+        index.record(CREATE, 1, &create_args(10));
+        index.record(CREATE, 2, &create_args(10));
+        index.record(CREATE, 3, &create_args(20));
+        let tags = index.trace_tags_of_span(2).unwrap();
+        tags.set_meta("_dd.p.dm".into(), "-4".into());
+        tags.set_origin("synthetics".into());
+        index.trace_tags_of_span(3).unwrap().set_metric("_sampling_priority_v1".into(), 2.0);
+
+        assert_eq!(index.trace_tags(10).unwrap().meta_attr("_dd.p.dm"), Some("-4"));
+        assert_eq!(index.state.len(), 2);
+
+        index.remove(1);
+        assert_eq!(index.trace_tags(10).unwrap().origin(), Some("synthetics"));
+        index.remove(2);
+        assert!(!index.state.contains_key(&10));
+        assert!(index.trace_tags(10).is_none());
+        assert_eq!(index.state.len(), 1);
+
+        // A new span in the same segment id starts from nothing.
+        index.record(CREATE, 4, &create_args(10));
+        assert_eq!(index.trace_tags(10), Some(&TraceTags::default()));
+
+        index.remove(3);
+        index.remove(4);
+        assert!(index.state.is_empty());
+    }
+
+    #[test]
+    fn remembers_evicted_spans_until_created_again() {
+        let mut index = SpanIndex::default();
+        index.record(CREATE, 1, &create_args(10));
+        index.record(CREATE, 2, &create_args(10));
+        index.evict(1);
+        index.remove(2);
+        assert_eq!(index.segment_of(1), None);
+        assert!(index.is_evicted(1));
+        assert!(!index.is_evicted(2), "exported, not evicted");
+        assert!(!index.is_evicted(3), "never live");
+
+        index.record(CREATE, 1, &create_args(11));
+        assert!(!index.is_evicted(1));
+        index.evict(1);
+        index.clear_evicted(1);
+        assert!(!index.is_evicted(1));
+    }
+
+    #[test]
+    fn bounds_the_evicted_spans() {
+        let mut index = SpanIndex::default();
+        for span_id in 0..=MAX_EVICTED as u64 {
+            index.evict(span_id);
+        }
+        assert!(!index.is_evicted(0));
+        assert!(index.is_evicted(MAX_EVICTED as u64));
+        assert_eq!(index.evicted.len(), MAX_EVICTED);
+    }
+
+    #[test]
+    fn commits_nothing_from_a_queue_that_fails_to_parse() {
+        let mut queue = 2u64.to_le_bytes().to_vec();
+        queue_op(&mut queue, CREATE, 1, &create_args(10));
+        queue_op(&mut queue, 200, 1, &[]);
+
+        let index = SpanIndex::default();
+        let mut pending = PendingOps::default();
+        let parsed = crate::change_queue::parse(&queue, &mut |opcode, span_id, args| {
+            pending.observe(opcode, span_id, args)
+        });
+        assert!(parsed.is_err());
+        // The Create was seen, but the caller bails out before committing it.
+        assert_eq!(pending.ops.len(), 1);
+        assert_eq!(index.segment_of(1), None);
+        assert_eq!(index.span_count(), 0);
+    }
+
+    #[test]
+    fn commits_only_the_runs_that_were_applied() {
+        // Create 1, SetMetaStruct on 1, Create 2 and finish it.
+        let mut queue = 4u64.to_le_bytes().to_vec();
+        queue_op(&mut queue, CREATE, 1, &create_args(10));
+        queue_op(&mut queue, crate::change_queue::SET_META_STRUCT, 1, &[0; 8]);
+        queue_op(&mut queue, CREATE, 2, &create_args(10));
+        queue_op(&mut queue, SET_DURATION, 2, &5i64.to_le_bytes());
+
+        let mut pending = PendingOps::default();
+        let entries = crate::change_queue::parse(&queue, &mut |opcode, span_id, args| {
+            pending.observe(opcode, span_id, args)
+        })
+        .unwrap()
+        .unwrap();
+        let counts: Vec<u64> = entries
+            .iter()
+            .filter_map(|entry| match entry {
+                crate::change_queue::Entry::Ops { count, .. } => Some(*count),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(counts, vec![1, 2]);
+
+        // The first run is applied, then the extended op fails.
+        let mut index = SpanIndex::default();
+        pending.commit_run(counts[0], &mut index);
+        assert_eq!(index.segment_of(1), Some(10));
+        assert_eq!(index.segment_of(2), None);
+        assert!(!index.is_finished(2));
+        assert_eq!(index.span_count(), 1);
+
+        // Had it succeeded, the second run commits the rest.
+        pending.commit_run(counts[1], &mut index);
+        assert_eq!(index.segment_of(2), Some(10));
+        assert!(index.is_finished(2));
+    }
+}
@@ -0,0 +1,177 @@
+// Copyright 2026-Present Datadog, Inc. https://www.datadoghq.com/
+// SPDX-License-Identifier: Apache-2.0
+
+//! Single span sampling (`DD_SPAN_SAMPLING_RULES`).
+//!
+//! When a trace is rejected, spans matching a span sampling rule are still
+//! kept: each rule has its own rate and optional per-second cap, and kept
+//! spans are tagged with the `_dd.span_sampling.*` metrics the agent uses to
+//! retain them out of a dropped trace.
+
+use libdd_trace_utils::span::v04::Span;
+use serde::Deserialize;
+
+use crate::sampling::sampled_by_rate;
+use crate::sampling_rules::{glob_match, RateLimiter};
+use crate::trace_data::WasmTraceData;
+
+pub const MECHANISM_KEY: &str = "_dd.span_sampling.mechanism";
+pub const RULE_RATE_KEY: &str = "_dd.span_sampling.rule_rate";
+pub const MAX_PER_SECOND_KEY: &str = "_dd.span_sampling.max_per_second";
+
+/// `_dd.span_sampling.mechanism` value for single span sampling.
+const MECHANISM_SPAN_SAMPLING_RULE: f64 = 8.0;
+
+/// One entry of the `DD_SPAN_SAMPLING_RULES` JSON array.
+#[derive(Deserialize)]
+struct RuleConfig {
+    #[serde(default = "match_all")]
+    service: String,
+    #[serde(default = "match_all")]
+    name: String,
+    #[serde(default = "default_sample_rate")]
+    sample_rate: f64,
+    max_per_second: Option<f64>,
+}
+
+fn match_all() -> String {
+    "*".to_string()
+}
+
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
+struct SpanSamplingRule {
+    service: String,
+    name: String,
+    sample_rate: f64,
+    max_per_second: Option<f64>,
+    limiter: RateLimiter,
+}
+
+/// Ordered span sampling rules; the first rule matching a span decides it.
+pub struct SpanSampler {
+    rules: Vec<SpanSamplingRule>,
+}
+
+impl SpanSampler {
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let configs: Vec<RuleConfig> = serde_json::from_str(json)
+            .map_err(|e| format!("invalid span sampling rules: {e}"))?;
+        let rules = configs
+            .into_iter()
+            .map(|c| {
+                if !(0.0..=1.0).contains(&c.sample_rate) {
+                    return Err(format!(
+                        "invalid span sampling rules: sample_rate {} is not between 0 and 1",
+                        c.sample_rate
+                    ));
+                }
+                Ok(SpanSamplingRule {
+                    service: c.service,
+                    name: c.name,
+                    sample_rate: c.sample_rate,
+                    max_per_second: c.max_per_second,
+                    // No `max_per_second` means no cap.
+                    limiter: RateLimiter::new(c.max_per_second.unwrap_or(-1.0)),
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(SpanSampler { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Tag every span in `spans` kept by a rule. Returns how many were kept.
+    pub fn sample_chunk(&mut self, spans: &mut [Span<WasmTraceData>], now_ns: u64) -> usize {
+        let mut kept = 0;
+        for span in spans.iter_mut() {
+            if self.sample_span(span, now_ns) {
+                kept += 1;
+            }
+        }
+        kept
+    }
+
+    fn sample_span(&mut self, span: &mut Span<WasmTraceData>, now_ns: u64) -> bool {
+        let Some(rule) = self.rules.iter_mut().find(|r| {
+            glob_match(&r.service, span.service.0.as_ref()) && glob_match(&r.name, span.name.0.as_ref())
+        }) else {
+            return false;
+        };
+        if !sampled_by_rate(span.span_id, rule.sample_rate) || !rule.limiter.allow(now_ns) {
+            return false;
+        }
+        span.metrics
+            .insert(MECHANISM_KEY.into(), MECHANISM_SPAN_SAMPLING_RULE);
+        span.metrics.insert(RULE_RATE_KEY.into(), rule.sample_rate);
+        if let Some(max) = rule.max_per_second {
+            span.metrics.insert(MAX_PER_SECOND_KEY.into(), max);
+        }
+        true
+    }
+}
+
+/// Whether `span` was kept by single span sampling.
+pub fn is_span_sampled(span: &Span<WasmTraceData>) -> bool {
+    span.metrics.get(MECHANISM_KEY).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(service: &str, name: &str, span_id: u64) -> Span<WasmTraceData> {
+        Span {
+            service: service.into(),
+            name: name.into(),
+            span_id,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        assert!(SpanSampler::from_json("not json").is_err());
+        assert!(SpanSampler::from_json(r#"[{"sample_rate": -1}]"#).is_err());
+        assert!(SpanSampler::from_json("[]").unwrap().is_empty());
+    }
+
+    #[test]
+    fn tags_matching_spans_only() {
+        let mut sampler =
+            SpanSampler::from_json(r#"[{"service": "db-*", "name": "*.query", "max_per_second": 5}]"#)
+                .unwrap();
+        let mut spans = vec![
+            span("web", "http.request", 1),
+            span("db-main", "pg.query", 2),
+            span("db-main", "pg.connect", 3),
+        ];
+        assert_eq!(sampler.sample_chunk(&mut spans, 0), 1);
+        assert!(!is_span_sampled(&spans[0]));
+        assert!(is_span_sampled(&spans[1]));
+        assert!(!is_span_sampled(&spans[2]));
+        assert_eq!(spans[1].metrics.get(MECHANISM_KEY).copied(), Some(8.0));
+        assert_eq!(spans[1].metrics.get(RULE_RATE_KEY).copied(), Some(1.0));
+        assert_eq!(spans[1].metrics.get(MAX_PER_SECOND_KEY).copied(), Some(5.0));
+    }
+
+    #[test]
+    fn applies_rate_and_cap() {
+        let mut never = SpanSampler::from_json(r#"[{"sample_rate": 0}]"#).unwrap();
+        let mut spans = vec![span("svc", "op", 1)];
+        assert_eq!(never.sample_chunk(&mut spans, 0), 0);
+
+        let mut capped = SpanSampler::from_json(r#"[{"max_per_second": 1}]"#).unwrap();
+        let mut spans = vec![span("svc", "op", 1), span("svc", "op", 2)];
+        assert_eq!(capped.sample_chunk(&mut spans, 0), 1);
+        // Without a cap, no max_per_second tag is set.
+        let mut uncapped = SpanSampler::from_json(r#"[{}]"#).unwrap();
+        let mut spans = vec![span("svc", "op", 1)];
+        assert_eq!(uncapped.sample_chunk(&mut spans, 0), 1);
+        assert!(spans[0].metrics.get(MAX_PER_SECOND_KEY).is_none());
+    }
+}
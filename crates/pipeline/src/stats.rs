@@ -5,16 +5,9 @@
 //!
 //! Wraps `SpanConcentrator` from `libdd-trace-stats` and provides encoding +
 //! HTTP transport for flushing stats to the Datadog agent's `/v0.6/stats`
-//! endpoint.
+//! endpoint, or to the intake in agentless mode.
 
-use std::time::{Duration, SystemTime};
-
-/// Wall-clock now() for wasm. `std::time::SystemTime::now()` is unimplemented on
-/// `wasm32-unknown-unknown` (it panics/traps), so derive the time from JS
-/// `Date.now()` (milliseconds since the Unix epoch).
-fn now() -> SystemTime {
-    SystemTime::UNIX_EPOCH + Duration::from_millis(js_sys::Date::now() as u64)
-}
+use std::time::Duration;
 
 use bytes::Bytes;
 use libdd_capabilities::http::HttpClientCapability;
@@ -22,10 +15,17 @@ use libdd_trace_protobuf::pb;
 use libdd_trace_stats::span_concentrator::SpanConcentrator;
 use libdatadog_nodejs_capabilities::WasmHttpClient;
 
+use crate::agentless::Intake;
+use crate::errors::{ErrorKind, PipelineError};
 use crate::trace_data::WasmTraceData;
+use crate::utils::now;
 
 const STATS_ENDPOINT_PATH: &str = "/v0.6/stats";
 
+/// Span kinds that are eligible for stats when the host doesn't configure its
+/// own (matches the agent's default).
+const DEFAULT_SPAN_KINDS: [&str; 4] = ["client", "server", "producer", "consumer"];
+
 /// Metadata for the stats payload envelope.
 pub struct StatsMeta {
     pub hostname: String,
@@ -35,37 +35,108 @@ pub struct StatsMeta {
     pub tracer_version: String,
     pub runtime_id: String,
     pub service: String,
+    pub container: ContainerMeta,
+    pub tags: Vec<String>,
+    pub git_commit_sha: String,
+    pub process_tags: String,
+    pub process_tags_hash: u64,
+}
+
+/// Container identification, sent both in the payload and as the
+/// `Datadog-Container-ID` / `Datadog-Entity-ID` / `Datadog-External-Env`
+/// headers. Empty fields are omitted. Resolved by the host (there is no
+/// cgroup access from WASM).
+#[derive(Default)]
+pub struct ContainerMeta {
+    pub container_id: String,
+    pub entity_id: String,
+    pub external_env: String,
+    pub image_tag: String,
 }
 
 /// Manages stats aggregation and flushing.
 pub struct StatsCollector {
     concentrator: SpanConcentrator,
+    /// Buckets force-flushed out of a replaced concentrator (see
+    /// `set_bucket_size`), sent with the next `prepare_request`.
+    pending: Vec<pb::ClientStatsBucket>,
+    bucket_size: Duration,
+    span_kinds: Vec<String>,
+    peer_tags: Vec<String>,
     meta: StatsMeta,
     agent_url: String,
+    /// When set, stats go to the intake instead of `agent_url`.
+    intake: Option<Intake>,
     sequence: u64,
 }
 
 impl StatsCollector {
     /// Create a new stats collector.
     pub fn new(bucket_size: Duration, agent_url: String, meta: StatsMeta) -> Self {
+        let span_kinds: Vec<String> = DEFAULT_SPAN_KINDS.iter().map(|k| k.to_string()).collect();
         StatsCollector {
-            concentrator: SpanConcentrator::new(
-                bucket_size,
-                now(),
-                vec![
-                    "client".to_string(),
-                    "server".to_string(),
-                    "producer".to_string(),
-                    "consumer".to_string(),
-                ],
-                Vec::new(),
-            ),
+            concentrator: SpanConcentrator::new(bucket_size, now(), span_kinds.clone(), Vec::new()),
+            pending: Vec::new(),
+            bucket_size,
+            span_kinds,
+            peer_tags: Vec::new(),
             meta,
             agent_url,
+            intake: None,
             sequence: 0,
         }
     }
 
+    /// Set the span kinds eligible for stats. An empty list restores the
+    /// defaults (`client`, `server`, `producer`, `consumer`).
+    pub fn set_span_kinds(&mut self, span_kinds: Vec<String>) {
+        let span_kinds = if span_kinds.is_empty() {
+            DEFAULT_SPAN_KINDS.iter().map(|k| k.to_string()).collect()
+        } else {
+            span_kinds
+        };
+        if span_kinds != self.span_kinds {
+            self.concentrator.set_span_kinds(span_kinds.clone());
+            self.span_kinds = span_kinds;
+        }
+    }
+
+    /// Set the peer tag keys (as advertised by the agent's `/info`
+    /// `peer_tags`) used to aggregate client/producer spans.
+    pub fn set_peer_tags(&mut self, peer_tags: Vec<String>) {
+        if peer_tags != self.peer_tags {
+            self.concentrator.set_peer_tags(peer_tags.clone());
+            self.peer_tags = peer_tags;
+        }
+    }
+
+    /// Change the bucket duration. The concentrator can't be resized, so it is
+    /// replaced; whatever the old one aggregated is flushed into `pending` so
+    /// no stats are lost.
+    pub fn set_bucket_size(&mut self, bucket_size: Duration) {
+        if bucket_size == self.bucket_size {
+            return;
+        }
+        let mut concentrator = SpanConcentrator::new(
+            bucket_size,
+            now(),
+            self.span_kinds.clone(),
+            self.peer_tags.clone(),
+        );
+        std::mem::swap(&mut self.concentrator, &mut concentrator);
+        self.pending.extend(concentrator.flush(now(), true));
+        self.bucket_size = bucket_size;
+    }
+
+    pub fn meta_mut(&mut self) -> &mut StatsMeta {
+        &mut self.meta
+    }
+
+    /// Send stats to the intake (agentless) instead of the agent.
+    pub fn set_intake(&mut self, intake: Option<Intake>) {
+        self.intake = intake;
+    }
+
     /// Add spans to the concentrator for stats aggregation.
     ///
     /// The spans should already have `_dd.top_level` and `_dd.measured` metrics
@@ -78,14 +149,19 @@ impl StatsCollector {
 
     /// Drain aggregated stats into a ready-to-send request, **synchronously**.
     ///
-    /// Returns `Ok(None)` when there is nothing to flush. The concentrator is
+    /// Returns the request and the number of buckets it carries, or `Ok(None)`
+    /// when there is nothing to flush. The concentrator is
     /// drained and the sequence advanced as part of this call, so the returned
     /// request must be sent (see `send_request`). Kept synchronous and separate
     /// from the send so a caller can build the request under a brief borrow and
     /// release the collector *before* the async send — leaving it available for
     /// `add_spans` while the stats request is in flight.
-    pub fn prepare_request(&mut self, force: bool) -> Result<Option<http::Request<Bytes>>, String> {
-        let buckets = self.concentrator.flush(now(), force);
+    pub fn prepare_request(
+        &mut self,
+        force: bool,
+    ) -> Result<Option<(http::Request<Bytes>, usize)>, PipelineError> {
+        let mut buckets = std::mem::take(&mut self.pending);
+        buckets.extend(self.concentrator.flush(now(), force));
         if buckets.is_empty() {
             return Ok(None);
         }
@@ -93,34 +169,78 @@ impl StatsCollector {
         self.sequence += 1;
         let payload = encode_stats_payload(&buckets, &self.meta, self.sequence);
 
-        let body = rmp_serde::encode::to_vec_named(&payload)
-            .map_err(|e| format!("stats msgpack encode error: {e}"))?;
+        // The intake takes the agent's envelope around the tracer payload.
+        let body = match &self.intake {
+            Some(_) => rmp_serde::encode::to_vec_named(&pb::StatsPayload {
+                agent_hostname: self.meta.hostname.clone(),
+                agent_env: self.meta.env.clone(),
+                stats: vec![payload],
+                client_computed: true,
+                ..Default::default()
+            }),
+            None => rmp_serde::encode::to_vec_named(&payload),
+        }
+        .map_err(|e| PipelineError::serialization(format!("stats msgpack encode error: {e}")))?;
 
-        let stats_url = format!("{}{}", self.agent_url, STATS_ENDPOINT_PATH);
+        let stats_url = match &self.intake {
+            Some(intake) => intake.stats_url(),
+            None => format!("{}{}", self.agent_url, STATS_ENDPOINT_PATH),
+        };
         let uri: http::Uri = stats_url
             .parse()
-            .map_err(|e| format!("invalid stats URL: {e}"))?;
+            .map_err(|e| PipelineError::invalid_input(format!("invalid stats URL: {e}")))?;
 
-        let req = http::Request::builder()
-            .method(http::Method::PUT)
+        let method = match self.intake {
+            Some(_) => http::Method::POST,
+            None => http::Method::PUT,
+        };
+        let mut builder = http::Request::builder()
+            .method(method)
             .uri(uri)
             .header("Content-Type", "application/msgpack")
             .header("Datadog-Meta-Lang", &self.meta.lang)
-            .header("Datadog-Meta-Tracer-Version", &self.meta.tracer_version)
+            .header("Datadog-Meta-Tracer-Version", &self.meta.tracer_version);
+        if let Some(intake) = &self.intake {
+            let (name, key) = intake.api_key_header();
+            builder = builder.header(name, key);
+        }
+        let container = &self.meta.container;
+        for (name, value) in [
+            ("Datadog-Container-ID", &container.container_id),
+            ("Datadog-Entity-ID", &container.entity_id),
+            ("Datadog-External-Env", &container.external_env),
+        ] {
+            if !value.is_empty() {
+                builder = builder.header(name, value);
+            }
+        }
+        let req = builder
             .body(Bytes::from(body))
-            .map_err(|e| format!("failed to build stats request: {e}"))?;
+            .map_err(|e| {
+                PipelineError::new(
+                    ErrorKind::Internal,
+                    format!("failed to build stats request: {e}"),
+                )
+            })?;
 
-        Ok(Some(req))
+        Ok(Some((req, buckets.len())))
     }
 
     /// Send a prepared stats request to the agent. Does **not** borrow the
     /// collector, so trace export (`add_spans`) can proceed during the await.
-    pub async fn send_request(req: http::Request<Bytes>) -> Result<(), String> {
+    pub async fn send_request(req: http::Request<Bytes>) -> Result<(), PipelineError> {
         let client = WasmHttpClient::new_client();
-        client
+        let resp = client
             .request(req)
             .await
-            .map_err(|e| format!("stats send error: {e:?}"))?;
+            .map_err(|e| PipelineError::from_http("stats send error", &e))?;
+        if !resp.status().is_success() {
+            return Err(PipelineError::http_status(
+                format!("stats send error: agent responded with {}", resp.status()),
+                resp.status().as_u16(),
+                libdatadog_nodejs_capabilities::http::parse_retry_after(resp.headers()),
+            ));
+        }
         Ok(())
     }
 }
@@ -142,12 +262,12 @@ fn encode_stats_payload(
         sequence,
         stats: buckets.to_vec(),
         service: meta.service.clone(),
-        container_id: String::new(),
-        tags: Vec::new(),
+        container_id: meta.container.container_id.clone(),
+        tags: meta.tags.clone(),
         agent_aggregation: String::new(),
-        git_commit_sha: String::new(),
-        image_tag: String::new(),
-        process_tags: String::new(),
-        process_tags_hash: 0,
+        git_commit_sha: meta.git_commit_sha.clone(),
+        image_tag: meta.container.image_tag.clone(),
+        process_tags: meta.process_tags.clone(),
+        process_tags_hash: meta.process_tags_hash,
     }
 }
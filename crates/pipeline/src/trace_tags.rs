@@ -0,0 +1,109 @@
+// Copyright 2026-Present Datadog, Inc. https://www.datadoghq.com/
+// SPDX-License-Identifier: Apache-2.0
+
+//! Trace-level attributes of a local trace.
+//!
+//! The `SetTraceMetaAttr`, `SetTraceMetricsAttr` and `SetTraceOrigin` ops set
+//! attributes shared by every span of a segment. libdatadog would keep them
+//! on its `Segment`, which it has no way to drop, so the pipeline applies
+//! those ops itself (see `change_queue`) and keeps the attributes with the
+//! segment's other state in the `SpanIndex`, gone with its last span. They
+//! are copied onto the root of each chunk prepared from the segment.
+
+use std::collections::HashMap;
+
+use libdd_trace_utils::span::v04::Span;
+
+use crate::span_string::SpanString;
+use crate::trace_data::WasmTraceData;
+
+const ORIGIN_KEY: &str = "_dd.origin";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TraceTags {
+    meta: HashMap<SpanString, SpanString>,
+    metrics: HashMap<SpanString, f64>,
+    origin: Option<SpanString>,
+}
+
+impl TraceTags {
+    pub fn set_meta(&mut self, key: SpanString, value: SpanString) {
+        self.meta.insert(key, value);
+    }
+
+    pub fn set_metric(&mut self, key: SpanString, value: f64) {
+        self.metrics.insert(key, value);
+    }
+
+    pub fn set_origin(&mut self, origin: SpanString) {
+        self.origin = Some(origin);
+    }
+
+    pub fn meta(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.meta.iter().map(|(k, v)| (&*k.0, &*v.0))
+    }
+
+    pub fn metrics(&self) -> impl Iterator<Item = (&str, f64)> {
+        self.metrics.iter().map(|(k, v)| (&*k.0, *v))
+    }
+
+    pub fn meta_attr(&self, key: &str) -> Option<&str> {
+        self.meta.get(key).map(|v| &*v.0)
+    }
+
+    pub fn metric_attr(&self, key: &str) -> Option<f64> {
+        self.metrics.get(key).copied()
+    }
+
+    pub fn origin(&self) -> Option<&str> {
+        self.origin.as_ref().map(|o| &*o.0)
+    }
+
+    /// Copy the attributes onto `root`, keeping values the span already has.
+    /// The origin goes in as `_dd.origin`.
+    pub fn apply_to(&self, root: &mut Span<WasmTraceData>) {
+        for (key, value) in &self.meta {
+            if !root.meta.contains_key(&*key.0) {
+                root.meta.insert(key.clone(), value.clone());
+            }
+        }
+        for (key, value) in &self.metrics {
+            if !root.metrics.contains_key(&*key.0) {
+                root.metrics.insert(key.clone(), *value);
+            }
+        }
+        if let Some(origin) = &self.origin {
+            if !root.meta.contains_key(ORIGIN_KEY) {
+                root.meta.insert(ORIGIN_KEY.into(), origin.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampling::SAMPLING_PRIORITY_KEY;
+
+    #[test]
+    fn copies_onto_the_root_without_overriding_it() {
+        let mut tags = TraceTags::default();
+        tags.set_meta("_dd.p.dm".into(), "-3".into());
+        tags.set_meta("env".into(), "prod".into());
+        tags.set_meta("env".into(), "prod-2".into());
+        tags.set_metric(SAMPLING_PRIORITY_KEY.into(), 2.0);
+        tags.set_origin("synthetics".into());
+        assert_eq!(tags.meta_attr("env"), Some("prod-2"));
+        assert_eq!(tags.metric_attr(SAMPLING_PRIORITY_KEY), Some(2.0));
+        assert_eq!(tags.origin(), Some("synthetics"));
+
+        let mut root = Span::<WasmTraceData>::default();
+        root.meta.insert("_dd.p.dm".into(), "-4".into());
+        tags.apply_to(&mut root);
+
+        assert_eq!(root.meta.get("_dd.p.dm").map(|v| &*v.0), Some("-4"));
+        assert_eq!(root.meta.get("env").map(|v| &*v.0), Some("prod-2"));
+        assert_eq!(root.meta.get(ORIGIN_KEY).map(|v| &*v.0), Some("synthetics"));
+        assert_eq!(root.metrics.get(SAMPLING_PRIORITY_KEY), Some(&2.0));
+    }
+}
@@ -1,3 +1,10 @@
+use std::future::Future;
+use std::task::Poll;
+use std::time::{Duration, SystemTime};
+
+use libdatadog_nodejs_capabilities::WasmSleepCapability;
+use libdd_capabilities::sleep::SleepCapability;
+
 pub trait FromBytes: Sized {
     type Bytes: ?Sized;
     fn from_bytes(bytes: &[u8]) -> Self;
@@ -42,3 +49,43 @@ pub(crate) fn get_num<T: Copy + FromBytes>(buf: &[u8], index: &mut usize) -> Opt
     *index += size;
     Some(result)
 }
+
+/// Wall-clock now() for wasm. `std::time::SystemTime::now()` is unimplemented on
+/// `wasm32-unknown-unknown` (it panics/traps), so derive the time from JS
+/// `Date.now()` (milliseconds since the Unix epoch).
+pub(crate) fn now() -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_millis(js_sys::Date::now() as u64)
+}
+
+/// [`now`] as nanoseconds since the Unix epoch.
+pub(crate) fn now_ns() -> u64 {
+    now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+/// Run `fut` until it completes or `timeout` elapses (timed with the JS-backed
+/// [`WasmSleepCapability`]). Returns `None` on timeout, dropping `fut`.
+pub(crate) async fn with_deadline<F: Future>(fut: F, timeout: Duration) -> Option<F::Output> {
+    let sleep = WasmSleepCapability::new();
+    race(async { Some(fut.await) }, async {
+        sleep.sleep(timeout).await;
+        None
+    })
+    .await
+}
+
+/// Poll both futures and return the output of whichever finishes first
+/// (`a` wins a tie), dropping the other.
+pub(crate) async fn race<T>(a: impl Future<Output = T>, b: impl Future<Output = T>) -> T {
+    let mut a = std::pin::pin!(a);
+    let mut b = std::pin::pin!(b);
+    std::future::poll_fn(|cx| {
+        if let Poll::Ready(out) = a.as_mut().poll(cx) {
+            return Poll::Ready(out);
+        }
+        b.as_mut().poll(cx)
+    })
+    .await
+}
@@ -91,6 +91,11 @@ class Span {
     return this
   }
 
+  queueMetaStruct (key, bytes) {
+    this.nativeSpans.queueOp(OpCode.SetMetaStruct, this.spanId, ['str', key], ['bytes', bytes])
+    return this
+  }
+
   getMetaStruct (key) {
     return this.nativeSpans.state.getMetaStruct(this.spanIdBig, key)
   }
@@ -105,10 +110,52 @@ class Span {
     return this
   }
 
+  queueSpanEvent (name, timeUnixNano, attributes = {}) {
+    this.nativeSpans.queueOp(
+      OpCode.AddSpanEvent,
+      this.spanId,
+      ['u64n', timeUnixNano],
+      ['str', name],
+      ['bytes', encodeSpanEventAttrs(attributes)],
+    )
+    return this
+  }
+
   getSpanEvents () {
     return JSON.parse(this.nativeSpans.state.getSpanEventsJson(this.spanIdBig))
   }
 
+  addSpanLink (linked, { tracestate = '', flags = 0, attributes = {} } = {}) {
+    this.nativeSpans.state.addSpanLink(
+      this.spanIdBig,
+      bytesToBigInt(linked.traceId[0]),
+      bytesToBigInt(linked.traceId[1]),
+      linked.spanIdBig,
+      tracestate,
+      flags,
+      encodeSpanEventAttrs(attributes),
+    )
+    return this
+  }
+
+  queueSpanLink (linked, { tracestate = '', flags = 0, attributes = {} } = {}) {
+    this.nativeSpans.queueOp(
+      OpCode.AddSpanLink,
+      this.spanId,
+      ['u64', linked.traceId[0]],
+      ['u64', linked.traceId[1]],
+      ['u64n', linked.spanIdBig],
+      ['u32n', flags],
+      ['str', tracestate],
+      ['bytes', encodeSpanEventAttrs(attributes)],
+    )
+    return this
+  }
+
+  getSpanLinks () {
+    return JSON.parse(this.nativeSpans.state.getSpanLinksJson(this.spanIdBig))
+  }
+
   finish () {
     this.duration = BigInt(Date.now()) * 1_000_000n - this._startTime
     return this
@@ -291,6 +338,15 @@ class NativeSpansInterface {
             this.cqbIndex += 8
             break
           }
+          case 'bytes': // [len: u32][bytes], for the extended opcodes
+          case 'str': {
+            const bytes = typ === 'str' ? Buffer.from(num) : num
+            view.setUint32(this.cqbIndex, bytes.length, true)
+            this.cqbIndex += 4
+            new Uint8Array(view.buffer, view.byteOffset + this.cqbIndex, bytes.length).set(bytes)
+            this.cqbIndex += bytes.length
+            break
+          }
           default: {
             throw new Error('unsupported number type: ' + typ)
           }
@@ -306,7 +362,9 @@ class NativeSpansInterface {
     return new Span(this, traceId, parentId)
   }
 
-  async flushSpans (...spans) {
+  // Prepare `spans` as one chunk, the first being the local root. Returns
+  // whether a send is due.
+  prepare (...spans) {
     this.flushBuffer.fill(0) // TODO is this necessary, since we're sending the length?
     let index = 0
     for (const span of spans) {
@@ -317,7 +375,11 @@ class NativeSpansInterface {
       }
       index += 8
     }
-    const hasSpans = this.state.prepareChunk(spans.length, true, this.flushBuffer)
+    return this.state.prepareChunk(spans.length, true, this.flushBuffer)
+  }
+
+  async flushSpans (...spans) {
+    const hasSpans = this.prepare(...spans)
     if (!hasSpans) return false
     return this.state.sendPreparedChunk()
   }
@@ -373,6 +435,44 @@ function encodeSpanEventAttrs (attributes) {
   return new Uint8Array(Buffer.concat(chunks))
 }
 
+// Stand-in agent (or intake, or OTLP collector with `http2`) on a local port,
+// closed together with the transport's kept-alive sockets once `fn(agent)`
+// settles. Every request is recorded in `agent.requests` as
+// `{ method, url, headers, body }`, trace payloads (POSTs to `.../traces`) also
+// in `agent.traces`, and `agent.connections` counts the accepted connections.
+// `respond(request, agent)` picks the answer as
+// `{ status, headers, body, trailers }` (by default a 200 with `{}`), or `null`
+// to leave the request hanging.
+async function withMockAgent ({ respond, http2 = false } = {}, fn) {
+  const onRequest = (req, res) => {
+    const chunks = []
+    req.on('data', c => chunks.push(c))
+    req.on('end', () => {
+      const request = { method: req.method, url: req.url, headers: req.headers, body: Buffer.concat(chunks) }
+      agent.requests.push(request)
+      if (req.method === 'POST' && req.url.endsWith('/traces')) agent.traces.push(request)
+      const answer = respond ? respond(request, agent) : {}
+      if (answer === null) return
+      res.writeHead(answer.status ?? 200, { 'content-type': 'application/json', ...answer.headers })
+      if (answer.trailers) res.addTrailers(answer.trailers)
+      res.end(answer.body ?? '{}')
+    })
+  }
+  const server = http2
+    ? require('node:http2').createServer(onRequest)
+    : require('node:http').createServer(onRequest)
+  server.on('connection', () => agent.connections++)
+  await new Promise(resolve => server.listen(0, '127.0.0.1', resolve))
+  const agent = { url: `http://127.0.0.1:${server.address().port}`, requests: [], traces: [], connections: 0 }
+  try {
+    return await fn(agent)
+  } finally {
+    pipeline.closeIdleConnections()
+    server.closeAllConnections?.()
+    server.close()
+  }
+}
+
 describe('pipeline', { skip }, () => {
   let nativeSpans
 
@@ -398,7 +498,7 @@ describe('pipeline', { skip }, () => {
         'Create', 'SetMetaAttr', 'SetMetricAttr', 'SetServiceName',
         'SetResourceName', 'SetError', 'SetStart', 'SetDuration',
         'SetType', 'SetName', 'SetTraceMetaAttr', 'SetTraceMetricsAttr',
-        'SetTraceOrigin',
+        'SetTraceOrigin', 'SetMetaStruct', 'AddSpanEvent', 'AddSpanLink',
       ]
       for (const opCode of expectedOpCodes) {
         assert.strictEqual(typeof OpCode[opCode], 'number')
@@ -489,6 +589,28 @@ describe('pipeline', { skip }, () => {
 
       assert.deepStrictEqual(span.getMetaStruct('k'), new Uint8Array([9]))
     })
+
+    it('applies queued SetMetaStruct ops in order with the other ops', () => {
+      const span = nativeSpans.createSpan()
+      span.queueMetaStruct('k', new Uint8Array([1, 2, 3]))
+      span.setTag('after', 'yes')
+      span.queueMetaStruct('k', new Uint8Array([9]))
+      span.queueMetaStruct('other', new Uint8Array([]))
+
+      assert.deepStrictEqual(span.getMetaStruct('k'), new Uint8Array([9]))
+      assert.deepStrictEqual(span.getMetaStruct('other'), new Uint8Array([]))
+      assert.strictEqual(span.getTag('after'), 'yes')
+    })
+
+    it('rejects a truncated queued entry and empties the queue', () => {
+      const span = nativeSpans.createSpan()
+      // key length far past the end of the change queue
+      nativeSpans.queueOp(OpCode.SetMetaStruct, span.spanId, ['u32n', 0xFFFF_FFFF])
+      assert.throws(() => nativeSpans.state.flushChangeQueue(), /truncated entry/)
+
+      span.queueMetaStruct('k', new Uint8Array([7]))
+      assert.deepStrictEqual(span.getMetaStruct('k'), new Uint8Array([7]))
+    })
   })
 
   describe('span_events', () => {
@@ -550,6 +672,32 @@ describe('pipeline', { skip }, () => {
       assert.deepStrictEqual(events[1].attributes.k, { type: 0, string_value: 'v' })
     })
 
+    it('applies queued AddSpanEvent ops in queue order', () => {
+      const span = nativeSpans.createSpan()
+      span.queueSpanEvent('first', 1n)
+      span.addSpanEvent('second', 2n)
+      span.queueSpanEvent('third', 3n, { k: 'v', n: [1, 2] })
+
+      const events = span.getSpanEvents()
+      assert.deepStrictEqual(events.map(e => e.name), ['first', 'second', 'third'])
+      assert.strictEqual(events[2].time_unix_nano, 3)
+      assert.deepStrictEqual(events[2].attributes.k, { type: 0, string_value: 'v' })
+      assert.strictEqual(events[2].attributes.n.type, 4)
+    })
+
+    it('rejects a queued event with a malformed attribute blob', () => {
+      const span = nativeSpans.createSpan()
+      nativeSpans.queueOp(
+        OpCode.AddSpanEvent,
+        span.spanId,
+        ['u64n', 1n],
+        ['str', 'evt'],
+        ['bytes', new Uint8Array([5, 0, 0, 0])],
+      )
+      assert.throws(() => nativeSpans.state.flushChangeQueue(), /truncated span-event attribute buffer/)
+      assert.deepStrictEqual(span.getSpanEvents(), [])
+    })
+
     it('returns an empty array for a span with no events', () => {
       const span = nativeSpans.createSpan()
       assert.deepStrictEqual(span.getSpanEvents(), [])
@@ -578,6 +726,116 @@ describe('pipeline', { skip }, () => {
     })
   })
 
+  describe('span_links', () => {
+    // Small, exactly-representable ids so the JSON round-trip (f64 numbers)
+    // can be compared without precision loss.
+    function fakeLinked (high, low, spanId) {
+      const be = (n) => {
+        const b = Buffer.alloc(8)
+        b.writeBigUInt64BE(BigInt(n), 0)
+        return new Uint8Array(b)
+      }
+      return { traceId: [be(high), be(low)], spanIdBig: BigInt(spanId) }
+    }
+
+    it('appends a link with trace id halves, tracestate and flags', () => {
+      const span = nativeSpans.createSpan()
+      span.addSpanLink(fakeLinked(1, 2, 3), { tracestate: 'dd=s:1', flags: 0x80_00_00_01 })
+
+      const links = span.getSpanLinks()
+      assert.strictEqual(links.length, 1)
+      assert.strictEqual(links[0].trace_id_high, 1)
+      assert.strictEqual(links[0].trace_id, 2)
+      assert.strictEqual(links[0].span_id, 3)
+      assert.strictEqual(links[0].tracestate, 'dd=s:1')
+      assert.strictEqual(links[0].flags, 0x80_00_00_01)
+    })
+
+    it('stringifies typed attributes and flattens arrays', () => {
+      const span = nativeSpans.createSpan()
+      span.addSpanLink(fakeLinked(0, 7, 8), {
+        attributes: { s: 'x', b: false, i: 42, d: 2.5, arr: ['a', 1] },
+      })
+
+      const [link] = span.getSpanLinks()
+      assert.deepStrictEqual(link.attributes, {
+        's': 'x',
+        'b': 'false',
+        'i': '42',
+        'd': '2.5',
+        'arr.0': 'a',
+        'arr.1': '1',
+      })
+    })
+
+    it('appends multiple links in order', () => {
+      const span = nativeSpans.createSpan()
+      span.addSpanLink(fakeLinked(0, 1, 1))
+      span.addSpanLink(fakeLinked(0, 2, 2))
+
+      assert.deepStrictEqual(span.getSpanLinks().map(l => l.span_id), [1, 2])
+    })
+
+    it('applies queued AddSpanLink ops in queue order', () => {
+      const span = nativeSpans.createSpan()
+      span.queueSpanLink(fakeLinked(1, 2, 3), {
+        tracestate: 'dd=s:1',
+        flags: 0x80_00_00_01,
+        attributes: { arr: ['a', 1] },
+      })
+      span.addSpanLink(fakeLinked(0, 4, 4))
+      span.queueSpanLink(fakeLinked(0, 5, 5))
+
+      const links = span.getSpanLinks()
+      assert.deepStrictEqual(links.map(l => l.span_id), [3, 4, 5])
+      assert.strictEqual(links[0].trace_id_high, 1)
+      assert.strictEqual(links[0].trace_id, 2)
+      assert.strictEqual(links[0].tracestate, 'dd=s:1')
+      assert.strictEqual(links[0].flags, 0x80_00_00_01)
+      assert.deepStrictEqual(links[0].attributes, { 'arr.0': 'a', 'arr.1': '1' })
+    })
+
+    it('returns an empty array for a span with no links', () => {
+      const span = nativeSpans.createSpan()
+      assert.deepStrictEqual(span.getSpanLinks(), [])
+    })
+
+    it('rejects a truncated attribute buffer instead of panicking', () => {
+      const span = nativeSpans.createSpan()
+      const bad = new Uint8Array([5, 0, 0, 0])
+      assert.throws(
+        () => span.nativeSpans.state.addSpanLink(span.spanIdBig, 0n, 1n, 1n, '', 0, bad),
+        /addSpanLink: truncated span-link attribute buffer/,
+      )
+    })
+
+    it('throws for an unknown span id', () => {
+      assert.throws(
+        () => nativeSpans.state.addSpanLink(0xDE_AD_BE_EFn, 0n, 1n, 1n, '', 0, new Uint8Array()),
+      )
+    })
+
+    it('serializes links into the v0.4 payload', async () => {
+      await withMockAgent({}, async (agent) => {
+        const ns = new NativeSpansInterface({ agentUrl: agent.url })
+        const span = ns.createSpan()
+        span.name = 'link-span'
+        span.duration = 1_000_000n
+        span.addSpanLink(fakeLinked(0, 9, 10), {
+          tracestate: 'dd=link-state',
+          attributes: { 'link.kind': 'producer' },
+        })
+
+        await ns.flushSpans(span)
+        assert.strictEqual(agent.traces.length, 1, 'agent received a trace payload')
+        const { body } = agent.traces[0]
+        assert.ok(body.includes('span_links'), 'payload carries span_links')
+        assert.ok(body.includes('dd=link-state'), 'payload carries the tracestate')
+        assert.ok(body.includes('link.kind'), 'payload carries the link attributes')
+      })
+    })
+  })
+
   describe('span timing', () => {
     it('should set and get start time', () => {
       const span = nativeSpans.createSpan()
@@ -644,6 +902,61 @@ describe('pipeline', { skip }, () => {
     })
   })
 
+  describe('span snapshot', () => {
+    it('returns every span field and the trace attributes in one call', () => {
+      const span = nativeSpans.createSpan()
+      span.name = 'web.request'
+      span.service = 'svc'
+      span.resource = 'GET /'
+      span.type = 'web'
+      span.error = 1
+      span.setTag('http.method', 'GET')
+      span.setTag('http.status_code', 200)
+      span.setTraceTag('_dd.p.dm', '-0')
+      span.setTraceTag('_sampling_priority_v1', 1)
+      span.setTraceOrigin('synthetics')
+      span.queueMetaStruct('appsec', new Uint8Array([0x80]))
+      span.queueSpanEvent('exception', 5n)
+
+      const snapshot = nativeSpans.state.getSpanSnapshot(span.spanIdBig)
+      assert.strictEqual(snapshot.spanId, span.spanIdBig)
+      assert.strictEqual(snapshot.traceIdHigh, bytesToBigInt(span.traceId[0]))
+      assert.strictEqual(snapshot.traceIdLow, bytesToBigInt(span.traceId[1]))
+      assert.strictEqual(snapshot.segmentId, span.segmentId)
+      assert.strictEqual(snapshot.start, span._startTime)
+      assert.strictEqual(snapshot.name, 'web.request')
+      assert.strictEqual(snapshot.service, 'svc')
+      assert.strictEqual(snapshot.resource, 'GET /')
+      assert.strictEqual(snapshot.type, 'web')
+      assert.strictEqual(snapshot.error, 1)
+      assert.strictEqual(snapshot.meta['http.method'], 'GET')
+      assert.strictEqual(snapshot.metrics['http.status_code'], 200)
+      assert.deepStrictEqual(snapshot.metaStructKeys, ['appsec'])
+      assert.deepStrictEqual(snapshot.spanEvents.map(e => e.name), ['exception'])
+      assert.deepStrictEqual(snapshot.spanLinks, [])
+      assert.strictEqual(snapshot.trace.meta['_dd.p.dm'], '-0')
+      assert.strictEqual(snapshot.trace.metrics._sampling_priority_v1, 1)
+      assert.strictEqual(snapshot.trace.origin, 'synthetics')
+    })
+
+    it('returns a msgpack map when encoded', () => {
+      const span = nativeSpans.createSpan()
+      span.name = 'encoded'
+
+      const bytes = nativeSpans.state.getSpanSnapshot(span.spanIdBig, true)
+      assert(bytes instanceof Uint8Array)
+      // map16 header: the snapshot has more than 15 fields
+      assert.strictEqual(bytes[0], 0xDE)
+      const text = Buffer.from(bytes).toString('latin1')
+      assert(text.includes('metaStructKeys'))
+      assert(text.includes('encoded'))
+    })
+
+    it('throws for an unknown span id', () => {
+      assert.throws(() => nativeSpans.state.getSpanSnapshot(0xDE_AD_BE_EFn))
+    })
+  })
+
   describe('absent values and error handling', () => {
     it('returns null for tags that were never set', () => {
       const span = nativeSpans.createSpan()
@@ -810,6 +1123,259 @@ describe('pipeline', { skip }, () => {
     })
   })
 
+  describe('sampling rules', () => {
+    it('rejects malformed rules', () => {
+      const ns = new NativeSpansInterface()
+      assert.throws(() => ns.state.setSamplingRules('{'), /setSamplingRules/)
+      assert.throws(() => ns.state.setSamplingRules('[{"sample_rate": 2}]'), /between 0 and 1/)
+      assert.doesNotThrow(() => ns.state.setSamplingRules('[]'))
+    })
+
+    it('applies the first matching rule to the chunk root', async () => {
+      await withMockAgent({}, async (agent) => {
+        const ns = new NativeSpansInterface({ agentUrl: agent.url })
+        ns.state.setSamplingRules(JSON.stringify([
+          { service: 'rules-*', name: 'web.request', sample_rate: 1 },
+        ]), 10)
+        const span = ns.createSpan()
+        span.name = 'web.request'
+        span.service = 'rules-svc'
+        span.duration = 1_000_000n
+        await ns.flushSpans(span)
+        const { body } = agent.traces[0]
+        assert.ok(body.includes('_dd.rule_psr'), 'root carries the rule rate')
+        assert.ok(body.includes('_dd.limit_psr'), 'root carries the limiter rate')
+        assert.ok(body.includes('_dd.p.dm'), 'root carries the decision maker')
+      })
+    })
+
+    it('samples a trace flushed over several chunks once', async () => {
+      await withMockAgent({}, async (agent) => {
+        const ns = new NativeSpansInterface({ agentUrl: agent.url })
+        // One trace per second: a second decision would be rate limited.
+        ns.state.setSamplingRules(JSON.stringify([{ service: 'rules-*', sample_rate: 1 }]), 1)
+        const root = ns.createSpan()
+        root.name = 'web.request'
+        root.service = 'rules-svc'
+        const late = ns.createSpan(root.traceId, root.spanId)
+        late.name = 'late.child'
+        late.service = 'rules-svc'
+        root.finish()
+        await ns.flushSpans(root)
+        late.finish()
+        await ns.flushSpans(late)
+
+        assert.ok(agent.traces[0].body.includes('_dd.rule_psr'), 'the first chunk is sampled')
+        const { body } = agent.traces[1]
+        assert.ok(body.includes('late.child'))
+        assert.ok(!body.includes('_dd.rule_psr'), 'the later chunk is not sampled again')
+        assert.ok(!body.includes('_dd.limit_psr'), 'nor rate limited')
+      })
+    })
+  })
+
+  describe('single span sampling', () => {
+    function flushTrace (configure) {
+      return withMockAgent({}, async (agent) => {
+        const ns = new NativeSpansInterface({ agentUrl: agent.url })
+        configure(ns.state)
+        const root = ns.createSpan()
+        root.name = 'span-sampling-root'
+        root.service = 'ss-svc'
+        root.duration = 2_000_000n
+        const child = ns.createSpan(root.traceId, root.spanId)
+        child.name = 'span-sampling-child'
+        child.service = 'ss-db'
+        child.duration = 1_000_000n
+        await ns.flushSpans(root, child)
+        return agent.traces[0].body
+      })
+    }
+
+    it('rejects malformed rules', () => {
+      const ns = new NativeSpansInterface()
+      assert.throws(() => ns.state.setSpanSamplingRules('[{"sample_rate": 3}]'), /setSpanSamplingRules/)
+    })
+
+    it('sends only the matching spans of a rejected chunk', async () => {
+      const body = await flushTrace((state) => {
+        state.setSamplingRules('[{"service": "ss-svc", "sample_rate": 0}]')
+        state.setSpanSamplingRules('[{"service": "ss-db", "max_per_second": 50}]')
+      })
+      assert.ok(body.includes('span-sampling-child'), 'matching span is kept')
+      assert.ok(!body.includes('span-sampling-root'), 'non-matching span is dropped')
+      assert.ok(body.includes('_dd.span_sampling.mechanism'))
+      assert.ok(body.includes('_dd.span_sampling.rule_rate'))
+      assert.ok(body.includes('_dd.span_sampling.max_per_second'))
+    })
+
+    it('leaves kept chunks untouched', async () => {
+      const body = await flushTrace((state) => {
+        state.setSamplingRules('[{"service": "ss-svc", "sample_rate": 1}]')
+        state.setSpanSamplingRules('[{"service": "ss-db"}]')
+      })
+      assert.ok(body.includes('span-sampling-root'))
+      assert.ok(body.includes('span-sampling-child'))
+      assert.ok(!body.includes('_dd.span_sampling.mechanism'))
+    })
+  })
+
+  describe('chunk batching', () => {
+    function makeSpan (ns, name) {
+      const span = ns.createSpan()
+      span.name = name
+      span.service = 'test-service'
+      span.resource = 'test-resource'
+      span.duration = 1_000_000n
+      return span
+    }
+
+    it('accumulates chunks until the span cap and sends them in one request', async () => {
+      await withMockAgent({}, async (agent) => {
+        const ns = new NativeSpansInterface({ agentUrl: agent.url })
+        ns.state.setBatchLimits(3, 0)
+
+        assert.strictEqual(ns.prepare(makeSpan(ns, 'batch-a')), false)
+        assert.strictEqual(ns.prepare(makeSpan(ns, 'batch-b')), false)
+        assert.strictEqual(ns.state.getPreparedSpanCount(), 2)
+        assert.ok(ns.state.getPreparedEncodedSize() > 0)
+        assert.strictEqual(ns.prepare(makeSpan(ns, 'batch-c')), true, 'span cap reached')
+
+        await ns.state.sendPreparedChunk()
+        assert.strictEqual(agent.traces.length, 1, 'all chunks went out in a single request')
+        for (const name of ['batch-a', 'batch-b', 'batch-c']) {
+          assert.ok(agent.traces[0].body.includes(name), `payload carries ${name}`)
+        }
+        assert.strictEqual(ns.state.getPreparedSpanCount(), 0, 'batch drained by the send')
+      })
+    })
+
+    it('reports a send as due once the byte cap is reached', () => {
+      const ns = new NativeSpansInterface()
+      ns.state.setBatchLimits(0, 1)
+      assert.strictEqual(ns.prepare(makeSpan(ns, 'byte-cap')), true)
+    })
+
+    it('keeps the batch across an empty prepareChunk call', () => {
+      const ns = new NativeSpansInterface()
+      ns.state.setBatchLimits(10, 0)
+      ns.prepare(makeSpan(ns, 'kept'))
+      assert.strictEqual(ns.state.prepareChunk(0, true, Buffer.alloc(0)), false)
+      assert.strictEqual(ns.state.getPreparedSpanCount(), 1)
+    })
+
+    it('rejects chunks while the batch is full and takes them after the send', async () => {
+      await withMockAgent({}, async (agent) => {
+        const ns = new NativeSpansInterface({ agentUrl: agent.url })
+        ns.state.setBatchLimits(2, 0)
+        ns.prepare(makeSpan(ns, 'full-a'))
+        assert.strictEqual(ns.prepare(makeSpan(ns, 'full-b')), true)
+
+        const late = makeSpan(ns, 'full-c')
+        assert.throws(() => ns.prepare(late), err =>
+          err.name === 'BatchFullError' && err.code === 'ERR_BATCH_FULL' && err.retryable === true)
+        assert.strictEqual(ns.state.getPreparedSpanCount(), 2, 'the batch did not grow')
+        assert.strictEqual(ns.state.getMetrics().liveSpans, 1, 'the rejected span is still live')
+
+        await ns.state.sendPreparedChunk()
+        assert.strictEqual(ns.prepare(late), false)
+        await ns.state.sendPreparedChunk()
+        assert.strictEqual(agent.traces.length, 2)
+        assert.ok(agent.traces[1].body.includes('full-c'))
+      })
+    })
+
+    it('keeps the pending batch when the limits change', async () => {
+      await withMockAgent({}, async (agent) => {
+        const ns = new NativeSpansInterface({ agentUrl: agent.url })
+        ns.state.setBatchLimits(10, 0)
+        ns.prepare(makeSpan(ns, 'pending-a'))
+        ns.prepare(makeSpan(ns, 'pending-b'))
+        assert.strictEqual(ns.state.setBatchLimits(10, 0), false, 'still under the cap')
+        assert.strictEqual(ns.state.setBatchLimits(0, 0), true, 'due without batching')
+        assert.strictEqual(ns.prepare(makeSpan(ns, 'unbatched')), true)
+        assert.strictEqual(ns.state.getPreparedSpanCount(), 3)
+
+        await ns.state.sendPreparedChunk()
+        for (const name of ['pending-a', 'pending-b', 'unbatched']) {
+          assert.ok(agent.traces[0].body.includes(name), `payload carries ${name}`)
+        }
+        assert.strictEqual(ns.state.getMetrics().staleChunksRecycled, 0)
+      })
+    })
+
+    it('replaces an unsent chunk when batching is disabled', () => {
+      const ns = new NativeSpansInterface()
+      assert.strictEqual(ns.prepare(makeSpan(ns, 'first')), true)
+      assert.strictEqual(ns.prepare(makeSpan(ns, 'second')), true)
+      assert.strictEqual(ns.state.getPreparedSpanCount(), 1, 'stale chunk was recycled')
+    })
+
+    it('rejects sendPreparedChunk with an empty batch', async () => {
+      const ns = new NativeSpansInterface()
+      ns.state.setBatchLimits(10, 0)
+      await assert.rejects(ns.state.sendPreparedChunk(), /no prepared chunk to send/)
+    })
+  })
+
+  describe('agent rate sampling', () => {
+    function withAgent (rateByService, fn) {
+      const respond = () => ({ body: JSON.stringify({ rate_by_service: rateByService }) })
+      return withMockAgent({ respond }, (agent) => fn(new NativeSpansInterface({ agentUrl: agent.url }), agent.traces))
+    }
+
+    function makeSpan (ns, name) {
+      const span = ns.createSpan()
+      span.name = name
+      span.service = 'test-service'
+      span.duration = 1_000_000n
+      return span
+    }
+
+    it('keeps every trace before the agent has sent rates', () => {
+      const ns = new NativeSpansInterface()
+      assert.strictEqual(ns.state.getAgentSampleRate('test-service', 'test-env'), 1)
+    })
+
+    it('retains the latest rate_by_service from agent responses', async () => {
+      const rates = {
+        'service:test-service,env:test-env': 0.25,
+        'service:,env:': 0.5,
+      }
+      await withAgent(rates, async (ns) => {
+        await ns.flushSpans(makeSpan(ns, 'rates'))
+        assert.strictEqual(ns.state.getAgentSampleRate('test-service', 'test-env'), 0.25)
+        // Unknown service/env pairs fall back to the agent default.
+        assert.strictEqual(ns.state.getAgentSampleRate('other', 'prod'), 0.5)
+      })
+    })
+
+    it('stamps the priority and agent rate on chunk roots when enabled', async () => {
+      await withAgent({ 'service:,env:': 0 }, async (ns, posts) => {
+        ns.state.setAgentSampling(true)
+        // First send learns the (zero) default rate from the response.
+        await ns.flushSpans(makeSpan(ns, 'learn'))
+        await ns.flushSpans(makeSpan(ns, 'sampled'))
+        const { body } = posts[1]
+        assert.ok(body.includes('_sampling_priority_v1'), 'root carries a sampling priority')
+        assert.ok(body.includes('_dd.agent_psr'), 'root carries the agent rate')
+        // Rate 0 never keeps, so no decision-maker tag is added.
+        assert.ok(!body.includes('_dd.p.dm'))
+      })
+    })
+
+    it('leaves a priority set by the host untouched', async () => {
+      await withAgent({ 'service:,env:': 0 }, async (ns, posts) => {
+        ns.state.setAgentSampling(true)
+        await ns.flushSpans(makeSpan(ns, 'learn'))
+        const span = makeSpan(ns, 'manual')
+        span.setTag('_sampling_priority_v1', 2)
+        await ns.flushSpans(span)
+        assert.ok(!posts[1].body.includes('_dd.agent_psr'), 'no agent decision on a manual priority')
+      })
+    })
+  })
+
   describe('v0.5 output format', () => {
     // Spin up a mock agent that records the request path, so we can assert the
     // exporter targets /v0.4/traces by default and /v0.5/traces after
@@ -944,9 +1510,98 @@ describe('pipeline', { skip }, () => {
       }
     })
 
-    it('rejects unsupported OTLP protocols (e.g. grpc)', () => {
+    it('rejects unsupported OTLP protocols', () => {
       const ns = new NativeSpansInterface({ agentUrl: 'http://127.0.0.1:8126' })
-      assert.throws(() => ns.state.setOtlpProtocol('grpc'), /setOtlpProtocol|not supported/)
+      assert.throws(() => ns.state.setOtlpProtocol('thrift'), /setOtlpProtocol|not supported/)
+    })
+
+    describe('over gRPC', () => {
+      const EXPORT_PATH = '/opentelemetry.proto.collector.trace.v1.TraceService/Export'
+
+      // Stand-in collector on cleartext HTTP/2. `statuses` are the grpc-status
+      // codes to answer with, one per call (then OK), or `{ status, pushbackMs }`
+      // to also send a grpc-retry-pushback-ms.
+      async function withCollector (statuses, fn) {
+        const respond = () => {
+          const next = statuses.shift() ?? 0
+          const { status, pushbackMs } = typeof next === 'object' ? next : { status: next }
+          const trailers = { 'grpc-status': String(status), 'grpc-message': status ? 'try%20later' : '' }
+          if (pushbackMs !== undefined) trailers['grpc-retry-pushback-ms'] = String(pushbackMs)
+          // An empty ExportTraceServiceResponse.
+          return { headers: { 'content-type': 'application/grpc' }, trailers, body: status ? '' : Buffer.alloc(5) }
+        }
+        await withMockAgent({ respond, http2: true }, async (collector) => {
+          const ns = new NativeSpansInterface({ agentUrl: 'http://127.0.0.1:1' })
+          ns.state.setOtlpEndpoint(collector.url)
+          ns.state.setOtlpProtocol('grpc')
+          ns.state.setOtlpHeaders(['authorization', 'Bearer test-token'])
+          await fn(ns, collector.requests)
+        })
+      }
+
+      function otlpSpan (ns) {
+        const span = ns.createSpan()
+        span.name = 'grpc-span'
+        span.service = 'test-service'
+        span.duration = 1_000_000n
+        return span
+      }
+
+      it('exports with TraceService/Export', async () => {
+        await withCollector([], async (ns, calls) => {
+          await ns.flushSpans(otlpSpan(ns))
+          assert.strictEqual(calls.length, 1)
+          const [{ headers, body }] = calls
+          assert.strictEqual(headers[':path'], EXPORT_PATH)
+          assert.strictEqual(headers['content-type'], 'application/grpc')
+          assert.strictEqual(headers.authorization, 'Bearer test-token')
+          // Length-prefixed, uncompressed protobuf message.
+          assert.strictEqual(body[0], 0)
+          assert.strictEqual(body.readUInt32BE(1), body.length - 5)
+          assert.ok(body.includes('grpc-span'))
+        })
+      })
+
+      it('retries retryable status codes', async () => {
+        // UNAVAILABLE, then RESOURCE_EXHAUSTED, then OK.
+        await withCollector([14, 8], async (ns, calls) => {
+          await ns.flushSpans(otlpSpan(ns))
+          assert.strictEqual(calls.length, 3)
+        })
+      })
+
+      it('gives up with the mapped status once the retries run out', async () => {
+        await withCollector([14, 14, 14, 14, 14, 14], async (ns, calls) => {
+          await assert.rejects(ns.flushSpans(otlpSpan(ns)), (err) => {
+            assert.strictEqual(err.name, 'HttpStatusError')
+            assert.strictEqual(err.statusCode, 503, 'UNAVAILABLE')
+            assert.strictEqual(err.retryable, true)
+            assert.match(err.message, /grpc-status 14: try later/)
+            return true
+          })
+          // Four gRPC attempts, and no second round of retries on top.
+          assert.strictEqual(calls.length, 4)
+        })
+      })
+
+      it('stops retrying when the pushback exceeds the maximum backoff', async () => {
+        await withCollector([{ status: 8, pushbackMs: 60_000 }], async (ns, calls) => {
+          await assert.rejects(ns.flushSpans(otlpSpan(ns)), { statusCode: 429, retryAfterMs: 60_000 })
+          assert.strictEqual(calls.length, 1)
+        })
+      })
+
+      it('fails on a non-retryable status without retrying', async () => {
+        // INVALID_ARGUMENT.
+        await withCollector([3], async (ns, calls) => {
+          await assert.rejects(ns.flushSpans(otlpSpan(ns)), (err) => {
+            assert.strictEqual(err.name, 'HttpStatusError')
+            assert.strictEqual(err.statusCode, 400)
+            return true
+          })
+          assert.strictEqual(calls.length, 1)
+        })
+      })
     })
   })
 
@@ -993,13 +1648,1014 @@ describe('pipeline', { skip }, () => {
       }
     })
 
+    it('aggregates by the configured peer tags and bucket duration', async () => {
+      await withMockAgent({}, async (agent) => {
+        const ns = new NativeSpansInterface({ agentUrl: agent.url, statsEnabled: true })
+        ns.state.setStatsPeerTags(['peer.service', 'db.instance'])
+        ns.state.setStatsSpanKinds([])
+        const span = ns.createSpan()
+        span.name = 'pg.query'
+        span.service = 'stats-svc'
+        span.duration = 5_000_000n
+        span.setTag('span.kind', 'client')
+        span.setTag('peer.service', 'orders-db')
+
+        await ns.flushSpans(span)
+        // Resizing buckets keeps what was already aggregated.
+        ns.state.setStatsBucketDuration(2000)
+        assert.strictEqual(await ns.state.flushStats(true), true)
+        const stats = agent.requests.find(r => r.url === '/v0.6/stats')
+        assert.ok(stats.body.includes('peer.service:orders-db'), 'peer tag is aggregated')
+      })
+    })
+
+    it('reports container, git and process-tags metadata', async () => {
+      await withMockAgent({}, async (agent) => {
+        const ns = new NativeSpansInterface({ agentUrl: agent.url, statsEnabled: true })
+        ns.state.setStatsContainerInfo('c0ffee', 'ci-c0ffee', '', 'my-image:1.2')
+        ns.state.setStatsTags(['team:apm'], 'abc123def')
+        ns.state.setStatsProcessTags('entrypoint.name:server', 42n)
+        const span = ns.createSpan()
+        span.name = 'meta-span'
+        span.service = 'stats-svc'
+        span.duration = 5_000_000n
+
+        await ns.flushSpans(span)
+        assert.strictEqual(await ns.state.flushStats(true), true)
+        const { headers, body } = agent.requests.find(r => r.url === '/v0.6/stats')
+        assert.strictEqual(headers['datadog-container-id'], 'c0ffee')
+        assert.strictEqual(headers['datadog-entity-id'], 'ci-c0ffee')
+        assert.strictEqual(headers['datadog-external-env'], undefined, 'empty fields are omitted')
+        for (const value of ['c0ffee', 'my-image:1.2', 'team:apm', 'abc123def', 'entrypoint.name:server']) {
+          assert.ok(body.includes(value), `payload carries ${value}`)
+        }
+      })
+    })
+
+    it('rejects a zero bucket duration', () => {
+      const ns = new NativeSpansInterface({ statsEnabled: true })
+      assert.throws(() => ns.state.setStatsBucketDuration(0), /setStatsBucketDuration/)
+    })
+
     it('flushStats returns false when stats are disabled', async () => {
       const ns = new NativeSpansInterface({ statsEnabled: false })
       assert.strictEqual(await ns.state.flushStats(true), false)
     })
   })
 
+  describe('discarding traces', () => {
+    it('discardChunk frees the given spans without exporting them', () => {
+      const ns = new NativeSpansInterface()
+      const root = ns.createSpan()
+      const child = ns.createSpan(root.traceId, root.spanId)
+      const kept = ns.createSpan()
+      ns.state.flushChangeQueue()
+      const liveBefore = ns.state.getMetrics().liveSpans
+
+      const ids = new BigUint64Array([root.spanIdBig, child.spanIdBig, child.spanIdBig, 0xDE_AD_BE_EFn])
+      assert.strictEqual(ns.state.discardChunk(ids), 2)
+
+      assert.throws(() => ns.state.getName(root.spanIdBig))
+      assert.throws(() => ns.state.getName(child.spanIdBig))
+      assert.strictEqual(typeof ns.state.getName(kept.spanIdBig), 'string')
+      const metrics = ns.state.getMetrics()
+      assert.strictEqual(metrics.liveSpans, liveBefore - 2)
+      assert.strictEqual(metrics.chunksDiscarded, 1)
+      assert.strictEqual(metrics.spansDiscarded, 2)
+      assert.strictEqual(metrics.chunksPrepared, 0)
+    })
+
+    it('discardSegment frees every span of the segment, including queued ones', () => {
+      const ns = new NativeSpansInterface()
+      const root = ns.createSpan()
+      root.setTraceTag('_dd.p.dm', '-0')
+      const children = [1, 2, 3].map(() => ns.createSpan(root.traceId, root.spanId))
+      const other = ns.createSpan()
+
+      assert.strictEqual(ns.state.discardSegment(BigInt(root.segmentId)), 4)
+      for (const span of [root, ...children]) {
+        assert.throws(() => ns.state.getName(span.spanIdBig))
+      }
+      assert.strictEqual(typeof ns.state.getName(other.spanIdBig), 'string')
+      // The segment's trace-level state is gone with its spans.
+      assert.strictEqual(ns.state.getTraceMetaAttr(BigInt(root.segmentId), '_dd.p.dm'), null)
+      assert.strictEqual(ns.state.discardSegment(BigInt(root.segmentId)), 0)
+      assert.strictEqual(ns.state.getMetrics().spansDiscarded, 4)
+    })
+
+    it('returns 0 for nothing to discard', () => {
+      const ns = new NativeSpansInterface()
+      assert.strictEqual(ns.state.discardChunk(new BigUint64Array()), 0)
+      assert.strictEqual(ns.state.discardSegment(12_345n), 0)
+      assert.strictEqual(ns.state.getMetrics().chunksDiscarded, 0)
+    })
+  })
+
+  describe('partial flush', () => {
+    async function withAgent (fn) {
+      await withMockAgent({}, (agent) => fn(new NativeSpansInterface({ agentUrl: agent.url }), agent.traces))
+    }
+
+    function longTrace (ns, finishedChildren) {
+      const root = ns.createSpan()
+      root.name = 'websocket'
+      root.setTraceTag('_dd.p.dm', '-4')
+      root.setTraceTag('_sampling_priority_v1', 2)
+      root.setTraceOrigin('synthetics')
+      const children = []
+      for (let i = 0; i < finishedChildren; i++) {
+        const child = ns.createSpan(root.traceId, root.spanId)
+        child.name = `message.${i}`
+        child.finish()
+        children.push(child)
+      }
+      return { root, children }
+    }
+
+    it('is off by default and waits for enough finished spans', () => {
+      const ns = new NativeSpansInterface()
+      const { root } = longTrace(ns, 2)
+      assert.strictEqual(ns.state.preparePartialChunk(root.segmentId), false)
+
+      ns.state.setPartialFlushMinSpans(3)
+      assert.strictEqual(ns.state.preparePartialChunk(root.segmentId), false)
+      assert.strictEqual(ns.state.getPreparedSpanCount(), 0)
+    })
+
+    it('exports finished spans with the trace tags and keeps the segment', async () => {
+      await withAgent(async (ns, payloads) => {
+        ns.state.setPartialFlushMinSpans(2)
+        const { root, children } = longTrace(ns, 2)
+
+        assert.strictEqual(ns.state.preparePartialChunk(root.segmentId), true)
+        assert.strictEqual(ns.state.getPreparedSpanCount(), 2)
+        await ns.state.sendPreparedChunk()
+
+        const first = payloads[0].body
+        assert(first.includes('message.0') && first.includes('message.1'))
+        assert(!first.includes('websocket'))
+        assert(first.includes('_dd.partial_version'))
+        assert(first.includes('_dd.p.dm') && first.includes('synthetics'))
+        for (const child of children) {
+          assert.throws(() => ns.state.getName(child.spanIdBig))
+        }
+        // The segment keeps its trace-level state for later chunks.
+        assert.strictEqual(root.getTraceTag('_dd.p.dm'), '-4')
+        assert.strictEqual(root.getTraceOrigin(), 'synthetics')
+
+        const late = ns.createSpan(root.traceId, root.spanId)
+        late.name = 'message.late'
+        late.finish()
+        root.finish()
+        await ns.flushSpans(root, late)
+        assert(payloads[1].body.includes('websocket') && payloads[1].body.includes('message.late'))
+        assert(payloads[1].body.includes('_dd.p.dm') && payloads[1].body.includes('synthetics'))
+        // ...and drops it with its last span.
+        assert.strictEqual(root.getTraceTag('_dd.p.dm'), null)
+        assert.strictEqual(root.getTraceOrigin(), null)
+
+        const metrics = ns.state.getMetrics()
+        assert.strictEqual(metrics.partialChunksPrepared, 1)
+        assert.strictEqual(metrics.chunksPrepared, 2)
+      })
+    })
+
+    it('numbers successive partial chunks of a trace', () => {
+      const ns = new NativeSpansInterface()
+      ns.state.setBatchLimits(1000, 0)
+      ns.state.setPartialFlushMinSpans(1)
+      const { root } = longTrace(ns, 1)
+      // Prepared, but the batch isn't due yet.
+      assert.strictEqual(ns.state.preparePartialChunk(root.segmentId), false)
+      assert.strictEqual(ns.state.getPreparedSpanCount(), 1)
+      ns.createSpan(root.traceId, root.spanId).finish()
+      ns.state.preparePartialChunk(root.segmentId)
+      assert.strictEqual(ns.state.getPreparedSpanCount(), 2)
+      assert.strictEqual(ns.state.getMetrics().partialChunksPrepared, 2)
+    })
+
+    it('leaves a trace whose spans have all finished to prepareChunk', () => {
+      const ns = new NativeSpansInterface()
+      ns.state.setPartialFlushMinSpans(1)
+      const { root } = longTrace(ns, 1)
+      root.finish()
+      assert.strictEqual(ns.state.preparePartialChunk(root.segmentId), false)
+      assert.strictEqual(ns.state.getPreparedSpanCount(), 0)
+    })
+  })
+
+  describe('orphan reaping', () => {
+    const later = () => BigInt(Date.now()) * 1_000_000n + 60_000_000_000n
+
+    function makeTraces (ns) {
+      const finished = ns.createSpan()
+      finished.finish()
+      const root = ns.createSpan()
+      const child = ns.createSpan(root.traceId, root.spanId)
+      child.finish()
+      return { finished, root, child }
+    }
+
+    it('does nothing until a max span age is set', () => {
+      const ns = new NativeSpansInterface()
+      makeTraces(ns)
+      assert.strictEqual(ns.state.reapOrphans(later()), false)
+    })
+
+    it('drops traces holding a span unfinished past the max age', () => {
+      const ns = new NativeSpansInterface()
+      ns.state.setMaxSpanAge(1000, false)
+      const { finished, root, child } = makeTraces(ns)
+
+      assert.strictEqual(ns.state.reapOrphans(BigInt(Date.now()) * 1_000_000n), false)
+      // Dropped: nothing to send.
+      assert.strictEqual(ns.state.reapOrphans(later()), false)
+
+      assert.throws(() => ns.state.getName(root.spanIdBig))
+      assert.throws(() => ns.state.getName(child.spanIdBig))
+      assert.strictEqual(typeof ns.state.getName(finished.spanIdBig), 'string')
+      assert.strictEqual(ns.state.getPreparedSpanCount(), 0)
+      const metrics = ns.state.getMetrics()
+      assert.strictEqual(metrics.orphanChunksReaped, 1)
+      assert.strictEqual(metrics.orphanSpansReaped, 2)
+      assert.strictEqual(metrics.orphanChunksDropped, 1)
+      assert.strictEqual(metrics.orphanSpansDropped, 2)
+    })
+
+    it('skips reaped spans when the host prepares their chunk later', () => {
+      const ns = new NativeSpansInterface()
+      ns.state.setMaxSpanAge(1000, false)
+      const { finished, root, child } = makeTraces(ns)
+      ns.state.reapOrphans(later())
+
+      // Only reaped spans: nothing to send.
+      assert.strictEqual(ns.prepare(child), false)
+      assert.strictEqual(ns.state.getPreparedSpanCount(), 0)
+      // The reaped root is skipped; the live span is prepared.
+      assert.strictEqual(ns.prepare(root, finished), true)
+      assert.strictEqual(ns.state.getPreparedSpanCount(), 1)
+    })
+
+    it('still rejects ids that were never live', () => {
+      const ns = new NativeSpansInterface()
+      ns.state.setMaxSpanAge(1000, false)
+      const { finished, root } = makeTraces(ns)
+      ns.state.reapOrphans(later())
+
+      const unknown = new Uint8Array(8).fill(0xEE)
+      assert.throws(() => ns.prepare(root, unknown, finished), { name: 'UnknownSpanError' })
+      // Nothing was taken out: the live span is still there.
+      assert.strictEqual(typeof ns.state.getName(finished.spanIdBig), 'string')
+      // A reaped id is skipped once; listing it again is an error too.
+      assert.strictEqual(ns.prepare(root, finished), true)
+      assert.throws(() => ns.prepare(root), { name: 'UnknownSpanError' })
+    })
+
+    it('exports reaped traces as partial chunks marked as errors', async () => {
+      await withMockAgent({}, async (agent) => {
+        const ns = new NativeSpansInterface({ agentUrl: agent.url })
+        ns.state.setMaxSpanAge(1000, true)
+        const { root } = makeTraces(ns)
+        root.name = 'abandoned'
+        root.setTraceTag('_dd.p.dm', '-4')
+        root.setTraceOrigin('synthetics')
+
+        assert.strictEqual(ns.state.reapOrphans(later()), true)
+        assert.strictEqual(ns.state.getPreparedSpanCount(), 2)
+        await ns.state.sendPreparedChunk()
+
+        assert.strictEqual(agent.traces.length, 1)
+        const body = agent.traces[0].body.toString('latin1')
+        assert(body.includes('abandoned'))
+        assert(body.includes('_dd.orphaned'))
+        assert(body.includes('span was never finished'))
+        assert(body.includes('_dd.p.dm') && body.includes('synthetics'))
+        assert(body.includes('_dd.partial_version'))
+        assert.strictEqual(ns.state.getMetrics().chunksSent, 1)
+      })
+    })
+
+    it('keeps exported orphans when the next chunk is prepared', async () => {
+      await withMockAgent({}, async (agent) => {
+        const ns = new NativeSpansInterface({ agentUrl: agent.url })
+        ns.state.setMaxSpanAge(1000, true)
+        const { finished } = makeTraces(ns)
+        assert.strictEqual(ns.state.reapOrphans(later()), true)
+
+        // Without batch limits this would recycle an unsent chunk.
+        assert.strictEqual(ns.prepare(finished), true)
+        assert.strictEqual(ns.state.getPreparedSpanCount(), 3)
+        await ns.state.sendPreparedChunk()
+        assert(agent.traces[0].body.toString('latin1').includes('_dd.orphaned'))
+        const metrics = ns.state.getMetrics()
+        assert.strictEqual(metrics.staleChunksRecycled, 0)
+        assert.strictEqual(metrics.spansSent, 3)
+      })
+    })
+
+    it('turns reaping off with a zero age', () => {
+      const ns = new NativeSpansInterface()
+      ns.state.setMaxSpanAge(1000, false)
+      ns.state.setMaxSpanAge(0, false)
+      makeTraces(ns)
+      assert.strictEqual(ns.state.reapOrphans(later()), false)
+    })
+  })
+
+  describe('dropping P0 traces', () => {
+    async function withAgent (fn) {
+      const statuses = []
+      const respond = (req) => ({ status: req.url.endsWith('/traces') ? statuses.shift() ?? 200 : 200 })
+      await withMockAgent({ respond }, async (agent) => {
+        const ns = new NativeSpansInterface({ agentUrl: agent.url, statsEnabled: true })
+        ns.state.setDropP0Traces(true)
+        ns.state.setSamplingRules('[{"service": "p0-drop", "sample_rate": 0}]')
+        await fn(ns, agent.traces, statuses)
+      })
+    }
+
+    function makeSpan (ns, service, name) {
+      const span = ns.createSpan()
+      span.name = name
+      span.service = service
+      span.duration = 1_000_000n
+      return span
+    }
+
+    it('drops rejected chunks and reports them with the next payload', async () => {
+      await withAgent(async (ns, posts) => {
+        const rejected = makeSpan(ns, 'p0-drop', 'p0-rejected')
+        assert.strictEqual(await ns.flushSpans(rejected), false, 'rejected chunk is not sent')
+
+        await ns.flushSpans(makeSpan(ns, 'p0-keep', 'p0-kept'))
+        assert.strictEqual(posts.length, 1)
+        assert.ok(posts[0].body.includes('p0-kept'))
+        assert.ok(!posts[0].body.includes('p0-rejected'))
+        assert.strictEqual(posts[0].headers['datadog-client-computed-stats'], 'yes')
+        assert.strictEqual(posts[0].headers['datadog-client-dropped-p0-traces'], '1')
+        assert.strictEqual(posts[0].headers['datadog-client-dropped-p0-spans'], '1')
+
+        // Counts are reset once reported.
+        await ns.flushSpans(makeSpan(ns, 'p0-keep', 'p0-kept-again'))
+        assert.strictEqual(posts[1].headers['datadog-client-computed-stats'], 'yes')
+        assert.strictEqual(posts[1].headers['datadog-client-dropped-p0-traces'], undefined)
+      })
+    })
+
+    it('keeps the dropped counts until the agent accepts a payload', async () => {
+      await withAgent(async (ns, posts, statuses) => {
+        await ns.flushSpans(makeSpan(ns, 'p0-drop', 'p0-rejected'))
+
+        statuses.push(400)
+        await assert.rejects(ns.flushSpans(makeSpan(ns, 'p0-keep', 'p0-refused')))
+        assert.strictEqual(posts[0].headers['datadog-client-dropped-p0-traces'], '1')
+
+        await ns.flushSpans(makeSpan(ns, 'p0-keep', 'p0-kept'))
+        const last = posts[posts.length - 1]
+        assert.strictEqual(last.headers['datadog-client-dropped-p0-traces'], '1')
+        assert.strictEqual(last.headers['datadog-client-dropped-p0-spans'], '1')
+      })
+    })
+
+    it('still sends rejected chunks that contain an error', async () => {
+      await withAgent(async (ns, posts) => {
+        const span = makeSpan(ns, 'p0-drop', 'p0-error')
+        span.error = 1
+        await ns.flushSpans(span)
+        assert.strictEqual(posts.length, 1)
+        assert.ok(posts[0].body.includes('p0-error'))
+      })
+    })
+
+    it('has no effect when stats are disabled', async () => {
+      const ns = new NativeSpansInterface({ statsEnabled: false })
+      ns.state.setDropP0Traces(true)
+      ns.state.setSamplingRules('[{"sample_rate": 0}]')
+      assert.strictEqual(ns.prepare(makeSpan(ns, 'p0-drop', 'p0-no-stats')), true)
+    })
+  })
+
+  describe('agent info discovery', () => {
+    // Stub agent serving a configurable `agent.info`. Trace responses carry
+    // the current `agent.state` as Datadog-Agent-State, like a real agent.
+    async function withAgent (info, fn) {
+      const respond = (req, agent) => {
+        const headers = { 'datadog-agent-state': agent.state }
+        if (req.url !== '/info') return { headers }
+        return agent.info ? { headers, body: JSON.stringify(agent.info) } : { status: 404, body: '' }
+      }
+      await withMockAgent({ respond }, async (agent) => {
+        Object.assign(agent, { info, state: 'state-1' })
+        await fn(new NativeSpansInterface({ agentUrl: agent.url, statsEnabled: true }), agent)
+      })
+    }
+
+    function makeSpan (ns, name) {
+      const span = ns.createSpan()
+      span.name = name
+      span.service = 'info-svc'
+      span.duration = 1_000_000n
+      return span
+    }
+
+    const fullInfo = {
+      version: '7.60.0',
+      endpoints: ['/v0.4/traces', '/v0.5/traces', '/v0.6/stats'],
+      client_drop_p0s: true,
+      peer_tags: ['peer.service', 'db.instance'],
+      feature_flags: ['discovery'],
+    }
+
+    it('caches /info and exposes it through getAgentInfo', async () => {
+      await withAgent(fullInfo, async (ns) => {
+        assert.strictEqual(ns.state.getAgentInfo(), null)
+        await ns.state.refreshAgentInfo()
+        const info = ns.state.getAgentInfo()
+        assert.strictEqual(info.version, '7.60.0')
+        assert.deepStrictEqual(info.peer_tags, ['peer.service', 'db.instance'])
+        assert.deepStrictEqual(info.feature_flags, ['discovery'])
+        assert.strictEqual(info.client_drop_p0s, true)
+      })
+    })
+
+    it('falls back to /v0.4/traces when the agent lacks /v0.5/traces', async () => {
+      await withAgent({ endpoints: ['/v0.4/traces', '/v0.6/stats'] }, async (ns, agent) => {
+        ns.state.setUseV05(true)
+        await ns.state.refreshAgentInfo()
+        await ns.flushSpans(makeSpan(ns, 'info-v04'))
+        assert.strictEqual(agent.requests.find(r => r.method === 'POST').url, '/v0.4/traces')
+      })
+    })
+
+    it('disables client stats when the agent lacks /v0.6/stats', async () => {
+      await withAgent({ endpoints: ['/v0.4/traces'] }, async (ns, agent) => {
+        await ns.state.refreshAgentInfo()
+        await ns.flushSpans(makeSpan(ns, 'info-no-stats'))
+        const post = agent.requests.find(r => r.method === 'POST')
+        assert.strictEqual(post.headers['datadog-client-computed-stats'], undefined)
+        assert.strictEqual(await ns.state.flushStats(true), false)
+        assert.ok(!agent.requests.some(r => r.url === '/v0.6/stats'))
+      })
+    })
+
+    it('refetches /info when the agent state changes', async () => {
+      await withAgent(fullInfo, async (ns, agent) => {
+        await ns.state.refreshAgentInfo()
+        await ns.flushSpans(makeSpan(ns, 'info-first'))
+        assert.strictEqual(agent.requests.filter(r => r.url === '/info').length, 1)
+
+        agent.state = 'state-2'
+        agent.info = { ...fullInfo, peer_tags: ['out.host'] }
+        await ns.flushSpans(makeSpan(ns, 'info-second')) // response carries state-2
+        await ns.flushSpans(makeSpan(ns, 'info-third')) // refreshes before sending
+        assert.strictEqual(agent.requests.filter(r => r.url === '/info').length, 2)
+        assert.deepStrictEqual(ns.state.getAgentInfo().peer_tags, ['out.host'])
+      })
+    })
+
+    it('only follows the state of its own agent', async () => {
+      await withAgent(fullInfo, async (ns, agent) => {
+        await withAgent(fullInfo, async (other, otherAgent) => {
+          await ns.state.refreshAgentInfo()
+          await other.state.refreshAgentInfo()
+
+          otherAgent.state = 'state-2'
+          await other.flushSpans(makeSpan(other, 'info-other')) // response carries state-2
+          await ns.flushSpans(makeSpan(ns, 'info-own'))
+          await ns.flushSpans(makeSpan(ns, 'info-own-again'))
+          assert.strictEqual(agent.requests.filter(r => r.url === '/info').length, 1)
+
+          await other.flushSpans(makeSpan(other, 'info-other-again'))
+          assert.strictEqual(otherAgent.requests.filter(r => r.url === '/info').length, 2)
+        })
+      })
+    })
+
+    it('refetches /info before a stats flush', async () => {
+      await withAgent(fullInfo, async (ns, agent) => {
+        await ns.state.refreshAgentInfo()
+        agent.state = 'state-2'
+        agent.info = { ...fullInfo, endpoints: ['/v0.4/traces'] }
+        await ns.flushSpans(makeSpan(ns, 'info-stats')) // response carries state-2
+        assert.strictEqual(agent.requests.filter(r => r.url === '/info').length, 1)
+
+        assert.strictEqual(await ns.state.flushStats(true), false)
+        assert.strictEqual(agent.requests.filter(r => r.url === '/info').length, 2)
+        assert.ok(!agent.requests.some(r => r.url === '/v0.6/stats'))
+      })
+    })
+
+    it('rejects when /info is unavailable', async () => {
+      await withAgent(null, async (ns) => {
+        await assert.rejects(ns.state.refreshAgentInfo(), /refreshAgentInfo/)
+        assert.strictEqual(ns.state.getAgentInfo(), null)
+      })
+    })
+  })
+
+  describe('shutdown', () => {
+    async function withAgent ({ hang = false } = {}, fn) {
+      await withMockAgent({ respond: hang ? () => null : undefined }, async (agent) => {
+        await fn(new NativeSpansInterface({ agentUrl: agent.url, statsEnabled: true }), agent.requests)
+      })
+    }
+
+    function prepareOne (ns, name) {
+      const span = ns.createSpan()
+      span.name = name
+      span.service = 'shutdown-svc'
+      span.duration = 1_000_000n
+      return ns.prepare(span)
+    }
+
+    it('sends prepared chunks and the final stats bucket', async () => {
+      await withAgent({}, async (ns, seen) => {
+        ns.state.setBatchLimits(100, 0)
+        assert.strictEqual(prepareOne(ns, 'shutdown-a'), false)
+        assert.strictEqual(prepareOne(ns, 'shutdown-b'), false)
+        const report = await ns.state.shutdown(5000)
+        assert.strictEqual(report.timedOut, false)
+        assert.strictEqual(report.sentChunks, 2)
+        assert.strictEqual(report.sentSpans, 2)
+        assert.strictEqual(report.droppedSpans, 0)
+        assert.strictEqual(report.statsFlushed, true)
+        assert.deepStrictEqual(report.errors, [])
+        assert.ok(seen.some(r => r.method === 'POST' && r.url === '/v0.4/traces'))
+        assert.ok(seen.some(r => r.method === 'PUT' && r.url === '/v0.6/stats'))
+      })
+    })
+
+    it('reports what it dropped when the deadline passes', async () => {
+      await withAgent({ hang: true }, async (ns) => {
+        prepareOne(ns, 'shutdown-hang')
+        const report = await ns.state.shutdown(50)
+        assert.strictEqual(report.timedOut, true)
+        assert.strictEqual(report.droppedChunks, 1)
+        assert.strictEqual(report.droppedSpans, 1)
+        assert.strictEqual(report.statsFlushed, false)
+      })
+    })
+
+    it('fails every later call', async () => {
+      const ns = new NativeSpansInterface()
+      await ns.state.shutdown(100)
+      assert.throws(() => prepareOne(ns, 'after-shutdown'), /shut down/)
+      assert.throws(() => ns.state.flushChangeQueue(), /flushChangeQueue: .*shut down/)
+      await assert.rejects(ns.state.sendPreparedChunk(), /shut down/)
+      await assert.rejects(ns.state.flushStats(true), /shut down/)
+      await assert.rejects(ns.state.shutdown(100), /shutdown: .*shut down/)
+    })
+
+    it('rejects configuration after shutdown', async () => {
+      const ns = new NativeSpansInterface()
+      await ns.state.shutdown(100)
+      assert.throws(() => ns.state.setUseV05(true), /setUseV05: .*shut down/)
+      assert.throws(() => ns.state.setBatchLimits(10, 0), /setBatchLimits: .*shut down/)
+      assert.throws(() => ns.state.setStatsPeerTags([]), /setStatsPeerTags: .*shut down/)
+      assert.throws(() => ns.state.setMaxSpanAge(1000, false), /setMaxSpanAge: .*shut down/)
+      assert.throws(() => ns.state.stringTableInsertOne(1, 'x'), /stringTableInsertOne: .*shut down/)
+      assert.throws(() => ns.state.getAgentSampleRate('svc', 'env'), /getAgentSampleRate: .*shut down/)
+      assert.throws(() => ns.state.flushHealthMetrics(), /flushHealthMetrics: .*shut down/)
+    })
+  })
+
+  describe('cancellable sends', () => {
+    // Agent that accepts connections but never answers.
+    async function withHungAgent (fn) {
+      await withMockAgent({ respond: () => null }, async (agent) => {
+        await fn(new NativeSpansInterface({ agentUrl: agent.url, statsEnabled: true }))
+      })
+    }
+
+    function prepareOne (ns) {
+      const span = ns.createSpan()
+      span.name = 'cancel-span'
+      span.service = 'cancel-svc'
+      span.duration = 1_000_000n
+      return ns.prepare(span)
+    }
+
+    it('rejects sendPreparedChunk with a TimeoutError after timeoutMs', async () => {
+      await withHungAgent(async (ns) => {
+        prepareOne(ns)
+        await assert.rejects(ns.state.sendPreparedChunk(50), { name: 'TimeoutError', message: /sendPreparedChunk/ })
+        // The in-flight guard is released, so a new send can start.
+        prepareOne(ns)
+        await assert.rejects(ns.state.sendPreparedChunk(50), { name: 'TimeoutError' })
+      })
+    })
+
+    it('rejects sendPreparedChunk with an AbortError when the signal aborts', async () => {
+      await withHungAgent(async (ns) => {
+        prepareOne(ns)
+        const controller = new AbortController()
+        setTimeout(() => controller.abort(), 20)
+        await assert.rejects(ns.state.sendPreparedChunk(undefined, controller.signal), { name: 'AbortError' })
+      })
+    })
+
+    it('applies the timeout to flushStats', async () => {
+      await withHungAgent(async (ns) => {
+        prepareOne(ns)
+        await assert.rejects(ns.state.flushStats(true, 50), { name: 'TimeoutError', message: /flushStats/ })
+      })
+    })
+  })
+
+  describe('typed errors', () => {
+    function prepareOne (ns) {
+      const span = ns.createSpan()
+      span.name = 'typed-error'
+      span.duration = 1_000_000n
+      return ns.prepare(span)
+    }
+
+    it('tags invalid input and unknown spans with a name and code', () => {
+      assert.throws(() => nativeSpans.state.getName(0xDE_AD_BE_EFn), {
+        name: 'UnknownSpanError', code: 'ERR_UNKNOWN_SPAN', retryable: false,
+      })
+      assert.throws(() => nativeSpans.state.setStatsBucketDuration(0), {
+        name: 'InvalidInputError', code: 'ERR_INVALID_INPUT',
+      })
+    })
+
+    it('reports the status and Retry-After of a rejected payload', async () => {
+      const respond = () => ({ status: 503, headers: { 'retry-after': '2' }, body: '' })
+      await withMockAgent({ respond }, async (agent) => {
+        const ns = new NativeSpansInterface({ agentUrl: agent.url })
+        prepareOne(ns)
+        await assert.rejects(ns.state.sendPreparedChunk(), {
+          name: 'HttpStatusError',
+          code: 'ERR_HTTP_STATUS',
+          statusCode: 503,
+          retryAfterMs: 2000,
+          retryable: true,
+        })
+      })
+    })
+
+    it('reports an unreachable agent as a retryable NetworkError', async () => {
+      const net = require('node:net')
+      const probe = net.createServer()
+      await new Promise(resolve => probe.listen(0, '127.0.0.1', resolve))
+      const { port } = probe.address()
+      await new Promise(resolve => probe.close(resolve))
+
+      const ns = new NativeSpansInterface({ agentUrl: `http://127.0.0.1:${port}` })
+      prepareOne(ns)
+      await assert.rejects(ns.state.sendPreparedChunk(), {
+        name: 'NetworkError', code: 'ERR_NETWORK', retryable: true,
+      })
+    })
+  })
+
+  describe('getMetrics', () => {
+    function prepareOne (ns, name) {
+      const span = ns.createSpan()
+      span.name = name
+      span.duration = 1_000_000n
+      return ns.prepare(span)
+    }
+
+    it('reports live spans, segments and string-table entries', () => {
+      const ns = new NativeSpansInterface()
+      const before = ns.state.getMetrics()
+      ns.getStringId('metrics-live-a')
+      ns.getStringId('metrics-live-b')
+      const span = ns.createSpan()
+      span.name = 'metrics-live'
+      ns.flushChangeQueue()
+
+      const live = ns.state.getMetrics()
+      assert.strictEqual(live.liveSpans, before.liveSpans + 1)
+      assert.strictEqual(live.liveSegments, before.liveSegments + 1)
+      assert.ok(live.stringTableEntries >= before.stringTableEntries + 2)
+
+      ns.state.stringTableEvict(ns.stringMap.get('metrics-live-a'))
+      assert.strictEqual(ns.state.getMetrics().stringTableEntries, live.stringTableEntries - 1)
+
+      span.duration = 1_000_000n
+      assert.ok(ns.prepare(span))
+      const after = ns.state.getMetrics()
+      assert.strictEqual(after.liveSpans, before.liveSpans)
+      assert.strictEqual(after.liveSegments, before.liveSegments)
+    })
+
+    it('counts prepared, recycled, sent and failed chunks', async () => {
+      let status = 200
+      await withMockAgent({ respond: () => ({ status }) }, async (agent) => {
+        const ns = new NativeSpansInterface({ agentUrl: agent.url })
+        prepareOne(ns, 'stale')
+        prepareOne(ns, 'sent')
+        await ns.state.sendPreparedChunk()
+        status = 404
+        prepareOne(ns, 'failed')
+        await assert.rejects(ns.state.sendPreparedChunk(), { name: 'HttpStatusError' })
+
+        const metrics = ns.state.getMetrics()
+        assert.strictEqual(metrics.chunksPrepared, 3)
+        assert.strictEqual(metrics.spansPrepared, 3)
+        assert.strictEqual(metrics.maxSpansPerChunk, 1)
+        assert.strictEqual(metrics.staleChunksRecycled, 1)
+        assert.strictEqual(metrics.payloadsSent, 1)
+        assert.strictEqual(metrics.chunksSent, 1)
+        assert.ok(metrics.bytesSent > 0)
+        assert.strictEqual(metrics.chunksFailed, 1)
+        assert.deepStrictEqual(metrics.sendErrors, { '4xx': 1 })
+        assert.strictEqual(metrics.pendingChunks, 0)
+        assert.strictEqual(typeof metrics.liveSpans, 'number')
+        assert.strictEqual(typeof metrics.stringTableEntries, 'number')
+      })
+    })
+
+    it('reports connection reuse across sends', async () => {
+      await withMockAgent({}, async (agent) => {
+        const ns = new NativeSpansInterface({ agentUrl: agent.url })
+        const before = ns.state.getMetrics()
+        for (const name of ['first', 'second', 'third']) {
+          prepareOne(ns, name)
+          await ns.state.sendPreparedChunk()
+        }
+        const after = ns.state.getMetrics()
+        assert.strictEqual(agent.connections, 1)
+        assert.strictEqual(after.connectionsOpened - before.connectionsOpened, 1)
+        assert.strictEqual(after.connectionsReused - before.connectionsReused, 2)
+      })
+    })
+  })
+
+  describe('DogStatsD health metrics', () => {
+    it('emits health counters to a UDP listener', async () => {
+      const dgram = require('node:dgram')
+      const listener = dgram.createSocket('udp4')
+      await new Promise(resolve => listener.bind(0, '127.0.0.1', resolve))
+      const messages = []
+      listener.on('message', msg => messages.push(...msg.toString('utf8').split('\n')))
+
+      const ns = new NativeSpansInterface()
+      try {
+        assert.strictEqual(ns.state.flushHealthMetrics(), false)
+        assert.throws(() => ns.state.setHealthMetrics('http://127.0.0.1:8125', [], 10_000), {
+          code: 'ERR_INVALID_INPUT',
+        })
+        ns.state.setHealthMetrics(`udp://127.0.0.1:${listener.address().port}`, ['env:test'], 10_000)
+
+        const span = ns.createSpan()
+        span.name = 'health'
+        ns.prepare(span)
+        assert.strictEqual(ns.state.flushHealthMetrics(), true)
+
+        for (let i = 0; i < 50 && !messages.some(m => m.startsWith('datadog.tracer.spans.live')); i++) {
+          await new Promise(resolve => setTimeout(resolve, 10))
+        }
+        assert.ok(messages.includes('datadog.tracer.queue.enqueued.traces:1|c|#env:test'), messages.join('\n'))
+        assert.ok(messages.includes('datadog.tracer.queue.enqueued.spans:1|c|#env:test'))
+        assert.ok(messages.some(m => /^datadog\.tracer\.spans\.live:\d+\|g\|#env:test$/.test(m)))
+      } finally {
+        ns.state.setHealthMetrics('', [], 0)
+        listener.close()
+      }
+    })
+
+    it('emits on a timer without any sends', async () => {
+      const dgram = require('node:dgram')
+      const listener = dgram.createSocket('udp4')
+      await new Promise(resolve => listener.bind(0, '127.0.0.1', resolve))
+      let payloads = 0
+      listener.on('message', () => payloads++)
+
+      const ns = new NativeSpansInterface()
+      ns.state.setHealthMetrics(`udp://127.0.0.1:${listener.address().port}`, [], 10)
+      const running = ns.state.runHealthMetrics()
+      try {
+        for (let i = 0; i < 50 && payloads < 2; i++) {
+          await new Promise(resolve => setTimeout(resolve, 10))
+        }
+        assert.ok(payloads >= 2, `got ${payloads} payloads`)
+      } finally {
+        ns.state.setHealthMetrics('', [], 0)
+        await running
+        listener.close()
+      }
+    })
+
+    it('rejects unix datagram sockets and a zero interval', () => {
+      const ns = new NativeSpansInterface()
+      assert.throws(() => ns.state.setHealthMetrics('unix:///var/run/datadog/dsd.socket', [], 10_000), {
+        code: 'ERR_INVALID_INPUT',
+        message: /unixstream:\/\//,
+      })
+      assert.throws(() => ns.state.setHealthMetrics('udp://127.0.0.1:8125', [], 0), {
+        code: 'ERR_INVALID_INPUT',
+      })
+    })
+  })
+
+  describe('log-output mode', () => {
+    it('writes payloads to the log sink instead of the agent', async () => {
+      const lines = []
+      pipeline.setLogOutputSink(line => lines.push(line))
+      try {
+        await withMockAgent({}, async (agent) => {
+          const ns = new NativeSpansInterface({ agentUrl: agent.url, statsEnabled: true })
+          ns.state.setLogOutput(true)
+          const span = ns.createSpan()
+          span.name = 'log-output-span'
+          span.duration = 1_000_000n
+          ns.prepare(span)
+          await ns.state.sendPreparedChunk()
+
+          assert.ok(lines.length > 0)
+          assert.ok(lines.some(line => line.includes('log-output-span')))
+          assert.strictEqual(await ns.state.flushStats(true), false)
+          assert.strictEqual(agent.requests.length, 0)
+        })
+      } finally {
+        pipeline.setLogOutputSink(null)
+      }
+    })
+  })
+
+  describe('agentless export', () => {
+    const API_KEY = 'abcdef0123456789abcdef0123456789'
+
+    async function withIntake (status, fn) {
+      await withMockAgent({ respond: () => ({ status }) }, async (intake) => {
+        // The agent URL points at a closed port: nothing may go there.
+        const ns = new NativeSpansInterface({ agentUrl: 'http://127.0.0.1:1', statsEnabled: true })
+        ns.state.setAgentless('datadoghq.com', API_KEY, intake.url)
+        await fn(ns, intake.requests)
+      })
+    }
+
+    function prepareOne (ns) {
+      const span = ns.createSpan()
+      span.name = 'agentless-span'
+      span.service = 'agentless-svc'
+      span.duration = 1_000_000n
+      return ns.prepare(span)
+    }
+
+    it('sends traces and stats to the intake with the API key', async () => {
+      await withIntake(202, async (ns, requests) => {
+        prepareOne(ns)
+        await ns.state.sendPreparedChunk()
+        assert.strictEqual(await ns.state.flushStats(true), true)
+
+        const traces = requests.find(r => r.url === '/api/v0.2/traces')
+        assert.ok(traces, 'traces sent to the intake')
+        assert.strictEqual(traces.method, 'POST')
+        assert.strictEqual(traces.headers['dd-api-key'], API_KEY)
+        assert.strictEqual(traces.headers['content-type'], 'application/x-protobuf')
+        assert.ok(traces.body.includes('agentless-span'))
+
+        const stats = requests.find(r => r.url === '/api/v0.2/stats')
+        assert.ok(stats, 'stats sent to the intake')
+        assert.strictEqual(stats.headers['dd-api-key'], API_KEY)
+      })
+    })
+
+    it('keeps the API key out of errors', async () => {
+      // An intake host named after the key: the DNS failure quotes it.
+      const ns = new NativeSpansInterface({ agentUrl: 'http://127.0.0.1:1' })
+      ns.state.setAgentless('datadoghq.com', API_KEY, `http://${API_KEY}.invalid`)
+      pipeline.setProxy({})
+      try {
+        prepareOne(ns)
+        await assert.rejects(ns.state.sendPreparedChunk(), (err) => {
+          assert.strictEqual(err.name, 'NetworkError')
+          assert.match(err.message, /<redacted>\.invalid/)
+          assert.ok(!err.message.includes(API_KEY))
+          return true
+        })
+      } finally {
+        pipeline.setProxy(null)
+      }
+    })
+
+    it('rejects a missing API key', () => {
+      const ns = new NativeSpansInterface()
+      assert.throws(() => ns.state.setAgentless('datadoghq.com', ''), { code: 'ERR_INVALID_INPUT' })
+    })
+  })
+
+  describe('proxy', () => {
+    it('sends traces through the configured forward proxy', async () => {
+      // The mock agent stands in for the proxy. The agent host doesn't
+      // resolve: only the proxy can deliver the request.
+      await withMockAgent({}, async (proxy) => {
+        const ns = new NativeSpansInterface({ agentUrl: 'http://agent.invalid:8126' })
+        try {
+          pipeline.setProxy({ httpProxy: proxy.url })
+          const span = ns.createSpan()
+          span.name = 'proxied'
+          ns.prepare(span)
+          await ns.state.sendPreparedChunk()
+          const [traces] = proxy.traces
+          assert.ok(traces, 'trace request reached the proxy')
+          assert.match(traces.url, /^http:\/\/agent\.invalid:8126\/v0\.[45]\/traces$/)
+          assert.strictEqual(traces.headers.host, 'agent.invalid:8126')
+        } finally {
+          pipeline.setProxy(null)
+        }
+      })
+    })
+
+    it('bypasses the proxy for NO_PROXY hosts', async () => {
+      const ns = new NativeSpansInterface({ agentUrl: 'http://agent.invalid:1' })
+      try {
+        pipeline.setProxy({ httpProxy: 'http://127.0.0.1:2', noProxy: 'agent.invalid' })
+        const span = ns.createSpan()
+        ns.prepare(span)
+        await assert.rejects(ns.state.sendPreparedChunk(), (err) => {
+          assert.strictEqual(err.code, 'ERR_NETWORK')
+          assert.match(err.message, /agent\.invalid/)
+          assert.doesNotMatch(err.message, /127\.0\.0\.1:2\b/)
+          return true
+        })
+      } finally {
+        pipeline.setProxy(null)
+      }
+    })
+
+    it('never proxies a loopback agent', async () => {
+      const ns = new NativeSpansInterface({ agentUrl: 'http://127.0.0.1:1' })
+      try {
+        pipeline.setProxy({ httpProxy: 'http://127.0.0.1:2' })
+        const span = ns.createSpan()
+        ns.prepare(span)
+        await assert.rejects(ns.state.sendPreparedChunk(), (err) => {
+          assert.strictEqual(err.code, 'ERR_NETWORK')
+          assert.match(err.message, /127\.0\.0\.1:1\b/)
+          return true
+        })
+      } finally {
+        pipeline.setProxy(null)
+      }
+    })
+  })
+
+  describe('TLS options', () => {
+    function prepareOne (ns) {
+      const span = ns.createSpan()
+      return ns.prepare(span)
+    }
+
+    it('fails the exporter build on invalid material, every time', async () => {
+      const ns = new NativeSpansInterface({ agentUrl: 'http://127.0.0.1:1' })
+      ns.state.setOtlpEndpoint('https://127.0.0.1:1/v1/traces')
+      ns.state.setTlsOptions({ cert: 'not a certificate', key: 'not a key' })
+      for (let i = 0; i < 2; i++) {
+        prepareOne(ns)
+        await assert.rejects(ns.state.sendPreparedChunk(), (err) => {
+          assert.strictEqual(err.name, 'NativeExporterBuildError')
+          assert.strictEqual(err.code, 'ERR_EXPORTER_BUILD')
+          assert.match(err.message, /invalid TLS options/)
+          return true
+        })
+      }
+    })
+
+    it('fails stats flushes and /info refreshes on invalid material too', async () => {
+      const ns = new NativeSpansInterface({ agentUrl: 'https://127.0.0.1:1', statsEnabled: true })
+      ns.state.setTlsOptions({ cert: 'not a certificate', key: 'not a key' })
+      const isBuildError = (err) => {
+        assert.strictEqual(err.code, 'ERR_EXPORTER_BUILD')
+        assert.match(err.message, /invalid TLS options/)
+        return true
+      }
+      await assert.rejects(ns.state.refreshAgentInfo(), isBuildError)
+      await assert.rejects(ns.state.flushStats(true), isBuildError)
+    })
+
+    it('accepts valid options', async () => {
+      const ns = new NativeSpansInterface({ agentUrl: 'http://127.0.0.1:1' })
+      ns.state.setTlsOptions({ servername: 'collector.internal', rejectUnauthorized: true })
+      prepareOne(ns)
+      // Nothing listens on the agent port; the send gets as far as the network.
+      await assert.rejects(ns.state.sendPreparedChunk(), { code: 'ERR_NETWORK' })
+    })
+  })
+
   describe('send re-entrancy', () => {
+    it('lets an overlapping flushStats wait for the one in flight', async () => {
+      await withMockAgent({}, async (agent) => {
+        const ns = new NativeSpansInterface({ agentUrl: agent.url, statsEnabled: true })
+        const span = ns.createSpan()
+        span.name = 'stats-overlap'
+        span.duration = 1_000_000n
+
+        await ns.flushSpans(span)
+        const flushed = await Promise.all([
+          ns.state.flushStats(true),
+          ns.state.flushStats(true),
+        ])
+        // The second waits, then finds nothing left to send.
+        assert.deepStrictEqual(flushed, [true, false])
+        assert.strictEqual(agent.requests.filter(r => r.url === '/v0.6/stats').length, 1)
+      })
+    })
+
     it('rejects an overlapping sendPreparedChunk call', async () => {
       const http = require('node:http')
       const server = http.createServer((req, res) => {
@@ -1027,9 +2683,9 @@ describe('pipeline', { skip }, () => {
         ])
         const reasons = settled
           .filter(s => s.status === 'rejected')
-          .map(s => String(s.reason))
+          .map(s => s.reason)
         assert.ok(
-          reasons.some(r => /already in flight/.test(r)),
+          reasons.some(r => r.code === 'ERR_REENTRANT_CALL' && /already in flight/.test(r.message)),
           'one overlapping call rejected as already-in-flight',
         )
       } finally {